@@ -0,0 +1,98 @@
+use lsm_storage_engine::bloom::BloomKey;
+use lsm_storage_engine::sstable::{SSTable, SSTableBuilder};
+use lsm_storage_engine::{Engine, EngineOptions, Entry};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+const TABLES: usize = 64;
+const KEYS_PER_TABLE: usize = 1_000;
+const SPARSE_INTERVAL: usize = 16;
+const LOOKUPS: usize = 200_000;
+
+fn setup_test_dir(name: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!(
+        "lsm_bench_{}_{}",
+        name,
+        SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+fn report(label: &str, elapsed: Duration) {
+    println!("{}:", label);
+    println!("  Total Time:   {:?}", elapsed);
+    println!(
+        "  Throughput:   {:.2} ops/sec",
+        LOOKUPS as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() -> std::io::Result<()> {
+    let dir = setup_test_dir("bloom_miss");
+    // The tables are written directly rather than flushed by an engine, whose background
+    // compaction would merge some of them while they are being written.
+    for t in 0..TABLES {
+        let mut builder = SSTableBuilder::new(dir.join(format!("{:020}.sst", t)), SPARSE_INTERVAL)?;
+        for i in 0..KEYS_PER_TABLE {
+            let key = format!("key{:03}{:06}", t, i).into_bytes();
+            builder.add_record(&key, t as u64 + 1, &Entry::Value(vec![0u8; 32]))?;
+        }
+        builder.finish()?;
+    }
+    Engine::repair(&dir, EngineOptions::default())?;
+    // A read-only engine runs no compaction, so every lookup sees all of the tables.
+    let engine = Engine::open_readonly(&dir, EngineOptions::default(), false)?;
+    assert_eq!(engine.stats().sstable_count, TABLES);
+    println!(
+        "--- Benchmarking {} lookups across {} SSTables ---",
+        LOOKUPS, TABLES
+    );
+
+    let misses: Vec<Vec<u8>> = (0..LOOKUPS)
+        .map(|i| format!("missing{:010}", i).into_bytes())
+        .collect();
+
+    // A miss consults every table's filter; these two compare hashing the key once for all
+    // of them with hashing it again for each one.
+    let tables = (0..TABLES)
+        .map(|t| SSTable::open(dir.join(format!("{:020}.sst", t))))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let start = Instant::now();
+    let mut passed = 0;
+    for key in &misses {
+        let key = BloomKey::new(key);
+        passed += tables.iter().filter(|t| t.may_contain_key(&key)).count();
+    }
+    report("Filter probes, key hashed once", start.elapsed());
+    let start = Instant::now();
+    let mut passed_per_table = 0;
+    for key in &misses {
+        passed_per_table += tables.iter().filter(|t| t.may_contain(key)).count();
+    }
+    report("Filter probes, key hashed per table", start.elapsed());
+    assert_eq!(passed, passed_per_table);
+    println!("  False positives: {}", passed);
+
+    let start = Instant::now();
+    for key in &misses {
+        assert!(engine.get(key)?.is_none());
+    }
+    report("Engine, all misses", start.elapsed());
+
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        let key = format!("key{:03}{:06}", i % TABLES, i % KEYS_PER_TABLE).into_bytes();
+        assert!(engine.get(&key)?.is_some());
+    }
+    report("Engine, all hits", start.elapsed());
+
+    drop(engine);
+    let _ = fs::remove_dir_all(dir);
+    Ok(())
+}
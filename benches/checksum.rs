@@ -0,0 +1,75 @@
+use lsm_storage_engine::SSTable;
+use lsm_storage_engine::memtable::Entry;
+use lsm_storage_engine::sstable::{ChecksumAlgorithm, SSTableBuilder};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Approximate size of the table each algorithm verifies.
+const TABLE_BYTES: usize = 100 * 1024 * 1024;
+const VALUE_LEN: usize = 4096;
+
+fn setup_test_dir(name: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!(
+        "lsm_bench_{}_{}",
+        name,
+        SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// Writes a table of about `TABLE_BYTES` protected by `algorithm` and returns its size.
+fn build(path: &Path, algorithm: ChecksumAlgorithm) -> std::io::Result<u64> {
+    let mut builder = SSTableBuilder::new(path, 16)?.with_checksum(algorithm);
+    let value = Entry::Value(vec![0xAB; VALUE_LEN]);
+    for i in 0..TABLE_BYTES / VALUE_LEN {
+        builder.add_record(format!("key{:010}", i).as_bytes(), 1, &value)?;
+    }
+    builder.finish()?;
+    Ok(fs::metadata(path)?.len())
+}
+
+fn report(label: &str, bytes: u64, elapsed: Duration) {
+    println!(
+        "  {:<14} {:>10.2?}  {:>8.1} MB/s",
+        label,
+        elapsed,
+        bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    );
+}
+
+fn main() -> std::io::Result<()> {
+    let dir = setup_test_dir("checksum");
+    println!(
+        "--- Verifying a {} MB SSTable (crc32c feature {}) ---",
+        TABLE_BYTES / (1024 * 1024),
+        if cfg!(feature = "crc32c") {
+            "on"
+        } else {
+            "off"
+        }
+    );
+
+    for algorithm in [ChecksumAlgorithm::Crc32, ChecksumAlgorithm::Crc32c] {
+        let path = dir.join(format!("{:?}.sst", algorithm));
+        let bytes = build(&path, algorithm)?;
+        println!("{:?}:", algorithm);
+
+        // Both read the file once; the first run also warms the page cache.
+        let start = Instant::now();
+        SSTable::open(&path)?;
+        report("open", bytes, start.elapsed());
+
+        let start = Instant::now();
+        assert!(SSTable::verify_stream(&path)?.checksum_ok);
+        report("verify_stream", bytes, start.elapsed());
+    }
+
+    let _ = fs::remove_dir_all(dir);
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use lsm_storage_engine::{Engine, EngineOptions, WalSyncMode};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+const WRITES: usize = 4_000;
+const WRITERS: usize = 8;
+
+fn setup_test_dir(name: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!(
+        "lsm_bench_{}_{}",
+        name,
+        SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// Writes `WRITES` keys split evenly across `writers` threads and returns the elapsed time.
+fn run(name: &str, writers: usize, window: Duration) -> std::io::Result<Duration> {
+    let dir = setup_test_dir(name);
+    let options = EngineOptions {
+        max_memtable_size: 64 * 1024 * 1024,
+        wal_sync: WalSyncMode::Fsync,
+        group_commit_window: window,
+        ..EngineOptions::default()
+    };
+    let engine = Arc::new(Engine::open_with_options(&dir, options)?);
+
+    let start = Instant::now();
+    let threads: Vec<_> = (0..writers)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            std::thread::spawn(move || -> std::io::Result<()> {
+                for i in 0..WRITES / writers {
+                    let key = format!("user{:02}{:010}", t, i).into_bytes();
+                    engine.put(key, vec![0u8; 128])?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    let elapsed = start.elapsed();
+
+    drop(engine);
+    let _ = fs::remove_dir_all(dir);
+    Ok(elapsed)
+}
+
+fn report(label: &str, elapsed: Duration) {
+    println!("{}:", label);
+    println!("  Total Time:   {:?}", elapsed);
+    println!(
+        "  Throughput:   {:.2} ops/sec",
+        WRITES as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() -> std::io::Result<()> {
+    println!(
+        "--- Benchmarking {} fsynced writes from {} writers ---",
+        WRITES, WRITERS
+    );
+
+    // Writers that arrive during an fsync share the next one even without a window.
+    let immediate = run("group_commit", WRITERS, Duration::ZERO)?;
+    report("Group commit, no window", immediate);
+
+    // On a fast disk the window mostly adds latency; it helps when fsyncs are slow.
+    let window = Duration::from_millis(1);
+    let windowed = run("group_commit_window", WRITERS, window)?;
+    report(&format!("Group commit, {:?} window", window), windowed);
+    println!(
+        "  Speedup over no window: {:.2}x",
+        immediate.as_secs_f64() / windowed.as_secs_f64()
+    );
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use lsm_storage_engine::Entry;
+use lsm_storage_engine::sstable::{SSTable, SSTableBuilder};
+use lsm_storage_engine::storage::MemoryStore;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const RECORDS: usize = 100_000;
+const SPARSE_INTERVAL: usize = 16;
+const LOOKUPS: usize = 500_000;
+
+fn report(label: &str, elapsed: Duration) {
+    println!("{}:", label);
+    println!("  Total Time:   {:?}", elapsed);
+    println!(
+        "  Throughput:   {:.2} ops/sec",
+        LOOKUPS as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() -> std::io::Result<()> {
+    let store = MemoryStore::new();
+    let mut builder = SSTableBuilder::with_store(store.clone(), "bench.sst", SPARSE_INTERVAL);
+    for i in 0..RECORDS {
+        let key = format!("key{:08}", i).into_bytes();
+        builder.add_record(&key, 1, &Entry::Value(vec![0u8; 32]))?;
+    }
+    builder.finish()?;
+    let sst = SSTable::from_store(Arc::new(store), "bench.sst")?;
+    println!(
+        "--- Benchmarking {} SSTable lookups over {} records (sparse interval {}) ---",
+        LOOKUPS, RECORDS, SPARSE_INTERVAL
+    );
+
+    // Keys are built up front so that only the lookups themselves are timed; each one
+    // seeks the sparse index with a borrowed key rather than a copy of it.
+    let hits: Vec<Vec<u8>> = (0..LOOKUPS)
+        .map(|i| format!("key{:08}", (i * 7919) % RECORDS).into_bytes())
+        .collect();
+    let start = Instant::now();
+    for key in &hits {
+        assert!(sst.get(key)?.is_some());
+    }
+    report("Hits", start.elapsed());
+
+    let misses: Vec<Vec<u8>> = (0..LOOKUPS)
+        .map(|i| format!("key{:08}x", (i * 7919) % RECORDS).into_bytes())
+        .collect();
+    let start = Instant::now();
+    for key in &misses {
+        assert!(sst.get(key)?.is_none());
+    }
+    report("Misses within blocks", start.elapsed());
+    Ok(())
+}
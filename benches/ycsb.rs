@@ -1,8 +1,7 @@
 use lsm_storage_engine::Engine;
 use std::fs;
-use std::hint::black_box;
 use std::path::PathBuf;
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Instant, SystemTime};
 
 fn setup_test_dir(name: &str) -> PathBuf {
     let mut path = std::env::temp_dir();
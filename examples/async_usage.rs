@@ -0,0 +1,44 @@
+//! Run with `cargo run --example async_usage --features async-example`.
+
+use lsm_storage_engine::{AsyncEngine, Engine};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> std::io::Result<()> {
+    let data_dir = std::env::temp_dir().join("lsm_async_example");
+    let engine = AsyncEngine::new(Engine::open(&data_dir, 1024)?);
+
+    // Each call runs on tokio's blocking pool, so many tasks can use the engine at once
+    // without tying up the executor's worker threads.
+    let writers: Vec<_> = (0..4)
+        .map(|task| {
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                for i in 0..25 {
+                    let key = format!("task{}:key{:02}", task, i).into_bytes();
+                    engine.put(key, format!("value{}", i).into_bytes()).await?;
+                }
+                std::io::Result::Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.await.map_err(std::io::Error::other)??;
+    }
+
+    if let Some(val) = engine.get(b"task2:key10".to_vec()).await? {
+        println!("Retrieved task2:key10 -> {}", String::from_utf8_lossy(&val));
+    }
+
+    engine.delete(b"task2:key10".to_vec()).await?;
+    println!(
+        "After delete: {:?}",
+        engine.get(b"task2:key10".to_vec()).await?
+    );
+
+    let live = engine.scan(false).await?;
+    println!("{} live keys remain", live.len());
+
+    drop(engine);
+    std::fs::remove_dir_all(&data_dir)?;
+    Ok(())
+}
@@ -16,19 +16,19 @@ fn main() -> std::io::Result<()> {
 
     println!("Inserting data...");
     // Insert/Update
-    engine.put(b"user:1".to_vec(), b"Albiere".to_vec())?;
-    engine.put(b"user:2".to_vec(), b"Antigravity".to_vec())?;
+    engine.put_str("user:1", "Albiere")?;
+    engine.put_str("user:2", "Antigravity")?;
 
     // Retrieve
-    if let Some(val) = engine.get(b"user:1")? {
-        println!("Retrieved user:1 -> {}", String::from_utf8(val).unwrap());
+    if let Some(val) = engine.get_str("user:1")? {
+        println!("Retrieved user:1 -> {}", val);
     }
 
     println!("Deleting user:2...");
     // Delete (inserts a tombstone)
-    engine.delete(b"user:2".to_vec())?;
+    engine.delete_str("user:2")?;
 
-    if engine.get(b"user:2")?.is_none() {
+    if engine.get_str("user:2")?.is_none() {
         println!("Successfully deleted user:2 (not found)");
     }
 
@@ -0,0 +1,84 @@
+//! A key-value store for wasm32, exported as plain C functions.
+//!
+//! Build with `cargo build --example wasm_kv --target wasm32-unknown-unknown --release`.
+//! The host copies keys and values into memory from `kv_alloc` and passes pointers and
+//! lengths; every function returns a negative number on error.
+
+use lsm_storage_engine::Engine;
+use std::sync::OnceLock;
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    // There is no filesystem on wasm32, so the store lives in memory. Flushes and
+    // compactions run on the calling thread once the 64 KiB MemTable fills.
+    ENGINE.get_or_init(|| Engine::open_in_memory(64 * 1024))
+}
+
+/// # Safety
+/// `ptr` must point to `len` readable bytes.
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    unsafe { std::slice::from_raw_parts(ptr, len) }
+}
+
+/// Allocates `len` bytes for the host to write a key or value into. They are never freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn kv_alloc(len: usize) -> *mut u8 {
+    Box::leak(vec![0u8; len].into_boxed_slice()).as_mut_ptr()
+}
+
+/// Stores a value under a key. Returns 0 on success.
+///
+/// # Safety
+/// Both pointers must point to the given number of readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_put(
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> i32 {
+    let (key, value) = unsafe { (bytes(key, key_len), bytes(value, value_len)) };
+    match engine().put(key.to_vec(), value.to_vec()) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Deletes a key. Returns 0 on success.
+///
+/// # Safety
+/// `key` must point to `key_len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_delete(key: *const u8, key_len: usize) -> i32 {
+    let key = unsafe { bytes(key, key_len) };
+    match engine().delete(key.to_vec()) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Copies the value of a key into `out` and returns its length, or -1 when the key is
+/// missing. A value longer than `out_len` is not copied, so the host can retry with a
+/// buffer of the returned length.
+///
+/// # Safety
+/// `key` must point to `key_len` readable bytes and `out` to `out_len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_get(
+    key: *const u8,
+    key_len: usize,
+    out: *mut u8,
+    out_len: usize,
+) -> i64 {
+    let key = unsafe { bytes(key, key_len) };
+    match engine().get(key) {
+        Ok(Some(value)) => {
+            if value.len() <= out_len {
+                unsafe { std::ptr::copy_nonoverlapping(value.as_ptr(), out, value.len()) };
+            }
+            value.len() as i64
+        }
+        Ok(None) => -1,
+        Err(_) => -2,
+    }
+}
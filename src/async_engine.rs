@@ -0,0 +1,135 @@
+//! An async front-end for [`Engine`], available with the `async` feature.
+//!
+//! [`AsyncEngine`] does not change how the engine performs I/O: every call still runs the
+//! same blocking code, just on tokio's blocking thread pool via `spawn_blocking`. That keeps
+//! disk reads, WAL appends and flushes from stalling the async executor's worker threads.
+
+use crate::engine::{Engine, ScanItem};
+use crate::sstable::SSTableMetadata;
+use std::io;
+use std::sync::Arc;
+
+/// A cheaply cloneable handle that runs [`Engine`] operations off the async executor.
+///
+/// Must be used from within a tokio runtime.
+#[derive(Clone)]
+pub struct AsyncEngine {
+    engine: Arc<Engine>,
+}
+
+impl AsyncEngine {
+    /// Wraps an already opened `Engine`.
+    pub fn new(engine: Engine) -> Self {
+        Self {
+            engine: Arc::new(engine),
+        }
+    }
+
+    /// Returns the wrapped `Engine` for synchronous use.
+    pub fn engine(&self) -> &Arc<Engine> {
+        &self.engine
+    }
+
+    /// Inserts or updates a key-value pair.
+    pub async fn put(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
+        self.run(move |engine| engine.put(key, value)).await
+    }
+
+    /// Retrieves a value by its key.
+    pub async fn get(&self, key: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        self.run(move |engine| engine.get(&key)).await
+    }
+
+    /// Marks a key as deleted.
+    pub async fn delete(&self, key: Vec<u8>) -> io::Result<()> {
+        self.run(move |engine| engine.delete(key)).await
+    }
+
+    /// Returns every key in ascending order with its value, or `None` for a deleted key if
+    /// `include_tombstones` is set; see [`Engine::scan`].
+    ///
+    /// The scan runs to completion on the blocking pool and its records are returned all at
+    /// once, so they must fit in memory. To stream a larger store, iterate
+    /// [`Engine::scan`] through [`AsyncEngine::engine`] inside a blocking task instead.
+    pub async fn scan(&self, include_tombstones: bool) -> io::Result<Vec<ScanItem>> {
+        self.run(move |engine| engine.scan(include_tombstones)?.collect())
+            .await
+    }
+
+    /// Flushes the current MemTable to an SSTable.
+    pub async fn flush(&self) -> io::Result<Option<SSTableMetadata>> {
+        self.run(|engine| engine.flush()).await
+    }
+
+    /// Runs a full compaction of all current SSTables.
+    pub async fn compact(&self) -> io::Result<Vec<SSTableMetadata>> {
+        self.run(|engine| engine.compact()).await
+    }
+
+    async fn run<T, F>(&self, op: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Engine) -> io::Result<T> + Send + 'static,
+    {
+        let engine = Arc::clone(&self.engine);
+        tokio::task::spawn_blocking(move || op(&engine))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `test` on a runtime built from tokio's `rt` feature alone, which is all the
+    /// `async` feature enables.
+    fn block_on<F: Future>(test: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(test)
+    }
+
+    #[test]
+    fn test_async_put_get_delete() {
+        block_on(async {
+            let engine = AsyncEngine::new(Engine::open_in_memory(64));
+            for i in 0..20u8 {
+                engine.put(vec![i], vec![i; 8]).await.unwrap();
+            }
+            engine.delete(vec![3]).await.unwrap();
+            engine.compact().await.unwrap();
+
+            assert_eq!(engine.get(vec![7]).await.unwrap(), Some(vec![7; 8]));
+            assert_eq!(engine.get(vec![3]).await.unwrap(), None);
+            assert_eq!(engine.engine().get(&[19]).unwrap(), Some(vec![19; 8]));
+        });
+    }
+
+    #[test]
+    fn test_async_scan() {
+        block_on(async {
+            let engine = AsyncEngine::new(Engine::open_in_memory(1 << 20));
+            for i in 0..4u8 {
+                engine.put(vec![i], vec![i]).await.unwrap();
+            }
+            engine.flush().await.unwrap();
+            engine.delete(vec![1]).await.unwrap();
+            engine.put(vec![2], vec![9]).await.unwrap();
+
+            let live = engine.scan(false).await.unwrap();
+            assert_eq!(
+                live,
+                vec![
+                    (vec![0], Some(vec![0])),
+                    (vec![2], Some(vec![9])),
+                    (vec![3], Some(vec![3])),
+                ]
+            );
+            let all = engine.scan(true).await.unwrap();
+            assert_eq!(all.len(), 4);
+            assert_eq!(all[1], (vec![1], None));
+        });
+    }
+}
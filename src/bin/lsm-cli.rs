@@ -1,4 +1,5 @@
-use lsm_storage_engine::{Entry, SSTable};
+use lsm_storage_engine::sstable::Diff;
+use lsm_storage_engine::{Engine, EngineOptions, Entry, SSTable};
 use std::env;
 use std::path::Path;
 
@@ -20,17 +21,50 @@ fn main() -> std::io::Result<()> {
         }
         "sst-verify" => {
             if args.len() < 3 {
-                println!("Usage: lsm-cli sst-verify <path>");
+                println!("Usage: lsm-cli sst-verify <path> [--stream]");
                 return Ok(());
             }
-            verify_sstable(&args[2])?;
+            match args.get(3).map(String::as_str) {
+                None => verify_sstable(&args[2])?,
+                Some("--stream") => verify_sstable_streaming(&args[2])?,
+                Some(other) => println!("Unknown option: {}", other),
+            }
+        }
+        "sst-repair" => {
+            if args.len() < 3 {
+                println!("Usage: lsm-cli sst-repair <path>");
+                return Ok(());
+            }
+            repair_sstable(&args[2])?;
+        }
+        "sst-diff" => {
+            if args.len() < 4 {
+                println!("Usage: lsm-cli sst-diff <a> <b>");
+                return Ok(());
+            }
+            diff_sstables(&args[2], &args[3])?;
         }
         "compact" => {
             if args.len() < 3 {
-                println!("Usage: lsm-cli compact <data_dir>");
+                println!("Usage: lsm-cli compact <data_dir> [--sparse-interval <n>]");
                 return Ok(());
             }
-            manual_compaction(&args[2])?;
+            let mut options = EngineOptions::default();
+            match args.get(3).map(String::as_str) {
+                None => {}
+                Some("--sparse-interval") => match args.get(4).and_then(|n| n.parse().ok()) {
+                    Some(n) if n > 0 => options.table.sparse_interval = n,
+                    _ => {
+                        println!("Error: --sparse-interval needs a positive integer");
+                        return Ok(());
+                    }
+                },
+                Some(other) => {
+                    println!("Unknown option: {}", other);
+                    return Ok(());
+                }
+            }
+            manual_compaction(&args[2], options)?;
         }
         _ => {
             println!("Unknown command: {}", command);
@@ -45,16 +79,26 @@ fn print_usage() {
     println!("LSM-Tree Storage Engine CLI");
     println!("Usage:");
     println!("  lsm-cli sst-dump <path>    - Dump metadata and records from an SSTable");
-    println!("  lsm-cli sst-verify <path>  - Verify the checksum of an SSTable");
+    println!("  lsm-cli sst-verify <path>  - Check the structure and checksum of an SSTable");
+    println!("      [--stream]             - Check in one pass without loading the table");
+    println!("  lsm-cli sst-repair <path>  - Rebuild an SSTable whose footer is damaged");
+    println!("  lsm-cli sst-diff <a> <b>   - List keys whose newest version differs");
     println!(
         "  lsm-cli compact <data_dir> - Manually trigger compaction on all SSTables in a directory"
     );
+    println!("      [--sparse-interval <n>] - Index every n-th record in the compacted output");
 }
 
-fn manual_compaction(dir: &str) -> std::io::Result<()> {
+fn manual_compaction(dir: &str, options: EngineOptions) -> std::io::Result<()> {
     println!("Manually triggering compaction for: {}", dir);
-    let engine = lsm_storage_engine::Engine::open(dir, 1024 * 1024)?; // default 1MB memtable for recovery
-    engine.compact()?;
+    let engine = Engine::open_with_options(dir, options)?; // default 1MB memtable for recovery
+    let produced = engine.compact()?;
+    if produced.is_empty() {
+        println!("Nothing to compact.");
+    }
+    for table in produced {
+        println!("Wrote {:?}", table.path);
+    }
     println!("Compaction completed successfully.");
     Ok(())
 }
@@ -69,23 +113,35 @@ fn dump_sstable(path: &str) -> std::io::Result<()> {
     let sst = SSTable::open(path)?;
     println!("--- Metadata ---");
     println!("Path: {:?}", sst.path());
+    let bloom = sst.bloom_filter();
+    println!(
+        "Bloom filter: {} bits, {} hashes, {} bytes",
+        bloom.num_bits(),
+        bloom.num_hashes(),
+        bloom.memory_bytes()
+    );
 
     println!("--- Records ---");
     let iter = sst.iter()?;
     let mut count = 0;
     for result in iter {
-        let (key, entry) = result?;
+        let (key, seqno, entry) = result?;
         match entry {
             Entry::Value(v) => {
                 println!(
-                    "  Key: {:?} | Value: {:?} ({} bytes)",
+                    "  Key: {:?} | Seq: {} | Value: {:?} ({} bytes)",
                     String::from_utf8_lossy(&key),
+                    seqno,
                     String::from_utf8_lossy(&v),
                     v.len()
                 );
             }
             Entry::Tombstone => {
-                println!("  Key: {:?} | [TOMBSTONE]", String::from_utf8_lossy(&key));
+                println!(
+                    "  Key: {:?} | Seq: {} | [TOMBSTONE]",
+                    String::from_utf8_lossy(&key),
+                    seqno
+                );
             }
         }
         count += 1;
@@ -94,11 +150,154 @@ fn dump_sstable(path: &str) -> std::io::Result<()> {
     Ok(())
 }
 
+fn diff_sstables(a: &str, b: &str) -> std::io::Result<()> {
+    println!("Diffing SSTables: {} and {}", a, b);
+    for path in [a, b] {
+        if !Path::new(path).exists() {
+            println!("Error: File not found: {}", path);
+            return Ok(());
+        }
+    }
+
+    let entry = |entry: &Entry| match entry {
+        Entry::Value(v) => format!("{:?}", String::from_utf8_lossy(v)),
+        Entry::Tombstone => "[TOMBSTONE]".to_string(),
+    };
+    let (a, b) = (SSTable::open(a)?, SSTable::open(b)?);
+    let mut count = 0;
+    for diff in SSTable::diff(&a, &b)? {
+        match diff? {
+            Diff::OnlyInA(key) => println!("  < {:?}", String::from_utf8_lossy(&key)),
+            Diff::OnlyInB(key) => println!("  > {:?}", String::from_utf8_lossy(&key)),
+            Diff::Differs(key, entry_a, entry_b) => println!(
+                "  ~ {:?}: {} -> {}",
+                String::from_utf8_lossy(&key),
+                entry(&entry_a),
+                entry(&entry_b)
+            ),
+        }
+        count += 1;
+    }
+    println!("Keys that differ: {}", count);
+    Ok(())
+}
+
+fn repair_sstable(path: &str) -> std::io::Result<()> {
+    println!("Repairing SSTable: {}", path);
+    if !Path::new(path).exists() {
+        println!("Error: File not found");
+        return Ok(());
+    }
+
+    match SSTable::repair(path) {
+        Ok(()) => {
+            let sst = SSTable::open(path)?;
+            println!("Repair: SUCCEEDED ({} records)", sst.record_count());
+        }
+        Err(e) => println!("Repair: FAILED - {}", e),
+    }
+    Ok(())
+}
+
+fn verify_sstable_streaming(path: &str) -> std::io::Result<()> {
+    println!("Verifying SSTable (streaming): {}", path);
+    if !Path::new(path).exists() {
+        println!("Error: File not found");
+        return Ok(());
+    }
+
+    match SSTable::verify_stream(path) {
+        Ok(stats) => {
+            println!("File size: {} bytes", stats.bytes);
+            println!("Records read: {}", stats.records);
+            println!("Tombstones read: {}", stats.tombstones);
+            println!(
+                "Checksum: {}",
+                if stats.checksum_ok {
+                    "valid"
+                } else {
+                    "MISMATCH"
+                }
+            );
+            println!("--- Result ---");
+            if stats.checksum_ok {
+                println!("Verification: PASSED");
+            } else {
+                println!("Verification: FAILED");
+            }
+        }
+        Err(e) => {
+            println!("--- Result ---");
+            println!("Verification: FAILED");
+            println!("  - {}", e);
+        }
+    }
+    Ok(())
+}
+
 fn verify_sstable(path: &str) -> std::io::Result<()> {
     println!("Verifying SSTable: {}", path);
-    match SSTable::open(path) {
-        Ok(_) => println!("Checksum verification: PASSED"),
-        Err(e) => println!("Checksum verification: FAILED ({})", e),
+    if !Path::new(path).exists() {
+        println!("Error: File not found");
+        return Ok(());
+    }
+
+    let diagnosis = SSTable::diagnose(path)?;
+
+    println!("--- Structure ---");
+    println!("File size: {} bytes", diagnosis.file_size);
+    match diagnosis.layout {
+        Some(layout) => {
+            println!(
+                "Bloom filter: offset {} size {}",
+                layout.bloom_offset, layout.bloom_size
+            );
+            println!(
+                "Index: offset {} size {}",
+                layout.index_offset, layout.index_size
+            );
+            println!(
+                "Properties: offset {} size {}",
+                layout.props_offset, layout.props_size
+            );
+        }
+        None => println!("Footer: missing"),
+    }
+    println!(
+        "Magic: {}",
+        if diagnosis.magic_valid {
+            "valid"
+        } else {
+            "INVALID"
+        }
+    );
+    if let Some(version) = diagnosis.version {
+        println!("Format version: {}", version);
+    }
+    if let (Some(stored), Some(computed)) = (diagnosis.stored_checksum, diagnosis.computed_checksum)
+    {
+        println!(
+            "Checksum: stored {:#010x}, computed {:#010x}",
+            stored, computed
+        );
+    }
+    if let Some(algorithm) = diagnosis.checksum_algorithm {
+        println!("Checksum algorithm: {:?}", algorithm);
+    }
+    println!("Records read: {}", diagnosis.records_read);
+    println!(
+        "Bloom filter false negatives: {}",
+        diagnosis.bloom_false_negatives
+    );
+
+    println!("--- Result ---");
+    if diagnosis.is_healthy() {
+        println!("Verification: PASSED");
+    } else {
+        println!("Verification: FAILED");
+        for problem in &diagnosis.problems {
+            println!("  - {}", problem);
+        }
     }
     Ok(())
 }
@@ -1,5 +1,63 @@
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::io;
+
+/// The hash function a [`BloomFilter`] maps keys to bits with.
+///
+/// It is recorded in the serialized header, so a filter is always read back with the function
+/// it was built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BloomHash {
+    /// The standard library's `DefaultHasher`, used by filters written before SSTable format
+    /// version 4. Its output is not guaranteed to stay the same across Rust releases.
+    Legacy = 0,
+    /// 64-bit FNV-1a of the key, mixed with the hash index through MurmurHash3's 64-bit
+    /// finalizer. It gives the same bits for the same keys on every platform.
+    Fnv1a = 1,
+}
+
+/// A key prepared for [`BloomFilter::contains_key`].
+///
+/// The FNV-1a hash of the key is the same for every probe of every filter, so a lookup that
+/// consults the filters of many tables computes it once here instead of once per probe.
+pub struct BloomKey<'a> {
+    key: &'a [u8],
+    fnv: u64,
+}
+
+impl<'a> BloomKey<'a> {
+    /// Hashes `key` for probing filters.
+    pub fn new(key: &'a [u8]) -> Self {
+        Self {
+            key,
+            fnv: fnv1a(FNV_OFFSET_BASIS, key),
+        }
+    }
+}
+
+/// Bits of the serialized hash count field that hold the [`BloomHash`] identifier.
+const HASH_ID_SHIFT: u32 = 24;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Folds `data` into the 64-bit FNV-1a state `hash`.
+fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// MurmurHash3's 64-bit finalizer, which spreads every input bit over the whole output.
+fn fmix64(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
 
 /// A probabilistic data structure for membership testing.
 ///
@@ -9,6 +67,7 @@ pub struct BloomFilter {
     bits: Vec<u8>,
     num_hashes: usize,
     num_bits: usize,
+    hash: BloomHash,
 }
 
 impl BloomFilter {
@@ -17,24 +76,75 @@ impl BloomFilter {
         // Optimal size calculations
         // m = -(n * ln(p)) / (ln(2)^2)
         // k = (m/n) * ln(2)
-        let n = num_items as f64;
+        //
+        // At least one bit and one hash are used, since a filter without them cannot be
+        // deserialized.
+        let n = num_items.max(1) as f64;
         let p = false_positive_rate;
 
-        let m = (-(n * p.ln()) / (2.0f64.ln().powi(2))).ceil() as usize;
-        let k = ((m as f64 / n) * 2.0f64.ln()).ceil() as usize;
+        let m = ((-(n * p.ln()) / (2.0f64.ln().powi(2))).ceil() as usize).max(1);
+        let k = (((m as f64 / n) * 2.0f64.ln()).ceil() as usize).max(1);
+        Self::with_size(m, k)
+    }
+
+    /// Creates a new `BloomFilter` with `bits_per_key` bits for each of the expected number of
+    /// items, so its size is known up front instead of following from a false positive rate.
+    ///
+    /// The hash count is the optimal `bits_per_key * ln(2)`, rounded. With 10 bits per key
+    /// that is 7 hashes and a false positive rate just under 1%.
+    pub fn with_bits_per_key(num_items: usize, bits_per_key: f64) -> Self {
+        // As in `new`, at least one bit and one hash are used.
+        let n = num_items.max(1) as f64;
+        let m = ((n * bits_per_key).ceil() as usize).max(1);
+        let k = ((bits_per_key * 2.0f64.ln()).round() as usize).max(1);
+        Self::with_size(m, k)
+    }
 
-        let num_bytes = m.div_ceil(8);
+    /// Creates an empty filter of at least `num_bits` bits, rounded up to whole bytes, that
+    /// sets `num_hashes` bits per key.
+    fn with_size(num_bits: usize, num_hashes: usize) -> Self {
+        let num_bytes = num_bits.div_ceil(8);
         Self {
             bits: vec![0u8; num_bytes],
-            num_hashes: k,
+            num_hashes,
             num_bits: num_bytes * 8,
+            hash: BloomHash::Fnv1a,
         }
     }
 
+    /// Returns the hash function the filter uses.
+    pub fn hash_function(&self) -> BloomHash {
+        self.hash
+    }
+
+    /// Resets the filter to contain no keys, keeping its size and hash count.
+    ///
+    /// The bit array is zeroed in place, so a cleared filter can be reused without
+    /// reallocating.
+    pub fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+
+    /// Returns the size of the filter in bits.
+    pub fn num_bits(&self) -> usize {
+        self.num_bits
+    }
+
+    /// Returns how many hash functions each key sets a bit for.
+    pub fn num_hashes(&self) -> usize {
+        self.num_hashes
+    }
+
+    /// Returns the size in bytes of the bit array, without the serialized header.
+    pub fn memory_bytes(&self) -> usize {
+        self.bits.len()
+    }
+
     /// Adds a key to the `BloomFilter`.
     pub fn add(&mut self, key: &[u8]) {
+        let key = BloomKey::new(key);
         for i in 0..self.num_hashes {
-            let h = self.hash(key, i);
+            let h = self.hash(&key, i);
             let bit_pos = h % self.num_bits;
             self.bits[bit_pos / 8] |= 1 << (bit_pos % 8);
         }
@@ -42,6 +152,12 @@ impl BloomFilter {
 
     /// Checks if a key might be in the `BloomFilter`.
     pub fn contains(&self, key: &[u8]) -> bool {
+        self.contains_key(&BloomKey::new(key))
+    }
+
+    /// Like [`BloomFilter::contains`], but takes a key already hashed for probing many
+    /// filters.
+    pub fn contains_key(&self, key: &BloomKey) -> bool {
         if self.num_bits == 0 {
             return false;
         }
@@ -55,32 +171,66 @@ impl BloomFilter {
         true
     }
 
-    fn hash(&self, key: &[u8], i: usize) -> usize {
-        let mut s = DefaultHasher::new();
-        key.hash(&mut s);
-        i.hash(&mut s);
-        s.finish() as usize
+    fn hash(&self, key: &BloomKey, i: usize) -> usize {
+        match self.hash {
+            BloomHash::Legacy => {
+                let mut s = DefaultHasher::new();
+                key.key.hash(&mut s);
+                i.hash(&mut s);
+                s.finish() as usize
+            }
+            BloomHash::Fnv1a => {
+                let seed = (i as u64).wrapping_mul(0x9e3779b97f4a7c15);
+                let h = fmix64(key.fnv ^ seed);
+                // Reduced in 64 bits so that 32-bit platforms pick the same bits.
+                (h % self.num_bits as u64) as usize
+            }
+        }
     }
 
     /// Serializes the `BloomFilter` into a byte vector.
+    ///
+    /// The header is the hash count, with the [`BloomHash`] identifier in its top byte, then
+    /// the number of bits, both as little-endian `u32`s. The bit array follows.
     pub fn serialize(&self) -> Vec<u8> {
         let mut res = Vec::new();
-        res.extend_from_slice(&(self.num_hashes as u32).to_le_bytes());
+        let hashes = self.num_hashes as u32 | (self.hash as u32) << HASH_ID_SHIFT;
+        res.extend_from_slice(&hashes.to_le_bytes());
         res.extend_from_slice(&(self.num_bits as u32).to_le_bytes());
         res.extend_from_slice(&self.bits);
         res
     }
 
     /// Deserializes a `BloomFilter` from a byte slice.
-    pub fn deserialize(data: &[u8]) -> Self {
-        let num_hashes = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    ///
+    /// Fails with `InvalidData` if the header is truncated, declares no bits or no hashes, or
+    /// declares more bits than follow it. Such a filter would report every key as absent.
+    pub fn deserialize(data: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if data.len() < 8 {
+            return Err(invalid("bloom filter header is truncated"));
+        }
+        let hashes = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let hash = match hashes >> HASH_ID_SHIFT {
+            0 => BloomHash::Legacy,
+            1 => BloomHash::Fnv1a,
+            id => return Err(invalid(&format!("unknown bloom filter hash {}", id))),
+        };
+        let num_hashes = (hashes & ((1 << HASH_ID_SHIFT) - 1)) as usize;
         let num_bits = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
         let bits = data[8..].to_vec();
-        Self {
+        if num_hashes == 0 || num_bits == 0 {
+            return Err(invalid("bloom filter declares no hashes or no bits"));
+        }
+        if bits.len() * 8 < num_bits {
+            return Err(invalid("bloom filter is shorter than its declared size"));
+        }
+        Ok(Self {
             bits,
             num_hashes,
             num_bits,
-        }
+            hash,
+        })
     }
 }
 
@@ -129,7 +279,7 @@ mod tests {
         let mut bf = BloomFilter::new(100, 0.01);
         bf.add(b"bloom");
         let data = bf.serialize();
-        let bf2 = BloomFilter::deserialize(&data);
+        let bf2 = BloomFilter::deserialize(&data).unwrap();
         assert!(bf2.contains(b"bloom"));
         assert!(!bf2.contains(b"not-bloom"));
     }
@@ -139,4 +289,133 @@ mod tests {
         let bf = BloomFilter::new(100, 0.01);
         assert!(!bf.contains(b"anything"));
     }
+
+    #[test]
+    fn test_bloom_clear_matches_fresh_filter() {
+        let mut bf = BloomFilter::new(100, 0.01);
+        for i in 0..100 {
+            bf.add(format!("key{}", i).as_bytes());
+        }
+        let capacity = bf.bits.capacity();
+        bf.clear();
+
+        assert_eq!(bf.serialize(), BloomFilter::new(100, 0.01).serialize());
+        assert_eq!(bf.bits.capacity(), capacity);
+        assert!(!bf.contains(b"key0"));
+        bf.add(b"reused");
+        assert!(bf.contains(b"reused"));
+    }
+
+    #[test]
+    fn test_bloom_deserialize_rejects_corrupt_header() {
+        let data = BloomFilter::new(100, 0.01).serialize();
+
+        let mut no_bits = data.clone();
+        no_bits[4..8].fill(0);
+        let mut no_hashes = data.clone();
+        no_hashes[0..4].fill(0);
+        let short = &data[..data.len() - 1];
+        for corrupt in [
+            &no_bits[..],
+            &no_hashes[..],
+            short,
+            &data[..7],
+            &[0u8; 8][..],
+        ] {
+            let err = BloomFilter::deserialize(corrupt).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        assert!(BloomFilter::deserialize(&data).is_ok());
+    }
+
+    #[test]
+    fn test_bloom_accessors_follow_optimal_sizing() {
+        // m = -(1000 * ln(0.01)) / ln(2)^2 = 9585.06..., k = (9586 / 1000) * ln(2) = 6.64...
+        let bf = BloomFilter::new(1000, 0.01);
+        assert_eq!(bf.memory_bytes(), 9586usize.div_ceil(8));
+        assert_eq!(bf.num_bits(), bf.memory_bytes() * 8);
+        assert_eq!(bf.num_hashes(), 7);
+
+        let restored = BloomFilter::deserialize(&bf.serialize()).unwrap();
+        assert_eq!(restored.num_bits(), bf.num_bits());
+        assert_eq!(restored.num_hashes(), bf.num_hashes());
+        assert_eq!(restored.memory_bytes(), bf.memory_bytes());
+    }
+
+    #[test]
+    fn test_bloom_with_bits_per_key() {
+        let mut bf = BloomFilter::with_bits_per_key(10_000, 10.0);
+        assert_eq!(bf.num_bits(), 100_000);
+        assert_eq!(bf.num_hashes(), 7);
+        assert_eq!(BloomFilter::with_bits_per_key(1000, 2.5).num_hashes(), 2);
+        assert_eq!(BloomFilter::with_bits_per_key(1000, 0.0).num_bits(), 8);
+
+        for i in 0..10_000 {
+            bf.add(format!("key{}", i).as_bytes());
+        }
+        let fps = (10_000..30_000)
+            .filter(|i| bf.contains(format!("key{}", i).as_bytes()))
+            .count();
+        // (1 - e^(-7 / 10))^7 = 0.0082...
+        let rate = fps as f64 / 20_000.0;
+        assert!(
+            (0.004..0.016).contains(&rate),
+            "false positive rate {}",
+            rate
+        );
+    }
+
+    #[test]
+    fn test_bloom_new_never_empty() {
+        let mut bf = BloomFilter::new(0, 0.01);
+        bf.add(b"key");
+        assert!(bf.contains(b"key"));
+        assert!(BloomFilter::deserialize(&bf.serialize()).is_ok());
+    }
+
+    #[test]
+    fn test_bloom_golden_bits() {
+        let mut bf = BloomFilter::new(4, 0.01);
+        for key in [&b"apple"[..], b"banana", b"cherry", b""] {
+            bf.add(key);
+        }
+        // 7 hashes with the FNV-1a identifier in the top byte, 40 bits, then the bit array.
+        // These bytes are part of the SSTable format and must never change.
+        assert_eq!(
+            bf.serialize(),
+            [7, 0, 0, 1, 40, 0, 0, 0, 77, 70, 206, 130, 86]
+        );
+    }
+
+    #[test]
+    fn test_bloom_legacy_hash_still_reads() {
+        let mut legacy = BloomFilter::new(100, 0.01);
+        legacy.hash = BloomHash::Legacy;
+        legacy.add(b"old");
+        let data = legacy.serialize();
+        assert_eq!(data[3], 0);
+
+        let restored = BloomFilter::deserialize(&data).unwrap();
+        assert_eq!(restored.hash_function(), BloomHash::Legacy);
+        assert!(restored.contains(b"old"));
+
+        let mut unknown = data;
+        unknown[3] = 9;
+        assert!(BloomFilter::deserialize(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_bloom_prepared_key_matches_contains() {
+        for hash in [BloomHash::Legacy, BloomHash::Fnv1a] {
+            let mut bf = BloomFilter::new(200, 0.05);
+            bf.hash = hash;
+            for i in 0..200u32 {
+                bf.add(&i.to_le_bytes());
+            }
+            for i in 0..2000u32 {
+                let key = i.to_le_bytes();
+                assert_eq!(bf.contains_key(&BloomKey::new(&key)), bf.contains(&key));
+            }
+        }
+    }
 }
@@ -0,0 +1,301 @@
+//! Caches that share one memory budget.
+//!
+//! A [`CacheManager`] holds the entries of every participating cache in a single LRU list and
+//! evicts across all of them, so together they never hold more than its capacity. Each entry
+//! is charged to a [`CacheKind`], whose usage can be read back. So far only the SSTable block
+//! cache takes part: [`BlockCachedStore`] serves a table's reads from cached blocks.
+
+use crate::storage::ReadableStore;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Size in bytes of the blocks that [`BlockCachedStore`] reads and caches.
+pub const BLOCK_SIZE: usize = 4096;
+
+/// The caches that draw on a [`CacheManager`]'s budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CacheKind {
+    /// Blocks of SSTable data read through a [`BlockCachedStore`].
+    Block,
+}
+
+impl CacheKind {
+    /// Number of kinds, for per-kind counters.
+    const COUNT: usize = 1;
+
+    fn index(self) -> usize {
+        match self {
+            CacheKind::Block => 0,
+        }
+    }
+}
+
+/// Identifies one cached entry: the cache it belongs to, its owner within that cache (such
+/// as one table) and its position within the owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CacheKey {
+    kind: CacheKind,
+    owner: u64,
+    offset: u64,
+}
+
+/// A total memory budget shared by several caches.
+///
+/// Inserting into a full manager evicts the least recently used entries, whichever cache
+/// they belong to. One manager can be shared by several engines to cap their caches together.
+pub struct CacheManager {
+    capacity: usize,
+    next_owner: AtomicU64,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Cached data by key, with the tick at which each entry was last used.
+    entries: HashMap<CacheKey, (Arc<[u8]>, u64)>,
+    /// Keys of the cached entries by last use, oldest first.
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    /// Bytes held for each kind of cache.
+    usage: [usize; CacheKind::COUNT],
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some((data, last_used)) = self.entries.remove(key) {
+            self.lru.remove(&last_used);
+            self.usage[key.kind.index()] -= data.len();
+        }
+    }
+}
+
+impl CacheManager {
+    /// Creates a manager whose caches together hold at most `capacity` bytes.
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            capacity,
+            next_owner: AtomicU64::new(0),
+            state: Mutex::new(CacheState::default()),
+        })
+    }
+
+    /// Returns the total number of bytes the caches may hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of bytes currently held by all caches.
+    pub fn usage(&self) -> usize {
+        self.state.lock().unwrap().usage.iter().sum()
+    }
+
+    /// Returns the number of bytes currently held by the cache of `kind`.
+    pub fn usage_of(&self, kind: CacheKind) -> usize {
+        self.state.lock().unwrap().usage[kind.index()]
+    }
+
+    /// Returns a new owner ID, under which a user of the manager keys its entries.
+    fn new_owner(&self) -> u64 {
+        self.next_owner.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the cached data for `key`, marking it as recently used.
+    fn get(&self, key: &CacheKey) -> Option<Arc<[u8]>> {
+        let mut state = self.state.lock().unwrap();
+        let CacheState {
+            entries, lru, tick, ..
+        } = &mut *state;
+        let (data, last_used) = entries.get_mut(key)?;
+        *tick += 1;
+        lru.remove(last_used);
+        *last_used = *tick;
+        lru.insert(*tick, *key);
+        Some(Arc::clone(data))
+    }
+
+    /// Caches `data` under `key`, evicting the least recently used entries to make room.
+    ///
+    /// Data larger than the whole budget is not cached.
+    fn insert(&self, key: CacheKey, data: Arc<[u8]>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        while state.usage.iter().sum::<usize>() + data.len() > self.capacity {
+            let Some((_, oldest)) = state.lru.pop_first() else {
+                break;
+            };
+            state.remove(&oldest);
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.usage[key.kind.index()] += data.len();
+        state.lru.insert(tick, key);
+        state.entries.insert(key, (data, tick));
+    }
+
+    /// Drops every entry of the cache of `kind` that belongs to `owner`.
+    fn forget_owner(&self, kind: CacheKind, owner: u64) {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<CacheKey> = state
+            .entries
+            .keys()
+            .filter(|key| key.kind == kind && key.owner == owner)
+            .copied()
+            .collect();
+        for key in keys {
+            state.remove(&key);
+        }
+    }
+}
+
+impl fmt::Debug for CacheManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheManager")
+            .field("capacity", &self.capacity)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
+
+/// A store that reads through the block cache of a [`CacheManager`].
+///
+/// Reads are served in [`BLOCK_SIZE`]-aligned blocks; a block missing from the cache is read
+/// from the wrapped store in full and cached. The wrapped store must not change while it is
+/// read this way. Its blocks are dropped from the cache when this store is dropped.
+pub struct BlockCachedStore {
+    inner: Arc<dyn ReadableStore>,
+    len: u64,
+    owner: u64,
+    cache: Arc<CacheManager>,
+}
+
+impl BlockCachedStore {
+    /// Wraps `inner` so that its reads go through the block cache of `cache`.
+    pub fn new(inner: Arc<dyn ReadableStore>, cache: &Arc<CacheManager>) -> io::Result<Self> {
+        Ok(Self {
+            len: inner.len()?,
+            inner,
+            owner: cache.new_owner(),
+            cache: Arc::clone(cache),
+        })
+    }
+
+    /// Returns the block starting at `start`, from the cache or else from the wrapped store.
+    fn block(&self, start: u64) -> io::Result<Arc<[u8]>> {
+        let key = CacheKey {
+            kind: CacheKind::Block,
+            owner: self.owner,
+            offset: start,
+        };
+        if let Some(block) = self.cache.get(&key) {
+            return Ok(block);
+        }
+
+        let mut block = vec![0u8; (self.len - start).min(BLOCK_SIZE as u64) as usize];
+        let mut filled = 0;
+        while filled < block.len() {
+            match self
+                .inner
+                .read_at(&mut block[filled..], start + filled as u64)?
+            {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "store ended before its recorded length",
+                    ));
+                }
+                n => filled += n,
+            }
+        }
+        let block: Arc<[u8]> = block.into();
+        self.cache.insert(key, Arc::clone(&block));
+        Ok(block)
+    }
+}
+
+impl ReadableStore for BlockCachedStore {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let start = offset - offset % BLOCK_SIZE as u64;
+        let block = self.block(start)?;
+        let from = (offset - start) as usize;
+        let n = buf.len().min(block.len() - from);
+        buf[..n].copy_from_slice(&block[from..from + n]);
+        Ok(n)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+impl Drop for BlockCachedStore {
+    fn drop(&mut self) {
+        self.cache.forget_owner(CacheKind::Block, self.owner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FaultyStore;
+
+    #[test]
+    fn test_block_cache_serves_repeated_reads() {
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let inner = FaultyStore::fail_reads_after(data.clone(), u64::MAX);
+        let cache = CacheManager::new(1 << 20);
+        let store = BlockCachedStore::new(Arc::new(inner.clone()), &cache).unwrap();
+
+        // A read straddling two blocks returns only the first one's part.
+        let mut buf = [0u8; 200];
+        let offset = BLOCK_SIZE as u64 - 50;
+        assert_eq!(store.read_at(&mut buf, offset).unwrap(), 50);
+        assert_eq!(&buf[..50], &data[BLOCK_SIZE - 50..BLOCK_SIZE]);
+        assert_eq!(cache.usage_of(CacheKind::Block), BLOCK_SIZE);
+
+        let before = inner.reads_left();
+        assert_eq!(store.read_at(&mut buf, 10).unwrap(), 200);
+        assert_eq!(&buf[..], &data[10..210]);
+        assert_eq!(inner.reads_left(), before);
+
+        // The short last block is cached at its real size.
+        assert_eq!(store.read_at(&mut buf, 3 * BLOCK_SIZE as u64).unwrap(), 100);
+        assert_eq!(store.read_at(&mut buf, data.len() as u64).unwrap(), 0);
+        assert_eq!(cache.usage(), BLOCK_SIZE + 100);
+
+        drop(store);
+        assert_eq!(cache.usage(), 0);
+    }
+
+    #[test]
+    fn test_budget_is_shared_and_evicts_least_recently_used() {
+        let data = vec![7u8; 4 * BLOCK_SIZE];
+        let cache = CacheManager::new(2 * BLOCK_SIZE);
+        let a = BlockCachedStore::new(Arc::new(data.clone()), &cache).unwrap();
+        let inner = FaultyStore::fail_reads_after(data, u64::MAX);
+        let b = BlockCachedStore::new(Arc::new(inner.clone()), &cache).unwrap();
+        let mut buf = [0u8; 1];
+
+        b.read_at(&mut buf, 0).unwrap();
+        a.read_at(&mut buf, 0).unwrap();
+        // Touching `b`'s block makes `a`'s the oldest, so it goes first.
+        b.read_at(&mut buf, 0).unwrap();
+        a.read_at(&mut buf, BLOCK_SIZE as u64).unwrap();
+        assert_eq!(cache.usage(), 2 * BLOCK_SIZE);
+
+        let before = inner.reads_left();
+        b.read_at(&mut buf, 0).unwrap();
+        assert_eq!(inner.reads_left(), before);
+        drop(a);
+        assert_eq!(cache.usage(), BLOCK_SIZE);
+    }
+}
@@ -1,39 +1,85 @@
 use crate::memtable::Entry;
-use crate::sstable::{RecordIterator, SSTable, SSTableBuilder};
+use crate::sstable::{SSTable, SSTableBuilder, SSTableMetadata, TableOptions, TaggedRecord};
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::io;
+use std::ops::Range;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-struct IterItem {
+/// A source of records for [`merge_iterators`]: keys in ascending order, and the versions of
+/// a key from newest to oldest.
+pub(crate) type RecordSource<'a> = Box<dyn Iterator<Item = io::Result<TaggedRecord>> + 'a>;
+
+/// Returns the records of `sst` as a [`RecordSource`].
+pub(crate) fn table_source(sst: &SSTable) -> io::Result<RecordSource<'static>> {
+    let mut iter = sst.iter()?;
+    Ok(Box::new(std::iter::from_fn(move || iter.next_with_meta())))
+}
+
+/// The next record of one source, ordered for the merge heap.
+///
+/// Items are ordered by key, then by sequence number, then by source, and compare equal only
+/// when all three do, so equality and ordering always agree. The winner among the versions
+/// of a key is the one with the highest sequence number, whatever order the sources come
+/// in. Sources only break ties between copies of one version (the same key and sequence
+/// number, as a table left behind by an interrupted compaction holds), which are identical,
+/// so their order never changes the result; the copy from the lowest source index is used.
+struct HeapItem {
     key: Vec<u8>,
+    seqno: u64,
     entry: Entry,
-    sstable_index: usize,
-    iterator: RecordIterator,
+    meta: u8,
+    /// Position of the record's source, where lower means newer.
+    source: usize,
 }
 
-impl PartialEq for IterItem {
+impl PartialEq for HeapItem {
     fn eq(&self, other: &Self) -> bool {
-        self.key == other.key && self.sstable_index == other.sstable_index
+        self.cmp(other) == Ordering::Equal
     }
 }
 
-impl Eq for IterItem {}
+impl Eq for HeapItem {}
 
-impl PartialOrd for IterItem {
+impl PartialOrd for HeapItem {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for IterItem {
+impl Ord for HeapItem {
     fn cmp(&self, other: &Self) -> Ordering {
-        // We want a min-heap on key.
-        // For the same key, we want to prioritize the newest SSTable (lowest index in the slice we received).
-        match other.key.cmp(&self.key) {
-            Ordering::Equal => self.sstable_index.cmp(&other.sstable_index).reverse(),
-            ord => ord,
+        // A min-heap on key. For the same key the newest version comes first: the highest
+        // sequence number, then the lowest source index.
+        other
+            .key
+            .cmp(&self.key)
+            .then(self.seqno.cmp(&other.seqno))
+            .then(self.source.cmp(&other.source).reverse())
+    }
+}
+
+/// Which versions of each key a compaction keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Retention {
+    /// Sequence number of the oldest live snapshot, or `u64::MAX` if there is none.
+    ///
+    /// A snapshot at sequence number `s` sees, for each key, the newest version with a
+    /// sequence number of at most `s`. Every version newer than `min_snapshot_seqno` is kept,
+    /// plus the newest one at or below it; anything older is invisible to every reader.
+    pub(crate) min_snapshot_seqno: u64,
+    /// Whether the inputs hold the oldest data in the store. Only then can a tombstone that
+    /// every reader already sees be dropped, since nothing older is left for it to shadow.
+    pub(crate) bottommost: bool,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            min_snapshot_seqno: u64::MAX,
+            bottommost: false,
         }
     }
 }
@@ -42,67 +88,344 @@ impl Ord for IterItem {
 ///
 /// This function uses a k-way merge algorithm to combine multiple SSTables,
 /// keeping only the latest version of each key and discarding overwritten records.
-pub fn compact(sstables: &[Arc<SSTable>], output_path: &Path) -> io::Result<()> {
+/// The output is written with the default [`TableOptions`]; use [`compact_with_builder`] to
+/// choose them. Returns the metadata of the tables written, which is empty if there was
+/// nothing to merge.
+pub fn compact(sstables: &[Arc<SSTable>], output_path: &Path) -> io::Result<Vec<SSTableMetadata>> {
     if sstables.is_empty() {
-        return Ok(());
+        return Ok(Vec::new());
     }
 
-    let mut heap = BinaryHeap::new();
+    let options = TableOptions::default();
+    let builder = SSTableBuilder::new(output_path, options.sparse_interval)?;
+    compact_with_builder(sstables, builder)
+}
+
+/// Like [`compact`], but writes the output through `builder`, which controls where it goes
+/// and how it is laid out.
+///
+/// If `sstables` is empty, nothing is merged and `builder` is dropped unfinished.
+pub fn compact_with_builder(
+    sstables: &[Arc<SSTable>],
+    builder: SSTableBuilder,
+) -> io::Result<Vec<SSTableMetadata>> {
+    if sstables.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let metadata = compact_into(sstables, builder, Retention::default())?;
+    Ok(vec![metadata])
+}
+
+/// A compaction of [`compact_with_builder`] that runs in bounded slices of time.
+///
+/// Each [`CompactionJob::step`] merges records for at most about `budget`, then returns so the
+/// caller can serve other work in between; the merge heap and the partly written output are
+/// kept across steps. The result is the same table a single-shot compaction would write.
+///
+/// ```no_run
+/// # fn example(inputs: &[std::sync::Arc<lsm_storage_engine::SSTable>]) -> std::io::Result<()> {
+/// use lsm_storage_engine::compaction::CompactionJob;
+/// use lsm_storage_engine::sstable::SSTableBuilder;
+/// use std::time::Duration;
+///
+/// let builder = SSTableBuilder::new("merged.sst", 16)?;
+/// let mut job = CompactionJob::new(inputs, builder)?;
+/// while job.step(Duration::from_millis(5))? {
+///     // Serve requests here.
+/// }
+/// let metadata = job.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct CompactionJob {
+    records: MergeIterator<'static>,
+    builder: SSTableBuilder,
+    done: bool,
+}
+
+impl CompactionJob {
+    /// Starts merging `sstables` (ordered newest first) into `builder`.
+    ///
+    /// This reads the first record of every input but writes nothing yet.
+    pub fn new(sstables: &[Arc<SSTable>], builder: SSTableBuilder) -> io::Result<Self> {
+        let sources = sstables
+            .iter()
+            .map(|sst| table_source(sst))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            records: merge_iterators(sources, Retention::default())?,
+            builder,
+            done: false,
+        })
+    }
+
+    /// Merges records into the output until `budget` has elapsed or the inputs run out, and
+    /// returns whether any work remains.
+    ///
+    /// At least one record is merged per call, so a job always makes progress even with a
+    /// zero budget. The time is checked between records, so a step can overrun its budget by
+    /// the cost of one record. After an error the output is incomplete and the job should be
+    /// dropped. wasm32 has no clock, so there a step ignores its budget and finishes the job.
+    pub fn step(&mut self, budget: Duration) -> io::Result<bool> {
+        // A budget too large to represent is no limit at all.
+        #[cfg(not(target_arch = "wasm32"))]
+        let deadline = Instant::now().checked_add(budget);
+        #[cfg(target_arch = "wasm32")]
+        let deadline: Option<Instant> = {
+            let _ = budget;
+            None
+        };
+        while !self.done {
+            match self.records.next() {
+                Some(record) => {
+                    let (key, seqno, entry, meta) = record?;
+                    self.builder
+                        .add_record_with_meta(&key, seqno, &entry, meta)?;
+                }
+                None => self.done = true,
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break;
+            }
+        }
+        Ok(!self.done)
+    }
+
+    /// Merges whatever is left without a time limit and finishes the output table.
+    pub fn finish(mut self) -> io::Result<SSTableMetadata> {
+        while self.step(Duration::MAX)? {}
+        self.builder.finish_with_metadata()
+    }
+}
+
+/// Merges `sstables` (ordered newest first) into the given `builder` and finishes it, keeping
+/// the versions `retention` asks for.
+pub(crate) fn compact_into(
+    sstables: &[Arc<SSTable>],
+    builder: SSTableBuilder,
+    retention: Retention,
+) -> io::Result<SSTableMetadata> {
+    let sources = sstables
+        .iter()
+        .map(|sst| table_source(sst))
+        .collect::<io::Result<_>>()?;
+    merge_into(sources, builder, retention)
+}
 
-    for (i, sst) in sstables.iter().enumerate() {
-        let mut iter = sst.iter()?;
-        if let Some(result) = iter.next() {
-            let (key, entry) = result?;
-            heap.push(IterItem {
+/// Merges `sources` (ordered newest first) into the given `builder` and finishes it, keeping
+/// the versions `retention` asks for.
+pub(crate) fn merge_into(
+    sources: Vec<RecordSource<'_>>,
+    mut builder: SSTableBuilder,
+    retention: Retention,
+) -> io::Result<SSTableMetadata> {
+    for record in merge_iterators(sources, retention)? {
+        let (key, seqno, entry, meta) = record?;
+        builder.add_record_with_meta(&key, seqno, &entry, meta)?;
+    }
+    builder.finish_with_metadata()
+}
+
+/// Merges `sources` (ordered newest first) with a k-way merge, yielding each key's versions
+/// that `retention` keeps, newest first.
+///
+/// A version that appears in several sources, as when a table was left behind by an
+/// interrupted compaction, is yielded once, from the newest source. Fails if reading the
+/// first record of a source fails; later read errors are yielded and end the merge.
+pub(crate) fn merge_iterators(
+    mut sources: Vec<RecordSource<'_>>,
+    retention: Retention,
+) -> io::Result<MergeIterator<'_>> {
+    let mut heap = BinaryHeap::new();
+    for (source, records) in sources.iter_mut().enumerate() {
+        if let Some(record) = records.next() {
+            let (key, seqno, entry, meta) = record?;
+            heap.push(HeapItem {
                 key,
+                seqno,
                 entry,
-                sstable_index: i,
-                iterator: iter,
+                meta,
+                source,
             });
         }
     }
+    Ok(MergeIterator {
+        sources,
+        heap,
+        retention,
+        last_key: None,
+        last_seqno: 0,
+        settled: false,
+    })
+}
+
+/// The records kept by a merge; see [`merge_iterators`].
+pub(crate) struct MergeIterator<'a> {
+    sources: Vec<RecordSource<'a>>,
+    heap: BinaryHeap<HeapItem>,
+    retention: Retention,
+    last_key: Option<Vec<u8>>,
+    last_seqno: u64,
+    /// Set once a version of the current key that every reader can see has been handled;
+    /// all older versions of that key are then shadowed for everyone.
+    settled: bool,
+}
 
-    let mut builder = SSTableBuilder::new(output_path, 16)?;
-    let mut last_key: Option<Vec<u8>> = None;
-
-    while let Some(mut current) = heap.pop() {
-        // If this key is the same as the last one, it's an older version, so skip it
-        if let Some(ref lk) = last_key
-            && lk == &current.key
-        {
-            // Advance this iterator and push back if not empty
-            if let Some(result) = current.iterator.next() {
-                let (next_key, next_entry) = result?;
-                current.key = next_key;
-                current.entry = next_entry;
-                heap.push(current);
+impl Iterator for MergeIterator<'_> {
+    type Item = io::Result<TaggedRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(current) = self.heap.pop() {
+            // Refill from the source the record came from before deciding on it.
+            match self.sources[current.source].next() {
+                Some(Ok((key, seqno, entry, meta))) => self.heap.push(HeapItem {
+                    key,
+                    seqno,
+                    entry,
+                    meta,
+                    source: current.source,
+                }),
+                Some(Err(e)) => {
+                    self.heap.clear();
+                    return Some(Err(e));
+                }
+                None => {}
+            }
+
+            let same_key = self.last_key.as_ref() == Some(&current.key);
+            // The same version can appear twice if a table was left behind by an interrupted
+            // compaction; it is only yielded once.
+            let duplicate = same_key && self.last_seqno == current.seqno;
+
+            if !same_key {
+                self.last_key = Some(current.key.clone());
+                self.settled = false;
+            }
+            if duplicate || self.settled {
+                continue;
+            }
+            self.last_seqno = current.seqno;
+            self.settled = current.seqno <= self.retention.min_snapshot_seqno;
+            let droppable =
+                self.settled && self.retention.bottommost && current.entry == Entry::Tombstone;
+            if !droppable {
+                return Some(Ok((
+                    current.key,
+                    current.seqno,
+                    current.entry,
+                    current.meta,
+                )));
             }
-            continue;
         }
+        None
+    }
+}
+
+/// How much a window's tombstone ratio counts relative to its age when picking inputs.
+///
+/// Age ranges from 0 (newest window) to 1 (oldest window), so with a weight of 2 a window
+/// that is half tombstones outranks an older, tombstone-free one.
+const TOMBSTONE_WEIGHT: f64 = 2.0;
 
-        // This is the newest version of this key
-        last_key = Some(current.key.clone());
+/// Picks `count` adjacent tables from `sstables` (ordered newest first) to merge next.
+///
+/// Only adjacent tables are considered so that the merged output can take their place
+/// without reordering any key. Older windows are preferred, but a window whose records are
+/// mostly tombstones wins over an older, clean one because merging it reclaims the most.
+pub(crate) fn select_inputs(sstables: &[Arc<SSTable>], count: usize) -> Range<usize> {
+    if sstables.len() <= count {
+        return 0..sstables.len();
+    }
 
-        // Write to new SSTable
-        builder.add_record(&current.key, &current.entry)?;
+    let last_start = sstables.len() - count;
+    let score = |start: usize| {
+        let window = &sstables[start..start + count];
+        let records: u64 = window.iter().map(|s| s.record_count()).sum();
+        let tombstones: u64 = window.iter().map(|s| s.tombstone_count()).sum();
+        let ratio = if records == 0 {
+            0.0
+        } else {
+            tombstones as f64 / records as f64
+        };
+        start as f64 / last_start as f64 + TOMBSTONE_WEIGHT * ratio
+    };
+
+    // Ties go to the older window.
+    let best = (0..=last_start)
+        .max_by(|&a, &b| score(a).total_cmp(&score(b)).then(a.cmp(&b)))
+        .unwrap_or(0);
+    best..best + count
+}
 
-        // Advance iterator and push back
-        if let Some(result) = current.iterator.next() {
-            let (next_key, next_entry) = result?;
-            current.key = next_key;
-            current.entry = next_entry;
-            heap.push(current);
+/// Picks the tables (newest first) to merge the `batch` newest tables of `sstables` with.
+///
+/// An older table joins only if it may share a key with the batch, meaning its key range
+/// covers a batch key that its bloom filter does not rule out, or if its key range overlaps
+/// another older table that joined. A table left out therefore shares no key with the merged
+/// output, so it keeps its place without reordering any key or being exposed by a dropped
+/// tombstone, and is not rewritten. Batch keys are read with [`SSTable::key_iter`].
+pub(crate) fn select_overlapping(
+    sstables: &[Arc<SSTable>],
+    batch: usize,
+) -> io::Result<Vec<Arc<SSTable>>> {
+    let batch = batch.min(sstables.len());
+    let mut keys = Vec::new();
+    for sst in &sstables[..batch] {
+        for key in sst.key_iter()? {
+            keys.push(key?.0);
+        }
+    }
+    keys.sort_unstable();
+    keys.dedup();
+
+    let older = &sstables[batch..];
+    let mut selected: Vec<bool> = older
+        .iter()
+        .map(|sst| match (sst.first_key(), sst.last_key()) {
+            (Some(first), Some(last)) => {
+                let from = keys.partition_point(|k| k.as_slice() < first);
+                let to = keys.partition_point(|k| k.as_slice() <= last);
+                keys[from..to].iter().any(|k| sst.may_contain(k))
+            }
+            _ => false,
+        })
+        .collect();
+    loop {
+        let mut changed = false;
+        for i in 0..older.len() {
+            if !selected[i]
+                && (0..older.len()).any(|j| selected[j] && ranges_overlap(&older[i], &older[j]))
+            {
+                selected[i] = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
         }
     }
 
-    builder.finish()?;
-    Ok(())
+    let chosen = older
+        .iter()
+        .zip(selected)
+        .filter(|(_, s)| *s)
+        .map(|(sst, _)| sst);
+    Ok(sstables[..batch].iter().chain(chosen).cloned().collect())
+}
+
+/// Returns `true` if the key ranges of `a` and `b` intersect.
+fn ranges_overlap(a: &SSTable, b: &SSTable) -> bool {
+    // An empty table has no range, which `overlaps` would read as unbounded.
+    b.first_key().is_some() && a.overlaps(b.first_key(), b.last_key())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::memtable::MemTable;
+    use crate::storage::{FaultyStore, MemoryStore};
+    use crate::version::Version;
     use std::path::PathBuf;
 
     fn setup_test_dir(name: &str) -> PathBuf {
@@ -218,11 +541,7 @@ mod tests {
         .unwrap();
 
         let compacted = SSTable::open(&output_path).unwrap();
-        let mut count = 0;
-        let mut iter = compacted.iter().unwrap();
-        while let Some(_) = iter.next() {
-            count += 1;
-        }
+        let count = compacted.iter().unwrap().count();
         assert_eq!(count, 2);
         let _ = std::fs::remove_dir_all(dir);
     }
@@ -234,4 +553,467 @@ mod tests {
         assert!(compact(&[], &output_path).is_ok());
         let _ = std::fs::remove_dir_all(dir);
     }
+
+    /// A record for hand-built merge sources: key, sequence number and value, where `None`
+    /// is a tombstone.
+    type Rec = (&'static str, u64, Option<&'static str>);
+
+    fn source(records: &[Rec]) -> RecordSource<'static> {
+        let records: Vec<_> = records
+            .iter()
+            .map(|&(key, seqno, value)| {
+                let entry = match value {
+                    Some(v) => Entry::Value(v.as_bytes().to_vec()),
+                    None => Entry::Tombstone,
+                };
+                Ok((key.as_bytes().to_vec(), seqno, entry, 0))
+            })
+            .collect();
+        Box::new(records.into_iter())
+    }
+
+    /// Merges hand-built sources, returning the records kept with their values as strings.
+    fn merge_records(sources: Vec<RecordSource<'static>>, retention: Retention) -> Vec<String> {
+        merge_iterators(sources, retention)
+            .unwrap()
+            .map(|record| {
+                let (key, seqno, entry, _) = record.unwrap();
+                let value = match entry {
+                    Entry::Value(v) => String::from_utf8(v).unwrap(),
+                    Entry::Tombstone => "-".to_string(),
+                };
+                format!("{}@{}={}", String::from_utf8(key).unwrap(), seqno, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_iterators_keeps_newest_versions() {
+        let newer = || source(&[("a", 5, Some("a5")), ("c", 6, None), ("d", 7, Some("d7"))]);
+        let older = || {
+            source(&[
+                ("a", 1, Some("a1")),
+                ("b", 2, Some("b2")),
+                ("c", 3, Some("c3")),
+                ("d", 7, Some("d7")),
+            ])
+        };
+
+        // Overwritten versions go, the tombstone stays since older data may lie beneath it,
+        // and the version of `d` found in both sources comes out once.
+        assert_eq!(
+            merge_records(vec![newer(), older()], Retention::default()),
+            ["a@5=a5", "b@2=b2", "c@6=-", "d@7=d7"]
+        );
+        let bottommost = Retention {
+            bottommost: true,
+            ..Retention::default()
+        };
+        assert_eq!(
+            merge_records(vec![newer(), older()], bottommost),
+            ["a@5=a5", "b@2=b2", "d@7=d7"]
+        );
+
+        // A snapshot at seqno 4 still needs the versions it sees beneath newer ones.
+        let snapshot = Retention {
+            min_snapshot_seqno: 4,
+            bottommost: true,
+        };
+        assert_eq!(
+            merge_records(vec![newer(), older()], snapshot),
+            ["a@5=a5", "a@1=a1", "b@2=b2", "c@6=-", "c@3=c3", "d@7=d7"]
+        );
+
+        assert!(merge_records(vec![], Retention::default()).is_empty());
+        assert_eq!(
+            merge_records(vec![source(&[]), older()], bottommost).len(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_newest_version_wins_in_any_input_order() {
+        let table = |records: &[(&str, u64, Option<&str>)]| {
+            let store = MemoryStore::new();
+            let mut builder = SSTableBuilder::with_store(store.clone(), "input.sst", 1);
+            for &(key, seqno, value) in records {
+                let entry = value.map_or(Entry::Tombstone, |v| Entry::Value(v.into()));
+                builder.add_record(key.as_bytes(), seqno, &entry).unwrap();
+            }
+            builder.finish().unwrap();
+            Arc::new(SSTable::from_store(Arc::new(store), "input.sst").unwrap())
+        };
+        let tables = [
+            table(&[("k", 4, Some("mid")), ("x", 2, Some("x2"))]),
+            table(&[("k", 9, Some("new")), ("x", 2, Some("x2"))]),
+            table(&[("k", 1, None), ("y", 3, Some("y3"))]),
+        ];
+
+        let orders = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+        for order in orders {
+            let inputs: Vec<_> = order.iter().map(|&i| Arc::clone(&tables[i])).collect();
+            let sources = inputs
+                .iter()
+                .map(|sst| table_source(sst).unwrap())
+                .collect();
+            assert_eq!(
+                merge_records(sources, Retention::default()),
+                ["k@9=new", "x@2=x2", "y@3=y3"],
+                "input order {:?}",
+                order
+            );
+
+            let output = MemoryStore::new();
+            compact_with_builder(
+                &inputs,
+                SSTableBuilder::with_store(output.clone(), "out.sst", 1),
+            )
+            .unwrap();
+            let merged = SSTable::from_store(Arc::new(output), "out.sst").unwrap();
+            assert_eq!(merged.get(b"k").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(merged.record_count(), 3);
+        }
+    }
+
+    #[test]
+    fn test_merge_iterators_stops_at_read_error() {
+        let failing: RecordSource<'static> = Box::new(
+            [
+                Ok((b"a".to_vec(), 1, Entry::Value(b"1".to_vec()), 0)),
+                Err(io::Error::other("disk gone")),
+            ]
+            .into_iter(),
+        );
+        let mut merge = merge_iterators(
+            vec![failing, source(&[("b", 2, Some("2"))])],
+            Retention::default(),
+        )
+        .unwrap();
+        assert!(merge.next().unwrap().is_err());
+        assert!(merge.next().is_none());
+    }
+
+    #[test]
+    fn test_compact_fault_leaves_inputs_intact() {
+        let dir = setup_test_dir("fault");
+
+        let mut mt1 = MemTable::new(1 << 20);
+        let mut mt2 = MemTable::new(1 << 20);
+        for i in 0..100 {
+            mt1.put(format!("k{:03}", i).into_bytes(), b"old".to_vec());
+            mt2.put(format!("k{:03}", i * 2).into_bytes(), b"new".to_vec());
+        }
+        let sst1_path = dir.join("sst1.sst");
+        let sst2_path = dir.join("sst2.sst");
+        SSTableBuilder::new(&sst1_path, 4)
+            .unwrap()
+            .build(&mt1)
+            .unwrap();
+        SSTableBuilder::new(&sst2_path, 4)
+            .unwrap()
+            .build(&mt2)
+            .unwrap();
+        let inputs = [
+            Arc::new(SSTable::open(&sst2_path).unwrap()),
+            Arc::new(SSTable::open(&sst1_path).unwrap()),
+        ];
+
+        let output = MemoryStore::new();
+        let builder = SSTableBuilder::with_store(
+            FaultyStore::fail_writes_after(output.clone(), 500),
+            "compact.sst",
+            16,
+        );
+        assert!(compact_into(&inputs, builder, Retention::default()).is_err());
+        assert!(SSTable::from_store(Arc::new(output), "compact.sst").is_err());
+
+        assert_eq!(inputs[0].get(b"k010").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(inputs[1].get(b"k011").unwrap(), Some(b"old".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    fn memory_table(puts: &[&[u8]], deletes: &[&[u8]]) -> Arc<SSTable> {
+        let mut mt = MemTable::new(1024);
+        for key in puts {
+            mt.put(key.to_vec(), b"v".to_vec());
+        }
+        for key in deletes {
+            mt.delete(key.to_vec());
+        }
+        let store = MemoryStore::new();
+        SSTableBuilder::with_store(store.clone(), "select.sst", 1)
+            .build(&mt)
+            .unwrap();
+        Arc::new(SSTable::from_store(Arc::new(store), "select.sst").unwrap())
+    }
+
+    #[test]
+    fn test_select_inputs_prefers_tombstone_heavy_tables() {
+        let clean = || memory_table(&[b"a", b"b", b"c", b"d"], &[]);
+        let heavy = || memory_table(&[b"a"], &[b"b", b"c", b"d"]);
+
+        // Without tombstones the oldest window wins.
+        let tables = vec![clean(), clean(), clean(), clean()];
+        assert_eq!(select_inputs(&tables, 2), 2..4);
+
+        // A tombstone-heavy pair of newer tables beats the older, clean pair.
+        let tables = vec![heavy(), heavy(), clean(), clean()];
+        assert_eq!(tables[0].tombstone_count(), 3);
+        assert_eq!(select_inputs(&tables, 2), 0..2);
+
+        // Asking for at least as many tables as exist selects them all.
+        assert_eq!(select_inputs(&tables, 4), 0..4);
+    }
+
+    /// Builds an in-memory table from `(key, seqno, entry)` records, already in table order.
+    fn versioned_table(records: &[(&[u8], u64, Entry)]) -> Arc<SSTable> {
+        let store = MemoryStore::new();
+        let mut builder = SSTableBuilder::with_store(store.clone(), "versions.sst", 1);
+        for (key, seqno, entry) in records {
+            builder.add_record(key, *seqno, entry).unwrap();
+        }
+        builder.finish().unwrap();
+        Arc::new(SSTable::from_store(Arc::new(store), "versions.sst").unwrap())
+    }
+
+    fn merged(tables: &[Arc<SSTable>], retention: Retention) -> Vec<(Vec<u8>, u64, Entry)> {
+        let store = MemoryStore::new();
+        let builder = SSTableBuilder::with_store(store.clone(), "merged.sst", 16);
+        compact_into(tables, builder, retention).unwrap();
+        SSTable::from_store(Arc::new(store), "merged.sst")
+            .unwrap()
+            .iter()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_highest_version_wins_whatever_the_merge_order() {
+        let version = |timestamp, node_id| Version { timestamp, node_id };
+        // Two nodes write the same keys without seeing each other's writes.
+        let value = |v: &[u8]| Entry::Value(v.to_vec());
+        let node_table = |writes: Vec<(&[u8], Entry, Version)>| {
+            let mut mt = MemTable::new(1 << 16);
+            for (key, entry, at) in writes {
+                match entry {
+                    Entry::Value(value) => mt.put_with_version(key.to_vec(), value, 0, at),
+                    Entry::Tombstone => mt.delete_with_version(key.to_vec(), at),
+                }
+            }
+            let store = MemoryStore::new();
+            mt.merge_into(SSTableBuilder::with_store(store.clone(), "node.sst", 4))
+                .unwrap();
+            Arc::new(SSTable::from_store(Arc::new(store), "node.sst").unwrap())
+        };
+        let one = node_table(vec![
+            (b"a", value(b"a@one"), version(20, 1)),
+            (b"b", value(b"b@one"), version(10, 1)),
+            (b"c", Entry::Tombstone, version(30, 1)),
+        ]);
+        let two = node_table(vec![
+            (b"a", value(b"a@two"), version(20, 2)),
+            (b"b", value(b"b@two"), version(9, 2)),
+            (b"c", value(b"c@two"), version(25, 2)),
+        ]);
+
+        let winners = |tables: &[Arc<SSTable>]| -> Vec<(Vec<u8>, Version, Entry)> {
+            merged(tables, Retention::default())
+                .into_iter()
+                .map(|(key, seqno, entry)| (key, Version::from_seqno(seqno), entry))
+                .collect()
+        };
+        let expected = vec![
+            // Equal timestamps fall back to the node id.
+            (b"a".to_vec(), version(20, 2), value(b"a@two")),
+            (b"b".to_vec(), version(10, 1), value(b"b@one")),
+            (b"c".to_vec(), version(30, 1), Entry::Tombstone),
+        ];
+        assert_eq!(winners(&[Arc::clone(&one), Arc::clone(&two)]), expected);
+        assert_eq!(winners(&[two, one]), expected);
+    }
+
+    #[test]
+    fn test_retention_keeps_versions_for_snapshots() {
+        let value = |v: &[u8]| Entry::Value(v.to_vec());
+        let newer = versioned_table(&[(b"a", 6, Entry::Tombstone), (b"b", 5, value(b"b5"))]);
+        let older = versioned_table(&[
+            (b"a", 4, value(b"a4")),
+            (b"a", 2, value(b"a2")),
+            (b"b", 1, value(b"b1")),
+        ]);
+        let tables = [newer, older];
+
+        // A snapshot at 4 still needs a@4 and b@1; a@2 is shadowed for every reader.
+        let retention = Retention {
+            min_snapshot_seqno: 4,
+            bottommost: true,
+        };
+        assert_eq!(
+            merged(&tables, retention),
+            vec![
+                (b"a".to_vec(), 6, Entry::Tombstone),
+                (b"a".to_vec(), 4, value(b"a4")),
+                (b"b".to_vec(), 5, value(b"b5")),
+                (b"b".to_vec(), 1, value(b"b1")),
+            ]
+        );
+
+        // Without snapshots only the newest versions remain, and a bottommost compaction
+        // drops the tombstone together with everything it shadows.
+        assert_eq!(
+            merged(&tables, Retention::default()),
+            vec![
+                (b"a".to_vec(), 6, Entry::Tombstone),
+                (b"b".to_vec(), 5, value(b"b5")),
+            ]
+        );
+        let retention = Retention {
+            bottommost: true,
+            ..Retention::default()
+        };
+        assert_eq!(
+            merged(&tables, retention),
+            vec![(b"b".to_vec(), 5, value(b"b5"))]
+        );
+
+        // The same version left behind in two tables is written once.
+        let dup = versioned_table(&[(b"c", 7, value(b"c7"))]);
+        let dup_again = versioned_table(&[(b"c", 7, value(b"c7"))]);
+        assert_eq!(
+            merged(&[dup, dup_again], Retention::default()),
+            vec![(b"c".to_vec(), 7, value(b"c7"))]
+        );
+    }
+
+    #[test]
+    fn test_compact_with_builder_controls_output() {
+        let inputs: Vec<Arc<SSTable>> = (0..2u8)
+            .map(|t| {
+                versioned_table(
+                    &(0..10u8)
+                        .map(|i| {
+                            (
+                                &b"abcdefghij"[i as usize..=i as usize],
+                                10 - t as u64,
+                                Entry::Value(vec![t]),
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect();
+
+        let store = MemoryStore::new();
+        let options = TableOptions {
+            sparse_interval: 3,
+            ..TableOptions::default()
+        };
+        let builder =
+            SSTableBuilder::with_store(store.clone(), "tuned.sst", 16).with_options(&options);
+        let produced = compact_with_builder(&inputs, builder).unwrap();
+        assert_eq!(produced.len(), 1);
+        assert_eq!(produced[0].path, PathBuf::from("tuned.sst"));
+
+        let output = SSTable::from_store(Arc::new(store), "tuned.sst").unwrap();
+        assert_eq!(output.record_count(), 10);
+        assert_eq!(output.index_keys(), vec![b"a", b"d", b"g", b"j"]);
+        assert_eq!(output.get(b"e").unwrap(), Some(vec![0]));
+    }
+
+    #[test]
+    fn test_compaction_job_in_steps_matches_single_shot() {
+        let inputs: Vec<Arc<SSTable>> = (0..3u64)
+            .map(|t| {
+                let keys: Vec<Vec<u8>> = (0..50u64)
+                    .filter(|i| i % (t + 1) == 0)
+                    .map(|i| format!("k{:03}", i).into_bytes())
+                    .collect();
+                versioned_table(
+                    &keys
+                        .iter()
+                        .enumerate()
+                        .map(|(i, key)| {
+                            let entry = match i % 7 {
+                                3 => Entry::Tombstone,
+                                _ => Entry::Value(vec![t as u8]),
+                            };
+                            (key.as_slice(), 100 - t, entry)
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect();
+        let read_output = |store: MemoryStore, name: &str| -> Vec<(Vec<u8>, u64, Entry)> {
+            SSTable::from_store(Arc::new(store), name)
+                .unwrap()
+                .iter()
+                .unwrap()
+                .map(Result::unwrap)
+                .collect()
+        };
+
+        let single = MemoryStore::new();
+        compact_with_builder(
+            &inputs,
+            SSTableBuilder::with_store(single.clone(), "single.sst", 4),
+        )
+        .unwrap();
+
+        let stepped = MemoryStore::new();
+        let builder = SSTableBuilder::with_store(stepped.clone(), "stepped.sst", 4);
+        let mut job = CompactionJob::new(&inputs, builder).unwrap();
+        let mut steps = 1;
+        while job.step(Duration::ZERO).unwrap() {
+            steps += 1;
+        }
+        assert!(!job.step(Duration::ZERO).unwrap());
+        let metadata = job.finish().unwrap();
+
+        let expected = read_output(single, "single.sst");
+        assert_eq!(expected.len(), 50);
+        // A zero budget merges one record per step.
+        assert_eq!(steps, expected.len() + 1);
+        assert_eq!(metadata.path, PathBuf::from("stepped.sst"));
+        assert_eq!(read_output(stepped, "stepped.sst"), expected);
+    }
+
+    #[test]
+    fn test_select_overlapping_skips_tables_without_batch_keys() {
+        let table = |keys: &[&[u8]]| {
+            versioned_table(
+                &keys
+                    .iter()
+                    .map(|k| (*k, 1, Entry::Value(k.to_vec())))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let batch = table(&[b"b", b"m"]);
+        // Covers "b" by range, but its bloom filter rules it out.
+        let around_b = table(&[b"a", b"c"]);
+        let holds_m = table(&[b"l", b"m", b"n"]);
+        let disjoint = table(&[b"x", b"z"]);
+        let tables = vec![batch.clone(), around_b, holds_m.clone(), disjoint];
+
+        let selected = select_overlapping(&tables, 1).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert!(Arc::ptr_eq(&selected[0], &batch));
+        assert!(Arc::ptr_eq(&selected[1], &holds_m));
+
+        // An older table overlapping a selected one must come along too, or its keys would
+        // end up newer than theirs.
+        let mut tables = tables;
+        let behind_m = table(&[b"n", b"o"]);
+        tables.push(behind_m.clone());
+        let selected = select_overlapping(&tables, 1).unwrap();
+        assert_eq!(selected.len(), 3);
+        assert!(Arc::ptr_eq(&selected[2], &behind_m));
+    }
 }
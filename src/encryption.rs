@@ -0,0 +1,391 @@
+//! At-rest encryption of SSTables and the WAL with XChaCha20-Poly1305.
+//!
+//! Every encrypted file starts with a header: a magic marker, a nonce prefix drawn at random
+//! for that file, and a tag computed with the key that lets a wrong key be told apart from a
+//! damaged file. The key itself is never written. Each sealed unit after the header (a chunk
+//! of an SSTable, a record of the WAL) is encrypted under the file's nonce prefix followed by
+//! its own counter, so no nonce is used twice with one key.
+//!
+//! SSTables are sealed in [`CHUNK_SIZE`] chunks by [`EncryptingStore`] and read back through
+//! [`EncryptedStore`], which decrypts whichever chunks a read covers. The final chunk is
+//! marked as such, so a table cut short at a chunk boundary fails authentication rather than
+//! reading as a shorter table.
+
+use crate::cache::BLOCK_SIZE;
+use crate::storage::{ReadableStore, WritableStore};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Bytes of plaintext sealed together in one chunk of an encrypted SSTable.
+///
+/// Matches the block cache's [`BLOCK_SIZE`], so a cached block costs one chunk to decrypt.
+pub const CHUNK_SIZE: usize = BLOCK_SIZE;
+
+/// Marks the start of an encrypted file.
+const MAGIC: &[u8; 8] = b"LSMCRYPT";
+/// Bytes of the random nonce prefix stored in the header.
+const PREFIX_SIZE: usize = 16;
+/// Bytes of the authentication tag that follows every sealed unit.
+const TAG_SIZE: usize = 16;
+/// Bytes of the header: the magic, the nonce prefix and the key check tag.
+pub(crate) const HEADER_SIZE: usize = MAGIC.len() + PREFIX_SIZE + TAG_SIZE;
+/// Counter of the nonce that the key check tag is computed under; never used for data.
+const KEY_CHECK_COUNTER: u64 = u64::MAX;
+
+/// The key that an engine's SSTables and WAL are encrypted with.
+///
+/// Set it as [`EngineOptions::encryption`](crate::EngineOptions::encryption). Opening a store
+/// with a different key fails with `PermissionDenied` before anything is read.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Encryption {
+    key: [u8; 32],
+}
+
+impl Encryption {
+    /// Encrypts with the 256-bit `key`.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.key.into())
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption").finish_non_exhaustive()
+    }
+}
+
+/// Returns `true` if `data` starts like an encrypted file.
+///
+/// Also accepts a prefix of the magic, which is what a header torn while it was being
+/// written leaves behind.
+pub(crate) fn is_sealed(data: &[u8]) -> bool {
+    let n = data.len().min(MAGIC.len());
+    n > 0 && data[..n] == MAGIC[..n]
+}
+
+/// Returns `true` if `store` holds an encrypted file.
+pub(crate) fn store_is_sealed(store: &dyn ReadableStore) -> io::Result<bool> {
+    let mut magic = [0u8; MAGIC.len()];
+    let n = read_fully_at(store, &mut magic, 0)?;
+    Ok(n == MAGIC.len() && is_sealed(&magic))
+}
+
+/// The key together with the nonce prefix of one file.
+pub(crate) struct FileCipher {
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_SIZE],
+}
+
+impl FileCipher {
+    /// Draws a nonce prefix for a new file, returning the cipher and the header to write
+    /// before anything it seals.
+    pub(crate) fn create(encryption: &Encryption) -> io::Result<(Self, Vec<u8>)> {
+        let mut prefix = [0u8; PREFIX_SIZE];
+        OsRng
+            .try_fill_bytes(&mut prefix)
+            .map_err(|e| io::Error::other(format!("cannot draw a nonce: {}", e)))?;
+        let file = Self {
+            cipher: encryption.cipher(),
+            prefix,
+        };
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&prefix);
+        header.extend_from_slice(&file.seal(KEY_CHECK_COUNTER, MAGIC, &[]));
+        Ok((file, header))
+    }
+
+    /// Reads the header at the start of `data`.
+    ///
+    /// Fails with `InvalidInput` if `data` is not an encrypted file, `InvalidData` if the
+    /// header is cut short, and `PermissionDenied` if the file was encrypted with another key.
+    pub(crate) fn from_header(data: &[u8], encryption: &Encryption) -> io::Result<Self> {
+        if !is_sealed(data) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file is not encrypted",
+            ));
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encryption header is truncated",
+            ));
+        }
+        let file = Self {
+            cipher: encryption.cipher(),
+            prefix: data[MAGIC.len()..MAGIC.len() + PREFIX_SIZE]
+                .try_into()
+                .unwrap(),
+        };
+        let check = &data[MAGIC.len() + PREFIX_SIZE..HEADER_SIZE];
+        if file.open(KEY_CHECK_COUNTER, MAGIC, check).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file was encrypted with a different key",
+            ));
+        }
+        Ok(file)
+    }
+
+    fn nonce(&self, counter: u64) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..PREFIX_SIZE].copy_from_slice(&self.prefix);
+        nonce[PREFIX_SIZE..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    /// Encrypts `plaintext` as the unit numbered `counter`, appending its tag.
+    pub(crate) fn seal(&self, counter: u64, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        self.cipher
+            .encrypt(&self.nonce(counter), payload)
+            .expect("sealing in memory cannot fail")
+    }
+
+    /// Decrypts the unit numbered `counter`, failing with `InvalidData` if it does not
+    /// authenticate.
+    pub(crate) fn open(&self, counter: u64, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let payload = Payload { msg: sealed, aad };
+        self.cipher
+            .decrypt(&self.nonce(counter), payload)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "encrypted data failed authentication",
+                )
+            })
+    }
+}
+
+/// Additional data that tells the final chunk of an SSTable from the others.
+fn chunk_aad(last: bool) -> &'static [u8] {
+    if last { &[1] } else { &[0] }
+}
+
+/// Fills as much of `buf` as `store` holds from `offset` on, returning how much that was.
+fn read_fully_at(store: &dyn ReadableStore, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match store.read_at(&mut buf[filled..], offset + filled as u64)? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Writes an encrypted SSTable to a wrapped store.
+///
+/// Writes are buffered into [`CHUNK_SIZE`] chunks. [`WritableStore::sync`] seals the final,
+/// possibly shorter, chunk, after which the store accepts no more writes: an SSTable is
+/// written once from start to end.
+pub struct EncryptingStore {
+    inner: Box<dyn WritableStore>,
+    cipher: FileCipher,
+    pending: Vec<u8>,
+    next_chunk: u64,
+    finished: bool,
+}
+
+impl EncryptingStore {
+    /// Starts an encrypted file in `inner`, which must be empty, by writing its header.
+    pub fn new(inner: impl WritableStore + 'static, encryption: &Encryption) -> io::Result<Self> {
+        let mut inner = Box::new(inner);
+        let (cipher, header) = FileCipher::create(encryption)?;
+        inner.write_all(&header)?;
+        Ok(Self {
+            inner,
+            cipher,
+            pending: Vec::with_capacity(CHUNK_SIZE),
+            next_chunk: 0,
+            finished: false,
+        })
+    }
+
+    /// Seals the first `len` pending bytes as the next chunk and writes it.
+    fn write_chunk(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let sealed = self
+            .cipher
+            .seal(self.next_chunk, chunk_aad(last), &self.pending[..len]);
+        self.next_chunk += 1;
+        self.pending.drain(..len);
+        self.inner.write_all(&sealed)
+    }
+}
+
+impl WritableStore for EncryptingStore {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other(
+                "encrypted table was already finished by sync",
+            ));
+        }
+        self.pending.extend_from_slice(buf);
+        // A full chunk is only sealed once more data follows, since the final chunk is
+        // sealed differently.
+        while self.pending.len() > CHUNK_SIZE {
+            self.write_chunk(CHUNK_SIZE, false)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.finished = true;
+            self.write_chunk(self.pending.len(), true)?;
+        }
+        self.inner.sync()
+    }
+}
+
+/// Reads an SSTable written by an [`EncryptingStore`], decrypting it on the fly.
+pub struct EncryptedStore {
+    inner: Arc<dyn ReadableStore>,
+    cipher: FileCipher,
+    /// Number of chunks, the last of which may be shorter than the others.
+    chunks: u64,
+    /// Bytes of plaintext.
+    len: u64,
+}
+
+impl EncryptedStore {
+    /// Wraps `inner` after checking its header against `encryption`.
+    ///
+    /// Fails with `PermissionDenied` if the file was encrypted with another key, and with
+    /// `InvalidInput` if it is not encrypted at all.
+    pub fn new(inner: Arc<dyn ReadableStore>, encryption: &Encryption) -> io::Result<Self> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        let n = read_fully_at(&*inner, &mut header, 0)?;
+        header.truncate(n);
+        let cipher = FileCipher::from_header(&header, encryption)?;
+
+        let body = inner.len()? - HEADER_SIZE as u64;
+        let sealed_chunk = (CHUNK_SIZE + TAG_SIZE) as u64;
+        let chunks = body.div_ceil(sealed_chunk).max(1);
+        let last_sealed = body - (chunks - 1) * sealed_chunk;
+        if last_sealed < TAG_SIZE as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encrypted table ends part-way through a chunk",
+            ));
+        }
+        Ok(Self {
+            inner,
+            cipher,
+            chunks,
+            len: (chunks - 1) * CHUNK_SIZE as u64 + last_sealed - TAG_SIZE as u64,
+        })
+    }
+
+    /// Reads and decrypts chunk `index`.
+    fn chunk(&self, index: u64) -> io::Result<Vec<u8>> {
+        let last = index + 1 == self.chunks;
+        let plain_len = match last {
+            true => self.len - index * CHUNK_SIZE as u64,
+            false => CHUNK_SIZE as u64,
+        };
+        let mut sealed = vec![0u8; plain_len as usize + TAG_SIZE];
+        let start = HEADER_SIZE as u64 + index * (CHUNK_SIZE + TAG_SIZE) as u64;
+        if read_fully_at(&*self.inner, &mut sealed, start)? < sealed.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.cipher.open(index, chunk_aad(last), &sealed)
+    }
+}
+
+impl ReadableStore for EncryptedStore {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() && offset + (read as u64) < self.len {
+            let pos = offset + read as u64;
+            let chunk = self.chunk(pos / CHUNK_SIZE as u64)?;
+            let within = (pos % CHUNK_SIZE as u64) as usize;
+            let n = (buf.len() - read).min(chunk.len() - within);
+            buf[read..read + n].copy_from_slice(&chunk[within..within + n]);
+            read += n;
+        }
+        Ok(read)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    fn seal_all(data: &[u8], encryption: &Encryption) -> MemoryStore {
+        let store = MemoryStore::new();
+        let mut writer = EncryptingStore::new(store.clone(), encryption).unwrap();
+        // Uneven writes, so chunks are filled across several of them.
+        for piece in data.chunks(1000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.sync().unwrap();
+        store
+    }
+
+    #[test]
+    fn test_encrypted_store_round_trips_across_chunks() {
+        let encryption = Encryption::new([7; 32]);
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 100] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let store = seal_all(&data, &encryption);
+            if len >= 64 {
+                let probe = &data[..64];
+                assert!(!store.to_vec().windows(probe.len()).any(|w| w == probe));
+            }
+
+            let reader = EncryptedStore::new(Arc::new(store), &encryption).unwrap();
+            assert_eq!(reader.len().unwrap(), len as u64);
+            let mut buf = vec![0u8; len + 10];
+            assert_eq!(reader.read_at(&mut buf, 0).unwrap(), len);
+            assert_eq!(&buf[..len], &data[..]);
+            if len > CHUNK_SIZE + 10 {
+                // A read that straddles two chunks.
+                let mut buf = [0u8; 20];
+                reader.read_at(&mut buf, CHUNK_SIZE as u64 - 10).unwrap();
+                assert_eq!(&buf, &data[CHUNK_SIZE - 10..CHUNK_SIZE + 10]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encrypted_store_rejects_wrong_key_and_tampering() {
+        let encryption = Encryption::new([7; 32]);
+        let data = vec![42u8; 2 * CHUNK_SIZE + 5];
+        let sealed = seal_all(&data, &encryption).to_vec();
+
+        let wrong = EncryptedStore::new(Arc::new(sealed.clone()), &Encryption::new([8; 32]));
+        assert_eq!(wrong.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        let plain = EncryptedStore::new(Arc::new(data.clone()), &encryption);
+        assert_eq!(plain.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+        let mut flipped = sealed.clone();
+        flipped[HEADER_SIZE + 3] ^= 1;
+        let reader = EncryptedStore::new(Arc::new(flipped), &encryption).unwrap();
+        let mut buf = [0u8; 10];
+        let err = reader.read_at(&mut buf, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Dropping the final chunk leaves a table whose new last chunk was not sealed as one.
+        let cut = sealed[..HEADER_SIZE + 2 * (CHUNK_SIZE + TAG_SIZE)].to_vec();
+        let reader = EncryptedStore::new(Arc::new(cut), &encryption).unwrap();
+        assert!(reader.read_at(&mut buf, CHUNK_SIZE as u64).is_err());
+    }
+}
@@ -1,10 +1,530 @@
+use crate::bloom::BloomKey;
+use crate::cache::{CacheKind, CacheManager};
+use crate::compaction;
+#[cfg(feature = "encryption")]
+use crate::encryption::{self, EncryptedStore, EncryptingStore, Encryption};
 use crate::memtable::{Entry, MemTable};
-use crate::sstable::{SSTable, SSTableBuilder};
-use crate::wal::{Wal, WalEntry};
+use crate::snapshot::{Snapshot, SnapshotList};
+use crate::sstable::{SSTable, SSTableBuilder, SSTableMetadata, TableOptions, half_open};
+use crate::storage::{self, CachedFile, FileCache, MemoryStore, ReadableStore};
+use crate::version::VersionClock;
+use crate::wal::{GroupCommit, RecoverReport, Wal, WalEntry, WalSyncMode};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::io;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard, TryLockError, TryLockResult};
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Number of SSTables that triggers a background compaction, and how many it merges, under
+/// [`CompactionPriority::Balanced`].
+const COMPACTION_TRIGGER: usize = 4;
+
+/// Number of SSTables that triggers a background compaction under [`CompactionPriority::Lazy`].
+const LAZY_COMPACTION_TRIGGER: usize = 8;
+
+/// Number of SSTables that triggers a background compaction under [`CompactionPriority::Eager`].
+const EAGER_COMPACTION_TRIGGER: usize = 2;
+
+/// Smallest `max_memtable_size` that opening an engine accepts without a warning.
+///
+/// Below it a MemTable holds only a few entries, so nearly every write flushes a table of its
+/// own. Tests that want frequent flushes opt in with [`EngineOptions::allow_tiny_memtable`].
+pub const MIN_MEMTABLE_SIZE: usize = 1024;
+
+/// File name of the write-ahead log inside the WAL directory.
+const WAL_FILE: &str = "active.wal";
+
+/// File in the data directory that records which directory holds the WAL.
+const WAL_LOCATION_FILE: &str = "wal.location";
+
+/// Appended to the file name of an SSTable while it is being written.
+const TMP_TABLE_SUFFIX: &str = ".tmp";
+
+/// File in the data directory that lists the SSTables, newest first.
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// First line of [`MANIFEST_FILE`], naming its format version.
+const MANIFEST_HEADER: &str = "lsm-manifest 1";
+
+/// What `Engine::open` does with an SSTable that cannot be loaded because it is corrupt, and
+/// what [`Engine::scrub_step`] does with one it finds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Fail to open the engine.
+    #[default]
+    Fail,
+    /// Leave the file where it is and open without it.
+    Skip,
+    /// Rename the file to `<name>.corrupt` for later inspection and open without it.
+    Quarantine,
+}
+
+/// How eagerly background compaction merges SSTables, trading write amplification (how often
+/// a record is rewritten) against read amplification (how many tables a read may probe).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompactionPriority {
+    /// Wait for eight tables and merge eight adjacent ones into one larger tier.
+    ///
+    /// Records are rewritten less often, which suits write-heavy ingest, but up to eight
+    /// tables may have to be probed by a read.
+    Lazy,
+    /// Wait for four tables and merge four adjacent ones.
+    #[default]
+    Balanced,
+    /// Merge every table into one as soon as there are two.
+    ///
+    /// Reads probe at most a couple of tables, which suits read-heavy serving, but every
+    /// compaction rewrites all data written so far.
+    Eager,
+}
+
+impl CompactionPriority {
+    /// Number of SSTables at which a background compaction is triggered.
+    fn trigger(self) -> usize {
+        match self {
+            CompactionPriority::Lazy => LAZY_COMPACTION_TRIGGER,
+            CompactionPriority::Balanced => COMPACTION_TRIGGER,
+            CompactionPriority::Eager => EAGER_COMPACTION_TRIGGER,
+        }
+    }
+
+    /// Picks the tables (newest first) that a background compaction merges.
+    fn select_inputs(self, sstables: &[Arc<SSTable>]) -> Range<usize> {
+        match self {
+            CompactionPriority::Eager => 0..sstables.len(),
+            _ => compaction::select_inputs(sstables, self.trigger()),
+        }
+    }
+
+    /// Estimates the bytes that compactions must rewrite before tables of `sizes` (newest
+    /// first) stop triggering one; see [`Engine::compaction_debt`].
+    fn debt(self, sizes: &[u64]) -> u64 {
+        let trigger = self.trigger();
+        if self == CompactionPriority::Eager {
+            return if sizes.len() >= trigger {
+                sizes.iter().sum()
+            } else {
+                0
+            };
+        }
+        // Each merge takes `trigger` tables, here always the oldest, and leaves one table
+        // as large as all of them.
+        let mut sizes = sizes.to_vec();
+        let mut debt = 0;
+        while sizes.len() >= trigger {
+            let merged: u64 = sizes.drain(sizes.len() - trigger..).sum();
+            debt += merged;
+            sizes.push(merged);
+        }
+        debt
+    }
+}
+
+/// When a flush merges the MemTable into the newest SSTable instead of adding a new table.
+///
+/// Workloads that keep overwriting a small hot set otherwise add a table per flush that
+/// mostly repeats the keys of the one before. Merging rewrites the newest table on every such
+/// flush, so it only pays off while that table stays small.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlushMergeOptions {
+    /// Largest data section, in bytes, that the newest table may have to be merged into.
+    pub max_table_size: u64,
+    /// Smallest fraction of the MemTable's keys that must already be in the newest table.
+    pub min_overlap: f64,
+}
+
+impl Default for FlushMergeOptions {
+    fn default() -> Self {
+        Self {
+            max_table_size: 1024 * 1024,
+            min_overlap: 0.5,
+        }
+    }
+}
+
+/// How [`Engine::get`] retries an SSTable read that failed with a transient error.
+///
+/// Only [`io::ErrorKind::Interrupted`], `WouldBlock`, `TimedOut` and `ResourceBusy` are
+/// retried, as networked and virtual filesystems may report them for a read that succeeds
+/// when repeated. Any other error, such as a checksum mismatch or a table that ends where
+/// a record should be, is returned at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadRetryPolicy {
+    /// Attempts per read, the first included; `1` never retries.
+    pub max_attempts: u32,
+    /// Pause before the first retry, doubled before each further one.
+    pub backoff: Duration,
+}
+
+impl Default for ReadRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+impl ReadRetryPolicy {
+    /// Runs `read`, retrying it under this policy while it fails with a transient error.
+    fn run<T>(&self, mut read: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match read() {
+                Err(e) if attempt < self.max_attempts && is_transient(&e) => {
+                    // There is no way to sleep on wasm32, so retries follow each other there.
+                    #[cfg(not(target_arch = "wasm32"))]
+                    std::thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Returns `true` if `error` may go away when the operation is repeated.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ResourceBusy
+    )
+}
+
+/// Options for [`Engine::open_with_options`].
+#[derive(Debug, Clone)]
+pub struct EngineOptions {
+    /// Size in bytes at which the active MemTable is flushed to an SSTable.
+    pub max_memtable_size: usize,
+    /// Accepts a `max_memtable_size` below [`MIN_MEMTABLE_SIZE`] without a warning in
+    /// [`OpenReport::warnings`]; useful in tests that want a flush every few writes.
+    pub allow_tiny_memtable: bool,
+    /// How to handle corrupt SSTables found while opening or by [`Engine::scrub_step`].
+    pub on_corrupt: CorruptionPolicy,
+    /// Settings for the SSTables the engine writes when flushing and compacting.
+    pub table: TableOptions,
+    /// Maximum number of SSTable files kept open at once, or `None` to keep every table's
+    /// file open.
+    ///
+    /// With a limit, table files are reopened on demand through a shared LRU cache; bloom
+    /// filters and indexes stay in memory either way. A read that runs into a compaction
+    /// removing the table it is reading may then fail with `NotFound` and can be retried.
+    pub max_open_files: Option<usize>,
+    /// When a write counts as durable in the WAL. [`Engine::put_durable`],
+    /// [`Engine::delete_durable`] and [`Engine::put_relaxed`] override it for one write.
+    pub wal_sync: WalSyncMode,
+    /// With [`WalSyncMode::Fsync`], how long a writer waits for others to share its fsync.
+    ///
+    /// The default of zero already lets writers that arrive during an fsync share the next
+    /// one, which suits fast disks. A window only pays off when an fsync is slow compared to
+    /// the gap between writes, since every write waits it out.
+    pub group_commit_window: Duration,
+    /// With [`WalSyncMode::Fsync`], the number of unsynced bytes at which the fsync is issued
+    /// without waiting out the rest of the window.
+    pub group_commit_max_bytes: u64,
+    /// Directory for the WAL, or `None` to keep it next to the SSTables.
+    ///
+    /// A separate directory lets the WAL live on a small, fast disk. The data directory
+    /// records where the WAL is, and the engine refuses to open with a different `wal_dir`
+    /// while the previous location still holds unflushed writes; move the `active.wal` file
+    /// along with the setting.
+    pub wal_dir: Option<PathBuf>,
+    /// Memory budget for caching SSTable blocks, or `None` to read every block from its file.
+    ///
+    /// The [`CacheManager`] may be shared with other engines, which then compete for one
+    /// budget. Tables written by the engine read through it too, once they are installed.
+    pub cache: Option<Arc<CacheManager>>,
+    /// Opens SSTables without reading their sparse indexes, which are loaded the first time
+    /// a lookup or scan reaches each table.
+    ///
+    /// Opening a store with many tables then reads only their bloom filters and properties.
+    /// Since the checksum of a table covers its index, tables are not verified on open
+    /// either, so [`EngineOptions::on_corrupt`] only sees tables whose footer, filter or
+    /// properties cannot be read; [`Engine::scrub_step`] checks the rest.
+    pub lazy_index: bool,
+    /// Reads SSTables with direct I/O, bypassing the operating system's page cache; see
+    /// [`DirectFile`](crate::storage::DirectFile).
+    ///
+    /// Reads are done in blocks of [`TableOptions::block_alignment`] bytes, or 4096 if it is
+    /// unset. Setting that alignment to the device block size also makes every table block
+    /// start on a block boundary, so a lookup reads no more device blocks than it needs.
+    /// Tables are written with buffered I/O either way, and [`EngineOptions::max_open_files`]
+    /// does not apply to them.
+    #[cfg(feature = "direct-io")]
+    pub direct_io: bool,
+    /// Lets concurrent [`Engine::get`]s of the same key that miss the MemTable share one
+    /// SSTable lookup.
+    ///
+    /// This saves repeated probes when many readers ask for the same hot key, at the cost of
+    /// some locking on every lookup that reaches the SSTables. Shared lookups count once in
+    /// [`EngineStats`].
+    pub coalesce_reads: bool,
+    /// How eagerly background compaction merges tables; see [`CompactionPriority`].
+    pub compaction_priority: CompactionPriority,
+    /// Lets a flush merge the MemTable into a small, heavily overlapping newest table, or
+    /// `None` to always write a new table.
+    ///
+    /// Only level 0 tables, which hold flushed data that no compaction has rewritten yet, are
+    /// merged into. A flush that finds a compaction running writes a new table instead.
+    pub flush_merge: Option<FlushMergeOptions>,
+    /// Number of SSTables at which [`Engine::put`] and [`Engine::delete`] start failing with
+    /// [`io::ErrorKind::QuotaExceeded`], or `None` for no limit.
+    ///
+    /// A lookup that misses probes every table, so a store whose compaction is disabled or
+    /// cannot keep up gets slower with every flush. The limit turns that into an error
+    /// instead. Writes are accepted again once compaction brings the count back down; reads
+    /// are never refused.
+    pub max_sstables: Option<usize>,
+    /// Size in bytes below which a full MemTable is not flushed yet if the last flush was
+    /// less than [`EngineOptions::flush_grace_window`] ago, or `0` to always flush it.
+    ///
+    /// Bursts of writes into a small MemTable would otherwise leave many tiny tables for
+    /// compaction to merge. The MemTable keeps growing instead, up to this size or until the
+    /// window has passed. [`Engine::flush`] always flushes.
+    pub min_flush_size: usize,
+    /// How long after a flush a full MemTable smaller than
+    /// [`EngineOptions::min_flush_size`] keeps accumulating writes.
+    pub flush_grace_window: Duration,
+    /// Retries SSTable reads of [`Engine::get`] that fail with a transient error, or `None`
+    /// to return the first error; see [`ReadRetryPolicy`].
+    pub read_retry: Option<ReadRetryPolicy>,
+    /// Identifies this store among replicas whose data is merged by last-writer-wins, or
+    /// `None` to number writes locally.
+    ///
+    /// With a node id every write is stamped with a [`Version`](crate::Version) from a clock
+    /// that follows wall-clock time, and its packed form serves as the write's sequence
+    /// number. Tables of different nodes then merge to the newest write by version, whatever
+    /// order they are merged in. Give every replica its own id, or writes made in the same
+    /// millisecond on two of them may tie. Versions are stored in the WAL, so replaying it
+    /// restores them unchanged.
+    pub node_id: Option<u16>,
+    /// Key to encrypt the SSTables and the WAL with, or `None` to store them in the clear.
+    ///
+    /// Only a store created with encryption can be opened with it: opening an existing
+    /// unencrypted store with a key, or an encrypted one without, fails with `InvalidInput`,
+    /// and opening one with a different key fails with `PermissionDenied`. Keys and values
+    /// are never written in the clear, but the size of each table and of each WAL entry is
+    /// not hidden.
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<Encryption>,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            max_memtable_size: 1024 * 1024,
+            allow_tiny_memtable: false,
+            on_corrupt: CorruptionPolicy::default(),
+            table: TableOptions::default(),
+            max_open_files: None,
+            wal_sync: WalSyncMode::default(),
+            group_commit_window: Duration::ZERO,
+            group_commit_max_bytes: 1024 * 1024,
+            wal_dir: None,
+            cache: None,
+            lazy_index: false,
+            #[cfg(feature = "direct-io")]
+            direct_io: false,
+            coalesce_reads: false,
+            compaction_priority: CompactionPriority::default(),
+            flush_merge: None,
+            max_sstables: None,
+            min_flush_size: 0,
+            flush_grace_window: Duration::ZERO,
+            read_retry: None,
+            node_id: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        }
+    }
+}
+
+/// What [`Engine::open_with_report`] found and did while recovering a store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenReport {
+    /// Number of WAL entries replayed into the MemTable.
+    pub wal_entries_replayed: usize,
+    /// WAL position at which replay stopped at a final entry torn by a crash, if it did.
+    ///
+    /// The torn entry is cut off the WAL, so new writes are appended after the last complete
+    /// one. Any other damage to the WAL still fails the open.
+    pub wal_truncated_at: Option<u64>,
+    /// How many bytes of the WAL were replayed and how many were discarded with a torn final
+    /// entry, and why replay stopped short if it did.
+    pub wal_recovery: RecoverReport,
+    /// Whether the replayed WAL filled the MemTable, so it was flushed to an SSTable before
+    /// the open returned.
+    pub recovered_memtable_flushed: bool,
+    /// Number of SSTables loaded.
+    pub sstables_loaded: usize,
+    /// Number of corrupt SSTables skipped or quarantined under [`CorruptionPolicy`].
+    pub sstables_skipped: usize,
+    /// Number of files left behind by an interrupted flush or compaction that were removed
+    /// instead of being loaded.
+    pub leftovers_removed: usize,
+    /// Problems with the options that did not prevent the open, such as a MemTable size
+    /// below [`MIN_MEMTABLE_SIZE`]. Each is also printed to stderr.
+    pub warnings: Vec<String>,
+}
+
+/// What [`Engine::repair`] found and did while rebuilding a store's manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of SSTables listed in the new manifest.
+    pub sstables_kept: usize,
+    /// Number of corrupt SSTables renamed aside with a `.corrupt` suffix.
+    pub sstables_quarantined: usize,
+    /// Number of files left behind by an interrupted flush or compaction that were removed.
+    pub leftovers_removed: usize,
+}
+
+/// Counters describing how an [`Engine`] has served reads since it was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    /// SSTable probes skipped because the bloom filter ruled the key out.
+    pub bloom_negatives: u64,
+    /// SSTable probes where the bloom filter passed and the key was found.
+    pub bloom_true_positives: u64,
+    /// SSTable probes where the bloom filter passed but the key was not in the table.
+    ///
+    /// Together with `bloom_true_positives` this gives the filters' real false positive rate.
+    pub bloom_false_positives: u64,
+    /// Bytes of SSTable blocks held in [`EngineOptions::cache`], or `0` without a cache.
+    ///
+    /// A cache shared by several engines reports the blocks of all of them.
+    pub block_cache_bytes: u64,
+    /// Number of SSTables the store holds right now; see [`EngineOptions::max_sstables`].
+    pub sstable_count: usize,
+    /// Bytes of SSTables written by flushes, counting whole tables.
+    ///
+    /// A flush that merges into the newest table counts the whole merged table.
+    pub flush_bytes_written: u64,
+    /// Bytes of the SSTables that compactions took as input, counting whole tables.
+    pub compaction_bytes_read: u64,
+    /// Bytes of the SSTables that compactions wrote.
+    ///
+    /// `(flush_bytes_written + compaction_bytes_written) / flush_bytes_written` is the write
+    /// amplification of the SSTables: how many times each flushed byte has been written.
+    pub compaction_bytes_written: u64,
+    /// Corrupt SSTables found by [`Engine::scrub_step`].
+    pub corruption_detected: u64,
+}
+
+/// What one call of [`Engine::scrub_step`] verified.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubProgress {
+    /// The table that was verified, or `None` if the store has no SSTables.
+    pub table: Option<PathBuf>,
+    /// Bytes of the table that were read and checksummed.
+    pub bytes_verified: u64,
+    /// Whether the table failed verification.
+    pub corrupt: bool,
+    /// Whether the table was the last of a pass over every table; the next call starts over.
+    pub pass_complete: bool,
+}
+
+/// What [`Engine::optimize`] did to the store's SSTables.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptimizeStats {
+    /// Number of tables before merging, counting the one the MemTable was flushed to.
+    pub tables_before: usize,
+    /// Number of tables afterwards.
+    pub tables_after: usize,
+    /// Total size in bytes of the tables before merging.
+    pub bytes_before: u64,
+    /// Total size in bytes of the tables afterwards.
+    pub bytes_after: u64,
+}
+
+impl OptimizeStats {
+    /// Returns how many bytes of table data were freed.
+    pub fn bytes_reclaimed(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
+
+/// One of the iterators returned by [`Engine::scan_shards`], yielding the live keys of its
+/// range in ascending order with their values.
+pub struct ScanShard(MergedChanges);
+
+impl Iterator for ScanShard {
+    type Item = io::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.0.next()? {
+                Ok((key, Entry::Value(value), _)) => return Some(Ok((key, value))),
+                Ok((_, Entry::Tombstone, _)) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// A key and its value as yielded by [`Engine::scan`], with `None` for a deleted key.
+pub type ScanItem = (Vec<u8>, Option<Vec<u8>>);
+
+/// Puts and deletes that [`Engine::write_batch`] logs and applies together.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    entries: Vec<WalEntry>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a put of `value` under `key`.
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.entries.push(WalEntry::Put {
+            key,
+            value,
+            meta: 0,
+            version: None,
+        });
+        self
+    }
+
+    /// Adds a delete of `key`.
+    pub fn delete(&mut self, key: Vec<u8>) -> &mut Self {
+        self.entries.push(WalEntry::Delete { key, version: None });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The live counters behind [`EngineStats`], shared with the compaction worker.
+#[derive(Default)]
+struct Metrics {
+    bloom_negatives: AtomicU64,
+    bloom_true_positives: AtomicU64,
+    bloom_false_positives: AtomicU64,
+    flush_bytes_written: AtomicU64,
+    compaction_bytes_read: AtomicU64,
+    compaction_bytes_written: AtomicU64,
+    corruption_detected: AtomicU64,
+}
 
 /// The main LSM-Tree storage engine.
 ///
@@ -12,12 +532,51 @@ use std::sync::{Arc, RwLock};
 /// key-value store with persistence and background compaction.
 pub struct Engine {
     active_memtable: RwLock<MemTable>,
-    wal: RwLock<Wal>,
+    wal: Option<RwLock<Wal>>,
+    /// Makes WAL writes durable before they return; `None` leaves syncing to the OS.
+    group_commit: Option<GroupCommit>,
     sstables: Arc<RwLock<Vec<Arc<SSTable>>>>,
-    dir: PathBuf,
+    location: TableLocation,
     #[allow(dead_code)]
     max_memtable_size: usize,
-    compaction_running: Arc<AtomicBool>,
+    /// Runs background compactions; `None` disables them.
+    compaction: Option<CompactionWorker>,
+    compaction_lock: Arc<Mutex<()>>,
+    snapshots: Arc<SnapshotList>,
+    metrics: Arc<Metrics>,
+    table_options: TableOptions,
+    compaction_priority: CompactionPriority,
+    /// SSTable lookups that concurrent `get`s may share; `None` disables sharing.
+    inflight_reads: Option<InflightReads>,
+    /// When a flush merges into the newest table; `None` always writes a new one.
+    flush_merge: Option<FlushMergeOptions>,
+    /// Table count at which writes are refused; `None` never refuses them.
+    max_sstables: Option<usize>,
+    /// A full MemTable below this size waits out `flush_grace_window` after the last flush.
+    min_flush_size: usize,
+    flush_grace_window: Duration,
+    /// When the MemTable was last flushed, or the engine opened; `None` without a clock.
+    last_flush: Mutex<Option<Instant>>,
+    /// Retries transient SSTable read errors of `get`; `None` returns them.
+    read_retry: Option<ReadRetryPolicy>,
+    /// What `scrub_step` does with a corrupt table.
+    on_corrupt: CorruptionPolicy,
+    /// The table `scrub_step` verified last, or `None` to start a new pass.
+    scrub_cursor: Mutex<Option<PathBuf>>,
+    /// Stamps writes with versions; `None` numbers them locally.
+    clock: Option<VersionClock>,
+    /// Refuses writes, flushes and compactions; see [`Engine::open_readonly`].
+    read_only: bool,
+}
+
+/// Whether opening a store may change its files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpenMode {
+    ReadWrite,
+    /// Changes nothing, and replays the WAL only if `include_wal` is set.
+    ReadOnly {
+        include_wal: bool,
+    },
 }
 
 impl Engine {
@@ -25,374 +584,5897 @@ impl Engine {
     ///
     /// Recovers state from the WAL and loads existing SSTables.
     pub fn open(dir: impl AsRef<Path>, max_memtable_size: usize) -> io::Result<Self> {
-        let dir = dir.as_ref().to_path_buf();
-        if !dir.exists() {
-            std::fs::create_dir_all(&dir)?;
-        }
+        Self::open_with_options(
+            dir,
+            EngineOptions {
+                max_memtable_size,
+                ..EngineOptions::default()
+            },
+        )
+    }
 
-        let wal_path = dir.join("active.wal");
+    /// Opens the storage engine in the specified directory with the given options.
+    ///
+    /// A table whose data fails validation is handled according to `options.on_corrupt`;
+    /// any other error while loading tables still fails the open.
+    pub fn open_with_options(dir: impl AsRef<Path>, options: EngineOptions) -> io::Result<Self> {
+        Self::open_with_report(dir, options).map(|(engine, _)| engine)
+    }
 
-        let wal_entries = Wal::recover(&wal_path)?;
-        let mut memtable = MemTable::new(max_memtable_size);
-        for entry in wal_entries {
-            match entry {
-                WalEntry::Put { key, value } => memtable.put(key, value),
-                WalEntry::Delete { key } => memtable.delete(key),
+    /// Like [`Engine::open_with_options`], but also reports what recovery did.
+    pub fn open_with_report(
+        dir: impl AsRef<Path>,
+        options: EngineOptions,
+    ) -> io::Result<(Self, OpenReport)> {
+        Self::open_in_mode(dir.as_ref(), options, OpenMode::ReadWrite)
+    }
+
+    /// Opens the existing store in `dir` for reads only, changing none of its files.
+    ///
+    /// Writes, flushes and compactions fail with `PermissionDenied`, and no background
+    /// compaction runs. A corrupt table that `options.on_corrupt` would quarantine is skipped
+    /// instead. With `include_wal`, the WAL is replayed into the MemTable as by
+    /// [`Engine::open`], except that a torn final entry is left in the file. Without it, the
+    /// WAL is not read at all, and the engine shows only what was flushed to SSTables: a
+    /// committed view for replicas and backups that must not see unflushed writes. That
+    /// view may be behind what a writer has in its MemTable, by up to a MemTable's worth of
+    /// writes.
+    pub fn open_readonly(
+        dir: impl AsRef<Path>,
+        options: EngineOptions,
+        include_wal: bool,
+    ) -> io::Result<Self> {
+        let mode = OpenMode::ReadOnly { include_wal };
+        Self::open_in_mode(dir.as_ref(), options, mode).map(|(engine, _)| engine)
+    }
+
+    /// Rebuilds the manifest of the store in `dir` from the SSTables in the directory.
+    ///
+    /// This recovers a store whose manifest is lost or corrupt, which [`Engine::open`]
+    /// refuses. The existing manifest is not read. Leftovers of an interrupted flush or
+    /// compaction are removed as for a store written before the manifest existed: tables
+    /// under a temporary name, and compaction outputs whose newest input is still present.
+    /// Every other `.sst` file is opened with `options`, so an encrypted store needs its key,
+    /// and its checksum verified whatever `options.lazy_index` says; a corrupt one is renamed
+    /// aside with a `.corrupt` suffix, and any other error fails the repair. The tables that open are listed newest first by their highest sequence
+    /// number, then by name, and the new manifest replaces the old one. The WAL is left as
+    /// it is and replayed by the next open.
+    ///
+    /// The live set is conservative: a table that a lost manifest no longer listed, such as
+    /// a compaction input not yet removed, is kept rather than risk losing writes. On a
+    /// healthy store the repair lists the same tables in the same order, and running it
+    /// again changes nothing. The store must not be open while it runs.
+    pub fn repair(dir: impl AsRef<Path>, options: EngineOptions) -> io::Result<RepairReport> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no store to repair at {}", dir.display()),
+            ));
+        }
+        let mut report = RepairReport {
+            leftovers_removed: remove_leftover_tables(dir, None)?,
+            ..RepairReport::default()
+        };
+        let caches = TableCaches {
+            files: options.max_open_files.map(FileCache::new),
+            blocks: None,
+            lazy_index: false,
+            #[cfg(feature = "direct-io")]
+            direct_io: options
+                .direct_io
+                .then(|| options.table.block_alignment.map_or(4096, u64::from)),
+            retired: Arc::default(),
+            skipped: Arc::default(),
+            #[cfg(feature = "encryption")]
+            encryption: options.encryption.clone(),
+        };
+
+        let mut tables = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("sst") {
+                continue;
+            }
+            match open_table(&path, &caches) {
+                Ok(sst) => tables.push((sst.max_seqno(), path)),
+                Err(e) if is_corruption(&e) => {
+                    let mut quarantined = path.clone().into_os_string();
+                    quarantined.push(".corrupt");
+                    std::fs::rename(&path, &quarantined)?;
+                    eprintln!(
+                        "Quarantined corrupt SSTable {} as {}: {}",
+                        path.display(),
+                        PathBuf::from(quarantined).display(),
+                        e
+                    );
+                    report.sstables_quarantined += 1;
+                }
+                Err(e) => return Err(e),
             }
         }
+        tables.sort_by(|a, b| b.cmp(a));
+        let names: Vec<String> = tables
+            .iter()
+            .filter_map(|(_, path)| path.file_name()?.to_str().map(String::from))
+            .collect();
+        write_manifest(dir, &names)?;
+        report.sstables_kept = names.len();
+        Ok(report)
+    }
 
-        let wal = Wal::open(&wal_path)?;
+    /// Opens the store in `dir`, changing its files only if `mode` allows it.
+    fn open_in_mode(
+        dir: &Path,
+        options: EngineOptions,
+        mode: OpenMode,
+    ) -> io::Result<(Self, OpenReport)> {
+        let writable = mode == OpenMode::ReadWrite;
+        let on_corrupt = match options.on_corrupt {
+            CorruptionPolicy::Quarantine if !writable => CorruptionPolicy::Skip,
+            policy => policy,
+        };
+        let mut report = OpenReport::default();
+        let max_memtable_size = options.max_memtable_size;
+        if max_memtable_size < MIN_MEMTABLE_SIZE && !options.allow_tiny_memtable {
+            let warning = format!(
+                "max_memtable_size of {} bytes is below {} bytes, so nearly every write will \
+                 flush an SSTable of its own; set allow_tiny_memtable if this is intended",
+                max_memtable_size, MIN_MEMTABLE_SIZE
+            );
+            eprintln!("Warning: {}", warning);
+            report.warnings.push(warning);
+        }
+        let dir = dir.to_path_buf();
+        let wal_dir = options.wal_dir.clone().unwrap_or_else(|| dir.clone());
+        if writable {
+            for dir in [&dir, &wal_dir] {
+                if !dir.exists() {
+                    std::fs::create_dir_all(dir)?;
+                }
+            }
+            record_wal_dir(&dir, &wal_dir)?;
+        } else if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no store to open read-only at {}", dir.display()),
+            ));
+        }
+        let manifest = read_manifest(&dir)?;
+        if writable {
+            report.leftovers_removed = remove_leftover_tables(&dir, manifest.as_deref())?;
+        }
 
+        let mut caches = TableCaches {
+            files: options.max_open_files.map(FileCache::new),
+            blocks: options.cache.clone(),
+            lazy_index: options.lazy_index,
+            #[cfg(feature = "direct-io")]
+            direct_io: options
+                .direct_io
+                .then(|| options.table.block_alignment.map_or(4096, u64::from)),
+            retired: Arc::default(),
+            skipped: Arc::default(),
+            #[cfg(feature = "encryption")]
+            encryption: options.encryption.clone(),
+        };
         let mut sstables = Vec::new();
-        let mut sstable_files: Vec<_> = std::fs::read_dir(&dir)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| entry.path().extension().and_then(|ext| ext.to_str()) == Some("sst"))
-            .collect();
+        let mut skipped = Vec::new();
+        // A store written before the manifest existed lists no order; its tables are put in
+        // order below, once their sequence numbers are known.
+        let table_files: Vec<PathBuf> = match &manifest {
+            Some(names) => names.iter().map(|name| dir.join(name)).collect(),
+            None => std::fs::read_dir(&dir)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("sst"))
+                .collect(),
+        };
+
+        for path in table_files {
+            let opened = if path.exists() {
+                open_table(&path, &caches).and_then(|sst| attach_codec(sst, &options.table))
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} lists {}, which is missing",
+                        MANIFEST_FILE,
+                        path.display()
+                    ),
+                ))
+            };
+            match opened {
+                Ok(sst) => sstables.push(Arc::new(sst)),
+                Err(e) if is_corruption(&e) && on_corrupt != CorruptionPolicy::Fail => {
+                    report.sstables_skipped += 1;
+                    if on_corrupt == CorruptionPolicy::Quarantine && path.exists() {
+                        let mut quarantined = path.clone().into_os_string();
+                        quarantined.push(".corrupt");
+                        std::fs::rename(&path, &quarantined)?;
+                        eprintln!(
+                            "Quarantined corrupt SSTable {} as {}: {}",
+                            path.display(),
+                            PathBuf::from(quarantined).display(),
+                            e
+                        );
+                    } else if on_corrupt == CorruptionPolicy::Skip {
+                        eprintln!("Skipping corrupt SSTable {}: {}", path.display(), e);
+                        skipped.extend(path.file_name().and_then(|n| n.to_str()).map(String::from));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if manifest.is_none() {
+            // Every table holds newer writes than the tables behind it, so the highest
+            // sequence number orders them. Tables too old to record one fall back on names.
+            sstables.sort_by(|a, b| (b.max_seqno(), b.path()).cmp(&(a.max_seqno(), a.path())));
+        }
+        caches.skipped = Arc::new(skipped);
+        let location = TableLocation::Dir(dir, caches);
+        if writable {
+            location.save_manifest(&sstables)?;
+        }
 
-        sstable_files.sort_by_key(|e| e.file_name());
-        sstable_files.reverse();
+        // WAL entries carry no sequence numbers; they are replayed after everything that was
+        // flushed, so they get the next ones in order.
+        let wal_path = wal_dir.join(WAL_FILE);
+        #[cfg(feature = "encryption")]
+        let recover = || match &options.encryption {
+            Some(encryption) => Wal::recover_encrypted_with_report(&wal_path, encryption),
+            None => Wal::recover_with_report(&wal_path),
+        };
+        #[cfg(not(feature = "encryption"))]
+        let recover = || Wal::recover_with_report(&wal_path);
+        let (wal_entries, wal_recovery) = match mode {
+            OpenMode::ReadOnly { include_wal: false } => Default::default(),
+            _ => recover()?,
+        };
+        let torn_at = wal_recovery.torn_at();
+        if let Some(offset) = torn_at
+            && writable
+        {
+            eprintln!(
+                "Discarding {} bytes of a torn entry at offset {} of {}",
+                wal_recovery.bytes_discarded,
+                offset,
+                wal_path.display()
+            );
+            let file = std::fs::OpenOptions::new().write(true).open(&wal_path)?;
+            file.set_len(offset)?;
+            file.sync_all()?;
+        }
+        report.wal_truncated_at = torn_at;
+        report.wal_recovery = wal_recovery;
+        report.sstables_loaded = sstables.len();
+        report.wal_entries_replayed = wal_entries.len();
+        let mut memtable = MemTable::new(max_memtable_size);
+        memtable.advance_seqno(sstables.iter().map(|s| s.max_seqno()).max().unwrap_or(0));
+        for entry in wal_entries {
+            apply_entry(&mut memtable, entry);
+        }
 
-        for entry in sstable_files {
-            sstables.push(Arc::new(SSTable::open(entry.path())?));
+        let mut group_commit = None;
+        let mut wal = None;
+        if writable {
+            #[cfg(feature = "encryption")]
+            let opened = match &options.encryption {
+                Some(encryption) => Wal::open_encrypted(&wal_path, encryption)?,
+                None => Wal::open(&wal_path)?,
+            };
+            #[cfg(not(feature = "encryption"))]
+            let opened = Wal::open(&wal_path)?;
+            // The WAL may have just been created; its directory entry must be durable before
+            // any write is acknowledged.
+            storage::fsync_dir(&wal_dir)?;
+            group_commit = (options.wal_sync == WalSyncMode::Fsync).then(|| {
+                GroupCommit::new(
+                    options.group_commit_window,
+                    options.group_commit_max_bytes,
+                    opened.position(),
+                )
+            });
+            wal = Some(opened);
         }
 
-        Ok(Self {
-            active_memtable: RwLock::new(memtable),
-            wal: RwLock::new(wal),
-            sstables: Arc::new(RwLock::new(sstables)),
-            dir,
+        let mut engine = Self::assemble(
+            memtable,
             max_memtable_size,
-            compaction_running: Arc::new(AtomicBool::new(false)),
+            wal,
+            sstables,
+            location,
+            options.table,
+            options.compaction_priority,
+        );
+        if !writable {
+            engine.compaction = None;
+            engine.read_only = true;
+        }
+        engine.group_commit = group_commit;
+        engine.inflight_reads = options.coalesce_reads.then(InflightReads::default);
+        engine.flush_merge = options.flush_merge;
+        engine.max_sstables = options.max_sstables;
+        engine.min_flush_size = options.min_flush_size;
+        engine.flush_grace_window = options.flush_grace_window;
+        engine.read_retry = options.read_retry;
+        engine.on_corrupt = on_corrupt;
+        let last_seqno = engine.active_memtable.read().unwrap().last_seqno();
+        engine.clock = options
+            .node_id
+            .map(|node_id| VersionClock::new(node_id, last_seqno));
+        // A WAL holding more than a MemTable's worth, for example after a burst of writes,
+        // would otherwise stay in memory until the next write.
+        if writable && engine.active_memtable.read().unwrap().is_full() {
+            engine.flush()?;
+            report.recovered_memtable_flushed = true;
+        }
+        Ok((engine, report))
+    }
+
+    /// Opens a storage engine that keeps all of its data in memory.
+    ///
+    /// No WAL is written and flushed MemTables become in-memory SSTables, so nothing touches
+    /// the filesystem and all data is lost when the engine is dropped. Flushing and compaction
+    /// otherwise behave exactly as for an on-disk engine.
+    pub fn open_in_memory(max_memtable_size: usize) -> Self {
+        Self::assemble(
+            MemTable::new(max_memtable_size),
+            max_memtable_size,
+            None,
+            Vec::new(),
+            TableLocation::Memory,
+            TableOptions::default(),
+            CompactionPriority::default(),
+        )
+    }
+
+    /// Builds an engine around recovered state and starts its compaction worker.
+    fn assemble(
+        memtable: MemTable,
+        max_memtable_size: usize,
+        wal: Option<Wal>,
+        sstables: Vec<Arc<SSTable>>,
+        location: TableLocation,
+        table_options: TableOptions,
+        compaction_priority: CompactionPriority,
+    ) -> Self {
+        let sstables = Arc::new(RwLock::new(sstables));
+        let compaction_lock = Arc::new(Mutex::new(()));
+        let snapshots = Arc::new(SnapshotList::default());
+        let metrics = Arc::new(Metrics::default());
+
+        let worker = {
+            let sstables = Arc::clone(&sstables);
+            let location = location.clone();
+            let table_options = table_options.clone();
+            let lock = Arc::clone(&compaction_lock);
+            let snapshots = Arc::clone(&snapshots);
+            let metrics = Arc::clone(&metrics);
+            CompactionWorker::spawn(move || {
+                let select = |ssts: &[Arc<SSTable>]| {
+                    Ok(ssts[compaction_priority.select_inputs(ssts)].to_vec())
+                };
+                compact_tables(
+                    &sstables,
+                    &location,
+                    &table_options,
+                    &lock,
+                    &snapshots,
+                    &metrics,
+                    select,
+                )
+                .map(|_| ())
+            })
+        };
+
+        Self {
+            max_memtable_size,
+            active_memtable: RwLock::new(memtable),
+            wal: wal.map(RwLock::new),
+            group_commit: None,
+            sstables,
+            location,
+            compaction: Some(worker),
+            compaction_lock,
+            snapshots,
+            metrics,
+            table_options,
+            compaction_priority,
+            inflight_reads: None,
+            flush_merge: None,
+            max_sstables: None,
+            min_flush_size: 0,
+            flush_grace_window: Duration::ZERO,
+            last_flush: Mutex::new(now()),
+            read_retry: None,
+            on_corrupt: CorruptionPolicy::default(),
+            scrub_cursor: Mutex::new(None),
+            clock: None,
+            read_only: false,
+        }
+    }
+
+    /// Inserts or updates a key-value pair.
+    ///
+    /// Fails with `InvalidInput`, writing nothing, if the key is longer than
+    /// [`MAX_KEY_LEN`](crate::sstable::MAX_KEY_LEN) or the value longer than
+    /// [`MAX_VALUE_LEN`](crate::sstable::MAX_VALUE_LEN), since the table could not store it.
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
+        self.put_with_meta(key, value, 0)
+    }
+
+    /// Inserts or updates a key-value pair tagged with an application-defined `meta` byte,
+    /// such as a content type or schema version.
+    ///
+    /// The tag is stored with the value and read back by [`Engine::get_with_meta`]; values
+    /// written with [`Engine::put`] have a tag of `0`.
+    pub fn put_with_meta(&self, key: Vec<u8>, value: Vec<u8>, meta: u8) -> io::Result<()> {
+        self.write(WalEntry::Put {
+            key,
+            value,
+            meta,
+            version: None,
         })
     }
 
-    /// Inserts or updates a key-value pair.
-    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
-        {
-            let mut wal = self.wal.write().unwrap();
-            wal.append(&WalEntry::Put {
-                key: key.clone(),
-                value: value.clone(),
-            })?;
+    /// Like [`Engine::put`], but accepts anything convertible to bytes, such as `&str`,
+    /// `String` or `&[u8]`.
+    ///
+    /// ```
+    /// use lsm_storage_engine::Engine;
+    ///
+    /// let engine = Engine::open_in_memory(1 << 20);
+    /// engine.put_str("user:1", "Albiere")?;
+    /// engine.put_str(format!("user:{}", 2), b"raw bytes".as_slice())?;
+    /// assert_eq!(engine.get_str("user:1")?, Some("Albiere".to_string()));
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn put_str(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> io::Result<()> {
+        self.put(key.into(), value.into())
+    }
+
+    /// Like [`Engine::put`], but the write is fsynced before it returns, whatever
+    /// [`EngineOptions::wal_sync`] is.
+    ///
+    /// Use it for the writes that must survive losing power while the bulk of them stay
+    /// cheap. Under [`WalSyncMode::None`] it fsyncs with the WAL lock held, so concurrent
+    /// writers wait for it; under [`WalSyncMode::Fsync`] it behaves exactly like `put`.
+    /// Without a WAL it is the same as `put`.
+    pub fn put_durable(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
+        let entry = WalEntry::Put {
+            key,
+            value,
+            meta: 0,
+            version: None,
+        };
+        self.write_with_sync(entry, Some(WalSyncMode::Fsync))
+    }
+
+    /// Like [`Engine::put`], but never waits for an fsync, whatever
+    /// [`EngineOptions::wal_sync`] is.
+    ///
+    /// The entry is handed to the operating system, as every WAL entry is, so it survives
+    /// the process crashing. Under [`WalSyncMode::Fsync`] it becomes durable with the next
+    /// fsync some other write waits for.
+    pub fn put_relaxed(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
+        let entry = WalEntry::Put {
+            key,
+            value,
+            meta: 0,
+            version: None,
+        };
+        self.write_with_sync(entry, Some(WalSyncMode::None))
+    }
+
+    /// Retrieves a value by its key.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.get_with_meta(key)?.map(|(value, _)| value))
+    }
+
+    /// Like [`Engine::get`], but accepts a `&str` or any other byte-like key and returns the
+    /// value as a `String`.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if the stored value is not valid UTF-8.
+    ///
+    /// ```
+    /// use lsm_storage_engine::Engine;
+    ///
+    /// let engine = Engine::open_in_memory(1 << 20);
+    /// engine.put_str("greeting", "hello")?;
+    /// assert_eq!(engine.get_str("greeting")?.as_deref(), Some("hello"));
+    /// assert_eq!(engine.get_str("missing")?, None);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn get_str(&self, key: impl AsRef<[u8]>) -> io::Result<Option<String>> {
+        self.get(key.as_ref())?
+            .map(|value| {
+                String::from_utf8(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .transpose()
+    }
+
+    /// Retrieves a value by its key along with its metadata tag; see
+    /// [`Engine::put_with_meta`].
+    pub fn get_with_meta(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, u8)>> {
+        {
+            let mt = self.active_memtable.read().unwrap();
+            if let Some((entry, meta)) = mt.get_with_meta(key) {
+                return match entry {
+                    Entry::Value(v) => Ok(Some((v.clone(), meta))),
+                    Entry::Tombstone => Ok(None),
+                };
+            }
+        }
+
+        self.get_from_disk(key)
+    }
+
+    /// Like [`Engine::get`], but fails with [`io::ErrorKind::WouldBlock`] instead of waiting
+    /// when another thread holds a lock it needs.
+    ///
+    /// A writer, flush or compaction holding the MemTable or the SSTable list makes it fail at
+    /// once, so callers can fall back or retry rather than stall. It may also report
+    /// contention spuriously, for example while a writer is merely queued for a lock. Only
+    /// waiting on engine locks is avoided: reading a table from disk still takes as long as it
+    /// takes, a lookup is never shared under [`EngineOptions::coalesce_reads`], and
+    /// [`EngineOptions::read_retry`] still applies.
+    pub fn try_get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        {
+            let mt = try_lock(self.active_memtable.try_read())?;
+            if let Some(entry) = mt.get(key) {
+                return match entry {
+                    Entry::Value(v) => Ok(Some(v.clone())),
+                    Entry::Tombstone => Ok(None),
+                };
+            }
+        }
+
+        let Some((ssts, _pin)) = self.tables_for_key(key, &try_lock(self.sstables.try_read())?)
+        else {
+            return Ok(None);
+        };
+        Ok(self.get_from_tables(key, &ssts)?.map(|(value, _)| value))
+    }
+
+    /// Calls `f` with the value of `key`, or `None` if it is absent or deleted, and returns
+    /// its result.
+    ///
+    /// A value found in the MemTable is lent to `f` without being copied, which saves a
+    /// clone per call for large values served from memory. The MemTable read lock is held
+    /// while `f` runs, so writes and flushes wait for it: keep `f` quick, and do not write
+    /// to the engine from it, which would deadlock. A value that has to come from an SSTable
+    /// is read into a buffer as for [`Engine::get`], and `f` runs without any lock held.
+    pub fn with_value<R>(&self, key: &[u8], f: impl FnOnce(Option<&[u8]>) -> R) -> io::Result<R> {
+        {
+            let mt = self.active_memtable.read().unwrap();
+            if let Some(entry) = mt.get(key) {
+                return Ok(match entry {
+                    Entry::Value(v) => f(Some(v)),
+                    Entry::Tombstone => f(None),
+                });
+            }
+        }
+
+        let value = self.get_from_disk(key)?;
+        Ok(f(value.as_ref().map(|(value, _)| value.as_slice())))
+    }
+
+    /// Looks `key` up in the current SSTables, for a key the MemTable does not hold.
+    fn get_from_disk(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, u8)>> {
+        // Pin the current tables and release the lock before touching disk, so a slow lookup
+        // never holds up a flush or compaction that needs to swap the list.
+        let Some((ssts, _pin)) = self.tables_for_key(key, &self.sstables.read().unwrap()) else {
+            return Ok(None);
+        };
+        match &self.inflight_reads {
+            Some(inflight) => inflight.run(key, ssts, |ssts| self.get_from_tables(key, ssts)),
+            None => self.get_from_tables(key, &ssts),
+        }
+    }
+
+    /// Returns `tables` from the newest one whose bloom filter admits `key`, pinned, or
+    /// `None` if every filter rules it out.
+    ///
+    /// Only the filters are consulted, so the caller can check them under the list's lock
+    /// and copy and pin the list only when some table may hold the key; a miss copies
+    /// nothing. The pin is taken while the caller still holds the lock, so a compaction
+    /// that replaces the tables cannot remove their files before the lookup has read them.
+    fn tables_for_key(
+        &self,
+        key: &[u8],
+        tables: &[Arc<SSTable>],
+    ) -> Option<(Vec<Arc<SSTable>>, TablePin)> {
+        let bloom_key = BloomKey::new(key);
+        let first = tables
+            .iter()
+            .position(|sst| sst.may_contain_key(&bloom_key));
+        let ruled_out = first.unwrap_or(tables.len());
+        if ruled_out > 0 {
+            self.metrics
+                .bloom_negatives
+                .fetch_add(ruled_out as u64, Ordering::Relaxed);
+        }
+        first.map(|first| (tables[first..].to_vec(), self.location.pin()))
+    }
+
+    /// Returns the current tables, pinned so that their files outlive any compaction that
+    /// replaces them while they are read.
+    fn pinned_tables(&self) -> (Vec<Arc<SSTable>>, TablePin) {
+        let ssts = self.sstables.read().unwrap();
+        (ssts.clone(), self.location.pin())
+    }
+
+    /// Looks `key` up in `ssts`, newest first.
+    fn get_from_tables(
+        &self,
+        key: &[u8],
+        ssts: &[Arc<SSTable>],
+    ) -> io::Result<Option<(Vec<u8>, u8)>> {
+        for sst in ssts {
+            if !sst.may_contain(key) {
+                self.metrics.bloom_negatives.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let read = || sst.read_entry_with_meta_at(key, u64::MAX);
+            let entry = match &self.read_retry {
+                Some(policy) => policy.run(read)?,
+                None => read()?,
+            };
+            let counter = match entry {
+                Some(_) => &self.metrics.bloom_true_positives,
+                None => &self.metrics.bloom_false_positives,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            match entry {
+                Some((Entry::Value(v), meta)) => return Ok(Some((v, meta))),
+                Some((Entry::Tombstone, _)) => return Ok(None),
+                None => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the length of the value stored for `key`, without reading the value.
+    ///
+    /// Returns `None` if the key is absent or deleted. On disk only the matching record's
+    /// header is read, so this is a cheap way to decide how to fetch a large value.
+    pub fn value_len(&self, key: &[u8]) -> io::Result<Option<usize>> {
+        {
+            let mt = self.active_memtable.read().unwrap();
+            if let Some(entry) = mt.get(key) {
+                return match entry {
+                    Entry::Value(v) => Ok(Some(v.len())),
+                    Entry::Tombstone => Ok(None),
+                };
+            }
+        }
+
+        let (ssts, _pin) = self.pinned_tables();
+        for sst in ssts.iter() {
+            if !sst.may_contain(key) {
+                self.metrics.bloom_negatives.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let len = sst.read_value_len_at(key, u64::MAX)?;
+            let counter = match len {
+                Some(_) => &self.metrics.bloom_true_positives,
+                None => &self.metrics.bloom_false_positives,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            if let Some(len) = len {
+                return Ok(len);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the engine's read statistics since it was opened.
+    pub fn stats(&self) -> EngineStats {
+        EngineStats {
+            bloom_negatives: self.metrics.bloom_negatives.load(Ordering::Relaxed),
+            bloom_true_positives: self.metrics.bloom_true_positives.load(Ordering::Relaxed),
+            bloom_false_positives: self.metrics.bloom_false_positives.load(Ordering::Relaxed),
+            block_cache_bytes: match &self.location {
+                TableLocation::Dir(_, caches) => caches.blocks.as_ref(),
+                _ => None,
+            }
+            .map_or(0, |cache| cache.usage_of(CacheKind::Block) as u64),
+            sstable_count: self.sstables.read().unwrap().len(),
+            flush_bytes_written: self.metrics.flush_bytes_written.load(Ordering::Relaxed),
+            compaction_bytes_read: self.metrics.compaction_bytes_read.load(Ordering::Relaxed),
+            compaction_bytes_written: self
+                .metrics
+                .compaction_bytes_written
+                .load(Ordering::Relaxed),
+            corruption_detected: self.metrics.corruption_detected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Estimates how many bytes background compaction still has to rewrite, or `0` if no
+    /// compaction is due.
+    ///
+    /// Under [`CompactionPriority::Lazy`] and [`CompactionPriority::Balanced`], the estimate
+    /// replays the merges the table count calls for: each takes the oldest eight or four
+    /// tables and adds their combined size, which becomes one table, until fewer tables than
+    /// that remain. Under [`CompactionPriority::Eager`] it is the size of every table once
+    /// there are two. Merges are assumed to drop nothing, so stores that overwrite or delete
+    /// much of their data are overestimated. A scheduler can throttle writes, or add
+    /// compaction capacity, while the debt grows.
+    pub fn compaction_debt(&self) -> u64 {
+        let sizes: Vec<u64> = self
+            .sstables
+            .read()
+            .unwrap()
+            .iter()
+            .map(|sst| sst.size())
+            .collect();
+        self.compaction_priority.debt(&sizes)
+    }
+
+    /// Verifies the checksum and records of one SSTable, so that bit rot is found before a
+    /// read runs into it.
+    ///
+    /// Each call rereads the next table in name order, wrapping around after the last one,
+    /// so a scheduler can pace a background scrub by how often it calls this. A corrupt
+    /// table adds to [`EngineStats::corruption_detected`] and is then handled according to
+    /// [`EngineOptions::on_corrupt`]: `Skip` leaves it in place, `Quarantine` renames it to
+    /// `<name>.corrupt` and stops reading it, losing its data, and `Fail` returns an
+    /// `InvalidData` error. Errors reading the table are returned as they are.
+    pub fn scrub_step(&self) -> io::Result<ScrubProgress> {
+        let (table, pass_complete, _pin) = {
+            let ssts = self.sstables.read().unwrap();
+            let mut cursor = self.scrub_cursor.lock().unwrap();
+            let after = |sst: &&Arc<SSTable>| cursor.as_deref().is_none_or(|c| sst.path() > c);
+            let next = ssts
+                .iter()
+                .filter(after)
+                .min_by(|a, b| a.path().cmp(b.path()))
+                .or_else(|| ssts.iter().min_by(|a, b| a.path().cmp(b.path())));
+            let Some(table) = next.cloned() else {
+                *cursor = None;
+                return Ok(ScrubProgress::default());
+            };
+            let pass_complete = !ssts.iter().any(|sst| sst.path() > table.path());
+            *cursor = (!pass_complete).then(|| table.path().to_path_buf());
+            (table, pass_complete, self.location.pin())
+        };
+
+        let (bytes_verified, problem) = match table.verify() {
+            Ok(stats) if stats.checksum_ok => (stats.bytes, None),
+            Ok(stats) => (stats.bytes, Some("checksum mismatch".to_string())),
+            Err(e) if is_corruption(&e) => (0, Some(e.to_string())),
+            Err(e) => return Err(e),
+        };
+        let progress = ScrubProgress {
+            table: Some(table.path().to_path_buf()),
+            bytes_verified,
+            corrupt: problem.is_some(),
+            pass_complete,
+        };
+        let Some(problem) = problem else {
+            return Ok(progress);
+        };
+
+        self.metrics
+            .corruption_detected
+            .fetch_add(1, Ordering::Relaxed);
+        match self.on_corrupt {
+            CorruptionPolicy::Fail => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("SSTable {} is corrupt: {}", table.path().display(), problem),
+            )),
+            CorruptionPolicy::Skip => {
+                eprintln!(
+                    "Scrub found corrupt SSTable {}: {}",
+                    table.path().display(),
+                    problem
+                );
+                Ok(progress)
+            }
+            CorruptionPolicy::Quarantine => {
+                // Keeps a compaction from merging the table while it is taken out.
+                let _lock = self.compaction_lock.lock().unwrap();
+                let mut ssts = self.sstables.write().unwrap();
+                let Some(pos) = ssts.iter().position(|sst| Arc::ptr_eq(sst, &table)) else {
+                    // A compaction replaced the table in the meantime.
+                    return Ok(progress);
+                };
+                let mut remaining = ssts.clone();
+                remaining.remove(pos);
+                self.location.save_manifest(&remaining)?;
+                *ssts = remaining;
+                if !matches!(self.location, TableLocation::Memory) {
+                    let mut quarantined = table.path().as_os_str().to_owned();
+                    quarantined.push(".corrupt");
+                    std::fs::rename(table.path(), &quarantined)?;
+                    eprintln!(
+                        "Quarantined corrupt SSTable {} as {}: {}",
+                        table.path().display(),
+                        PathBuf::from(quarantined).display(),
+                        problem
+                    );
+                }
+                Ok(progress)
+            }
+        }
+    }
+
+    /// Returns an iterator over the live keys of the engine in ascending order.
+    ///
+    /// Each key is yielded once, and keys whose newest version is a tombstone are skipped.
+    /// Values are never read, so this is much cheaper than a full scan when values are large.
+    /// The iterator sees the MemTable and SSTables as they were when it was created.
+    pub fn key_iter(&self) -> io::Result<impl Iterator<Item = io::Result<Vec<u8>>> + use<>> {
+        let pin = self.location.pin();
+        // Holding the MemTable lock keeps a flush from moving entries between the two.
+        let (memtable_keys, ssts): (Vec<(Vec<u8>, bool)>, _) = {
+            let mt = self.active_memtable.read().unwrap();
+            let keys = mt
+                .iter()
+                .map(|(key, entry)| (key.clone(), *entry == Entry::Tombstone))
+                .collect();
+            (keys, self.sstables.read().unwrap().clone())
+        };
+
+        // Sources are ordered newest first: the MemTable, then the tables in list order.
+        let mut sources: Vec<KeySource> = vec![Box::new(memtable_keys.into_iter().map(Ok))];
+        for sst in ssts.iter() {
+            sources.push(Box::new(sst.key_iter()?));
+        }
+        MergedKeys::new(sources, pin)
+    }
+
+    /// Returns the keys whose newest version was written after sequence number `seqno`.
+    ///
+    /// Each key is yielded once, in ascending order, with its newest entry and that entry's
+    /// sequence number. Deletions are included as [`Entry::Tombstone`], so replaying the
+    /// results onto a copy taken at `seqno` brings it up to date. A tombstone that compaction
+    /// has already dropped is no longer reported. Tables holding nothing newer than `seqno`
+    /// are not read. Like [`Engine::key_iter`], the iterator sees the MemTable and SSTables as
+    /// they were when it was created.
+    pub fn changes_since(
+        &self,
+        seqno: u64,
+    ) -> io::Result<impl Iterator<Item = io::Result<(Vec<u8>, Entry, u64)>> + use<>> {
+        self.merged_records(seqno)
+    }
+
+    /// Returns an iterator over every key of the engine in ascending order, with its value.
+    ///
+    /// Deleted keys are skipped unless `include_tombstones` is set, in which case they are
+    /// yielded with a value of `None`. Tombstones that compaction has already dropped are not
+    /// reported either way. Like [`Engine::key_iter`], the iterator sees the MemTable and
+    /// SSTables as they were when it was created.
+    pub fn scan(
+        &self,
+        include_tombstones: bool,
+    ) -> io::Result<impl Iterator<Item = io::Result<ScanItem>> + use<>> {
+        Ok(self
+            .merged_records(0)?
+            .filter_map(move |record| match record {
+                Ok((key, Entry::Value(value), _)) => Some(Ok((key, Some(value)))),
+                Ok((key, Entry::Tombstone, _)) => include_tombstones.then_some(Ok((key, None))),
+                Err(e) => Some(Err(e)),
+            }))
+    }
+
+    /// Returns an iterator over every live key of the engine in ascending order, with its
+    /// value, as of the moment it is created.
+    ///
+    /// The iterator owns all it reads: a copy of the MemTable and the SSTables of that moment.
+    /// It holds no engine lock, so it can be consumed slowly while the engine keeps taking
+    /// writes, flushing and compacting, and it sees none of their effects. Until it is
+    /// dropped, compaction leaves the files of the tables it reads in place and removes them
+    /// afterwards. [`Engine::scan`], [`Engine::range`] and the other iterators behave the same.
+    pub fn iter(&self) -> io::Result<impl Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>> + use<>> {
+        Ok(live_values(self.merged_records(0)?))
+    }
+
+    /// Returns an iterator over the live keys in `range` in ascending order, with their values.
+    ///
+    /// Any kind of range works: `..` covers the whole store, and `a..`, `..b`, `a..b` and
+    /// `a..=b` are bounded the way they are for slices, comparing keys byte-wise. Deleted keys
+    /// are skipped. Only the part of each SSTable that can hold keys in the range is read, and
+    /// tables entirely outside it are not read at all. Like [`Engine::key_iter`], the iterator
+    /// sees the MemTable and SSTables as they were when it was created.
+    pub fn range<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+    ) -> io::Result<impl Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>> + use<R>> {
+        let (start, end) = half_open((
+            range.start_bound().map(Vec::as_slice),
+            range.end_bound().map(Vec::as_slice),
+        ));
+        let pin = self.location.pin();
+        let (memtable_records, ssts): (Vec<(Vec<u8>, u64, Entry)>, _) = {
+            let mt = self.active_memtable.read().unwrap();
+            let records = mt
+                .range_with_seqno(&start, end.as_deref())
+                .map(|(key, entry, seqno)| (key.clone(), seqno, entry.clone()))
+                .collect();
+            (records, self.sstables.read().unwrap().clone())
+        };
+        let merged = merge_range(memtable_records, &ssts, &start, end.as_deref(), pin)?;
+        Ok(live_values(merged))
+    }
+
+    /// Splits a scan of the whole store into `n` iterators over adjacent key ranges, to be
+    /// consumed in parallel.
+    ///
+    /// The ranges are disjoint and together cover every key, so the iterators yield each live
+    /// key exactly once between them, in ascending order within each. They all see the
+    /// MemTable and SSTables as they were when this was called, and otherwise behave like
+    /// [`Engine::iter`]. Split points are picked from the SSTables' sparse indexes and the
+    /// MemTable's keys so that the ranges hold about as many records each; without enough
+    /// distinct keys to split at, the last iterators yield nothing. Fails with `InvalidInput` if `n` is
+    /// zero.
+    pub fn scan_shards(&self, n: usize) -> io::Result<Vec<ScanShard>> {
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a scan needs at least one shard",
+            ));
+        }
+        let mut pins: Vec<TablePin> = (0..n).map(|_| self.location.pin()).collect();
+        let (mut memtable_records, ssts): (Vec<(Vec<u8>, u64, Entry)>, _) = {
+            let mt = self.active_memtable.read().unwrap();
+            let records = mt
+                .iter_with_seqno()
+                .map(|(key, entry, seqno)| (key.clone(), seqno, entry.clone()))
+                .collect();
+            (records, self.sstables.read().unwrap().clone())
+        };
+
+        // Each index point stands for the records up to the next one.
+        let mut samples: Vec<(&[u8], f64)> = memtable_records
+            .iter()
+            .map(|(key, _, _)| (key.as_slice(), 1.0))
+            .collect();
+        for sst in ssts.iter() {
+            let keys = sst.index_keys();
+            let weight = sst.record_count() as f64 / keys.len().max(1) as f64;
+            samples.extend(keys.into_iter().map(|key| (key, weight)));
+        }
+        let splits = split_points(samples, n);
+
+        // Shards are built last to first, each taking the MemTable records from its start on.
+        let mut shards = Vec::with_capacity(n);
+        let mut end = None;
+        for start in splits.into_iter().rev().chain([Vec::new()]) {
+            let from = memtable_records.partition_point(|(key, _, _)| *key < start);
+            let records = memtable_records.split_off(from);
+            let pin = pins.pop().unwrap();
+            let merged = merge_range(records, &ssts, &start, end.as_deref(), pin)?;
+            shards.push(ScanShard(merged));
+            end = Some(start);
+        }
+        shards.reverse();
+        for pin in pins {
+            shards.push(ScanShard(MergedChanges::new(Vec::new(), 0, pin)?));
+        }
+        Ok(shards)
+    }
+
+    /// Merges the MemTable and SSTables into each key's newest record written after `seqno`.
+    fn merged_records(&self, seqno: u64) -> io::Result<MergedChanges> {
+        let pin = self.location.pin();
+        let (memtable_records, ssts): (Vec<(Vec<u8>, u64, Entry)>, _) = {
+            let mt = self.active_memtable.read().unwrap();
+            let records = mt
+                .iter_with_seqno()
+                .map(|(key, entry, seqno)| (key.clone(), seqno, entry.clone()))
+                .collect();
+            (records, self.sstables.read().unwrap().clone())
+        };
+
+        let mut sources: Vec<RecordSource> = vec![Box::new(memtable_records.into_iter().map(Ok))];
+        for sst in ssts.iter().filter(|sst| sst.max_seqno() > seqno) {
+            sources.push(Box::new(sst.iter()?));
+        }
+        MergedChanges::new(sources, seqno, pin)
+    }
+
+    /// Estimates how many bytes of data the store holds for keys in `[start, end)`.
+    ///
+    /// This is a cheap estimate for planning scans and compactions, not an exact count: each
+    /// SSTable contributes the span between the sparse index points that bracket the range
+    /// (see [`SSTable::estimate_range_size`]), so the result is accurate to about one index
+    /// interval per table at each end. Overwritten and deleted versions still on disk are
+    /// included. The MemTable contributes its in-range entries exactly.
+    pub fn estimate_range_size(&self, start: &[u8], end: &[u8]) -> u64 {
+        let memtable = self
+            .active_memtable
+            .read()
+            .unwrap()
+            .approximate_range_size(start, end) as u64;
+        let ssts = self.sstables.read().unwrap();
+        memtable
+            + ssts
+                .iter()
+                .map(|sst| sst.estimate_range_size(start, end))
+                .sum::<u64>()
+    }
+
+    /// Estimates how many keys the store holds in `[start, end)`.
+    ///
+    /// Like [`Engine::estimate_range_size`] this is meant for planning, for example to choose
+    /// between point lookups and a scan. Each SSTable contributes an estimate from its sparse
+    /// index (see [`SSTable::estimate_key_count`]), accurate to about one sparse interval per
+    /// table at each end. A key with versions in several tables, or deleted, is counted in
+    /// each of them. The MemTable contributes its in-range keys exactly.
+    pub fn keys_count_in_range(&self, start: &[u8], end: &[u8]) -> u64 {
+        let memtable = self.active_memtable.read().unwrap().count_range(start, end) as u64;
+        let ssts = self.sstables.read().unwrap();
+        memtable
+            + ssts
+                .iter()
+                .map(|sst| sst.estimate_key_count(start, end))
+                .sum::<u64>()
+    }
+
+    /// Takes a consistent, read-only snapshot of the engine's current state.
+    ///
+    /// Until the snapshot is dropped, compaction keeps every version of a key that it can
+    /// still observe, including values deleted after it was taken.
+    pub fn snapshot(&self) -> Snapshot {
+        let mt = self.active_memtable.read().unwrap();
+        Snapshot::new(
+            &mt,
+            Arc::clone(&self.sstables),
+            self.location.clone(),
+            Arc::clone(&self.snapshots),
+        )
+    }
+
+    /// Retrieves the value a key had as of sequence number `seqno`: the newest version
+    /// written at or before it.
+    ///
+    /// This is a snapshot read at an arbitrary point in the past, so it only sees the versions
+    /// that are still retained. Compaction keeps older versions only while a live snapshot can
+    /// observe them, so values older than the last compaction of their tables may be
+    /// unavailable; likewise the MemTable keeps only the newest version of each key. When the
+    /// version asked for is gone, the newest older version still on disk is returned, or
+    /// `None` if there is none.
+    pub fn get_at_seq(&self, key: &[u8], seqno: u64) -> io::Result<Option<Vec<u8>>> {
+        {
+            let mt = self.active_memtable.read().unwrap();
+            if let Some((entry, version)) = mt.get_with_seqno(key)
+                && version <= seqno
+            {
+                return Ok(match entry {
+                    Entry::Value(v) => Some(v.clone()),
+                    Entry::Tombstone => None,
+                });
+            }
+        }
+
+        let (ssts, _pin) = self.pinned_tables();
+        for sst in ssts.iter() {
+            match sst.get_entry_at(key, seqno)? {
+                Some(Entry::Value(v)) => return Ok(Some(v)),
+                Some(Entry::Tombstone) => return Ok(None),
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the sequence number of the oldest live snapshot, if any.
+    ///
+    /// Compaction never drops a version that a snapshot at this sequence number could read,
+    /// nor a tombstone written after it.
+    pub fn min_snapshot_seqno(&self) -> Option<u64> {
+        self.snapshots.min_seqno()
+    }
+
+    /// Marks a key as deleted.
+    pub fn delete(&self, key: Vec<u8>) -> io::Result<()> {
+        self.write(WalEntry::Delete { key, version: None })
+    }
+
+    /// Like [`Engine::delete`], but the deletion is fsynced before it returns; see
+    /// [`Engine::put_durable`].
+    pub fn delete_durable(&self, key: Vec<u8>) -> io::Result<()> {
+        self.write_with_sync(
+            WalEntry::Delete { key, version: None },
+            Some(WalSyncMode::Fsync),
+        )
+    }
+
+    /// Deletes `key` only if its current value equals `expected`, returning whether it did.
+    ///
+    /// The read, comparison and delete are atomic: no other write can land in between, so of
+    /// several callers expecting the same value exactly one succeeds. A missing or deleted
+    /// key never matches. This suits releasing a lock or evicting a cache entry only if it
+    /// has not been replaced.
+    ///
+    /// Writes and flushes wait while the check runs, and so do reads of the MemTable. When
+    /// the key is not in the MemTable its SSTables are read with the lock held, so on a large
+    /// store each call can stall other operations for a disk lookup.
+    pub fn delete_if(&self, key: &[u8], expected: &[u8]) -> io::Result<bool> {
+        self.check_writable()?;
+        self.check_table_limit()?;
+        // The same lock order as a write, so a flush cannot move the key in between.
+        let mut wal = self.wal.as_ref().map(|wal| wal.write().unwrap());
+        let mt = self.active_memtable.write().unwrap();
+        let current = match mt.get(key) {
+            Some(Entry::Value(value)) => Some(value.clone()),
+            Some(Entry::Tombstone) => None,
+            None => {
+                let (ssts, _pin) = self.pinned_tables();
+                self.get_from_tables(key, &ssts)?.map(|(value, _)| value)
+            }
+        };
+        if current.as_deref() != Some(expected) {
+            return Ok(false);
+        }
+
+        let mut entry = WalEntry::Delete {
+            key: key.to_vec(),
+            version: None,
+        };
+        self.stamp(&mut entry);
+        let mut position = None;
+        if let Some(wal) = wal.as_mut() {
+            wal.append(&entry)?;
+            position = Some(wal.position());
+        }
+        drop(wal);
+        self.apply_logged(mt, [entry], position)?;
+        Ok(true)
+    }
+
+    /// Logs and applies every write in `batch`, in order, with at most one WAL fsync.
+    ///
+    /// The entries are appended to the WAL one after another under a single hold of its lock,
+    /// so no other write lands between them. Under [`WalSyncMode::Fsync`] the call then waits
+    /// for a single fsync covering the whole batch, shared through group commit like any
+    /// other write's, however many writes the batch holds; otherwise it does not fsync. This
+    /// is what makes a batch cheaper than the same writes made one at a time.
+    ///
+    /// Every entry is checked against the length limits before anything is logged. If an
+    /// append fails part-way, the writes logged before it are still applied, matching what
+    /// replaying the WAL would restore, and the error is returned. A batch is not atomic
+    /// across a crash: a torn log may keep only some of its writes.
+    pub fn write_batch(&self, batch: WriteBatch) -> io::Result<()> {
+        self.check_writable()?;
+        for entry in &batch.entries {
+            entry.check_lengths()?;
+        }
+        self.check_table_limit()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut wal = self.wal.as_ref().map(|wal| wal.write().unwrap());
+        let mut entries = batch.entries;
+        let mut logged = entries.len();
+        let mut failed = None;
+        for (i, entry) in entries.iter_mut().enumerate() {
+            self.stamp(entry);
+            if let Some(wal) = wal.as_mut()
+                && let Err(e) = wal.append_with_sync(entry, WalSyncMode::None)
+            {
+                (logged, failed) = (i, Some(e));
+                break;
+            }
+        }
+        entries.truncate(logged);
+        let position = wal.as_ref().map(|wal| wal.position());
+
+        let mt = self.active_memtable.write().unwrap();
+        drop(wal);
+        let applied = self.apply_logged(mt, entries, position);
+        match failed {
+            Some(e) => Err(e),
+            None => applied,
+        }
+    }
+
+    /// Like [`Engine::delete`], but accepts a `&str` or any other key convertible to bytes.
+    ///
+    /// ```
+    /// use lsm_storage_engine::Engine;
+    ///
+    /// let engine = Engine::open_in_memory(1 << 20);
+    /// engine.put_str("session", "token")?;
+    /// engine.delete_str("session")?;
+    /// assert_eq!(engine.get_str("session")?, None);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn delete_str(&self, key: impl Into<Vec<u8>>) -> io::Result<()> {
+        self.delete(key.into())
+    }
+
+    /// Fails with `PermissionDenied` if the engine was opened with [`Engine::open_readonly`].
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the engine was opened read-only",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fails if the store holds [`EngineOptions::max_sstables`] tables or more.
+    fn check_table_limit(&self) -> io::Result<()> {
+        let count = self.sstables.read().unwrap().len();
+        match self.max_sstables {
+            Some(max) if count >= max => Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                format!(
+                    "the store holds {} SSTables, at or above max_sstables of {}; writes are \
+                     refused until compaction reduces the count",
+                    count, max
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Logs `entry` to the WAL, applies it to the active MemTable and flushes if it is full.
+    fn write(&self, entry: WalEntry) -> io::Result<()> {
+        self.write_with_sync(entry, None)
+    }
+
+    /// Like [`Engine::write`], but `sync` overrides [`EngineOptions::wal_sync`] for `entry`.
+    fn write_with_sync(&self, mut entry: WalEntry, sync: Option<WalSyncMode>) -> io::Result<()> {
+        self.check_writable()?;
+        // Checked here too, since an in-memory engine has no WAL to reject the entry.
+        entry.check_lengths()?;
+        self.check_table_limit()?;
+        let mut wal = self.wal.as_ref().map(|wal| wal.write().unwrap());
+        self.stamp(&mut entry);
+        let mut position = None;
+        if let Some(wal) = wal.as_mut() {
+            // Without group commit nobody else fsyncs, so a durable entry does it on its own.
+            let fsync_now = sync == Some(WalSyncMode::Fsync) && self.group_commit.is_none();
+            let mode = if fsync_now {
+                WalSyncMode::Fsync
+            } else {
+                WalSyncMode::None
+            };
+            wal.append_with_sync(&entry, mode)?;
+            // Only entries with a position wait for group commit.
+            if sync != Some(WalSyncMode::None) {
+                position = Some(wal.position());
+            }
+        }
+
+        // The MemTable lock is taken before the WAL lock is released, so a flush never records
+        // a WAL position that covers an entry missing from the MemTable it writes out.
+        let mt = self.active_memtable.write().unwrap();
+        drop(wal);
+        self.apply_logged(mt, [entry], position)
+    }
+
+    /// Applies `entries` already logged up to WAL `position` to the locked MemTable, then
+    /// waits for them to be durable and flushes if the MemTable is full.
+    fn apply_logged(
+        &self,
+        mut mt: RwLockWriteGuard<MemTable>,
+        entries: impl IntoIterator<Item = WalEntry>,
+        position: Option<u64>,
+    ) -> io::Result<()> {
+        for entry in entries {
+            apply_entry(&mut mt, entry);
+        }
+        let full = mt.is_full() && !self.defer_flush(mt.approximate_size());
+        drop(mt);
+
+        // The entry is visible to readers from here on, but the write only returns once it is
+        // durable.
+        if let (Some(group_commit), Some(position)) = (&self.group_commit, position) {
+            group_commit.commit(position, || self.sync_wal())?;
+        }
+        if full {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives `entry` the next version if the engine stamps writes with one.
+    ///
+    /// Callers hold the WAL lock, so versions grow in the order entries reach the MemTable.
+    /// Only an engine opened on a directory, which always has a WAL, has a clock.
+    fn stamp(&self, entry: &mut WalEntry) {
+        if let Some(clock) = &self.clock {
+            let (WalEntry::Put { version, .. } | WalEntry::Delete { version, .. }) = entry;
+            *version = Some(clock.next());
+        }
+    }
+
+    /// Returns `true` if a full MemTable of `size` bytes should keep accumulating writes
+    /// under [`EngineOptions::min_flush_size`].
+    fn defer_flush(&self, size: usize) -> bool {
+        size < self.min_flush_size
+            && self
+                .last_flush
+                .lock()
+                .unwrap()
+                .is_some_and(|last| last.elapsed() < self.flush_grace_window)
+    }
+
+    /// Fsyncs the WAL and returns the position it is now durable up to.
+    fn sync_wal(&self) -> io::Result<u64> {
+        let mut wal = self.wal.as_ref().unwrap().write().unwrap();
+        wal.sync()?;
+        Ok(wal.position())
+    }
+
+    /// Manually triggers a flush of the current MemTable to an SSTable.
+    ///
+    /// Returns the metadata of the new table, or `None` if the MemTable was empty.
+    pub fn flush(&self) -> io::Result<Option<SSTableMetadata>> {
+        let (metadata, ()) = self.flush_then(|_| Ok(()))?;
+        if metadata.is_some() {
+            self.check_compaction();
+        }
+        Ok(metadata)
+    }
+
+    /// Writes the MemTable as it is now to a new SSTable file at `path`, leaving the engine
+    /// untouched: the table is not loaded, the MemTable is not cleared and the WAL is kept.
+    ///
+    /// This separates building a table from making it live, for callers that name, move or
+    /// upload tables themselves. The MemTable is copied under its read lock and the table is
+    /// built from the copy, so writers only wait for the copy. The table is written with the
+    /// engine's [`TableOptions`] at level 0, and is encrypted if the engine's tables are. The
+    /// file is written in place and synced, but its directory entry is not.
+    pub fn export_memtable_snapshot(&self, path: impl AsRef<Path>) -> io::Result<SSTableMetadata> {
+        let mt = self.active_memtable.read().unwrap().clone();
+        let builder = match &self.location {
+            TableLocation::Dir(_, caches) => caches.builder(path.as_ref())?,
+            _ => SSTableBuilder::new(path, 16)?,
+        };
+        builder
+            .with_options(&self.table_options.for_level(0))
+            .with_level(0)
+            .build(&mt)
+    }
+
+    /// Writes a consistent copy of the store as it is now into `target_dir`, which can then be
+    /// opened as an independent engine.
+    ///
+    /// The MemTable is flushed first. The tables live at that point are then hard-linked into
+    /// `target_dir`, or copied if they are on another filesystem, along with a manifest
+    /// listing them and an empty WAL. Writes that arrive once the flush is done are not part
+    /// of the copy. Tables skipped as corrupt on open are left out. `target_dir` is created
+    /// if needed and must be empty; anything else fails with `AlreadyExists`. An engine that
+    /// keeps its tables in memory fails with `Unsupported`.
+    pub fn checkpoint(&self, target_dir: impl AsRef<Path>) -> io::Result<()> {
+        let target_dir = target_dir.as_ref();
+        if !matches!(self.location, TableLocation::Dir(..)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only an engine with a directory can be checkpointed",
+            ));
+        }
+        std::fs::create_dir_all(target_dir)?;
+        if std::fs::read_dir(target_dir)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("checkpoint target {} is not empty", target_dir.display()),
+            ));
+        }
+
+        // Pinning the tables while the MemTable is still locked keeps a compaction that
+        // replaces them from removing their files before they are linked.
+        let (_, (tables, _pin)) = self.flush_then(|_| {
+            let tables = self.sstables.read().unwrap().clone();
+            Ok((tables, self.location.pin()))
+        })?;
+        let mut names = Vec::with_capacity(tables.len());
+        for table in &tables {
+            let name = table.path().file_name().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "SSTable path has no file name")
+            })?;
+            let target = target_dir.join(name);
+            if std::fs::hard_link(table.path(), &target).is_err() {
+                std::fs::copy(table.path(), &target)?;
+                std::fs::File::open(&target)?.sync_all()?;
+            }
+            names.push(name.to_string_lossy().into_owned());
+        }
+        std::fs::File::create(target_dir.join(WAL_FILE))?.sync_all()?;
+        write_manifest(target_dir, &names)
+    }
+
+    /// Loads records in strictly ascending key order straight into a new SSTable.
+    ///
+    /// This is the bulk load path: the records skip the WAL and the MemTable and are durable
+    /// once the table file is synced. They are newer than everything already written, so the
+    /// MemTable is flushed first and the new table is installed ahead of every other one.
+    /// Writers wait until the table is in place. Fails with `InvalidInput`, installing
+    /// nothing, if a key is not greater than the one before it. An empty input does nothing.
+    pub fn ingest_sorted(
+        &self,
+        records: impl Iterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> io::Result<()> {
+        let mut records = records.peekable();
+        if records.peek().is_none() {
+            return Ok(());
+        }
+
+        self.flush_then(|mt| {
+            // One sequence number serves the whole table, since it holds each key only once.
+            // With a clock it is a version like any write's, so it names this node.
+            let seqno = match &self.clock {
+                Some(clock) => clock.next().to_seqno(),
+                None => mt.last_seqno() + 1,
+            };
+            let new_sst = self.location.build_table(
+                &new_table_name("sst"),
+                &self.table_options,
+                0,
+                |builder| {
+                    let records = records.map(|(key, value)| (key, seqno, Entry::Value(value), 0));
+                    builder.build_from_iter(records).map(|_| ())
+                },
+            )?;
+            let mut ssts = self.sstables.write().unwrap();
+            let mut next = ssts.clone();
+            next.insert(0, Arc::new(new_sst));
+            self.location.save_manifest(&next)?;
+            *ssts = next;
+            drop(ssts);
+            mt.advance_seqno(seqno);
+            Ok(())
+        })?;
+
+        self.check_compaction();
+        Ok(())
+    }
+
+    /// Flushes the MemTable, then runs `then` on it before any other write can land.
+    ///
+    /// Returns the metadata of the flushed table, or `None` if the MemTable was empty, along
+    /// with what `then` returned. The flush is kept even if `then` fails.
+    fn flush_then<T>(
+        &self,
+        then: impl FnOnce(&mut MemTable) -> io::Result<T>,
+    ) -> io::Result<(Option<SSTableMetadata>, T)> {
+        self.check_writable()?;
+        // Every entry logged before this position is in the MemTable once we hold its lock;
+        // writes that arrive while the table is built land after it and survive truncation.
+        let wal = self.wal.as_ref().map(|wal| wal.write().unwrap());
+        let flushed_position = wal.as_ref().map(|wal| wal.position());
+        let mut mt = self.active_memtable.write().unwrap();
+        drop(wal);
+
+        let mut metadata = None;
+        if mt.approximate_size() != 0 {
+            // Merging replaces the newest table, which must not race a compaction of it.
+            let merge_guard = self
+                .flush_merge
+                .and_then(|_| self.compaction_lock.try_lock().ok());
+            let target = match merge_guard {
+                Some(_) => self.flush_merge_target(&mt)?,
+                None => None,
+            };
+            let new_sst = match &target {
+                Some((newest, bottommost)) => self.merge_flush(&mt, newest, *bottommost)?,
+                None => self.location.build_table(
+                    &new_table_name("sst"),
+                    &self.table_options,
+                    0,
+                    |builder| builder.build(&mt).map(|_| ()),
+                )?,
+            };
+
+            metadata = Some(new_sst.metadata());
+            self.metrics
+                .flush_bytes_written
+                .fetch_add(new_sst.size(), Ordering::Relaxed);
+            // There is no frozen MemTable: readers wait on the lock held here while the table
+            // is built, and the table joins the list before the MemTable is cleared, so a
+            // read that misses the cleared MemTable always finds the entries in the list.
+            {
+                let mut ssts = self.sstables.write().unwrap();
+                let mut next = ssts.clone();
+                if let Some((newest, _)) = &target {
+                    next.retain(|s| !Arc::ptr_eq(s, newest));
+                }
+                next.insert(0, Arc::new(new_sst));
+                self.location.save_manifest(&next)?;
+                *ssts = next;
+            }
+            mt.clear();
+            *self.last_flush.lock().unwrap() = now();
+            if let Some((newest, _)) = target {
+                self.location.remove_tables(&[newest])?;
+            }
+        }
+
+        let result = then(&mut mt);
+        drop(mt);
+        if metadata.is_some()
+            && let (Some(wal), Some(position)) = (&self.wal, flushed_position)
+        {
+            wal.write().unwrap().truncate_to(position)?;
+        }
+
+        result.map(|value| (metadata, value))
+    }
+
+    /// Returns the newest table if [`EngineOptions::flush_merge`] allows flushing `mt` into
+    /// it, along with whether it is the only table.
+    ///
+    /// The caller must hold the compaction lock, so that the table list cannot change.
+    fn flush_merge_target(&self, mt: &MemTable) -> io::Result<Option<(Arc<SSTable>, bool)>> {
+        let Some(options) = self.flush_merge else {
+            return Ok(None);
+        };
+        let (newest, bottommost) = {
+            let ssts = self.sstables.read().unwrap();
+            match ssts.first() {
+                Some(newest) => (Arc::clone(newest), ssts.len() == 1),
+                None => return Ok(None),
+            }
+        };
+        if newest.level() != 0 || newest.data_size() > options.max_table_size {
+            return Ok(None);
+        }
+
+        // Keys come in order, with the versions of a key next to each other.
+        let mut shared = 0;
+        let mut previous = None;
+        for key in newest.key_iter()? {
+            let (key, _) = key?;
+            if previous.as_ref() != Some(&key) && mt.get(&key).is_some() {
+                shared += 1;
+            }
+            previous = Some(key);
+        }
+        let keys = mt.iter().count();
+        Ok((shared as f64 >= options.min_overlap * keys as f64).then_some((newest, bottommost)))
+    }
+
+    /// Writes `mt` merged with `newest` into a new level 0 table, keeping every version a
+    /// live snapshot can still read.
+    fn merge_flush(
+        &self,
+        mt: &MemTable,
+        newest: &Arc<SSTable>,
+        bottommost: bool,
+    ) -> io::Result<SSTable> {
+        let retention = compaction::Retention {
+            min_snapshot_seqno: self.snapshots.min_seqno().unwrap_or(u64::MAX),
+            bottommost,
+        };
+        self.location
+            .build_table(&new_table_name("sst"), &self.table_options, 0, |builder| {
+                let memtable = mt
+                    .iter_with_meta()
+                    .map(|(key, entry, seqno, meta)| Ok((key.clone(), seqno, entry.clone(), meta)));
+                let sources = vec![Box::new(memtable) as _, compaction::table_source(newest)?];
+                compaction::merge_into(sources, builder, retention).map(|_| ())
+            })
+    }
+
+    /// Manually triggers a full compaction of all current SSTables.
+    ///
+    /// Returns the metadata of the tables written, which is empty if there was nothing to merge.
+    pub fn compact(&self) -> io::Result<Vec<SSTableMetadata>> {
+        self.compact_range(None, None)
+    }
+
+    /// Compacts the SSTables whose key ranges overlap `[start, end]` into a single table.
+    ///
+    /// Both bounds are inclusive and `None` leaves that side unbounded, so
+    /// `compact_range(None, None)` is a full compaction. Tables that sit between the selected
+    /// ones and overlap them are pulled in as well, so newer data is never shadowed by the
+    /// merged output. Nothing happens unless at least two tables are selected.
+    ///
+    /// Returns the metadata of the tables written, which is empty if nothing was merged.
+    pub fn compact_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> io::Result<Vec<SSTableMetadata>> {
+        self.check_writable()?;
+        compact_tables(
+            &self.sstables,
+            &self.location,
+            &self.table_options,
+            &self.compaction_lock,
+            &self.snapshots,
+            &self.metrics,
+            |ssts| Ok(select_for_compaction(ssts, start, end)),
+        )
+    }
+
+    /// Flushes the MemTable and merges every SSTable into one, whatever the compaction
+    /// strategy would do.
+    ///
+    /// This is a one-off "compact everything now" for reclaiming space: overwritten versions
+    /// are dropped, and so are tombstones along with the data they delete, since the merge
+    /// covers the oldest table. Versions that a live [`Snapshot`] can still read are kept.
+    /// Tables flushed while the merge runs are left out of it.
+    pub fn optimize(&self) -> io::Result<OptimizeStats> {
+        let totals = |ssts: &[Arc<SSTable>]| (ssts.len(), ssts.iter().map(|s| s.size()).sum());
+
+        self.flush()?;
+        let (tables_before, bytes_before) = totals(&self.sstables.read().unwrap());
+        self.compact()?;
+        let (tables_after, bytes_after) = totals(&self.sstables.read().unwrap());
+        Ok(OptimizeStats {
+            tables_before,
+            tables_after,
+            bytes_before,
+            bytes_after,
+        })
+    }
+
+    /// Merges the `count` newest SSTables with only the older tables that may share keys with
+    /// them.
+    ///
+    /// An older table is left alone when its key range or bloom filter shows that it holds
+    /// none of the newer tables' keys, and it overlaps none of the older tables that are
+    /// merged, so recent writes can be folded in without rewriting unrelated data. Reads the
+    /// keys of the newest tables to decide. Nothing happens unless at least two tables are
+    /// selected.
+    ///
+    /// Returns the metadata of the tables written, which is empty if nothing was merged.
+    pub fn compact_newest(&self, count: usize) -> io::Result<Vec<SSTableMetadata>> {
+        self.check_writable()?;
+        compact_tables(
+            &self.sstables,
+            &self.location,
+            &self.table_options,
+            &self.compaction_lock,
+            &self.snapshots,
+            &self.metrics,
+            |ssts| compaction::select_overlapping(ssts, count),
+        )
+    }
+
+    /// Blocks until every background compaction requested so far has installed its output.
+    ///
+    /// Returns the error the last of them failed with, if it did.
+    pub fn wait_for_background(&self) -> io::Result<()> {
+        match &self.compaction {
+            Some(worker) => worker.wait(),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes the MemTable and waits for any compaction that triggers, leaving the engine
+    /// quiescent.
+    pub fn flush_and_wait_compaction(&self) -> io::Result<()> {
+        self.flush()?;
+        self.wait_for_background()
+    }
+
+    /// Waits for background compaction to finish and shuts the engine down.
+    ///
+    /// Dropping the engine also stops the compaction worker, but abandons a compaction that
+    /// was requested and has not started yet, and cannot report errors.
+    pub fn close(self) -> io::Result<()> {
+        self.wait_for_background()
+    }
+
+    fn check_compaction(&self) {
+        let Some(worker) = &self.compaction else {
+            return;
+        };
+        if self.sstables.read().unwrap().len() >= self.compaction_priority.trigger() {
+            worker.request();
+        }
+    }
+}
+
+/// The result of a shared SSTable lookup. Errors keep only their kind and message, since
+/// `io::Error` cannot be cloned for every waiter.
+type SharedLookup = Result<Option<(Vec<u8>, u8)>, (io::ErrorKind, String)>;
+
+/// SSTable lookups in progress, by key, that concurrent `get`s can wait on instead of
+/// repeating.
+#[derive(Default)]
+struct InflightReads {
+    flights: Mutex<HashMap<Vec<u8>, Arc<Flight>>>,
+}
+
+/// One lookup of a key in a pinned list of SSTables.
+struct Flight {
+    tables: Vec<Arc<SSTable>>,
+    state: Mutex<FlightState>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct FlightState {
+    result: Option<SharedLookup>,
+    /// Number of callers waiting for the result.
+    followers: usize,
+}
+
+impl InflightReads {
+    /// Looks `key` up in `tables` with `lookup`, or waits for a lookup already running.
+    ///
+    /// A running lookup is only joined if it pinned the same tables, so the caller gets
+    /// exactly the result it would have computed itself.
+    fn run(
+        &self,
+        key: &[u8],
+        tables: Vec<Arc<SSTable>>,
+        lookup: impl FnOnce(&[Arc<SSTable>]) -> io::Result<Option<(Vec<u8>, u8)>>,
+    ) -> io::Result<Option<(Vec<u8>, u8)>> {
+        let flight = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(key) {
+                Some(flight) if same_tables(&flight.tables, &tables) => {
+                    let flight = Arc::clone(flight);
+                    drop(flights);
+                    return flight.wait();
+                }
+                // Another list of tables is being searched for this key; search ours alone.
+                Some(_) => {
+                    drop(flights);
+                    return lookup(&tables);
+                }
+                None => {
+                    let flight = Arc::new(Flight {
+                        tables,
+                        state: Mutex::new(FlightState::default()),
+                        done: Condvar::new(),
+                    });
+                    flights.insert(key.to_vec(), Arc::clone(&flight));
+                    flight
+                }
+            }
+        };
+
+        // Followers are released even if the lookup panics.
+        let landing = Landing {
+            reads: self,
+            key,
+            flight: &flight,
+        };
+        let result = lookup(&flight.tables);
+        landing.finish(match &result {
+            Ok(value) => Ok(value.clone()),
+            Err(e) => Err((e.kind(), e.to_string())),
+        });
+        result
+    }
+
+    #[cfg(test)]
+    fn followers(&self, key: &[u8]) -> usize {
+        let flights = self.flights.lock().unwrap();
+        flights
+            .get(key)
+            .map_or(0, |flight| flight.state.lock().unwrap().followers)
+    }
+}
+
+impl Flight {
+    /// Blocks until the lookup has finished and returns its result.
+    fn wait(&self) -> io::Result<Option<(Vec<u8>, u8)>> {
+        let mut state = self.state.lock().unwrap();
+        state.followers += 1;
+        while state.result.is_none() {
+            state = self.done.wait(state).unwrap();
+        }
+        state
+            .result
+            .clone()
+            .unwrap()
+            .map_err(|(kind, message)| io::Error::new(kind, message))
+    }
+}
+
+/// Publishes the result of a [`Flight`] and retires it, or fails it if dropped first.
+struct Landing<'a> {
+    reads: &'a InflightReads,
+    key: &'a [u8],
+    flight: &'a Flight,
+}
+
+impl Landing<'_> {
+    fn finish(self, result: SharedLookup) {
+        self.flight.state.lock().unwrap().result = Some(result);
+    }
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        self.reads.flights.lock().unwrap().remove(self.key);
+        let mut state = self.flight.state.lock().unwrap();
+        if state.result.is_none() {
+            state.result = Some(Err((io::ErrorKind::Other, "shared lookup failed".into())));
+        }
+        self.flight.done.notify_all();
+    }
+}
+
+/// Returns `true` if `a` and `b` are the same tables in the same order.
+fn same_tables(a: &[Arc<SSTable>], b: &[Arc<SSTable>]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| Arc::ptr_eq(a, b))
+}
+
+/// A long-lived thread that runs background compactions when asked to.
+///
+/// Requests made while one is already pending are merged into it, so a burst of flushes
+/// triggers at most one more compaction. Dropping the worker stops the thread after the
+/// compaction in progress, if any.
+///
+/// wasm32 has no threads, so there each request runs the compaction on the calling thread
+/// before it returns.
+struct CompactionWorker {
+    shared: Arc<WorkerShared>,
+    #[cfg(not(target_arch = "wasm32"))]
+    thread: Option<JoinHandle<()>>,
+    #[cfg(target_arch = "wasm32")]
+    job: Mutex<Box<dyn FnMut() -> io::Result<()> + Send>>,
+}
+
+struct WorkerShared {
+    state: Mutex<WorkerState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct WorkerState {
+    pending: bool,
+    running: bool,
+    #[cfg(not(target_arch = "wasm32"))]
+    shutdown: bool,
+    /// Error of the first compaction that failed since `wait` last reported one.
+    error: Option<io::Error>,
+    /// Thread each compaction ran on.
+    #[cfg(test)]
+    runs: Vec<std::thread::ThreadId>,
+}
+
+impl CompactionWorker {
+    /// Sets up a worker that runs `job` on the calling thread for each request.
+    #[cfg(target_arch = "wasm32")]
+    fn spawn(job: impl FnMut() -> io::Result<()> + Send + 'static) -> Self {
+        Self {
+            shared: Arc::new(WorkerShared {
+                state: Mutex::new(WorkerState::default()),
+                changed: Condvar::new(),
+            }),
+            job: Mutex::new(Box::new(job)),
+        }
+    }
+
+    /// Runs the compaction right away, keeping its error for `wait`.
+    #[cfg(target_arch = "wasm32")]
+    fn request(&self) {
+        let result = (self.job.lock().unwrap())();
+        if let Err(e) = &result {
+            eprintln!("Compaction failed: {}", e);
+        }
+        if let Err(e) = result {
+            self.shared.state.lock().unwrap().error.get_or_insert(e);
+        }
+    }
+
+    /// Starts the worker thread, which runs `job` once per request.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn(mut job: impl FnMut() -> io::Result<()> + Send + 'static) -> Self {
+        let shared = Arc::new(WorkerShared {
+            state: Mutex::new(WorkerState::default()),
+            changed: Condvar::new(),
+        });
+        let worker_shared = Arc::clone(&shared);
+        let thread = std::thread::spawn(move || {
+            let shared = worker_shared;
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                while !state.pending && !state.shutdown {
+                    state = shared.changed.wait(state).unwrap();
+                }
+                if state.shutdown {
+                    return;
+                }
+                state.pending = false;
+                state.running = true;
+                drop(state);
+
+                let result = job();
+                if let Err(e) = &result {
+                    eprintln!("Compaction failed: {}", e);
+                }
+
+                state = shared.state.lock().unwrap();
+                state.running = false;
+                if let Err(e) = result {
+                    state.error.get_or_insert(e);
+                }
+                #[cfg(test)]
+                state.runs.push(std::thread::current().id());
+                shared.changed.notify_all();
+            }
+        });
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Asks for a compaction to run once the current one, if any, has finished.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn request(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.pending {
+            state.pending = true;
+            self.shared.changed.notify_all();
+        }
+    }
+
+    /// Blocks until no compaction is pending or running, returning the first error since the
+    /// last call.
+    fn wait(&self) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.pending || state.running {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        state.error.take().map_or(Ok(()), Err)
+    }
+
+    /// Returns `true` while a compaction is pending or running.
+    #[cfg(test)]
+    fn is_busy(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.pending || state.running
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for CompactionWorker {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Merges the tables of `sstables` chosen by `select` and swaps in the result.
+///
+/// `select` must return tables in list order that can be merged without reordering any key.
+/// `lock` serialises compactions so that manual and background runs never merge the same
+/// inputs twice. Versions still visible to a live snapshot in `snapshots` are kept.
+/// Lookups keep working throughout; the list is only locked for the swap.
+fn compact_tables(
+    sstables: &RwLock<Vec<Arc<SSTable>>>,
+    location: &TableLocation,
+    options: &TableOptions,
+    lock: &Mutex<()>,
+    snapshots: &SnapshotList,
+    metrics: &Metrics,
+    select: impl FnOnce(&[Arc<SSTable>]) -> io::Result<Vec<Arc<SSTable>>>,
+) -> io::Result<Vec<SSTableMetadata>> {
+    let _guard = lock.lock().unwrap();
+    let (to_compact, bottommost) = {
+        let ssts = sstables.read().unwrap();
+        let to_compact = select(&ssts)?;
+        // New tables are only ever added at the front, so once the oldest table is among the
+        // inputs nothing older can appear for the output's tombstones to shadow.
+        let bottommost = match (ssts.last(), to_compact.last()) {
+            (Some(oldest), Some(last)) => Arc::ptr_eq(oldest, last),
+            _ => false,
+        };
+        (to_compact, bottommost)
+    };
+    if to_compact.len() < 2 {
+        return Ok(Vec::new());
+    }
+
+    let retention = compaction::Retention {
+        min_snapshot_seqno: snapshots.min_seqno().unwrap_or(u64::MAX),
+        bottommost,
+    };
+    let output_name = compaction_output_name(&to_compact[0]);
+    // The output sits one level below its deepest input.
+    let level = to_compact.iter().map(|s| s.level()).max().unwrap_or(0) + 1;
+    let new_sst = location.build_table(&output_name, options, level, |builder| {
+        compaction::compact_into(&to_compact, builder, retention).map(|_| ())
+    })?;
+    let metadata = new_sst.metadata();
+    let bytes_read = to_compact.iter().map(|s| s.size()).sum();
+    metrics
+        .compaction_bytes_read
+        .fetch_add(bytes_read, Ordering::Relaxed);
+    metrics
+        .compaction_bytes_written
+        .fetch_add(new_sst.size(), Ordering::Relaxed);
+
+    // The output takes the place of the newest input. Any table in between was either
+    // selected or holds no overlapping keys, so the relative order of every key is preserved.
+    let mut ssts = sstables.write().unwrap();
+    let position = ssts
+        .iter()
+        .position(|s| Arc::ptr_eq(s, &to_compact[0]))
+        .unwrap_or(0);
+    let mut next = ssts.clone();
+    next.retain(|s| !to_compact.iter().any(|c| Arc::ptr_eq(s, c)));
+    next.insert(position, Arc::new(new_sst));
+    location.save_manifest(&next)?;
+    *ssts = next;
+    drop(ssts);
+
+    location.remove_tables(&to_compact)?;
+    Ok(vec![metadata])
+}
+
+/// Picks the tables (newest first) that a compaction of `[start, end]` has to merge.
+fn select_for_compaction(
+    ssts: &[Arc<SSTable>],
+    start: Option<&[u8]>,
+    end: Option<&[u8]>,
+) -> Vec<Arc<SSTable>> {
+    if start.is_none() && end.is_none() {
+        return ssts.to_vec();
+    }
+
+    let mut selected: Vec<bool> = ssts.iter().map(|s| s.overlaps(start, end)).collect();
+    // A table between two selected ones must join them if its keys overlap theirs, otherwise
+    // the merged output would reorder it relative to older versions of the same keys.
+    loop {
+        let (Some(lo), Some(hi)) = (
+            selected.iter().position(|&s| s),
+            selected.iter().rposition(|&s| s),
+        ) else {
+            return Vec::new();
+        };
+        let chosen = || (lo..=hi).filter(|&i| selected[i]).map(|i| &ssts[i]);
+        let union_start = chosen()
+            .filter_map(|s| s.first_key())
+            .min()
+            .map(<[u8]>::to_vec);
+        let union_end = chosen()
+            .filter_map(|s| s.last_key())
+            .max()
+            .map(<[u8]>::to_vec);
+
+        let mut changed = false;
+        for i in lo..=hi {
+            if !selected[i] && ssts[i].overlaps(union_start.as_deref(), union_end.as_deref()) {
+                selected[i] = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    ssts.iter()
+        .zip(selected)
+        .filter(|(_, s)| *s)
+        .map(|(sst, _)| Arc::clone(sst))
+        .collect()
+}
+
+/// A source of `(key, is_tombstone)` pairs in key order.
+type KeySource = Box<dyn Iterator<Item = io::Result<(Vec<u8>, bool)>> + Send>;
+
+/// Merges key sources ordered newest first, yielding each live key once.
+struct MergedKeys {
+    /// The current key of every unfinished source, smallest key (then newest source) on top.
+    heap: BinaryHeap<Reverse<(Vec<u8>, usize, bool)>>,
+    sources: Vec<KeySource>,
+    /// Keeps the files of the tables among `sources` until the iterator is dropped.
+    _pin: TablePin,
+}
+
+impl MergedKeys {
+    fn new(sources: Vec<KeySource>, pin: TablePin) -> io::Result<Self> {
+        let mut merged = Self {
+            heap: BinaryHeap::new(),
+            sources,
+            _pin: pin,
+        };
+        for source in 0..merged.sources.len() {
+            merged.advance(source)?;
+        }
+        Ok(merged)
+    }
+
+    /// Pushes the next key of `source`, if it has one, onto the heap.
+    fn advance(&mut self, source: usize) -> io::Result<()> {
+        if let Some(next) = self.sources[source].next() {
+            let (key, tombstone) = next?;
+            self.heap.push(Reverse((key, source, tombstone)));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for MergedKeys {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Reverse((key, source, tombstone))) = self.heap.pop() {
+            // Older versions of this key, in this source or an older one, are shadowed.
+            let mut result = self.advance(source);
+            while let Some(Reverse((next_key, next_source, _))) = self.heap.peek() {
+                if *next_key != key {
+                    break;
+                }
+                let next_source = *next_source;
+                self.heap.pop();
+                result = result.and(self.advance(next_source));
+            }
+            if let Err(e) = result {
+                self.heap.clear();
+                return Some(Err(e));
+            }
+            if !tombstone {
+                return Some(Ok(key));
+            }
+        }
+        None
+    }
+}
+
+/// A source of `(key, seqno, entry)` records in key order.
+type RecordSource = Box<dyn Iterator<Item = io::Result<(Vec<u8>, u64, Entry)>> + Send>;
+
+/// Turns a lock that is held elsewhere into a [`io::ErrorKind::WouldBlock`] error.
+fn try_lock<G>(result: TryLockResult<G>) -> io::Result<G> {
+    match result {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::WouldBlock) => Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "the engine is busy; another thread holds a lock this read needs",
+        )),
+        Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+    }
+}
+
+/// Merges `memtable_records`, which must already lie in `[start, end)`, with the records of
+/// `ssts` in that range.
+fn merge_range(
+    memtable_records: Vec<(Vec<u8>, u64, Entry)>,
+    ssts: &[Arc<SSTable>],
+    start: &[u8],
+    end: Option<&[u8]>,
+    pin: TablePin,
+) -> io::Result<MergedChanges> {
+    let mut sources: Vec<RecordSource> = vec![Box::new(memtable_records.into_iter().map(Ok))];
+    for sst in ssts.iter().filter(|sst| sst.overlaps(Some(start), end)) {
+        sources.push(Box::new(sst.range_bounds((
+            Bound::Included(start),
+            end.map_or(Bound::Unbounded, Bound::Excluded),
+        ))?));
+    }
+    MergedChanges::new(sources, 0, pin)
+}
+
+/// Yields the keys and values of `merged`, skipping deleted keys.
+fn live_values(merged: MergedChanges) -> impl Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>> {
+    merged.filter_map(|record| match record {
+        Ok((key, Entry::Value(value), _)) => Some(Ok((key, value))),
+        Ok((_, Entry::Tombstone, _)) => None,
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// Picks up to `n - 1` ascending, distinct, non-empty keys that split the weighted `samples`
+/// into `n` parts of about equal weight.
+fn split_points(mut samples: Vec<(&[u8], f64)>, n: usize) -> Vec<Vec<u8>> {
+    samples.sort_by(|a, b| a.0.cmp(b.0));
+    let total: f64 = samples.iter().map(|(_, weight)| weight).sum();
+    let mut splits: Vec<Vec<u8>> = Vec::new();
+    let mut seen = 0.0;
+    for (key, weight) in samples {
+        // A split at `key` puts the weight seen so far before it.
+        let wanted = total * (splits.len() + 1) as f64 / n as f64;
+        if splits.len() + 1 < n
+            && seen >= wanted
+            && !key.is_empty()
+            && splits.last().is_none_or(|last| last.as_slice() < key)
+        {
+            splits.push(key.to_vec());
+        }
+        seen += weight;
+    }
+    splits
+}
+
+/// A record's key, sequence number and source, ordered so that a min-heap pops the smallest
+/// key first and, among its versions, the highest sequence number.
+type ChangeHead = (Vec<u8>, Reverse<u64>, usize);
+
+/// Merges record sources, yielding each key's newest version if it is newer than `since`.
+struct MergedChanges {
+    /// The current record of every unfinished source.
+    heap: BinaryHeap<Reverse<ChangeHead>>,
+    /// The entry belonging to each source's key on the heap.
+    entries: Vec<Option<Entry>>,
+    sources: Vec<RecordSource>,
+    since: u64,
+    /// Keeps the files of the tables among `sources` until the iterator is dropped.
+    _pin: TablePin,
+}
+
+impl MergedChanges {
+    fn new(sources: Vec<RecordSource>, since: u64, pin: TablePin) -> io::Result<Self> {
+        let mut merged = Self {
+            heap: BinaryHeap::new(),
+            entries: vec![None; sources.len()],
+            sources,
+            since,
+            _pin: pin,
+        };
+        for source in 0..merged.sources.len() {
+            merged.advance(source)?;
+        }
+        Ok(merged)
+    }
+
+    /// Pushes the next record of `source`, if it has one, onto the heap.
+    fn advance(&mut self, source: usize) -> io::Result<()> {
+        if let Some(next) = self.sources[source].next() {
+            let (key, seqno, entry) = next?;
+            self.entries[source] = Some(entry);
+            self.heap.push(Reverse((key, Reverse(seqno), source)));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for MergedChanges {
+    type Item = io::Result<(Vec<u8>, Entry, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Reverse((key, Reverse(seqno), source))) = self.heap.pop() {
+            let entry = self.entries[source].take().unwrap();
+            // Every other version of this key has a lower sequence number.
+            let mut result = self.advance(source);
+            while let Some(Reverse((next_key, _, next_source))) = self.heap.peek() {
+                if *next_key != key {
+                    break;
+                }
+                let next_source = *next_source;
+                self.heap.pop();
+                result = result.and(self.advance(next_source));
+            }
+            if let Err(e) = result {
+                self.heap.clear();
+                return Some(Err(e));
+            }
+            if seqno > self.since {
+                return Some(Ok((key, entry, seqno)));
+            }
+        }
+        None
+    }
+}
+
+/// Whether `e` means an SSTable's contents are damaged, as opposed to it being unreadable.
+fn is_corruption(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Where the engine stores the SSTables it creates.
+#[derive(Clone)]
+pub(crate) enum TableLocation {
+    /// SSTables are files in this directory, read through whichever caches are set.
+    Dir(PathBuf, TableCaches),
+    /// SSTables are kept in memory and never touch the filesystem.
+    Memory,
+    /// SSTables are files in this directory whose writes fail after the given number of bytes.
+    #[cfg(test)]
+    FaultyDir(PathBuf, u64),
+}
+
+impl TableLocation {
+    /// Builds a new SSTable named `file_name` at `level` with `options` using `build` and
+    /// opens it.
+    ///
+    /// The table's directory entry is synced before it is opened, so once this returns the
+    /// table survives a crash and callers may discard the data it replaces (such as the WAL).
+    /// If building or opening fails, the partially written table is removed so that it can
+    /// never be picked up later as if it were valid.
+    fn build_table(
+        &self,
+        file_name: &str,
+        options: &TableOptions,
+        level: u32,
+        build: impl FnOnce(SSTableBuilder) -> io::Result<()>,
+    ) -> io::Result<SSTable> {
+        let (builder, new_table) = self.create_table(file_name)?;
+        let builder = builder
+            .with_options(&options.for_level(level))
+            .with_level(level);
+        let result = build(builder)
+            .and_then(|()| new_table.install())
+            .and_then(|()| self.sync_dir())
+            .and_then(|()| new_table.open())
+            .and_then(|sst| attach_codec(sst, options));
+        if result.is_err() {
+            new_table.discard();
+        }
+        result
+    }
+
+    /// Removes the files of tables that have been replaced by a compaction.
+    ///
+    /// `tables` are removed newest first, stopping at the first file that cannot be removed
+    /// (for example because a reader still has it open on a platform that forbids that).
+    /// Whatever is left behind only holds data that the compaction output already has, and
+    /// the manifest no longer lists it, so the next open removes it.
+    ///
+    /// While a [`TablePin`] is held the files are only queued, and they are removed in the
+    /// same order once the last pin is dropped.
+    fn remove_tables(&self, tables: &[Arc<SSTable>]) -> io::Result<()> {
+        let paths = tables.iter().map(|table| table.path().to_path_buf());
+        match self {
+            TableLocation::Dir(_, caches) => {
+                let pending = {
+                    let mut retired = caches.retired.lock().unwrap();
+                    retired.pending.extend(paths);
+                    if retired.pins > 0 {
+                        return Ok(());
+                    }
+                    std::mem::take(&mut retired.pending)
+                };
+                // Pins taken from here on only see the list without these tables, so the
+                // files are removed without holding up readers that pin the list.
+                self.remove_files(&pending)
+            }
+            TableLocation::Memory => Ok(()),
+            #[cfg(test)]
+            TableLocation::FaultyDir(..) => self.remove_files(&paths.collect::<Vec<_>>()),
+        }
+    }
+
+    /// Removes `paths` in order, stopping at the first that cannot be removed.
+    fn remove_files(&self, paths: &[PathBuf]) -> io::Result<()> {
+        for path in paths {
+            if std::fs::remove_file(path).is_err() {
+                break;
+            }
+        }
+        self.sync_dir()
+    }
+
+    /// Records `tables` (newest first) as the location's tables in its manifest.
+    ///
+    /// Callers hold the table list's write lock, so manifests are written in the order the
+    /// list changes, and install the new list only once this succeeds. Corrupt tables that
+    /// were skipped on open stay listed after the others, so that the next open neither loads
+    /// nor removes them.
+    fn save_manifest(&self, tables: &[Arc<SSTable>]) -> io::Result<()> {
+        let names = |tables: &[Arc<SSTable>]| -> Vec<String> {
+            tables
+                .iter()
+                .filter_map(|table| table.path().file_name()?.to_str().map(String::from))
+                .collect()
+        };
+        match self {
+            TableLocation::Dir(dir, caches) => {
+                let mut listed = names(tables);
+                listed.extend(caches.skipped.iter().cloned());
+                write_manifest(dir, &listed)
+            }
+            TableLocation::Memory => Ok(()),
+            #[cfg(test)]
+            TableLocation::FaultyDir(dir, _) => write_manifest(dir, &names(tables)),
+        }
+    }
+
+    /// Keeps the table files in this location from being removed until the pin is dropped.
+    ///
+    /// Only the tables in the list at the time are protected, so take the pin under the
+    /// table list's lock, before a compaction can swap in a list without them.
+    pub(crate) fn pin(&self) -> TablePin {
+        if let TableLocation::Dir(_, caches) = self {
+            caches.retired.lock().unwrap().pins += 1;
+        }
+        TablePin(self.clone())
+    }
+
+    /// Makes file creations and removals in this location durable.
+    fn sync_dir(&self) -> io::Result<()> {
+        match self {
+            TableLocation::Dir(dir, _) => storage::fsync_dir(dir),
+            TableLocation::Memory => Ok(()),
+            #[cfg(test)]
+            TableLocation::FaultyDir(dir, _) => storage::fsync_dir(dir),
+        }
+    }
+
+    /// Creates a builder for a new SSTable named `file_name`.
+    ///
+    /// Returns the builder along with a handle that installs and opens the table once it has
+    /// been built. A table in a directory is written under a temporary name until then, so an
+    /// interrupted build never leaves a partial file that `Engine::open` would load.
+    fn create_table(&self, file_name: &str) -> io::Result<(SSTableBuilder, NewTable)> {
+        match self {
+            TableLocation::Dir(dir, cache) => {
+                let tmp = dir.join(format!("{}{}", file_name, TMP_TABLE_SUFFIX));
+                let builder = cache.builder(&tmp)?;
+                Ok((
+                    builder,
+                    NewTable::File {
+                        tmp,
+                        path: dir.join(file_name),
+                        caches: cache.clone(),
+                    },
+                ))
+            }
+            TableLocation::Memory => {
+                let store = MemoryStore::new();
+                let builder = SSTableBuilder::with_store(store.clone(), file_name, 16);
+                Ok((builder, NewTable::Memory(store, PathBuf::from(file_name))))
+            }
+            #[cfg(test)]
+            TableLocation::FaultyDir(dir, fail_after) => {
+                let tmp = dir.join(format!("{}{}", file_name, TMP_TABLE_SUFFIX));
+                let file = std::fs::File::create(&tmp)?;
+                let store = crate::storage::FaultyStore::fail_writes_after(file, *fail_after);
+                let builder = SSTableBuilder::with_store(store, &tmp, 16);
+                Ok((
+                    builder,
+                    NewTable::File {
+                        tmp,
+                        path: dir.join(file_name),
+                        caches: TableCaches::default(),
+                    },
+                ))
+            }
+        }
+    }
+}
+
+/// Defers the removal of replaced table files while a read or an iterator may still use
+/// them; see [`TableLocation::pin`].
+pub(crate) struct TablePin(TableLocation);
+
+impl Drop for TablePin {
+    fn drop(&mut self) {
+        if let TableLocation::Dir(_, caches) = &self.0 {
+            let pending = {
+                let mut retired = caches.retired.lock().unwrap();
+                retired.pins -= 1;
+                if retired.pins > 0 {
+                    return;
+                }
+                std::mem::take(&mut retired.pending)
+            };
+            if !pending.is_empty() {
+                // Whatever cannot be removed now is left behind as after a failed removal.
+                let _ = self.0.remove_files(&pending);
+            }
+        }
+    }
+}
+
+/// Table files replaced by compactions that still have to be removed.
+#[derive(Default)]
+struct RetiredTables {
+    /// Number of live [`TablePin`]s.
+    pins: usize,
+    /// Files to remove once no pin is left, in the order to remove them.
+    pending: Vec<PathBuf>,
+}
+
+/// An SSTable that is being built and can be opened once its builder has finished.
+enum NewTable {
+    /// A table file written at `tmp` and moved to `path` once complete.
+    File {
+        tmp: PathBuf,
+        path: PathBuf,
+        caches: TableCaches,
+    },
+    Memory(MemoryStore, PathBuf),
+}
+
+impl NewTable {
+    /// Moves a finished table file to its final name.
+    fn install(&self) -> io::Result<()> {
+        match self {
+            NewTable::File { tmp, path, .. } => std::fs::rename(tmp, path),
+            NewTable::Memory(..) => Ok(()),
+        }
+    }
+
+    fn open(&self) -> io::Result<SSTable> {
+        match self {
+            NewTable::File { path, caches, .. } => open_table(path, caches),
+            NewTable::Memory(store, path) => SSTable::from_store(Arc::new(store.clone()), path),
+        }
+    }
+
+    /// Removes whatever was written for this table.
+    fn discard(self) {
+        if let NewTable::File { tmp, path, .. } = self {
+            let _ = std::fs::remove_file(tmp);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Applies a logged `entry` to `mt`, at its version if it has one.
+fn apply_entry(mt: &mut MemTable, entry: WalEntry) {
+    match entry {
+        WalEntry::Put {
+            key,
+            value,
+            meta,
+            version: Some(version),
+        } => mt.put_with_version(key, value, meta, version),
+        WalEntry::Put {
+            key, value, meta, ..
+        } => mt.put_with_meta(key, value, meta),
+        WalEntry::Delete {
+            key,
+            version: Some(version),
+        } => mt.delete_with_version(key, version),
+        WalEntry::Delete { key, .. } => mt.delete(key),
+    }
+}
+
+/// Records `wal_dir` as the WAL directory of the store in `dir`.
+///
+/// Fails with `InvalidInput` if the WAL was last kept somewhere else and a non-empty WAL is
+/// still there, since opening without it would lose the writes it holds.
+fn record_wal_dir(dir: &Path, wal_dir: &Path) -> io::Result<()> {
+    let wal_dir = wal_dir.canonicalize()?;
+    let record = dir.join(WAL_LOCATION_FILE);
+    let previous = match std::fs::read_to_string(&record) {
+        Ok(previous) => PathBuf::from(previous),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => dir.canonicalize()?,
+        Err(e) => return Err(e),
+    };
+    if previous == wal_dir {
+        return Ok(());
+    }
+
+    let previous_wal = previous.join(WAL_FILE);
+    if std::fs::metadata(&previous_wal).is_ok_and(|m| m.len() > 0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the WAL of {} is {}, not in {}; move it there or open with its directory",
+                dir.display(),
+                previous_wal.display(),
+                wal_dir.display()
+            ),
+        ));
+    }
+
+    let mut tmp = record.clone().into_os_string();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, wal_dir.to_string_lossy().as_bytes())?;
+    std::fs::File::open(&tmp)?.sync_all()?;
+    std::fs::rename(&tmp, &record)?;
+    storage::fsync_dir(dir)
+}
+
+/// The caches that SSTable files in a [`TableLocation::Dir`] are read through, the key they
+/// are encrypted with, and the replaced files waiting to be removed.
+#[derive(Clone, Default)]
+pub(crate) struct TableCaches {
+    /// Bounds the number of open descriptors.
+    files: Option<Arc<FileCache>>,
+    /// Caches blocks of table data.
+    blocks: Option<Arc<CacheManager>>,
+    /// Leaves each table's sparse index unread until it is needed.
+    lazy_index: bool,
+    /// Alignment to read table files in with direct I/O, or `None` to read them buffered.
+    #[cfg(feature = "direct-io")]
+    direct_io: Option<u64>,
+    /// Replaced files that iterators may still read; shared by every clone of the location.
+    retired: Arc<Mutex<RetiredTables>>,
+    /// Names of corrupt tables skipped on open, which the manifest keeps listing.
+    skipped: Arc<Vec<String>>,
+    /// Encrypts and decrypts the table files.
+    #[cfg(feature = "encryption")]
+    encryption: Option<Encryption>,
+}
+
+impl TableCaches {
+    /// Creates a builder for a table file at `path`, encrypted if the tables are.
+    fn builder(&self, path: &Path) -> io::Result<SSTableBuilder> {
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = &self.encryption {
+            let file = std::fs::File::create(path)?;
+            return Ok(SSTableBuilder::with_store(
+                EncryptingStore::new(file, encryption)?,
+                path,
+                16,
+            ));
+        }
+        SSTableBuilder::new(path, 16)
+    }
+}
+
+/// Opens the SSTable file at `path` through whichever of `caches` are set.
+fn open_table(path: &Path, caches: &TableCaches) -> io::Result<SSTable> {
+    let store = open_table_file(path, caches)?;
+    // Decrypting below the block cache keeps the cached blocks ready to use.
+    #[cfg(feature = "encryption")]
+    let store: Arc<dyn ReadableStore> = match &caches.encryption {
+        Some(encryption) => Arc::new(EncryptedStore::new(store, encryption)?),
+        None if encryption::store_is_sealed(&*store)? => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is encrypted; open the engine with its key",
+                    path.display()
+                ),
+            ));
+        }
+        None => store,
+    };
+    let sst = if caches.lazy_index {
+        SSTable::from_store_lazy(store, path)?
+    } else {
+        SSTable::from_store(store, path)?
+    };
+    match &caches.blocks {
+        Some(cache) => sst.with_block_cache(cache),
+        None => Ok(sst),
+    }
+}
+
+/// Opens the file of the table at `path` for reading, through the file cache if there is one.
+fn open_table_file(path: &Path, caches: &TableCaches) -> io::Result<Arc<dyn ReadableStore>> {
+    #[cfg(feature = "direct-io")]
+    if let Some(alignment) = caches.direct_io {
+        return Ok(Arc::new(storage::DirectFile::open(path, alignment)?));
+    }
+    Ok(match &caches.files {
+        Some(cache) => Arc::new(CachedFile::open(path, cache)?),
+        None => Arc::new(std::fs::File::open(path)?),
+    })
+}
+
+/// Gives `sst` the value codec of `options`.
+///
+/// Fails with `InvalidInput` if the table was written with a codec and `options` has none or
+/// a different one, since its values could not be read.
+fn attach_codec(sst: SSTable, options: &TableOptions) -> io::Result<SSTable> {
+    match (&options.value_codec, sst.value_codec_name()) {
+        (Some(codec), _) => sst.with_value_codec(Arc::clone(codec)),
+        (None, Some(name)) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} was written with value codec {:?}; open the engine with it",
+                sst.path().display(),
+                name
+            ),
+        )),
+        (None, None) => Ok(sst),
+    }
+}
+
+/// Returns the file name of the newest input of the compaction that wrote `output`, if
+/// `output` was named by [`compaction_output_name`] after another table.
+fn compaction_input_name(output: &str) -> Option<String> {
+    let (id, generation) = output.strip_suffix(".compact.sst")?.split_once('.')?;
+    match generation.parse::<u32>().ok()? {
+        0 => None,
+        1 => Some(format!("{}.sst", id)),
+        generation => Some(format!("{}.{:06}.compact.sst", id, generation - 1)),
+    }
+}
+
+/// Reads the table names listed in the manifest in `dir`, newest first, or `None` if there
+/// is no manifest.
+fn read_manifest(dir: &Path) -> io::Result<Option<Vec<String>>> {
+    let contents = match std::fs::read_to_string(dir.join(MANIFEST_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut lines = contents.lines();
+    if lines.next() != Some(MANIFEST_HEADER) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} does not start with {:?}",
+                MANIFEST_FILE, MANIFEST_HEADER
+            ),
+        ));
+    }
+    let names: Vec<String> = lines.map(String::from).collect();
+    if let Some(name) = names
+        .iter()
+        .find(|name| name.is_empty() || name.contains(['/', '\\']))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} lists an invalid table name {:?}", MANIFEST_FILE, name),
+        ));
+    }
+    Ok(Some(names))
+}
+
+/// Replaces the manifest in `dir` with one listing `names`, newest first.
+///
+/// The new manifest is written and synced under a temporary name and renamed over the old
+/// one, so a crash leaves one or the other in place, never a mix.
+fn write_manifest(dir: &Path, names: &[String]) -> io::Result<()> {
+    let mut contents = format!("{}\n", MANIFEST_HEADER);
+    for name in names {
+        contents.push_str(name);
+        contents.push('\n');
+    }
+    let tmp_path = dir.join(format!("{}{}", MANIFEST_FILE, TMP_TABLE_SUFFIX));
+    let mut file = std::fs::File::create(&tmp_path)?;
+    io::Write::write_all(&mut file, contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, dir.join(MANIFEST_FILE))?;
+    storage::fsync_dir(dir)
+}
+
+/// Removes the files that an interrupted flush or compaction may have left in `dir`, and
+/// returns how many there were.
+///
+/// These are tables still under their temporary name, and, given the names `listed` by the
+/// manifest, every table it does not list: a new table is only listed once it is complete,
+/// and a replaced one is unlisted before its file is removed. Without a manifest, from a
+/// store written before there was one, they are instead the compaction outputs whose newest
+/// input is still present. Inputs were removed only once their output was in place, newest
+/// first, so such an output merely duplicates inputs that all survive.
+fn remove_leftover_tables(dir: &Path, listed: Option<&[String]>) -> io::Result<usize> {
+    let names: HashSet<String> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    let leftovers: Vec<&String> = names
+        .iter()
+        .filter(|name| {
+            name.strip_suffix(TMP_TABLE_SUFFIX)
+                .is_some_and(|table| table.ends_with(".sst"))
+                || match listed {
+                    Some(listed) => name.ends_with(".sst") && !listed.iter().any(|l| l == *name),
+                    None => compaction_input_name(name).is_some_and(|input| names.contains(&input)),
+                }
+        })
+        .collect();
+    for name in &leftovers {
+        std::fs::remove_file(dir.join(name))?;
+        eprintln!(
+            "Removed {} left by an interrupted flush or compaction",
+            name
+        );
+    }
+    if !leftovers.is_empty() {
+        storage::fsync_dir(dir)?;
+    }
+    Ok(leftovers.len())
+}
+
+/// Returns a unique, time-ordered file name for a new SSTable with the given extension.
+///
+/// wasm32 has no clock, so there the names count up from zero instead; only in-memory
+/// tables are made there, and they do not outlive the process.
+fn new_table_name(extension: &str) -> String {
+    #[cfg(not(target_arch = "wasm32"))]
+    let sstable_id = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    #[cfg(target_arch = "wasm32")]
+    let sstable_id = {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    };
+    format!("{:020}.{}", sstable_id, extension)
+}
+
+/// Returns the current time, or `None` on wasm32, where reading the clock panics.
+fn now() -> Option<Instant> {
+    #[cfg(target_arch = "wasm32")]
+    return None;
+    #[cfg(not(target_arch = "wasm32"))]
+    Some(Instant::now())
+}
+
+/// Returns the file name for the output of a compaction whose newest input is `newest`.
+///
+/// The manifest records the order of tables, but names still sort the way tables are ordered,
+/// so that a directory listing reads newest last. The output must therefore sort exactly where
+/// its newest input did: after every newer table, which may have been left out of the
+/// compaction. It keeps the input's time-ordered ID and adds a generation that grows with each
+/// compaction of the same ID, e.g. `…0042.sst` becomes `…0042.000001.compact.sst`.
+fn compaction_output_name(newest: &SSTable) -> String {
+    let name = newest
+        .path()
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let mut parts = name.split('.');
+    let id = parts
+        .next()
+        .filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()));
+    let generation = match (parts.next(), parts.next()) {
+        (Some(generation), Some("compact")) => generation.parse::<u32>().ok(),
+        (Some("sst"), None) => Some(0),
+        _ => None,
+    };
+
+    match (id, generation) {
+        (Some(id), Some(generation)) => format!("{}.{:06}.compact.sst", id, generation + 1),
+        _ => new_table_name("compact.sst"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bloom::BloomFilter;
+    use crate::sstable::ValueCodec;
+    use crate::version::Version;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::atomic::AtomicBool;
+
+    fn setup_test_dir(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "lsm_test_{}_{}",
+            name,
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Opens an engine whose MemTable flushes after a few bytes, opting out of the warning.
+    fn open_tiny(dir: &Path, max_memtable_size: usize) -> Engine {
+        let options = EngineOptions {
+            max_memtable_size,
+            allow_tiny_memtable: true,
+            ..EngineOptions::default()
+        };
+        Engine::open_with_options(dir, options).unwrap()
+    }
+
+    #[test]
+    fn test_put_get_basic() {
+        let dir = setup_test_dir("engine_basic");
+        let engine = Engine::open(&dir, 1024).unwrap();
+        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_get_nonexistent() {
+        let dir = setup_test_dir("engine_nonexistent");
+        let engine = Engine::open(&dir, 1024).unwrap();
+        assert_eq!(engine.get(b"k1").unwrap(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_update() {
+        let dir = setup_test_dir("engine_update");
+        let engine = Engine::open(&dir, 1024).unwrap();
+        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        engine.put(b"k1".to_vec(), b"v2".to_vec()).unwrap();
+        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v2".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_delete() {
+        let dir = setup_test_dir("engine_delete");
+        let engine = Engine::open(&dir, 1024).unwrap();
+        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        engine.delete(b"k1".to_vec()).unwrap();
+        assert_eq!(engine.get(b"k1").unwrap(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_delete_nonexistent() {
+        let dir = setup_test_dir("engine_del_nonexistent");
+        let engine = Engine::open(&dir, 1024).unwrap();
+        engine.delete(b"k1".to_vec()).unwrap();
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_flush_trigger() {
+        let dir = setup_test_dir("engine_flush");
+        // Each of these puts accounts for 24 bytes.
+        let engine = open_tiny(&dir, 40);
+        engine.put(b"key1".to_vec(), b"val1".to_vec()).unwrap();
+
+        let sstable_count = || {
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().extension().and_then(|ext| ext.to_str()) == Some("sst"))
+                .count()
+        };
+
+        assert_eq!(sstable_count(), 0);
+        engine.put(b"key2".to_vec(), b"val2".to_vec()).unwrap();
+        assert!(sstable_count() >= 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_flush_and_compact_return_metadata() {
+        let dir = setup_test_dir("engine_flush_metadata");
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.flush().unwrap(), None);
+
+        engine.put(b"b".to_vec(), b"1".to_vec()).unwrap();
+        engine.put(b"d".to_vec(), b"1".to_vec()).unwrap();
+        let first = engine.flush().unwrap().unwrap();
+        assert_eq!(first.first_key, b"b");
+        assert_eq!(first.last_key, b"d");
+        assert!(first.path.exists());
+
+        engine.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+        let second = engine.flush().unwrap().unwrap();
+        assert_eq!(sstable_files(&dir).len(), 2);
+
+        let compacted = engine.compact().unwrap();
+        assert_eq!(compacted.len(), 1);
+        assert_eq!(compacted[0].first_key, b"a");
+        assert_eq!(compacted[0].last_key, b"d");
+        assert_eq!(sstable_files(&dir), vec![compacted[0].path.clone()]);
+        assert!(!first.path.exists() && !second.path.exists());
+
+        assert!(engine.compact().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_get_after_flush() {
+        let dir = setup_test_dir("engine_get_flush");
+        let engine = open_tiny(&dir, 10);
+        engine.put(b"key1".to_vec(), b"val1".to_vec()).unwrap();
+        engine.put(b"key2".to_vec(), b"val2".to_vec()).unwrap();
+        assert_eq!(engine.get(b"key1").unwrap(), Some(b"val1".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_recovery_from_wal() {
+        let dir = setup_test_dir("engine_recovery_wal");
+        {
+            let engine = Engine::open(&dir, 1024).unwrap();
+            engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        }
+
+        let engine = Engine::open(&dir, 1024).unwrap();
+        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_versions_survive_wal_replay() {
+        let dir = setup_test_dir("engine_versions");
+        let options = || EngineOptions {
+            node_id: Some(7),
+            ..EngineOptions::default()
+        };
+        let seqnos = |engine: &Engine| -> Vec<u64> {
+            let mt = engine.active_memtable.read().unwrap();
+            mt.iter_with_seqno().map(|(_, _, seqno)| seqno).collect()
+        };
+        let stamped = {
+            let engine = Engine::open_with_options(&dir, options()).unwrap();
+            engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+            engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+            engine.delete(b"a".to_vec()).unwrap();
+            seqnos(&engine)
+        };
+        let versions: Vec<Version> = stamped.iter().map(|&s| Version::from_seqno(s)).collect();
+        assert!(versions.iter().all(|v| v.node_id == 7 && v.timestamp > 0));
+        assert!(versions[1] < versions[0]);
+
+        let engine = Engine::open_with_options(&dir, options()).unwrap();
+        assert_eq!(seqnos(&engine), stamped);
+        engine.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        assert!(seqnos(&engine)[2] > stamped[0]);
+        assert_eq!(engine.get(b"a").unwrap(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_recovery_with_flush() {
+        let dir = setup_test_dir("engine_recovery_flush");
+        {
+            let engine = open_tiny(&dir, 10);
+            engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+            engine.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+        }
+
+        let engine = open_tiny(&dir, 10);
+        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(engine.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_multiple_sstables_read() {
+        let dir = setup_test_dir("engine_multiple");
+        let engine = open_tiny(&dir, 10);
+
+        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        engine.put(b"k1_f".to_vec(), b"v".to_vec()).unwrap();
+
+        engine.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+        engine.put(b"k2_f".to_vec(), b"v".to_vec()).unwrap();
+
+        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(engine.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_tombstone_across_sstables() {
+        let dir = setup_test_dir("engine_tombstone");
+        let engine = open_tiny(&dir, 10);
+
+        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        engine.put(b"f1".to_vec(), b"v".to_vec()).unwrap();
+
+        engine.delete(b"k1".to_vec()).unwrap();
+        engine.put(b"f2".to_vec(), b"v".to_vec()).unwrap();
+
+        assert_eq!(engine.get(b"k1").unwrap(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_in_memory_basic() {
+        let engine = Engine::open_in_memory(1024);
+        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        engine.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+        engine.delete(b"k2".to_vec()).unwrap();
+        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(engine.get(b"k2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_in_memory_flush_and_compact() {
+        let engine = Engine::open_in_memory(10);
+        for i in 0..20 {
+            engine
+                .put(format!("k{:02}", i).into_bytes(), vec![i as u8; 8])
+                .unwrap();
+        }
+        engine.delete(b"k05".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.compact().unwrap();
+
+        for i in 0..20 {
+            let expected = if i == 5 { None } else { Some(vec![i as u8; 8]) };
+            assert_eq!(
+                engine.get(format!("k{:02}", i).as_bytes()).unwrap(),
+                expected
+            );
+        }
+    }
+
+    fn sstable_files(dir: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().and_then(|ext| ext.to_str()) == Some("sst"))
+            .collect()
+    }
+
+    #[test]
+    fn test_fsync_writes_from_many_threads_survive_reopen() {
+        let dir = setup_test_dir("engine_group_commit");
+        let options = EngineOptions {
+            wal_sync: WalSyncMode::Fsync,
+            ..EngineOptions::default()
+        };
+        {
+            let engine = Arc::new(Engine::open_with_options(&dir, options.clone()).unwrap());
+            let writers: Vec<_> = (0..4u8)
+                .map(|t| {
+                    let engine = Arc::clone(&engine);
+                    std::thread::spawn(move || {
+                        for i in 0..25u8 {
+                            engine.put(vec![t, i], vec![i]).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+        }
+
+        let engine = Engine::open_with_options(&dir, options).unwrap();
+        for t in 0..4u8 {
+            for i in 0..25u8 {
+                assert_eq!(engine.get(&[t, i]).unwrap(), Some(vec![i]));
+            }
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_flush_fault_keeps_memtable_and_wal() {
+        let dir = setup_test_dir("engine_flush_fault");
+        {
+            let engine = Engine {
+                location: TableLocation::FaultyDir(dir.clone(), 20),
+                ..Engine::open(&dir, 1 << 20).unwrap()
+            };
+            engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+            engine.put(b"k2".to_vec(), vec![7; 64]).unwrap();
+
+            assert!(engine.flush().is_err());
+            assert!(sstable_files(&dir).is_empty());
+            assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+            assert_eq!(engine.get(b"k2").unwrap(), Some(vec![7; 64]));
+        }
+
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(engine.get(b"k2").unwrap(), Some(vec![7; 64]));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_compaction_fault_keeps_inputs() {
+        let dir = setup_test_dir("engine_compact_fault");
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+        engine.put(b"b".to_vec(), vec![3; 64]).unwrap();
+        engine.flush().unwrap();
+        let before = sstable_files(&dir);
+
+        let engine = Engine {
+            location: TableLocation::FaultyDir(dir.clone(), 30),
+            ..engine
+        };
+        assert!(engine.compact().is_err());
+        let mut after = sstable_files(&dir);
+        after.sort();
+        let mut expected = before;
+        expected.sort();
+        assert_eq!(after, expected);
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(engine.get(b"b").unwrap(), Some(vec![3; 64]));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_separate_wal_dir() {
+        let root = setup_test_dir("engine_wal_dir");
+        let (dir, fast, moved) = (root.join("data"), root.join("fast"), root.join("moved"));
+        let with_wal_dir = |wal_dir: &Path| EngineOptions {
+            wal_dir: Some(wal_dir.to_path_buf()),
+            ..EngineOptions::default()
+        };
+        {
+            let engine = Engine::open_with_options(&dir, with_wal_dir(&fast)).unwrap();
+            engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+            engine.flush().unwrap();
+            engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        }
+        assert!(fast.join(WAL_FILE).exists());
+        assert!(!dir.join(WAL_FILE).exists());
+
+        // Opening without the WAL would silently drop `b`.
+        let err = Engine::open(&dir, 1 << 20).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = Engine::open_with_options(&dir, with_wal_dir(&moved))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        std::fs::rename(fast.join(WAL_FILE), moved.join(WAL_FILE)).unwrap();
+        let (engine, report) = Engine::open_with_report(&dir, with_wal_dir(&moved)).unwrap();
+        assert_eq!(report.wal_entries_replayed, 1);
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        // Once the WAL is flushed away, the store can go back to a WAL next to its tables.
+        engine.flush().unwrap();
+        drop(engine);
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_wal_append_fault_does_not_apply_write() {
+        let dir = setup_test_dir("engine_wal_fault");
+        let wal_path = dir.join("active.wal");
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&wal_path)
+            .unwrap();
+        // Enough for the first record (1 + 4 + 2 + 4 + 2 bytes) but not the second.
+        let store = crate::storage::FaultyStore::fail_writes_after(file, 20);
+        let engine = Engine {
+            wal: Some(RwLock::new(Wal::with_store(store, wal_path.clone()))),
+            ..engine
+        };
+
+        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        assert!(engine.put(b"k2".to_vec(), b"v2".to_vec()).is_err());
+        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(engine.get(b"k2").unwrap(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// A file that counts how often it is synced.
+    struct SyncCountingFile {
+        file: std::fs::File,
+        syncs: Arc<AtomicU64>,
+    }
+
+    impl crate::storage::WritableStore for SyncCountingFile {
+        fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+            std::io::Write::write_all(&mut self.file, buf)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+            self.file.sync_all()
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.file.set_len(len)
+        }
+    }
+
+    #[test]
+    fn test_write_batch_fsyncs_once() {
+        let dir = setup_test_dir("engine_write_batch");
+        let wal_path = dir.join("active.wal");
+        let syncs = Arc::new(AtomicU64::new(0));
+        {
+            let options = EngineOptions {
+                max_memtable_size: 64 << 20,
+                wal_sync: WalSyncMode::Fsync,
+                ..EngineOptions::default()
+            };
+            let engine = Engine::open_with_options(&dir, options).unwrap();
+            let file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&wal_path)
+                .unwrap();
+            let store = SyncCountingFile {
+                file,
+                syncs: Arc::clone(&syncs),
+            };
+            let engine = Engine {
+                wal: Some(RwLock::new(Wal::with_store(store, wal_path.clone()))),
+                ..engine
+            };
+            let mut batch = WriteBatch::new();
+            for i in 0..10_000u32 {
+                match i % 10 {
+                    9 => batch.delete((i - 1).to_be_bytes().to_vec()),
+                    _ => batch.put(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()),
+                };
+            }
+            assert_eq!(batch.len(), 10_000);
+            engine.write_batch(batch).unwrap();
+            assert_eq!(syncs.load(Ordering::SeqCst), 1);
+            assert_eq!(engine.get(&8u32.to_be_bytes()).unwrap(), None);
+            engine.write_batch(WriteBatch::new()).unwrap();
+            engine.put(b"one".to_vec(), b"more".to_vec()).unwrap();
+            assert_eq!(syncs.load(Ordering::SeqCst), 2);
+        }
+
+        let engine = Engine::open(&dir, 64 << 20).unwrap();
+        assert_eq!(
+            engine.get(&6u32.to_be_bytes()).unwrap(),
+            Some(6u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(engine.get(&8u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(engine.iter().unwrap().count(), 8_000 + 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// A file that keeps written bytes in memory until they are synced, so that dropping it
+    /// loses them like a power cut would.
+    struct PowerLossFile {
+        file: std::fs::File,
+        unsynced: Vec<u8>,
+    }
+
+    impl crate::storage::WritableStore for PowerLossFile {
+        fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.unsynced.extend_from_slice(buf);
+            Ok(())
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            std::io::Write::write_all(&mut self.file, &self.unsynced)?;
+            self.unsynced.clear();
+            self.file.sync_all()
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            let synced = self.file.metadata()?.len();
+            if len < synced {
+                self.unsynced.clear();
+                return self.file.set_len(len);
+            }
+            self.unsynced.truncate((len - synced) as usize);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_put_durable_survives_power_loss_without_fsync_mode() {
+        let dir = setup_test_dir("engine_put_durable");
+        let wal_path = dir.join("active.wal");
+        {
+            let engine = Engine::open(&dir, 1 << 20).unwrap();
+            let file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&wal_path)
+                .unwrap();
+            let store = PowerLossFile {
+                file,
+                unsynced: Vec::new(),
+            };
+            let engine = Engine {
+                wal: Some(RwLock::new(Wal::with_store(store, wal_path.clone()))),
+                ..engine
+            };
+            engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+            engine.put_durable(b"b".to_vec(), b"2".to_vec()).unwrap();
+            engine.delete_durable(b"a".to_vec()).unwrap();
+            engine.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+            engine.put_relaxed(b"d".to_vec(), b"4".to_vec()).unwrap();
+            assert_eq!(engine.get(b"d").unwrap(), Some(b"4".to_vec()));
+        }
+
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.get(b"a").unwrap(), None);
+        assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(engine.get(b"c").unwrap(), None);
+        assert_eq!(engine.get(b"d").unwrap(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_read_retry_recovers_from_transient_errors() {
+        let mut mt = MemTable::new(1 << 20);
+        for i in 0..20u8 {
+            mt.put(vec![i], vec![i; 8]);
+        }
+        let data = MemoryStore::new();
+        SSTableBuilder::with_store(data.clone(), "flaky.sst", 4)
+            .build(&mt)
+            .unwrap();
+        let store = crate::storage::FaultyStore::fail_reads_after(data.to_vec(), u64::MAX);
+        let table = SSTable::from_store(Arc::new(store.clone()), "flaky.sst").unwrap();
+        let engine = Engine::open_in_memory(1 << 20);
+        engine.sstables.write().unwrap().push(Arc::new(table));
+
+        store.time_out_reads(1);
+        let err = engine.get(&[3]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let engine = Engine {
+            read_retry: Some(ReadRetryPolicy {
+                max_attempts: 3,
+                backoff: Duration::from_millis(1),
+            }),
+            ..engine
+        };
+        store.time_out_reads(2);
+        assert_eq!(engine.get(&[3]).unwrap(), Some(vec![3; 8]));
+        store.time_out_reads(3);
+        let err = engine.get(&[4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(engine.get(&[4]).unwrap(), Some(vec![4; 8]));
+
+        // Damage is not transient and is reported on the first attempt.
+        let policy = engine.read_retry.unwrap();
+        let mut attempts = 0;
+        let err = policy
+            .run(|| -> io::Result<()> {
+                attempts += 1;
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "checksum mismatch",
+                ))
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(attempts, 1);
+    }
+
+    /// A store whose reads block while it is armed, to hold a lookup in the middle of disk I/O.
+    struct GatedStore {
+        data: Vec<u8>,
+        armed: std::sync::atomic::AtomicBool,
+        gate: Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>,
+        entered: std::sync::Mutex<std::sync::mpsc::Sender<()>>,
+    }
+
+    impl crate::storage::ReadableStore for GatedStore {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            if self.armed.load(Ordering::SeqCst) {
+                let _ = self.entered.lock().unwrap().send(());
+                let (open, cvar) = &*self.gate;
+                let mut open = open.lock().unwrap();
+                while !*open {
+                    open = cvar.wait(open).unwrap();
+                }
+            }
+            self.data.read_at(buf, offset)
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            Ok(self.data.len() as u64)
+        }
+    }
+
+    #[test]
+    fn test_reader_does_not_block_flush() {
+        let store = MemoryStore::new();
+        let mut mt = MemTable::new(1024);
+        mt.put(b"slow".to_vec(), b"value".to_vec());
+        SSTableBuilder::with_store(store.clone(), "gated.sst", 1)
+            .build(&mt)
+            .unwrap();
+
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let gate = Arc::new((std::sync::Mutex::new(false), std::sync::Condvar::new()));
+        let gated = Arc::new(GatedStore {
+            data: store.to_vec(),
+            armed: AtomicBool::new(false),
+            gate: Arc::clone(&gate),
+            entered: std::sync::Mutex::new(entered_tx),
+        });
+        let sst = SSTable::from_store(Arc::clone(&gated) as _, "gated.sst").unwrap();
+        gated.armed.store(true, Ordering::SeqCst);
+
+        let engine = Arc::new(Engine::open_in_memory(1 << 20));
+        engine.sstables.write().unwrap().push(Arc::new(sst));
+
+        let reader = {
+            let engine = Arc::clone(&engine);
+            std::thread::spawn(move || engine.get(b"slow").unwrap())
+        };
+        entered_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+
+        // The reader is now parked inside an SSTable read; a flush must still go through.
+        let (flushed_tx, flushed_rx) = std::sync::mpsc::channel();
+        {
+            let engine = Arc::clone(&engine);
+            std::thread::spawn(move || {
+                engine.put(b"k".to_vec(), b"v".to_vec()).unwrap();
+                engine.flush().unwrap();
+                flushed_tx.send(()).unwrap();
+            });
+        }
+        let flushed = flushed_rx.recv_timeout(std::time::Duration::from_secs(5));
+
+        let (open, cvar) = &*gate;
+        *open.lock().unwrap() = true;
+        cvar.notify_all();
+        assert!(flushed.is_ok(), "flush was blocked by an in-flight read");
+        assert_eq!(reader.join().unwrap(), Some(b"value".to_vec()));
+        assert_eq!(engine.sstables.read().unwrap().len(), 2);
+    }
+
+    /// An in-memory engine with background compaction disabled and one table per `flushes` entry.
+    fn engine_with_tables(flushes: &[&[(&str, &str)]]) -> Engine {
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_in_memory(1 << 20)
+        };
+        for table in flushes {
+            for (k, v) in table.iter() {
+                engine
+                    .put(k.as_bytes().to_vec(), v.as_bytes().to_vec())
+                    .unwrap();
+            }
+            engine.flush().unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn test_concurrent_gets_share_one_lookup() {
+        const READERS: usize = 16;
+        let store = MemoryStore::new();
+        let mut mt = MemTable::new(1024);
+        mt.put(b"present".to_vec(), b"value".to_vec());
+        SSTableBuilder::with_store(store.clone(), "gated.sst", 1)
+            .build(&mt)
+            .unwrap();
+
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let gate = Arc::new((std::sync::Mutex::new(false), std::sync::Condvar::new()));
+        let gated = Arc::new(GatedStore {
+            data: store.to_vec(),
+            armed: AtomicBool::new(false),
+            gate: Arc::clone(&gate),
+            entered: std::sync::Mutex::new(entered_tx),
+        });
+        let sst = SSTable::from_store(Arc::clone(&gated) as _, "gated.sst").unwrap();
+        gated.armed.store(true, Ordering::SeqCst);
+
+        let mut engine = Engine::open_in_memory(1 << 20);
+        engine.inflight_reads = Some(InflightReads::default());
+        engine.sstables.write().unwrap().push(Arc::new(sst));
+        let engine = Arc::new(engine);
+
+        let spawn_reader = || {
+            let engine = Arc::clone(&engine);
+            std::thread::spawn(move || engine.get(b"present").unwrap())
+        };
+        let mut readers = vec![spawn_reader()];
+        entered_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        // The first reader is parked inside the SSTable read; the rest wait for its result.
+        readers.extend((1..READERS).map(|_| spawn_reader()));
+        let inflight = engine.inflight_reads.as_ref().unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while inflight.followers(b"present") < READERS - 1 {
+            assert!(std::time::Instant::now() < deadline, "readers did not join");
+            std::thread::yield_now();
+        }
+
+        let (open, cvar) = &*gate;
+        *open.lock().unwrap() = true;
+        cvar.notify_all();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), Some(b"value".to_vec()));
+        }
+        // One lookup served every reader: it is the only one that read from the store.
+        let reads = 1 + entered_rx.try_iter().count();
+        assert!(
+            reads < READERS / 4,
+            "{} reads for {} readers",
+            reads,
+            READERS
+        );
+        assert_eq!(engine.stats().bloom_true_positives, 1);
+
+        // Once it has finished, a later read looks the key up again.
+        assert_eq!(engine.get(b"present").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(engine.stats().bloom_true_positives, 2);
+    }
+
+    #[test]
+    fn test_compact_range_only_touches_overlapping_tables() {
+        let engine = engine_with_tables(&[
+            &[("a1", "x"), ("a2", "x")],
+            &[("m1", "old"), ("m2", "old")],
+            &[("m2", "new"), ("m3", "new")],
+            &[("z1", "x"), ("z2", "x")],
+        ]);
+        let before = engine.sstables.read().unwrap().clone();
+
+        engine.compact_range(Some(b"m"), Some(b"n")).unwrap();
+
+        let after = engine.sstables.read().unwrap().clone();
+        assert_eq!(after.len(), 3);
+        assert!(Arc::ptr_eq(&after[0], &before[0]));
+        assert!(Arc::ptr_eq(&after[2], &before[3]));
+        assert_eq!(after[1].first_key(), Some(&b"m1"[..]));
+        assert_eq!(after[1].last_key(), Some(&b"m3"[..]));
+        assert_eq!(engine.get(b"m2").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(engine.get(b"a1").unwrap(), Some(b"x".to_vec()));
+        assert_eq!(engine.get(b"z2").unwrap(), Some(b"x".to_vec()));
+
+        engine.compact_range(None, None).unwrap();
+        assert_eq!(engine.sstables.read().unwrap().len(), 1);
+        assert_eq!(engine.get(b"m2").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_compact_range_pulls_in_overlapping_tables_in_between() {
+        let engine = engine_with_tables(&[&[("a", "1"), ("d", "1")], &[("c", "2")], &[("a", "3")]]);
+
+        // Only the oldest and newest tables hold "a", but the middle one holds a newer "c"
+        // than the oldest, so it has to be merged too.
+        engine.compact_range(Some(b"a"), Some(b"a")).unwrap();
+        assert_eq!(engine.sstables.read().unwrap().len(), 1);
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(engine.get(b"c").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(engine.get(b"d").unwrap(), Some(b"1".to_vec()));
+
+        // A range that selects at most one table is a no-op.
+        engine.compact_range(Some(b"x"), None).unwrap();
+        assert_eq!(engine.sstables.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_compaction_output_keeps_recency_order_on_reopen() {
+        let dir = setup_test_dir("engine_compact_order");
+        {
+            let engine = Engine {
+                compaction: None,
+                ..Engine::open(&dir, 1 << 20).unwrap()
+            };
+            for version in 0..5 {
+                engine
+                    .put(b"k".to_vec(), format!("v{}", version).into_bytes())
+                    .unwrap();
+                engine.flush().unwrap();
+            }
+
+            // Merge everything but the newest table, as a background compaction may.
+            for _ in 0..2 {
+                compact_tables(
+                    &engine.sstables,
+                    &engine.location,
+                    &engine.table_options,
+                    &engine.compaction_lock,
+                    &engine.snapshots,
+                    &engine.metrics,
+                    |ssts| Ok(ssts[1..].to_vec()),
+                )
+                .unwrap();
+            }
+            assert_eq!(engine.get(b"k").unwrap(), Some(b"v4".to_vec()));
+        }
+
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"v4".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_open_removes_leftovers_of_interrupted_compaction() {
+        let dir = setup_test_dir("engine_compact_leftovers");
+        let newest = {
+            let engine = Engine {
+                compaction: None,
+                ..Engine::open(&dir, 1 << 20).unwrap()
+            };
+            engine.put(b"k".to_vec(), b"old".to_vec()).unwrap();
+            engine.put(b"gone".to_vec(), b"x".to_vec()).unwrap();
+            engine.flush().unwrap();
+            engine.put(b"k".to_vec(), b"new".to_vec()).unwrap();
+            engine.delete(b"gone".to_vec()).unwrap();
+            engine.flush().unwrap();
+            Arc::clone(&engine.sstables.read().unwrap()[0])
+        };
+
+        // A compaction that wrote its output but crashed before removing its inputs, with
+        // stale data in the output that would resurrect the deleted key.
+        let orphan = dir.join(compaction_output_name(&newest));
+        let mut builder = SSTableBuilder::new(&orphan, 16).unwrap();
+        builder
+            .add_record(b"gone", 1, &Entry::Value(b"x".to_vec()))
+            .unwrap();
+        builder
+            .add_record(b"k", 1, &Entry::Value(b"stale".to_vec()))
+            .unwrap();
+        builder.finish().unwrap();
+        // And one torn while being written.
+        let torn = dir.join(format!("{}{}", new_table_name("sst"), TMP_TABLE_SUFFIX));
+        std::fs::write(&torn, b"partial").unwrap();
+
+        let (engine, report) = Engine::open_with_report(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(report.leftovers_removed, 2);
+        assert_eq!(report.sstables_loaded, 2);
+        assert!(!orphan.exists());
+        assert!(!torn.exists());
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(engine.get(b"gone").unwrap(), None);
+
+        // A compaction that completed is kept.
+        engine.compact().unwrap();
+        drop(engine);
+        let (engine, report) = Engine::open_with_report(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(report.leftovers_removed, 0);
+        assert_eq!(report.sstables_loaded, 1);
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"new".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_tombstone_in_newer_sstable_shadows_older_value() {
+        let engine = engine_with_tables(&[&[("k", "v")]]);
+        engine.delete(b"k".to_vec()).unwrap();
+        engine.flush().unwrap();
+        assert_eq!(engine.get(b"k").unwrap(), None);
+    }
+
+    #[test]
+    fn test_snapshot_survives_delete_and_compaction() {
+        let engine = engine_with_tables(&[&[("k", "before"), ("other", "x")]]);
+        let snapshot = engine.snapshot();
+        assert_eq!(engine.min_snapshot_seqno(), Some(snapshot.seqno()));
+
+        engine.delete(b"k".to_vec()).unwrap();
+        engine.put(b"new".to_vec(), b"y".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.compact().unwrap();
+
+        assert_eq!(engine.get(b"k").unwrap(), None);
+        assert_eq!(snapshot.get(b"k").unwrap(), Some(b"before".to_vec()));
+        assert_eq!(snapshot.get(b"new").unwrap(), None);
+        assert_eq!(snapshot.get(b"other").unwrap(), Some(b"x".to_vec()));
+        let records = |engine: &Engine| -> u64 {
+            let ssts = engine.sstables.read().unwrap();
+            ssts.iter().map(|s| s.record_count()).sum()
+        };
+        // The tombstone and the value it shadows are both still needed.
+        assert_eq!(records(&engine), 4);
+
+        drop(snapshot);
+        assert_eq!(engine.min_snapshot_seqno(), None);
+        engine.put(b"z".to_vec(), b"z".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.compact().unwrap();
+
+        // With no snapshot left, the bottommost compaction drops both.
+        assert_eq!(records(&engine), 3);
+        assert_eq!(engine.get(b"k").unwrap(), None);
+    }
+
+    #[test]
+    fn test_snapshot_ignores_later_writes_in_memtable() {
+        let engine = Engine::open_in_memory(1 << 20);
+        engine.put(b"k".to_vec(), b"1".to_vec()).unwrap();
+        let snapshot = engine.snapshot();
+        engine.put(b"k".to_vec(), b"2".to_vec()).unwrap();
+        engine.flush().unwrap();
+
+        assert_eq!(snapshot.get(b"k").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_get_at_seq_returns_version_as_of_seqno() {
+        let engine = Engine::open_in_memory(1 << 20);
+        engine.put(b"k".to_vec(), b"v1".to_vec()).unwrap(); // seqno 1
+        engine.flush().unwrap();
+        engine.put(b"k".to_vec(), b"v2".to_vec()).unwrap(); // seqno 2
+        engine.flush().unwrap();
+        engine.delete(b"k".to_vec()).unwrap(); // seqno 3
+        engine.flush().unwrap();
+        engine.put(b"k".to_vec(), b"v4".to_vec()).unwrap(); // seqno 4, in the MemTable
+
+        assert_eq!(engine.get_at_seq(b"k", 0).unwrap(), None);
+        assert_eq!(engine.get_at_seq(b"k", 1).unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(engine.get_at_seq(b"k", 2).unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(engine.get_at_seq(b"k", 3).unwrap(), None);
+        assert_eq!(engine.get_at_seq(b"k", 4).unwrap(), Some(b"v4".to_vec()));
+        assert_eq!(
+            engine.get_at_seq(b"k", u64::MAX).unwrap(),
+            Some(b"v4".to_vec())
+        );
+        assert_eq!(engine.get_at_seq(b"other", u64::MAX).unwrap(), None);
+
+        // A full compaction with no live snapshot drops the older versions.
+        engine.compact().unwrap();
+        assert_eq!(engine.get_at_seq(b"k", 2).unwrap(), None);
+        assert_eq!(engine.get_at_seq(b"k", 4).unwrap(), Some(b"v4".to_vec()));
+    }
+
+    #[test]
+    fn test_seqnos_continue_after_reopen() {
+        let dir = setup_test_dir("engine_seqno_reopen");
+        {
+            let engine = Engine::open(&dir, 1 << 20).unwrap();
+            engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+            engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+            engine.flush().unwrap();
+            engine.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        }
+
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.snapshot().seqno(), 3);
+        engine.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        assert_eq!(engine.snapshot().seqno(), 4);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_meta_survives_recovery_flush_and_compaction() {
+        let dir = setup_test_dir("engine_meta");
+        {
+            let engine = Engine::open(&dir, 1 << 20).unwrap();
+            engine
+                .put_with_meta(b"a".to_vec(), b"1".to_vec(), 3)
+                .unwrap();
+            engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+            assert_eq!(
+                engine.get_with_meta(b"a").unwrap(),
+                Some((b"1".to_vec(), 3))
+            );
+        }
+
+        // Replayed from the WAL.
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(
+            engine.get_with_meta(b"a").unwrap(),
+            Some((b"1".to_vec(), 3))
+        );
+        assert_eq!(
+            engine.get_with_meta(b"b").unwrap(),
+            Some((b"2".to_vec(), 0))
+        );
+        engine.flush().unwrap();
+        assert_eq!(
+            engine.get_with_meta(b"a").unwrap(),
+            Some((b"1".to_vec(), 3))
+        );
+
+        engine
+            .put_with_meta(b"b".to_vec(), b"3".to_vec(), 255)
+            .unwrap();
+        engine.flush().unwrap();
+        engine.compact().unwrap();
+        assert_eq!(engine.sstables.read().unwrap().len(), 1);
+        assert_eq!(
+            engine.get_with_meta(b"a").unwrap(),
+            Some((b"1".to_vec(), 3))
+        );
+        assert_eq!(
+            engine.get_with_meta(b"b").unwrap(),
+            Some((b"3".to_vec(), 255))
+        );
+        assert_eq!(engine.get(b"b").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(engine.value_len(b"b").unwrap(), Some(1));
+
+        engine.delete(b"a".to_vec()).unwrap();
+        assert_eq!(engine.get_with_meta(b"a").unwrap(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_ingest_sorted() {
+        let dir = setup_test_dir("engine_ingest");
+        {
+            let engine = Engine::open(&dir, 1 << 20).unwrap();
+            engine.put(b"k05".to_vec(), b"old".to_vec()).unwrap();
+            engine.put(b"x".to_vec(), b"kept".to_vec()).unwrap();
+            let snapshot = engine.snapshot();
+
+            let records = (0..100u32).map(|i| (format!("k{:02}", i).into_bytes(), vec![i as u8]));
+            engine.ingest_sorted(records).unwrap();
+            // The MemTable was flushed ahead of the ingested table, which shadows it.
+            assert_eq!(engine.sstables.read().unwrap().len(), 2);
+            assert_eq!(engine.get(b"k05").unwrap(), Some(vec![5]));
+            assert_eq!(engine.get(b"x").unwrap(), Some(b"kept".to_vec()));
+            assert_eq!(snapshot.get(b"k05").unwrap(), Some(b"old".to_vec()));
+            assert_eq!(snapshot.get(b"k06").unwrap(), None);
+
+            // Later writes shadow ingested ones.
+            engine.put(b"k07".to_vec(), b"new".to_vec()).unwrap();
+            assert_eq!(engine.get(b"k07").unwrap(), Some(b"new".to_vec()));
+
+            let unsorted = vec![(b"b".to_vec(), vec![1]), (b"a".to_vec(), vec![2])];
+            let err = engine.ingest_sorted(unsorted.into_iter()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            let duplicate = vec![(b"c".to_vec(), vec![1]), (b"c".to_vec(), vec![2])];
+            let err = engine.ingest_sorted(duplicate.into_iter()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(engine.get(b"b").unwrap(), None);
+            assert_eq!(engine.get(b"c").unwrap(), None);
+        }
+
+        // The failed ingests flushed the MemTable but left no partial tables behind.
+        let tables = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+            .count();
+        assert_eq!(tables, 3);
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.get(b"k05").unwrap(), Some(vec![5]));
+        assert_eq!(engine.get(b"k07").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(engine.get(b"k99").unwrap(), Some(vec![99]));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_ingest_sorted_stamps_version() {
+        let dir = setup_test_dir("engine_ingest_versioned");
+        let options = || EngineOptions {
+            node_id: Some(7),
+            ..EngineOptions::default()
+        };
+        {
+            let engine = Engine::open_with_options(&dir, options()).unwrap();
+            engine.put(b"a".to_vec(), b"old".to_vec()).unwrap();
+            let records = vec![(b"a".to_vec(), b"new".to_vec()), (b"b".to_vec(), vec![2])];
+            engine.ingest_sorted(records.into_iter()).unwrap();
+
+            let ssts = engine.sstables.read().unwrap().clone();
+            let flushed = Version::from_seqno(ssts[1].max_seqno());
+            let ingested = Version::from_seqno(ssts[0].max_seqno());
+            assert_eq!(ingested.node_id, 7);
+            assert!(ingested > flushed);
+            assert_eq!(engine.get(b"a").unwrap(), Some(b"new".to_vec()));
+
+            engine.put(b"b".to_vec(), b"later".to_vec()).unwrap();
+            assert_eq!(engine.get(b"b").unwrap(), Some(b"later".to_vec()));
+        }
+
+        let engine = Engine::open_with_options(&dir, options()).unwrap();
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(engine.get(b"b").unwrap(), Some(b"later".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_open_with_corrupt_sstable() {
+        let dir = setup_test_dir("engine_corrupt_sst");
+        {
+            let engine = Engine::open(&dir, 1024 * 1024).unwrap();
+            for key in [b"a", b"b", b"c"] {
+                engine.put(key.to_vec(), key.to_vec()).unwrap();
+                engine.flush().unwrap();
+            }
+        }
+        let mut tables: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "sst"))
+            .collect();
+        tables.sort();
+        let corrupt = tables[1].clone();
+        let mut bytes = std::fs::read(&corrupt).unwrap();
+        bytes[0] ^= 0xFF;
+        std::fs::write(&corrupt, bytes).unwrap();
+
+        let options = |on_corrupt| EngineOptions {
+            on_corrupt,
+            ..EngineOptions::default()
+        };
+        let err = Engine::open_with_options(&dir, options(CorruptionPolicy::Fail))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let (engine, report) =
+            Engine::open_with_report(&dir, options(CorruptionPolicy::Skip)).unwrap();
+        assert_eq!(report.sstables_loaded, 2);
+        assert_eq!(report.sstables_skipped, 1);
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"a".to_vec()));
+        assert_eq!(engine.get(b"b").unwrap(), None);
+        assert_eq!(engine.get(b"c").unwrap(), Some(b"c".to_vec()));
+        assert!(corrupt.exists());
+        drop(engine);
+
+        let engine =
+            Engine::open_with_options(&dir, options(CorruptionPolicy::Quarantine)).unwrap();
+        assert_eq!(engine.get(b"c").unwrap(), Some(b"c".to_vec()));
+        assert!(!corrupt.exists());
+        let mut quarantined = corrupt.into_os_string();
+        quarantined.push(".corrupt");
+        assert!(Path::new(&quarantined).exists());
+        drop(engine);
+
+        // Once quarantined, the file no longer stops a default open.
+        assert!(Engine::open(&dir, 1024).is_ok());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_manifest_order_wins_over_file_names() {
+        let dir = setup_test_dir("engine_manifest_order");
+        {
+            let engine = Engine::open(&dir, 1 << 20).unwrap();
+            engine.put(b"k".to_vec(), b"old".to_vec()).unwrap();
+            engine.flush().unwrap();
+            engine.put(b"k".to_vec(), b"new".to_vec()).unwrap();
+            engine.flush().unwrap();
+        }
+        let mut tables = sstable_files(&dir);
+        tables.sort();
+        // Give the newer table the name that sorts oldest, and the other way around.
+        let (older, newer) = (
+            dir.join("99999999999999999999.sst"),
+            dir.join("00000000000000000001.sst"),
+        );
+        std::fs::rename(&tables[0], &older).unwrap();
+        std::fs::rename(&tables[1], &newer).unwrap();
+        let names = ["00000000000000000001.sst", "99999999999999999999.sst"].map(String::from);
+        write_manifest(&dir, &names).unwrap();
+        // A table the manifest does not list was never installed, so it is a leftover.
+        std::fs::copy(&older, dir.join("55555555555555555555.sst")).unwrap();
+
+        let (engine, report) = Engine::open_with_report(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(report.leftovers_removed, 1);
+        assert_eq!(report.sstables_loaded, 2);
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"new".to_vec()));
+        engine.put(b"other".to_vec(), vec![1]).unwrap();
+        engine.flush().unwrap();
+        let listed = read_manifest(&dir).unwrap().unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(&listed[1..], &names);
+        drop(engine);
+
+        // Without a manifest, as written by older versions, sequence numbers give the order
+        // and the manifest is written on open.
+        std::fs::remove_file(dir.join(MANIFEST_FILE)).unwrap();
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(read_manifest(&dir).unwrap().unwrap(), listed);
+
+        // A manifest naming a table that is gone fails the open by default.
+        drop(engine);
+        std::fs::remove_file(&older).unwrap();
+        let err = Engine::open(&dir, 1 << 20).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_repair_rebuilds_lost_manifest() {
+        let dir = setup_test_dir("engine_repair");
+        {
+            let engine = Engine {
+                compaction: None,
+                ..Engine::open(&dir, 1 << 20).unwrap()
+            };
+            for round in 0..4u32 {
+                for i in 0..10u32 {
+                    let value = format!("{}-{}", i, round).into_bytes();
+                    engine.put(i.to_be_bytes().to_vec(), value).unwrap();
+                }
+                engine.delete(round.to_be_bytes().to_vec()).unwrap();
+                engine.flush().unwrap();
+                if round == 1 {
+                    engine.compact().unwrap();
+                }
+            }
+            engine.put(b"unflushed".to_vec(), vec![1]).unwrap();
+        }
+        let listed = read_manifest(&dir).unwrap().unwrap();
+
+        // A healthy store keeps its tables in the same order.
+        let report = Engine::repair(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(report.sstables_kept, listed.len());
+        assert_eq!(read_manifest(&dir).unwrap().unwrap(), listed);
+
+        // A lost manifest is rebuilt, and leftovers and corrupt tables are cleared away.
+        std::fs::remove_file(dir.join(MANIFEST_FILE)).unwrap();
+        let newest = dir.join(&listed[0]);
+        let orphan = dir.join(compaction_output_name(&SSTable::open(&newest).unwrap()));
+        std::fs::copy(&newest, &orphan).unwrap();
+        let corrupt = dir.join("00000000000000000000.sst");
+        std::fs::write(&corrupt, b"not a table").unwrap();
+        let report = Engine::repair(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(
+            report,
+            RepairReport {
+                sstables_kept: listed.len(),
+                sstables_quarantined: 1,
+                leftovers_removed: 1,
+            }
+        );
+        assert!(!orphan.exists() && !corrupt.exists());
+        assert_eq!(read_manifest(&dir).unwrap().unwrap(), listed);
+        assert_eq!(
+            Engine::repair(&dir, EngineOptions::default()).unwrap(),
+            RepairReport {
+                sstables_kept: listed.len(),
+                ..RepairReport::default()
+            }
+        );
+
+        // So is a corrupt one.
+        std::fs::write(dir.join(MANIFEST_FILE), b"garbage").unwrap();
+        assert!(Engine::open(&dir, 1 << 20).is_err());
+        Engine::repair(&dir, EngineOptions::default()).unwrap();
+
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        for i in 0..10u32 {
+            let expected = (i != 3).then(|| format!("{}-3", i).into_bytes());
+            assert_eq!(engine.get(&i.to_be_bytes()).unwrap(), expected);
+        }
+        assert_eq!(engine.get(b"unflushed").unwrap(), Some(vec![1]));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_repair_quarantines_table_with_damaged_data_block() {
+        let dir = setup_test_dir("engine_repair_damaged_block");
+        {
+            let engine = Engine {
+                compaction: None,
+                ..Engine::open(&dir, 1 << 20).unwrap()
+            };
+            for round in 0..2u32 {
+                for i in 0..10u32 {
+                    let value = format!("{}-{}", i, round).into_bytes();
+                    engine.put(i.to_be_bytes().to_vec(), value).unwrap();
+                }
+                engine.flush().unwrap();
+            }
+        }
+        let listed = read_manifest(&dir).unwrap().unwrap();
+
+        // The footer and index still parse; only the checksum over the records catches this.
+        let damaged = dir.join(&listed[0]);
+        let mut bytes = std::fs::read(&damaged).unwrap();
+        bytes[8] ^= 0xff;
+        std::fs::write(&damaged, bytes).unwrap();
+        let report = Engine::repair(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(
+            report,
+            RepairReport {
+                sstables_kept: 1,
+                sstables_quarantined: 1,
+                leftovers_removed: 0,
+            }
+        );
+        assert!(!damaged.exists());
+        assert_eq!(read_manifest(&dir).unwrap().unwrap(), listed[1..]);
+
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(
+            engine.get(&1u32.to_be_bytes()).unwrap(),
+            Some(b"1-0".to_vec())
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_scrub_step_finds_and_quarantines_rotten_table() {
+        let dir = setup_test_dir("engine_scrub");
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_with_options(
+                &dir,
+                EngineOptions {
+                    on_corrupt: CorruptionPolicy::Quarantine,
+                    ..EngineOptions::default()
+                },
+            )
+            .unwrap()
+        };
+        for key in [b"a", b"b", b"c"] {
+            engine.put(key.to_vec(), key.to_vec()).unwrap();
+            engine.flush().unwrap();
+        }
+        let mut tables = sstable_files(&dir);
+        tables.sort();
+        assert_eq!(tables.len(), 3);
+
+        // Rot a byte of the middle table's data after it was opened and verified.
+        let rotten = tables[1].clone();
+        let mut bytes = std::fs::read(&rotten).unwrap();
+        bytes[2] ^= 0x01;
+        std::fs::write(&rotten, bytes).unwrap();
+
+        let first = engine.scrub_step().unwrap();
+        assert_eq!(first.table.as_deref(), Some(tables[0].as_path()));
+        assert!(!first.corrupt && !first.pass_complete);
+        assert!(first.bytes_verified > 0);
+        let second = engine.scrub_step().unwrap();
+        assert_eq!(second.table.as_deref(), Some(rotten.as_path()));
+        assert!(second.corrupt && !second.pass_complete);
+        let third = engine.scrub_step().unwrap();
+        assert_eq!(third.table.as_deref(), Some(tables[2].as_path()));
+        assert!(!third.corrupt && third.pass_complete);
+
+        assert_eq!(engine.stats().corruption_detected, 1);
+        assert_eq!(engine.stats().sstable_count, 2);
+        assert!(!rotten.exists());
+        let mut quarantined = rotten.into_os_string();
+        quarantined.push(".corrupt");
+        assert!(Path::new(&quarantined).exists());
+        assert_eq!(engine.get(b"b").unwrap(), None);
+        assert_eq!(engine.get(b"c").unwrap(), Some(b"c".to_vec()));
+
+        // A new pass starts over with the first table and finds nothing more.
+        let next = engine.scrub_step().unwrap();
+        assert_eq!(next.table.as_deref(), Some(tables[0].as_path()));
+        assert!(!engine.scrub_step().unwrap().corrupt);
+        assert_eq!(engine.stats().corruption_detected, 1);
+        drop(engine);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// Flips every bit of a value, so its plain bytes never reach the disk.
+    #[derive(Debug)]
+    struct XorCodec(&'static str);
+
+    impl ValueCodec for XorCodec {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn encode(&self, value: &[u8]) -> Vec<u8> {
+            value.iter().map(|b| b ^ 0xff).collect()
+        }
+
+        fn decode(&self, encoded: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(self.encode(encoded))
+        }
+    }
+
+    #[test]
+    fn test_value_codec_round_trips_through_flush_and_compaction() {
+        let dir = setup_test_dir("engine_value_codec");
+        let options_with = |codec: Option<XorCodec>| EngineOptions {
+            table: TableOptions {
+                value_codec: codec.map(|c| Arc::new(c) as Arc<dyn ValueCodec>),
+                ..TableOptions::default()
+            },
+            ..EngineOptions::default()
+        };
+        {
+            let engine = Engine {
+                compaction: None,
+                ..Engine::open_with_options(&dir, options_with(Some(XorCodec("xor")))).unwrap()
+            };
+            for round in 0..3u8 {
+                for i in 0..20u8 {
+                    engine
+                        .put(
+                            vec![b'k', i],
+                            format!("secret-{}-{}", round, i).into_bytes(),
+                        )
+                        .unwrap();
+                }
+                engine.flush().unwrap();
+            }
+            engine.delete(vec![b'k', 0]).unwrap();
+            engine.flush().unwrap();
+            assert_eq!(engine.value_len(&[b'k', 5]).unwrap(), Some(10));
+            engine.compact().unwrap();
+
+            let ssts = engine.sstables.read().unwrap().clone();
+            assert_eq!(ssts.len(), 1);
+            assert_eq!(ssts[0].value_codec_name(), Some("xor"));
+            let raw = std::fs::read(ssts[0].path()).unwrap();
+            assert!(!raw.windows(6).any(|w| w == b"secret"));
+            assert_eq!(engine.get(&[b'k', 0]).unwrap(), None);
+            assert_eq!(
+                engine.get(&[b'k', 7]).unwrap(),
+                Some(b"secret-2-7".to_vec())
+            );
+            assert_eq!(engine.scan(false).unwrap().count(), 19);
+        }
+
+        // The tables cannot be read without the codec they were written with.
+        for codec in [None, Some(XorCodec("other"))] {
+            let err = Engine::open_with_options(&dir, options_with(codec))
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        let engine = Engine::open_with_options(&dir, options_with(Some(XorCodec("xor")))).unwrap();
+        assert_eq!(
+            engine.get(&[b'k', 19]).unwrap(),
+            Some(b"secret-2-19".to_vec())
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_store_reopens_only_with_its_key() {
+        let dir = setup_test_dir("engine_encryption");
+        let options_with = |key: Option<[u8; 32]>| EngineOptions {
+            max_open_files: Some(2),
+            cache: Some(CacheManager::new(64 * 1024)),
+            encryption: key.map(Encryption::new),
+            ..EngineOptions::default()
+        };
+        {
+            let engine = Engine {
+                compaction: None,
+                ..Engine::open_with_options(&dir, options_with(Some([1; 32]))).unwrap()
+            };
+            for round in 0..3u8 {
+                for i in 0..200u8 {
+                    engine
+                        .put(
+                            vec![b'k', i],
+                            format!("secret-{}-{}", round, i).into_bytes(),
+                        )
+                        .unwrap();
+                }
+                engine.flush().unwrap();
+            }
+            engine.compact().unwrap();
+            // These stay in the WAL.
+            engine
+                .put(b"unflushed".to_vec(), b"secret-wal".to_vec())
+                .unwrap();
+            engine.delete(vec![b'k', 0]).unwrap();
+        }
+
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext == "sst" || ext == "wal")
+            })
+            .collect();
+        assert_eq!(files.len(), 2);
+        for path in files {
+            let raw = std::fs::read(path).unwrap();
+            assert!(!raw.windows(6).any(|w| w == b"secret"));
+        }
+
+        let err = Engine::open_with_options(&dir, options_with(Some([2; 32])))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = Engine::open_with_options(&dir, options_with(None))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Reopening twice seals the recovered WAL again each time.
+        for _ in 0..2 {
+            let engine = Engine::open_with_options(&dir, options_with(Some([1; 32]))).unwrap();
+            assert_eq!(
+                engine.get(b"unflushed").unwrap(),
+                Some(b"secret-wal".to_vec())
+            );
+            assert_eq!(engine.get(&[b'k', 0]).unwrap(), None);
+            assert_eq!(
+                engine.get(&[b'k', 150]).unwrap(),
+                Some(b"secret-2-150".to_vec())
+            );
+            assert_eq!(engine.scan(false).unwrap().count(), 200);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_open_readonly_can_leave_out_the_wal() {
+        let dir = setup_test_dir("engine_readonly");
+        let writer = Engine::open(&dir, 1 << 20).unwrap();
+        writer.put(b"flushed".to_vec(), b"1".to_vec()).unwrap();
+        writer.flush().unwrap();
+        writer.put(b"unflushed".to_vec(), b"2".to_vec()).unwrap();
+        let wal_path = dir.join(WAL_FILE);
+        let wal_len = std::fs::metadata(&wal_path).unwrap().len();
+
+        let committed = Engine::open_readonly(&dir, EngineOptions::default(), false).unwrap();
+        assert_eq!(committed.get(b"flushed").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(committed.get(b"unflushed").unwrap(), None);
+        let latest = Engine::open_readonly(&dir, EngineOptions::default(), true).unwrap();
+        assert_eq!(latest.get(b"unflushed").unwrap(), Some(b"2".to_vec()));
+
+        for engine in [&committed, &latest] {
+            let denied = |result: io::Result<()>| result.unwrap_err().kind();
+            assert_eq!(
+                denied(engine.put(b"k".to_vec(), b"v".to_vec())),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                denied(engine.delete(b"flushed".to_vec())),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                denied(engine.flush().map(|_| ())),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                denied(engine.compact().map(|_| ())),
+                io::ErrorKind::PermissionDenied
+            );
+        }
+        // The writer's files are untouched, and it carries on.
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), wal_len);
+        writer.put(b"later".to_vec(), b"3".to_vec()).unwrap();
+        drop(writer);
+        let reopened = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(reopened.get(b"unflushed").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(reopened.get(b"later").unwrap(), Some(b"3".to_vec()));
+
+        let missing = dir.join("missing");
+        let err = Engine::open_readonly(&missing, EngineOptions::default(), true)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!missing.exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_open_report() {
+        let dir = setup_test_dir("engine_open_report");
+        {
+            let engine = Engine::open(&dir, 1024 * 1024).unwrap();
+            engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+            engine.flush().unwrap();
+            engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+            engine.delete(b"a".to_vec()).unwrap();
+        }
+
+        let (engine, report) = Engine::open_with_report(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(
+            report,
+            OpenReport {
+                wal_entries_replayed: 2,
+                wal_truncated_at: None,
+                wal_recovery: RecoverReport {
+                    bytes_consumed: std::fs::metadata(dir.join(WAL_FILE)).unwrap().len(),
+                    ..RecoverReport::default()
+                },
+                recovered_memtable_flushed: false,
+                sstables_loaded: 1,
+                sstables_skipped: 0,
+                leftovers_removed: 0,
+                warnings: Vec::new(),
+            }
+        );
+        assert_eq!(engine.get(b"a").unwrap(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_open_cuts_off_torn_wal_tail() {
+        let dir = setup_test_dir("engine_torn_wal");
+        {
+            let engine = Engine::open(&dir, 1 << 20).unwrap();
+            engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        }
+        let wal_path = dir.join(WAL_FILE);
+        let clean_len = std::fs::metadata(&wal_path).unwrap().len();
+        // A Put whose key claims 100 bytes, of which only two were written.
+        let mut wal = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&wal_path)
+            .unwrap();
+        std::io::Write::write_all(&mut wal, &[0, 100, 0, 0, 0, b'b', b'b']).unwrap();
+        drop(wal);
+
+        let (engine, report) = Engine::open_with_report(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(report.wal_entries_replayed, 1);
+        assert_eq!(report.wal_truncated_at, Some(clean_len));
+        assert_eq!(report.wal_recovery.bytes_consumed, clean_len);
+        assert_eq!(report.wal_recovery.bytes_discarded, 7);
+        assert!(report.wal_recovery.stopped_reason.is_some());
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), clean_len);
+        engine.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        drop(engine);
+
+        // Writes made after the cut are not hidden behind the torn entry.
+        let (engine, report) = Engine::open_with_report(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(report.wal_truncated_at, None);
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(engine.get(b"c").unwrap(), Some(b"3".to_vec()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_open_flushes_overfull_recovered_memtable() {
+        let dir = setup_test_dir("engine_overfull_wal");
+        {
+            let engine = Engine::open(&dir, 1 << 20).unwrap();
+            for i in 0..100u32 {
+                engine
+                    .put(format!("key{:03}", i).into_bytes(), vec![b'v'; 500])
+                    .unwrap();
+            }
+        }
+
+        let options = EngineOptions {
+            max_memtable_size: 16 * 1024,
+            ..EngineOptions::default()
+        };
+        let (engine, report) = Engine::open_with_report(&dir, options.clone()).unwrap();
+        assert_eq!(report.wal_entries_replayed, 100);
+        assert!(report.recovered_memtable_flushed);
+        assert_eq!(engine.sstables.read().unwrap().len(), 1);
+        assert_eq!(engine.active_memtable.read().unwrap().approximate_size(), 0);
+        assert_eq!(engine.get(b"key042").unwrap(), Some(vec![b'v'; 500]));
+        drop(engine);
+
+        // The WAL was truncated by the flush, so the next open has nothing to replay.
+        let (_, report) = Engine::open_with_report(&dir, options).unwrap();
+        assert_eq!(report.wal_entries_replayed, 0);
+        assert!(!report.recovered_memtable_flushed);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_open_warns_about_tiny_memtable() {
+        let dir = setup_test_dir("engine_tiny_memtable");
+        let options = EngineOptions {
+            max_memtable_size: 10,
+            ..EngineOptions::default()
+        };
+        let (engine, report) = Engine::open_with_report(&dir, options.clone()).unwrap();
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("max_memtable_size of 10 bytes"));
+        // The engine still works, flushing on every write.
+        engine.put(b"k".to_vec(), b"v".to_vec()).unwrap();
+        assert_eq!(engine.sstables.read().unwrap().len(), 1);
+        drop(engine);
+
+        let options = EngineOptions {
+            allow_tiny_memtable: true,
+            ..options
+        };
+        let (_, report) = Engine::open_with_report(&dir, options).unwrap();
+        assert!(report.warnings.is_empty());
+        let (_, report) = Engine::open_with_report(&dir, EngineOptions::default()).unwrap();
+        assert!(report.warnings.is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_key_iter_resolves_versions() {
+        let engine = Engine::open_in_memory(1024 * 1024);
+        let mut expected = std::collections::BTreeSet::new();
+        for i in 0..30u8 {
+            engine.put(vec![i], vec![0; 1000]).unwrap();
+            expected.insert(vec![i]);
+        }
+        engine.flush().unwrap();
+        for i in (0..30u8).step_by(3) {
+            engine.delete(vec![i]).unwrap();
+            expected.remove(&vec![i]);
+        }
+        engine.put(vec![100], vec![1]).unwrap();
+        expected.insert(vec![100]);
+        engine.flush().unwrap();
+        // Resurrected in the MemTable, and deleted only in the MemTable.
+        engine.put(vec![3], vec![2]).unwrap();
+        engine.delete(vec![4]).unwrap();
+        engine.delete(vec![200]).unwrap();
+        expected.insert(vec![3]);
+        expected.remove(&vec![4]);
+
+        let keys: Vec<Vec<u8>> = engine.key_iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(keys, expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_value_len() {
+        let engine = Engine::open_in_memory(1024 * 1024);
+        engine.put(b"big".to_vec(), vec![0; 100_000]).unwrap();
+        engine.put(b"gone".to_vec(), b"v".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.delete(b"gone".to_vec()).unwrap();
+        engine.put(b"small".to_vec(), b"abc".to_vec()).unwrap();
+
+        assert_eq!(engine.value_len(b"big").unwrap(), Some(100_000));
+        assert_eq!(engine.value_len(b"small").unwrap(), Some(3));
+        assert_eq!(engine.value_len(b"gone").unwrap(), None);
+        assert_eq!(engine.value_len(b"missing").unwrap(), None);
+
+        // A tombstone on disk hides the value in an older table too.
+        engine.flush().unwrap();
+        assert_eq!(engine.value_len(b"gone").unwrap(), None);
+        assert_eq!(engine.value_len(b"big").unwrap(), Some(100_000));
+    }
+
+    #[test]
+    fn test_delete_if_lets_exactly_one_racer_win() {
+        let dir = setup_test_dir("engine_delete_if");
+        let engine = Arc::new(Engine {
+            compaction: None,
+            ..Engine::open(&dir, 1 << 20).unwrap()
+        });
+
+        for round in 0..50u32 {
+            let owner = format!("owner{}", round).into_bytes();
+            engine.put(b"lock".to_vec(), owner.clone()).unwrap();
+            // Every other round the value has to be read from an SSTable.
+            if round % 2 == 0 {
+                engine.flush().unwrap();
+            }
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+            let racers: Vec<_> = (0..2)
+                .map(|_| {
+                    let (engine, barrier, owner) =
+                        (Arc::clone(&engine), Arc::clone(&barrier), owner.clone());
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        engine.delete_if(b"lock", &owner).unwrap()
+                    })
+                })
+                .collect();
+            let wins = racers
+                .into_iter()
+                .map(|racer| racer.join().unwrap())
+                .filter(|&won| won)
+                .count();
+            assert_eq!(wins, 1, "round {}", round);
+            assert_eq!(engine.get(b"lock").unwrap(), None);
+        }
+
+        engine.put(b"lock".to_vec(), b"other".to_vec()).unwrap();
+        assert!(!engine.delete_if(b"lock", b"owner0").unwrap());
+        assert!(!engine.delete_if(b"missing", b"").unwrap());
+        assert_eq!(engine.get(b"lock").unwrap(), Some(b"other".to_vec()));
+        drop(engine);
+
+        // The conditional delete was logged like any other.
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert!(engine.delete_if(b"lock", b"other").unwrap());
+        drop(engine);
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.get(b"lock").unwrap(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_writes_refused_at_sstable_limit() {
+        let dir = setup_test_dir("engine_sstable_limit");
+        let options = EngineOptions {
+            max_sstables: Some(3),
+            ..EngineOptions::default()
+        };
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_with_options(&dir, options).unwrap()
+        };
+        for i in 0..3u8 {
+            engine.put(vec![i], vec![i]).unwrap();
+            engine.flush().unwrap();
+        }
+        assert_eq!(engine.stats().sstable_count, 3);
+
+        let err = engine.put(vec![9], vec![9]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::QuotaExceeded);
+        let err = engine.delete(vec![0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::QuotaExceeded);
+        assert!(engine.delete_if(&[0], &[0]).is_err());
+        // Nothing was logged or applied, and reads still work.
+        assert_eq!(engine.get(&[9]).unwrap(), None);
+        assert_eq!(engine.get(&[0]).unwrap(), Some(vec![0]));
+
+        engine.compact().unwrap();
+        assert_eq!(engine.stats().sstable_count, 1);
+        engine.put(vec![9], vec![9]).unwrap();
+        assert_eq!(engine.get(&[9]).unwrap(), Some(vec![9]));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_min_flush_size_coalesces_small_flushes() {
+        let tables_after_bursts = |name: &str, min_flush_size: usize| {
+            let dir = setup_test_dir(name);
+            let options = EngineOptions {
+                max_memtable_size: 1024,
+                min_flush_size,
+                flush_grace_window: Duration::from_secs(3600),
+                ..EngineOptions::default()
+            };
+            let engine = Engine {
+                compaction: None,
+                ..Engine::open_with_options(&dir, options).unwrap()
+            };
+            for burst in 0..10u32 {
+                for i in 0..50u32 {
+                    let key = (burst * 50 + i).to_be_bytes().to_vec();
+                    engine.put(key, vec![b'v'; 40]).unwrap();
+                }
+            }
+            assert_eq!(
+                engine.get(&123u32.to_be_bytes()).unwrap(),
+                Some(vec![b'v'; 40])
+            );
+            let tables = engine.stats().sstable_count;
+
+            // An explicit flush never waits for the window.
+            engine.flush().unwrap();
+            assert_eq!(engine.active_memtable.read().unwrap().approximate_size(), 0);
+            let _ = std::fs::remove_dir_all(dir);
+            tables
+        };
+
+        let plain = tables_after_bursts("engine_flush_plain", 0);
+        let coalesced = tables_after_bursts("engine_flush_coalesced", 8 * 1024);
+        assert!(plain >= 20, "{} tables without coalescing", plain);
+        assert!(coalesced * 4 <= plain, "{} vs {} tables", coalesced, plain);
+    }
+
+    #[test]
+    fn test_try_get_reports_contention_instead_of_blocking() {
+        let engine = Arc::new(Engine::open_in_memory(1 << 20));
+        engine.put(b"hot".to_vec(), b"1".to_vec()).unwrap();
+        engine.put(b"cold".to_vec(), b"2".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.put(b"hot".to_vec(), b"3".to_vec()).unwrap();
+        assert_eq!(engine.try_get(b"hot").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(engine.try_get(b"cold").unwrap(), Some(b"2".to_vec()));
+
+        // Holds the MemTable or the table list on another thread until told to let go.
+        let hold = |memtable: bool| {
+            let (held_tx, held_rx) = std::sync::mpsc::channel();
+            let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+            let engine = Arc::clone(&engine);
+            let holder = std::thread::spawn(move || {
+                if memtable {
+                    let _guard = engine.active_memtable.write().unwrap();
+                    held_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                } else {
+                    let _guard = engine.sstables.write().unwrap();
+                    held_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                }
+            });
+            held_rx.recv().unwrap();
+            (holder, release_tx)
+        };
+
+        for memtable in [true, false] {
+            let (holder, release) = hold(memtable);
+            let err = engine.try_get(b"cold").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+            if !memtable {
+                // The MemTable answers without touching the table list.
+                assert_eq!(engine.try_get(b"hot").unwrap(), Some(b"3".to_vec()));
+            }
+            release.send(()).unwrap();
+            holder.join().unwrap();
+            assert_eq!(engine.try_get(b"cold").unwrap(), Some(b"2".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_with_value_lends_memtable_values() {
+        let engine = Engine::open_in_memory(1 << 20);
+        engine.put(b"disk".to_vec(), b"flushed".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.put(b"big".to_vec(), vec![7; 4096]).unwrap();
+        engine.put(b"gone".to_vec(), b"x".to_vec()).unwrap();
+        engine.delete(b"gone".to_vec()).unwrap();
+
+        let stored = match engine.active_memtable.read().unwrap().get(b"big") {
+            Some(Entry::Value(value)) => value.as_ptr(),
+            _ => panic!("expected the value in the MemTable"),
+        };
+        // The closure sees the MemTable's own bytes, not a copy of them.
+        let lent = engine
+            .with_value(b"big", |value| value.unwrap().as_ptr())
+            .unwrap();
+        assert_eq!(lent, stored);
+        assert_eq!(
+            engine.with_value(b"big", |v| v.map(<[u8]>::len)).unwrap(),
+            Some(4096)
+        );
+
+        let read = |key: &[u8]| engine.with_value(key, |v| v.map(<[u8]>::to_vec)).unwrap();
+        assert_eq!(read(b"disk"), Some(b"flushed".to_vec()));
+        assert_eq!(read(b"gone"), None);
+        assert_eq!(read(b"missing"), None);
+    }
+
+    #[test]
+    fn test_reads_see_own_writes_across_flushes() {
+        let engine = Arc::new(Engine::open_in_memory(1 << 20));
+        let written = Arc::new(AtomicU64::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (engine, written, done) = (engine.clone(), written.clone(), done.clone());
+            std::thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    let latest = written.load(Ordering::SeqCst);
+                    for i in [0, latest / 2, latest] {
+                        let key = format!("key{:04}", i).into_bytes();
+                        let found = latest == 0 || engine.get(&key).unwrap().is_some();
+                        assert!(found, "key {} missing after {} writes", i, latest);
+                    }
+                }
+            })
+        };
+
+        for i in 1..=300u64 {
+            let key = format!("key{:04}", i).into_bytes();
+            engine.put(key.clone(), i.to_le_bytes().to_vec()).unwrap();
+            // The writer reads its own write right away, whatever the flush is doing.
+            assert!(engine.get(&key).unwrap().is_some());
+            if i == 1 {
+                engine.put(b"key0000".to_vec(), vec![0]).unwrap();
+            }
+            written.store(i, Ordering::SeqCst);
+            if i % 10 == 0 {
+                engine.flush().unwrap();
+                assert!(engine.get(&key).unwrap().is_some());
+            }
+        }
+        done.store(true, Ordering::SeqCst);
+        reader.join().unwrap();
+        assert!(engine.stats().sstable_count >= 1);
+    }
+
+    #[test]
+    fn test_scan_with_and_without_tombstones() {
+        let engine = Engine::open_in_memory(1024 * 1024);
+        engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        engine.put(b"b".to_vec(), b"1".to_vec()).unwrap();
+        engine.put(b"c".to_vec(), b"1".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.delete(b"b".to_vec()).unwrap();
+        engine.put(b"c".to_vec(), b"2".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.delete(b"d".to_vec()).unwrap();
+
+        let live: Vec<_> = engine.scan(false).unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            live,
+            vec![
+                (b"a".to_vec(), Some(b"1".to_vec())),
+                (b"c".to_vec(), Some(b"2".to_vec())),
+            ]
+        );
+        let all: Vec<_> = engine.scan(true).unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            all,
+            vec![
+                (b"a".to_vec(), Some(b"1".to_vec())),
+                (b"b".to_vec(), None),
+                (b"c".to_vec(), Some(b"2".to_vec())),
+                (b"d".to_vec(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_range_honors_every_kind_of_bound() {
+        let engine = engine_with_tables(&[
+            &[("a", "1"), ("b", "1"), ("c", "1"), ("e", "1")],
+            &[("b", "2"), ("bb", "1"), ("d", "1")],
+        ]);
+        engine.put(b"c".to_vec(), b"2".to_vec()).unwrap();
+        engine.delete(b"d".to_vec()).unwrap();
+        engine.put(b"f".to_vec(), b"1".to_vec()).unwrap();
+
+        let keys = |range: (Bound<&str>, Bound<&str>)| -> Vec<String> {
+            let bound = |b: Bound<&str>| b.map(|k| k.as_bytes().to_vec());
+            engine
+                .range((bound(range.0), bound(range.1)))
+                .unwrap()
+                .map(|r| {
+                    let (key, value) = r.unwrap();
+                    format!("{}={}", String::from_utf8(key).unwrap(), value[0] as char)
+                })
+                .collect()
+        };
+        use Bound::{Excluded, Included, Unbounded};
+        assert_eq!(
+            keys((Unbounded, Unbounded)),
+            ["a=1", "b=2", "bb=1", "c=2", "e=1", "f=1"]
+        );
+        assert_eq!(
+            keys((Included("b"), Unbounded)),
+            ["b=2", "bb=1", "c=2", "e=1", "f=1"]
+        );
+        assert_eq!(
+            keys((Excluded("b"), Unbounded)),
+            ["bb=1", "c=2", "e=1", "f=1"]
+        );
+        assert_eq!(keys((Unbounded, Excluded("c"))), ["a=1", "b=2", "bb=1"]);
+        assert_eq!(
+            keys((Unbounded, Included("c"))),
+            ["a=1", "b=2", "bb=1", "c=2"]
+        );
+        assert_eq!(keys((Included("b"), Excluded("c"))), ["b=2", "bb=1"]);
+        assert_eq!(keys((Included("b"), Included("b"))), ["b=2"]);
+        assert_eq!(keys((Excluded("b"), Included("e"))), ["bb=1", "c=2", "e=1"]);
+        assert_eq!(keys((Excluded("a"), Excluded("bb"))), ["b=2"]);
+        assert!(keys((Included("c"), Excluded("c"))).is_empty());
+        assert!(keys((Included("x"), Unbounded)).is_empty());
+
+        // The standard range syntax works directly.
+        assert_eq!(engine.range(b"bb".to_vec()..).unwrap().count(), 4);
+        assert_eq!(engine.range(..b"b".to_vec()).unwrap().count(), 1);
+        assert_eq!(
+            engine.range(b"a".to_vec()..=b"c".to_vec()).unwrap().count(),
+            4
+        );
+        assert_eq!(engine.range(..).unwrap().count(), 6);
+    }
+
+    #[test]
+    fn test_changes_since_reports_newest_versions() {
+        let engine = Engine::open_in_memory(1024 * 1024);
+        engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        engine.put(b"b".to_vec(), b"1".to_vec()).unwrap();
+        engine.put(b"c".to_vec(), b"1".to_vec()).unwrap();
+        engine.flush().unwrap();
+        let since = engine.snapshot().seqno();
+
+        engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        engine.delete(b"c".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.put(b"d".to_vec(), b"2".to_vec()).unwrap();
+        // Rewritten after `since` twice; only the newest version is reported.
+        engine.put(b"b".to_vec(), b"3".to_vec()).unwrap();
+
+        let changes: Vec<_> = engine
+            .changes_since(since)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (b"b".to_vec(), Entry::Value(b"3".to_vec()), 7),
+                (b"c".to_vec(), Entry::Tombstone, 5),
+                (b"d".to_vec(), Entry::Value(b"2".to_vec()), 6),
+            ]
+        );
+
+        assert_eq!(engine.changes_since(0).unwrap().count(), 4);
+        assert_eq!(engine.changes_since(7).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_engines_share_a_block_cache_budget() {
+        let dirs = [
+            setup_test_dir("engine_cache_a"),
+            setup_test_dir("engine_cache_b"),
+        ];
+        let cache = CacheManager::new(8 * crate::cache::BLOCK_SIZE);
+        let options = EngineOptions {
+            cache: Some(Arc::clone(&cache)),
+            ..EngineOptions::default()
+        };
+        let engines: Vec<Engine> = dirs
+            .iter()
+            .map(|dir| Engine::open_with_options(dir, options.clone()).unwrap())
+            .collect();
+        for engine in &engines {
+            for i in 0..200u32 {
+                engine.put(i.to_be_bytes().to_vec(), vec![1; 100]).unwrap();
+            }
+            engine.flush().unwrap();
+            assert_eq!(engine.stats().block_cache_bytes, 0);
+        }
+
+        for engine in &engines {
+            for i in 0..200u32 {
+                assert_eq!(engine.get(&i.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+            }
+        }
+        // Together the two engines read far more than fits, but stay within the budget.
+        let stats = engines[0].stats();
+        assert!(stats.block_cache_bytes > 0);
+        assert!(stats.block_cache_bytes <= cache.capacity() as u64);
+        assert_eq!(
+            engines[1].stats().block_cache_bytes,
+            stats.block_cache_bytes
+        );
+
+        drop(engines);
+        assert_eq!(cache.usage(), 0);
+        for dir in dirs {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+
+    #[test]
+    fn test_reads_with_fewer_open_files_than_tables() {
+        let dir = setup_test_dir("engine_file_cache");
+        {
+            let engine = Engine::open(&dir, 1024 * 1024).unwrap();
+            for table in 0..3u8 {
+                for i in 0..20u8 {
+                    engine.put(vec![table, i], vec![table ^ i]).unwrap();
+                }
+                engine.flush().unwrap();
+            }
+        }
+
+        let options = EngineOptions {
+            max_open_files: Some(1),
+            ..EngineOptions::default()
+        };
+        let engine = Engine::open_with_options(&dir, options).unwrap();
+        let TableLocation::Dir(
+            _,
+            TableCaches {
+                files: Some(cache), ..
+            },
+        ) = &engine.location
+        else {
+            panic!("expected a file cache");
+        };
+        assert_eq!(engine.sstables.read().unwrap().len(), 3);
+        for i in 0..20u8 {
+            for table in 0..3u8 {
+                assert_eq!(engine.get(&[table, i]).unwrap(), Some(vec![table ^ i]));
+                assert_eq!(cache.open_files(), 1);
+            }
+        }
+
+        // New tables go through the cache as well.
+        engine.put(vec![9], vec![9]).unwrap();
+        engine.flush().unwrap();
+        assert_eq!(engine.get(&[9]).unwrap(), Some(vec![9]));
+        assert_eq!(engine.get(&[0, 0]).unwrap(), Some(vec![0]));
+        assert_eq!(cache.open_files(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_point_reads_race_compaction_with_file_cache() {
+        let dir = setup_test_dir("engine_get_vs_compaction");
+        let options = EngineOptions {
+            max_open_files: Some(1),
+            ..EngineOptions::default()
+        };
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_with_options(&dir, options).unwrap()
+        };
+        for i in 0..50u8 {
+            engine.put(vec![i], vec![i]).unwrap();
+        }
+        engine.flush().unwrap();
+        let snapshot = engine.snapshot();
+        let done = AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            for reader in 0..4u8 {
+                let (engine, snapshot, done) = (&engine, &snapshot, &done);
+                scope.spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        for i in 0..50u8 {
+                            let key = [i];
+                            let value = Some(vec![i]);
+                            match reader {
+                                0 => assert_eq!(engine.get(&key).unwrap(), value),
+                                1 => match engine.try_get(&key) {
+                                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                                    found => assert_eq!(found.unwrap(), value),
+                                },
+                                2 => assert_eq!(engine.value_len(&key).unwrap(), Some(1)),
+                                _ => {
+                                    assert_eq!(engine.get_at_seq(&key, u64::MAX).unwrap(), value);
+                                    assert_eq!(snapshot.get(&key).unwrap(), value);
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+
+            for round in 0..30u8 {
+                for i in 0..50u8 {
+                    engine.put(vec![i, round], vec![round]).unwrap();
+                }
+                engine.flush().unwrap();
+                engine.compact().unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_iter_outlives_writes_and_compaction() {
+        let dir = setup_test_dir("engine_iter_snapshot");
+        let options = EngineOptions {
+            max_open_files: Some(1),
+            ..EngineOptions::default()
+        };
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_with_options(&dir, options).unwrap()
+        };
+        let mut expected = BTreeMap::new();
+        for table in 0..3u8 {
+            for i in 0..20u8 {
+                engine.put(vec![i, table], vec![table]).unwrap();
+                expected.insert(vec![i, table], vec![table]);
+            }
+            engine.flush().unwrap();
         }
+        engine.put(vec![100], vec![1]).unwrap();
+        expected.insert(vec![100], vec![1]);
+        let table_files = |dir: &Path| {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "sst"))
+                .collect::<BTreeSet<_>>()
+        };
+        let original_files = table_files(&dir);
 
-        let mut mt = self.active_memtable.write().unwrap();
-        mt.put(key, value);
-
-        if mt.is_full() {
-            drop(mt);
-            self.flush()?;
+        let iter = engine.iter().unwrap();
+        for i in 0..20u8 {
+            engine.delete(vec![i, 0]).unwrap();
+            engine.put(vec![i, 1], vec![42]).unwrap();
         }
+        engine.put(vec![100], vec![2]).unwrap();
+        engine.flush().unwrap();
+        engine.compact().unwrap();
+        assert_eq!(engine.sstables.read().unwrap().len(), 1);
+        // The replaced files stay until the iterator is done with them.
+        assert!(table_files(&dir).is_superset(&original_files));
 
-        Ok(())
+        let seen: BTreeMap<_, _> = iter.map(Result::unwrap).collect();
+        assert_eq!(seen, expected);
+        assert!(table_files(&dir).is_disjoint(&original_files));
+        assert_eq!(engine.get(&[0, 0]).unwrap(), None);
+        assert_eq!(engine.get(&[100]).unwrap(), Some(vec![2]));
+        let _ = std::fs::remove_dir_all(dir);
     }
 
-    /// Retrieves a value by its key.
-    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
-        {
-            let mt = self.active_memtable.read().unwrap();
-            if let Some(entry) = mt.get(key) {
-                return match entry {
-                    Entry::Value(v) => Ok(Some(v.clone())),
-                    Entry::Tombstone => Ok(None),
-                };
+    #[test]
+    fn test_scan_shards_cover_the_store_once() {
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_in_memory(1 << 20)
+        };
+        for table in 0..4u32 {
+            for i in 0..500u32 {
+                let key = format!("key{:05}", i * 7 % 2000 + table).into_bytes();
+                engine.put(key, table.to_le_bytes().to_vec()).unwrap();
             }
+            engine.flush().unwrap();
         }
+        for i in (0..2000u32).step_by(13) {
+            engine.delete(format!("key{:05}", i).into_bytes()).unwrap();
+            engine
+                .put(format!("key{:05}x", i).into_bytes(), vec![9])
+                .unwrap();
+        }
+        let full: Vec<_> = engine.iter().unwrap().map(Result::unwrap).collect();
 
-        let ssts = self.sstables.read().unwrap();
-        for sst in ssts.iter() {
-            if let Some(val) = sst.get(key)? {
-                return Ok(Some(val));
+        for n in [1, 4, 7, 10_000] {
+            let shards = engine.scan_shards(n).unwrap();
+            assert_eq!(shards.len(), n);
+            let outputs: Vec<Vec<_>> = std::thread::scope(|scope| {
+                let workers: Vec<_> = shards
+                    .into_iter()
+                    .map(|shard| scope.spawn(move || shard.map(Result::unwrap).collect()))
+                    .collect();
+                workers.into_iter().map(|w| w.join().unwrap()).collect()
+            });
+            if n == 4 {
+                // The split points spread the keys over every shard.
+                for output in &outputs {
+                    assert!(output.len() > full.len() / 8, "{} records", output.len());
+                }
             }
+            assert_eq!(outputs.concat(), full, "{} shards", n);
         }
-
-        Ok(None)
+        assert_eq!(
+            engine.scan_shards(0).err().unwrap().kind(),
+            io::ErrorKind::InvalidInput
+        );
     }
 
-    /// Marks a key as deleted.
-    pub fn delete(&self, key: Vec<u8>) -> io::Result<()> {
-        {
-            let mut wal = self.wal.write().unwrap();
-            wal.append(&WalEntry::Delete { key: key.clone() })?;
+    #[test]
+    fn test_bloom_stats() {
+        let engine = Engine::open_in_memory(1024 * 1024);
+        for i in 0..1000u32 {
+            engine
+                .put(format!("present{}", i).into_bytes(), vec![1])
+                .unwrap();
         }
+        engine.flush().unwrap();
+        assert_eq!(
+            engine.stats(),
+            EngineStats {
+                sstable_count: 1,
+                flush_bytes_written: engine.sstables.read().unwrap()[0].size(),
+                ..EngineStats::default()
+            }
+        );
 
-        let mut mt = self.active_memtable.write().unwrap();
-        mt.delete(key);
+        for i in 0..1000u32 {
+            assert!(
+                engine
+                    .get(format!("present{}", i).as_bytes())
+                    .unwrap()
+                    .is_some()
+            );
+        }
+        let stats = engine.stats();
+        assert_eq!(stats.bloom_true_positives, 1000);
+        assert_eq!(stats.bloom_negatives + stats.bloom_false_positives, 0);
 
-        if mt.is_full() {
-            drop(mt);
-            self.flush()?;
+        for i in 0..1000u32 {
+            assert_eq!(engine.get(format!("absent{}", i).as_bytes()).unwrap(), None);
         }
+        let stats = engine.stats();
+        assert_eq!(stats.bloom_true_positives, 1000);
+        assert_eq!(stats.bloom_negatives + stats.bloom_false_positives, 1000);
+        // The filter is sized for a 1% false positive rate at 1000 keys.
+        assert!(stats.bloom_false_positives > 0);
+        assert!(stats.bloom_false_positives < 50, "{:?}", stats);
 
-        Ok(())
+        // Keys answered by the MemTable never reach a bloom filter.
+        engine.put(b"fresh".to_vec(), vec![2]).unwrap();
+        engine.get(b"fresh").unwrap();
+        assert_eq!(engine.stats(), stats);
     }
 
-    /// Manually triggers a flush of the current MemTable to an SSTable.
-    pub fn flush(&self) -> io::Result<()> {
-        let mut mt = self.active_memtable.write().unwrap();
-        if mt.approximate_size() == 0 {
-            return Ok(());
-        }
+    #[test]
+    fn test_export_memtable_snapshot_leaves_engine_untouched() {
+        let root = setup_test_dir("engine_export_memtable");
+        let (dir, export) = (root.join("data"), root.join("exported.sst"));
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open(&dir, 1 << 20).unwrap()
+        };
+        engine.put(b"flushed".to_vec(), b"old".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        engine.delete(b"flushed".to_vec()).unwrap();
+        let manifest = read_manifest(&dir).unwrap();
 
-        let sstable_id = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
+        let metadata = engine.export_memtable_snapshot(&export).unwrap();
+        assert_eq!(metadata.path, export);
+        assert_eq!(metadata.first_key, b"a");
+        assert_eq!(metadata.last_key, b"flushed");
+        let table = SSTable::open(&export).unwrap();
+        let records: Vec<(Vec<u8>, Entry)> = table
+            .iter()
             .unwrap()
-            .as_nanos();
-        let sst_path = self.dir.join(format!("{:020}.sst", sstable_id));
+            .map(|r| r.map(|(key, _, entry)| (key, entry)))
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (b"a".to_vec(), Entry::Value(b"1".to_vec())),
+                (b"b".to_vec(), Entry::Value(b"2".to_vec())),
+                (b"flushed".to_vec(), Entry::Tombstone),
+            ]
+        );
+        assert_eq!(table.level(), 0);
 
-        let builder = SSTableBuilder::new(&sst_path, 16)?;
-        builder.build(&mt)?;
+        // The engine neither loaded the table nor gave up its MemTable.
+        assert_eq!(engine.stats().sstable_count, 1);
+        assert_eq!(sstable_files(&dir).len(), 1);
+        assert_eq!(read_manifest(&dir).unwrap(), manifest);
+        assert_eq!(engine.active_memtable.read().unwrap().iter().count(), 3);
+        engine.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(engine.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(engine.get(b"flushed").unwrap(), None);
+        assert_eq!(table.record_count(), 3);
+        drop(engine);
 
-        {
-            let mut ssts = self.sstables.write().unwrap();
-            ssts.insert(0, Arc::new(SSTable::open(&sst_path)?));
+        // The WAL still holds the exported writes.
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_checkpoint_keeps_only_state_at_checkpoint_time() {
+        let root = setup_test_dir("checkpoint");
+        let (dir, target) = (root.join("store"), root.join("checkpoint"));
+        let engine = Engine::open(&dir, 1 << 20).unwrap();
+        for i in 0..10u8 {
+            engine.put(vec![i], vec![i]).unwrap();
+            if i % 3 == 2 {
+                engine.flush().unwrap();
+            }
         }
+        engine.delete(vec![0]).unwrap();
 
-        mt.clear();
-        let mut wal = self.wal.write().unwrap();
-        wal.truncate()?;
+        engine.checkpoint(&target).unwrap();
+        engine.put(vec![1], b"later".to_vec()).unwrap();
+        engine.put(vec![20], vec![20]).unwrap();
+        engine.delete(vec![2]).unwrap();
+        engine.flush().unwrap();
+        engine.compact().unwrap();
+        assert_eq!(
+            engine.checkpoint(&target).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
 
-        self.check_compaction();
+        let copy = Engine::open(&target, 1 << 20).unwrap();
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = (1..10u8).map(|i| (vec![i], vec![i])).collect();
+        assert_eq!(
+            copy.iter()
+                .unwrap()
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap(),
+            expected
+        );
+        assert_eq!(std::fs::metadata(target.join(WAL_FILE)).unwrap().len(), 0);
 
-        Ok(())
+        // The copy is independent of the original in both directions.
+        copy.put(vec![3], b"copy".to_vec()).unwrap();
+        assert_eq!(engine.get(&[3]).unwrap(), Some(vec![3]));
+        assert_eq!(engine.get(&[1]).unwrap(), Some(b"later".to_vec()));
+        assert_eq!(engine.get(&[2]).unwrap(), None);
+        drop(engine);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(copy.get(&[2]).unwrap(), Some(vec![2]));
+        assert_eq!(copy.get(&[3]).unwrap(), Some(b"copy".to_vec()));
+        assert_eq!(
+            Engine::open_in_memory(1 << 20)
+                .checkpoint(root.join("memory"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::Unsupported
+        );
+        let _ = std::fs::remove_dir_all(root);
     }
 
-    /// Manually triggers a full compaction of all current SSTables.
-    pub fn compact(&self) -> io::Result<()> {
-        let to_compact = {
-            let ssts = self.sstables.read().unwrap();
-            if ssts.len() < 2 {
-                return Ok(());
+    #[test]
+    fn test_lazy_index_defers_loading_until_a_table_is_read() {
+        let dir = setup_test_dir("lazy_index");
+        {
+            let engine = Engine::open(&dir, 1 << 20).unwrap();
+            for table in 0..3u8 {
+                for i in 0..50u8 {
+                    engine.put(vec![table, i], vec![i]).unwrap();
+                }
+                engine.flush().unwrap();
             }
-            ssts.clone()
-        };
+        }
 
-        let sstable_id = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        let output_path = self.dir.join(format!("{:020}.compact.sst", sstable_id));
+        let options = EngineOptions {
+            lazy_index: true,
+            ..EngineOptions::default()
+        };
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_with_options(&dir, options).unwrap()
+        };
+        let loaded = |engine: &Engine| -> Vec<bool> {
+            let tables = engine.sstables.read().unwrap();
+            tables.iter().map(|t| t.index_loaded()).collect()
+        };
+        assert_eq!(loaded(&engine), vec![false; 3]);
 
-        crate::compaction::compact(&to_compact, &output_path)?;
+        // Each table's bloom filter rules out the keys of the others.
+        assert_eq!(engine.get(&[1, 7]).unwrap(), Some(vec![7]));
+        assert_eq!(loaded(&engine).iter().filter(|&&l| l).count(), 1);
+        assert_eq!(engine.range(vec![0]..vec![3]).unwrap().count(), 150);
+        assert_eq!(loaded(&engine), vec![true; 3]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
 
-        let new_sst = SSTable::open(&output_path)?;
+    #[cfg(feature = "direct-io")]
+    #[test]
+    fn test_direct_io_reads_aligned_tables() {
+        let dir = setup_test_dir("direct_io");
+        let options = || EngineOptions {
+            direct_io: true,
+            table: TableOptions {
+                sparse_interval: 4,
+                block_alignment: Some(512),
+                ..TableOptions::default()
+            },
+            ..EngineOptions::default()
+        };
         {
-            let mut ssts = self.sstables.write().unwrap();
-            let compacted_paths: std::collections::HashSet<_> =
-                to_compact.iter().map(|s| s.path().to_path_buf()).collect();
-            ssts.retain(|s| !compacted_paths.contains(s.path()));
-            ssts.push(Arc::new(new_sst));
+            let engine = Engine::open_with_options(&dir, options()).unwrap();
+            for i in 0..100u8 {
+                engine.put(vec![i], vec![i; i as usize]).unwrap();
+            }
+            engine.flush().unwrap();
         }
 
-        Ok(())
-    }
-
-    fn check_compaction(&self) {
-        if self.compaction_running.load(Ordering::SeqCst) {
-            return;
+        let engine = Engine::open_with_options(&dir, options()).unwrap();
+        for i in (0..100u8).step_by(7) {
+            assert_eq!(engine.get(&[i]).unwrap(), Some(vec![i; i as usize]));
         }
+        assert_eq!(engine.range(vec![10]..vec![60]).unwrap().count(), 50);
+        let tables = engine.sstables.read().unwrap();
+        let size = std::fs::metadata(tables[0].path()).unwrap().len();
+        assert!(size.is_multiple_of(512));
+        drop(tables);
+        let _ = std::fs::remove_dir_all(dir);
+    }
 
-        let sstable_count = {
-            let ssts = self.sstables.read().unwrap();
-            ssts.len()
+    #[test]
+    fn test_filters_rule_out_misses_across_tables() {
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_in_memory(1 << 20)
         };
-
-        if sstable_count >= 4 {
-            if self
-                .compaction_running
-                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-                .is_err()
-            {
-                return;
+        for table in 0..5u8 {
+            for i in 0..200u8 {
+                engine.put(vec![table, i], vec![table]).unwrap();
             }
+            engine.flush().unwrap();
+        }
+        engine.delete(vec![0, 7]).unwrap();
+        engine.flush().unwrap();
 
-            let sst_ref = Arc::clone(&self.sstables);
-            let dir = self.dir.clone();
-            let running_flag = Arc::clone(&self.compaction_running);
-
-            std::thread::spawn(move || {
-                let to_compact = {
-                    let ssts = sst_ref.read().unwrap();
-                    ssts.clone()
-                };
-
-                let sstable_id = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_nanos();
-                let output_path = dir.join(format!("{:020}.compact.sst", sstable_id));
-
-                if let Err(e) = crate::compaction::compact(&to_compact, &output_path) {
-                    eprintln!("Compaction failed: {}", e);
-                    running_flag.store(false, Ordering::SeqCst);
-                    return;
-                }
+        for i in 0..1000u32 {
+            let key = format!("absent{}", i).into_bytes();
+            assert_eq!(engine.get(&key).unwrap(), None);
+            assert_eq!(engine.try_get(&key).unwrap(), None);
+        }
+        // Each miss consults every table's filter exactly once, whichever path it takes.
+        let stats = engine.stats();
+        assert_eq!(
+            stats.bloom_negatives + stats.bloom_false_positives,
+            2 * 1000 * 6
+        );
+        assert_eq!(stats.bloom_true_positives, 0);
 
-                match SSTable::open(&output_path) {
-                    Ok(new_sst) => {
-                        let mut ssts = sst_ref.write().unwrap();
-                        let compacted_paths: std::collections::HashSet<_> =
-                            to_compact.iter().map(|s| s.path().to_path_buf()).collect();
-                        ssts.retain(|s| !compacted_paths.contains(s.path()));
-                        ssts.push(Arc::new(new_sst));
-                    }
-                    Err(e) => eprintln!("Failed to open compacted SSTable: {}", e),
-                }
-                running_flag.store(false, Ordering::SeqCst);
-            });
+        // Keys are still found behind tables whose filters rule them out.
+        for table in 0..5u8 {
+            assert_eq!(engine.get(&[table, 199]).unwrap(), Some(vec![table]));
         }
+        assert_eq!(engine.get(&[0, 7]).unwrap(), None);
+        assert_eq!(engine.try_get(&[0, 7]).unwrap(), None);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_compaction_stats_count_table_bytes() {
+        let dir = setup_test_dir("engine_compaction_bytes");
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open(&dir, 1 << 20).unwrap()
+        };
+        let file_sizes = || {
+            sstable_files(&dir)
+                .iter()
+                .map(|path| std::fs::metadata(path).unwrap().len())
+                .sum::<u64>()
+        };
+        for table in 0..3u8 {
+            for i in 0..50u8 {
+                engine.put(vec![i], vec![table; 20]).unwrap();
+            }
+            engine.flush().unwrap();
+        }
+        let flushed = file_sizes();
+        let stats = engine.stats();
+        assert_eq!(stats.flush_bytes_written, flushed);
+        assert_eq!(stats.compaction_bytes_read, 0);
+        assert_eq!(stats.compaction_bytes_written, 0);
 
-    fn setup_test_dir(name: &str) -> PathBuf {
-        let mut path = std::env::temp_dir();
-        path.push(format!(
-            "lsm_test_{}_{}",
-            name,
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_nanos()
-        ));
-        std::fs::create_dir_all(&path).unwrap();
-        path
+        engine.compact().unwrap();
+        let compacted = file_sizes();
+        assert!(compacted < flushed);
+        let stats = engine.stats();
+        assert_eq!(stats.flush_bytes_written, flushed);
+        assert_eq!(stats.compaction_bytes_read, flushed);
+        assert_eq!(stats.compaction_bytes_written, compacted);
+        let _ = std::fs::remove_dir_all(dir);
     }
 
     #[test]
-    fn test_put_get_basic() {
-        let dir = setup_test_dir("engine_basic");
-        let engine = Engine::open(&dir, 1024).unwrap();
-        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
-        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+    fn test_table_options_apply_to_new_tables() {
+        let dir = setup_test_dir("engine_table_options");
+        let options = EngineOptions {
+            table: TableOptions {
+                sparse_interval: 2,
+                truncate_index_keys: true,
+                ..TableOptions::default()
+            },
+            ..EngineOptions::default()
+        };
+        let engine = Engine::open_with_options(&dir, options).unwrap();
+        for i in 0..6u8 {
+            engine.put(vec![b'k', i], vec![i]).unwrap();
+        }
+        engine.flush().unwrap();
+        engine.put(vec![b'k', 9], vec![9]).unwrap();
+        engine.flush().unwrap();
+        engine.compact().unwrap();
+
+        let ssts = engine.sstables.read().unwrap().clone();
+        assert_eq!(ssts.len(), 1);
+        // Every second key, truncated to the byte that separates it from its predecessor.
+        let expected: Vec<&[u8]> = vec![b"", b"k\x02", b"k\x04", b"k\x09"];
+        assert_eq!(ssts[0].index_keys(), expected);
+        assert_eq!(engine.get(&[b'k', 5]).unwrap(), Some(vec![5]));
         let _ = std::fs::remove_dir_all(dir);
     }
 
     #[test]
-    fn test_get_nonexistent() {
-        let dir = setup_test_dir("engine_nonexistent");
-        let engine = Engine::open(&dir, 1024).unwrap();
-        assert_eq!(engine.get(b"k1").unwrap(), None);
-        let _ = std::fs::remove_dir_all(dir);
+    fn test_flush_and_wait_compaction_installs_output() {
+        let engine = Engine::open_in_memory(1 << 20);
+        for i in 0..COMPACTION_TRIGGER as u8 {
+            engine.put(vec![i], vec![i]).unwrap();
+            engine.flush_and_wait_compaction().unwrap();
+        }
+
+        // The last flush reached the trigger; its compaction is done by the time we return.
+        assert!(!engine.compaction.as_ref().unwrap().is_busy());
+        assert!(engine.sstables.read().unwrap().len() < COMPACTION_TRIGGER);
+        for i in 0..COMPACTION_TRIGGER as u8 {
+            assert_eq!(engine.get(&[i]).unwrap(), Some(vec![i]));
+        }
+        // Nothing left to wait for.
+        engine.wait_for_background().unwrap();
     }
 
     #[test]
-    fn test_update() {
-        let dir = setup_test_dir("engine_update");
-        let engine = Engine::open(&dir, 1024).unwrap();
-        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
-        engine.put(b"k1".to_vec(), b"v2".to_vec()).unwrap();
-        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v2".to_vec()));
-        let _ = std::fs::remove_dir_all(dir);
+    fn test_compact_newest_leaves_unrelated_tables() {
+        let engine = engine_with_tables(&[
+            &[("a", "1"), ("c", "1")],
+            &[("l", "1"), ("m", "1")],
+            &[("x", "1"), ("z", "1")],
+            &[("b", "2"), ("m", "2")],
+        ]);
+        let before = engine.sstables.read().unwrap().clone();
+
+        let produced = engine.compact_newest(1).unwrap();
+        assert_eq!(produced.len(), 1);
+        let after = engine.sstables.read().unwrap().clone();
+        assert_eq!(after.len(), 3);
+        // Only the table sharing "m" was merged; the others were not rewritten.
+        assert!(Arc::ptr_eq(&after[1], &before[1]));
+        assert!(Arc::ptr_eq(&after[2], &before[3]));
+        for (key, value) in [("a", "1"), ("b", "2"), ("l", "1"), ("m", "2"), ("z", "1")] {
+            assert_eq!(
+                engine.get(key.as_bytes()).unwrap(),
+                Some(value.as_bytes().to_vec())
+            );
+        }
     }
 
     #[test]
-    fn test_delete() {
-        let dir = setup_test_dir("engine_delete");
-        let engine = Engine::open(&dir, 1024).unwrap();
-        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
-        engine.delete(b"k1".to_vec()).unwrap();
-        assert_eq!(engine.get(b"k1").unwrap(), None);
-        let _ = std::fs::remove_dir_all(dir);
+    fn test_optimize_collapses_store_into_one_table() {
+        let engine = engine_with_tables(&[
+            &[("a", "1"), ("b", "1"), ("c", "1")],
+            &[("a", "2"), ("d", "1")],
+            &[("b", "2"), ("e", "1")],
+        ]);
+        engine.delete(b"c".to_vec()).unwrap();
+        engine.flush().unwrap();
+        engine.delete(b"d".to_vec()).unwrap();
+        engine.put(b"a".to_vec(), b"3".to_vec()).unwrap();
+
+        let stats = engine.optimize().unwrap();
+        assert_eq!(stats.tables_before, 5);
+        assert_eq!(stats.tables_after, 1);
+        assert!(stats.bytes_reclaimed() > 0);
+
+        let sstables = engine.sstables.read().unwrap().clone();
+        assert_eq!(sstables.len(), 1);
+        assert_eq!(sstables[0].tombstone_count(), 0);
+        assert_eq!(sstables[0].record_count(), 3);
+        assert_eq!(stats.bytes_after, sstables[0].size());
+        for (key, value) in [("a", Some("3")), ("b", Some("2")), ("c", None)] {
+            assert_eq!(
+                engine.get(key.as_bytes()).unwrap(),
+                value.map(|v| v.as_bytes().to_vec())
+            );
+        }
+        assert_eq!(engine.get(b"d").unwrap(), None);
+        assert_eq!(engine.get(b"e").unwrap(), Some(b"1".to_vec()));
+
+        let again = engine.optimize().unwrap();
+        assert_eq!((again.tables_before, again.tables_after), (1, 1));
     }
 
     #[test]
-    fn test_delete_nonexistent() {
-        let dir = setup_test_dir("engine_del_nonexistent");
-        let engine = Engine::open(&dir, 1024).unwrap();
-        engine.delete(b"k1".to_vec()).unwrap();
-        let _ = std::fs::remove_dir_all(dir);
+    fn test_compaction_output_uses_deeper_level_bloom_rate() {
+        let engine = Engine {
+            compaction: None,
+            table_options: TableOptions {
+                level_bloom_false_positive_rates: vec![0.1, 0.001],
+                ..TableOptions::default()
+            },
+            ..Engine::open_in_memory(1 << 20)
+        };
+        for i in 0..2u8 {
+            engine.put(vec![i], vec![i]).unwrap();
+            engine.flush().unwrap();
+        }
+        let flushed = engine.sstables.read().unwrap()[0].clone();
+        assert_eq!(flushed.level(), 0);
+
+        engine.compact().unwrap();
+        let compacted = engine.sstables.read().unwrap()[0].clone();
+        assert_eq!(compacted.level(), 1);
+        let expected_bits = |rate| BloomFilter::new(1000, rate).num_bits();
+        assert_eq!(flushed.bloom_bits(), expected_bits(0.1));
+        assert_eq!(compacted.bloom_bits(), expected_bits(0.001));
+        assert!(compacted.bloom_bits() > flushed.bloom_bits());
+
+        // Deeper levels than configured keep the last rate.
+        engine.put(vec![9], vec![9]).unwrap();
+        engine.flush().unwrap();
+        engine.compact().unwrap();
+        let deeper = engine.sstables.read().unwrap()[0].clone();
+        assert_eq!(deeper.level(), 2);
+        assert_eq!(deeper.bloom_bits(), expected_bits(0.001));
     }
 
     #[test]
-    fn test_flush_trigger() {
-        let dir = setup_test_dir("engine_flush");
-        let engine = Engine::open(&dir, 10).unwrap();
-        engine.put(b"key1".to_vec(), b"val1".to_vec()).unwrap();
+    fn test_bloom_bits_per_key_overrides_rates() {
+        let engine = Engine {
+            compaction: None,
+            table_options: TableOptions {
+                level_bloom_false_positive_rates: vec![0.1, 0.001],
+                bloom_bits_per_key: Some(12.0),
+                ..TableOptions::default()
+            },
+            ..Engine::open_in_memory(1 << 20)
+        };
+        for i in 0..2u8 {
+            engine.put(vec![i], vec![i]).unwrap();
+            engine.flush().unwrap();
+        }
+        engine.compact().unwrap();
+        let compacted = engine.sstables.read().unwrap()[0].clone();
+        assert_eq!(compacted.level(), 1);
+        assert_eq!(
+            compacted.bloom_bits(),
+            BloomFilter::with_bits_per_key(1000, 12.0).num_bits()
+        );
+    }
 
-        let sstable_count = || {
-            std::fs::read_dir(&dir)
-                .unwrap()
-                .filter_map(|e| e.ok())
-                .filter(|e| e.path().extension().and_then(|ext| ext.to_str()) == Some("sst"))
-                .count()
+    #[test]
+    fn test_estimate_range_size() {
+        let engine = Engine {
+            compaction: None,
+            table_options: TableOptions {
+                sparse_interval: 1,
+                ..TableOptions::default()
+            },
+            ..Engine::open_in_memory(1 << 20)
         };
+        let key = |i: u32| format!("k{:02}", i).into_bytes();
+        // Each record is 4 + 3 (key) + 8 + 4 + 5 (value) + 1 (tag) bytes on disk; the
+        // MemTable does not count the tag.
+        for i in 0..20 {
+            engine.put(key(i), vec![0; 5]).unwrap();
+        }
+        engine.flush().unwrap();
+        for i in 10..30 {
+            engine.put(key(i), vec![0; 5]).unwrap();
+        }
 
-        assert_eq!(sstable_count(), 0);
-        engine.put(b"key2".to_vec(), b"val2".to_vec()).unwrap();
-        assert!(sstable_count() >= 1);
-        let _ = std::fs::remove_dir_all(dir);
+        // With an index point per record, tables are estimated exactly.
+        assert_eq!(engine.estimate_range_size(&key(0), &key(5)), 5 * 25);
+        assert_eq!(
+            engine.estimate_range_size(&key(15), &key(25)),
+            5 * 25 + 10 * 24
+        );
+        assert_eq!(engine.estimate_range_size(&key(25), &key(99)), 5 * 24);
+        assert_eq!(engine.estimate_range_size(&key(5), &key(5)), 0);
     }
 
     #[test]
-    fn test_get_after_flush() {
-        let dir = setup_test_dir("engine_get_flush");
-        let engine = Engine::open(&dir, 10).unwrap();
-        engine.put(b"key1".to_vec(), b"val1".to_vec()).unwrap();
-        engine.put(b"key2".to_vec(), b"val2".to_vec()).unwrap();
-        assert_eq!(engine.get(b"key1").unwrap(), Some(b"val1".to_vec()));
-        let _ = std::fs::remove_dir_all(dir);
+    fn test_keys_count_in_range() {
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_in_memory(1 << 20)
+        };
+        let interval = engine.table_options.sparse_interval as u64;
+        let key = |i: u32| format!("k{:03}", i).into_bytes();
+        for i in 0..200 {
+            engine.put(key(i), vec![0; 5]).unwrap();
+        }
+        engine.flush().unwrap();
+        for i in 300..400 {
+            engine.put(key(i), vec![0; 5]).unwrap();
+        }
+        engine.flush().unwrap();
+        // Counted exactly from the MemTable.
+        for i in 500..520 {
+            engine.put(key(i), vec![0; 5]).unwrap();
+        }
+
+        for (start, end, actual) in [
+            (0, 999, 320),
+            (50, 150, 100),
+            (150, 350, 100),
+            (510, 515, 5),
+        ] {
+            let estimate = engine.keys_count_in_range(&key(start), &key(end));
+            assert!(
+                estimate.abs_diff(actual) <= 2 * 2 * interval,
+                "[{}, {}): estimated {} for {} keys",
+                start,
+                end,
+                estimate,
+                actual
+            );
+        }
+        assert_eq!(engine.keys_count_in_range(&key(510), &key(515)), 5);
+        assert_eq!(engine.keys_count_in_range(&key(200), &key(300)), 0);
+        assert_eq!(engine.keys_count_in_range(&key(5), &key(5)), 0);
     }
 
     #[test]
-    fn test_recovery_from_wal() {
-        let dir = setup_test_dir("engine_recovery_wal");
-        {
-            let engine = Engine::open(&dir, 1024).unwrap();
-            engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+    fn test_compactions_share_one_worker_thread() {
+        let engine = Engine::open_in_memory(1 << 20);
+        for i in 0..40u8 {
+            engine.put(vec![i], vec![i]).unwrap();
+            engine.flush_and_wait_compaction().unwrap();
         }
 
-        let engine = Engine::open(&dir, 1024).unwrap();
-        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
-        let _ = std::fs::remove_dir_all(dir);
+        let runs = engine
+            .compaction
+            .as_ref()
+            .unwrap()
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .runs
+            .clone();
+        assert!(runs.len() >= 10, "only {} compactions ran", runs.len());
+        assert!(runs.iter().all(|id| *id == runs[0]));
+        assert_ne!(runs[0], std::thread::current().id());
+        for i in 0..40u8 {
+            assert_eq!(engine.get(&[i]).unwrap(), Some(vec![i]));
+        }
+        engine.close().unwrap();
     }
 
     #[test]
-    fn test_recovery_with_flush() {
-        let dir = setup_test_dir("engine_recovery_flush");
-        {
-            let engine = Engine::open(&dir, 10).unwrap();
-            engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
-            engine.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+    fn test_compaction_worker_keeps_first_unreported_error() {
+        let mut runs = 0;
+        let worker = CompactionWorker::spawn(move || {
+            runs += 1;
+            Err(io::Error::other(format!("compaction {} failed", runs)))
+        });
+        worker.request();
+        while worker.is_busy() {
+            std::thread::sleep(Duration::from_millis(1));
         }
+        worker.request();
+        let err = worker.wait().unwrap_err();
+        assert_eq!(err.to_string(), "compaction 1 failed");
 
-        let engine = Engine::open(&dir, 10).unwrap();
-        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
-        assert_eq!(engine.get(b"k2").unwrap(), Some(b"v2".to_vec()));
-        let _ = std::fs::remove_dir_all(dir);
+        worker.request();
+        let err = worker.wait().unwrap_err();
+        assert_eq!(err.to_string(), "compaction 3 failed");
+        assert!(worker.wait().is_ok());
     }
 
     #[test]
-    fn test_multiple_sstables_read() {
-        let dir = setup_test_dir("engine_multiple");
-        let engine = Engine::open(&dir, 10).unwrap();
+    fn test_compaction_debt_rises_with_flushes_and_clears_on_compaction() {
+        let engine = Engine {
+            compaction: None,
+            ..Engine::open_in_memory(1 << 20)
+        };
+        let mut debts = Vec::new();
+        for i in 0..8u8 {
+            engine.put(vec![i], vec![i; 100]).unwrap();
+            engine.flush().unwrap();
+            debts.push(engine.compaction_debt());
+        }
+        let sizes: Vec<u64> = engine
+            .sstables
+            .read()
+            .unwrap()
+            .iter()
+            .map(|sst| sst.size())
+            .collect();
+        // Three tables are below the trigger of four; from then on the debt never falls, and
+        // grows whenever another merge becomes due.
+        assert_eq!(debts[..3], [0, 0, 0]);
+        assert_eq!(debts[3], sizes[4..].iter().sum::<u64>());
+        assert!(debts.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(debts[6] > debts[5]);
+        // Eight tables take two merges: the oldest four, then their output with the next
+        // three.
+        let oldest: u64 = sizes[4..].iter().sum();
+        assert_eq!(debts[7], oldest + oldest + sizes[1..4].iter().sum::<u64>());
 
-        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
-        engine.put(b"k1_f".to_vec(), b"v".to_vec()).unwrap();
+        engine.compact().unwrap();
+        assert_eq!(engine.compaction_debt(), 0);
+        assert_eq!(CompactionPriority::Eager.debt(&sizes[..1]), 0);
+        assert_eq!(
+            CompactionPriority::Eager.debt(&sizes[..2]),
+            sizes[0] + sizes[1]
+        );
+    }
 
-        engine.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
-        engine.put(b"k2_f".to_vec(), b"v".to_vec()).unwrap();
+    #[test]
+    fn test_compaction_priority_trades_rewrites_for_table_count() {
+        // Returns the table count and the number of background compactions after the same
+        // 32 flushes.
+        let run = |priority| {
+            let dir = setup_test_dir(&format!("engine_priority_{:?}", priority));
+            let options = EngineOptions {
+                compaction_priority: priority,
+                ..EngineOptions::default()
+            };
+            let engine = Engine::open_with_options(&dir, options).unwrap();
+            for i in 0..32u8 {
+                engine.put(vec![i], vec![i]).unwrap();
+                engine.flush_and_wait_compaction().unwrap();
+            }
+            for i in 0..32u8 {
+                assert_eq!(engine.get(&[i]).unwrap(), Some(vec![i]));
+            }
+            let tables = engine.sstables.read().unwrap().len();
+            let runs = engine
+                .compaction
+                .as_ref()
+                .unwrap()
+                .shared
+                .state
+                .lock()
+                .unwrap()
+                .runs
+                .len();
+            engine.close().unwrap();
+            let _ = std::fs::remove_dir_all(dir);
+            (tables, runs)
+        };
 
-        assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
-        assert_eq!(engine.get(b"k2").unwrap(), Some(b"v2".to_vec()));
-        let _ = std::fs::remove_dir_all(dir);
+        let (lazy_tables, lazy_runs) = run(CompactionPriority::Lazy);
+        let (balanced_tables, balanced_runs) = run(CompactionPriority::Balanced);
+        let (eager_tables, eager_runs) = run(CompactionPriority::Eager);
+
+        // Lazier modes rewrite data less often but leave more tables for reads to probe.
+        assert!(lazy_runs < balanced_runs && balanced_runs < eager_runs);
+        assert!(lazy_tables > balanced_tables && balanced_tables > eager_tables);
+        assert_eq!((lazy_tables, lazy_runs), (4, 4));
+        assert_eq!((balanced_tables, balanced_runs), (2, 10));
+        assert_eq!((eager_tables, eager_runs), (1, 31));
     }
 
     #[test]
-    fn test_tombstone_across_sstables() {
-        let dir = setup_test_dir("engine_tombstone");
-        let engine = Engine::open(&dir, 10).unwrap();
+    fn test_flush_merges_hot_keys_into_newest_table() {
+        // Returns the engine's table count after overwriting the same 10 keys over 20
+        // flushes, checking the data along the way.
+        let run = |flush_merge| {
+            let dir = setup_test_dir("engine_flush_merge");
+            let options = EngineOptions {
+                flush_merge,
+                ..EngineOptions::default()
+            };
+            let engine = Engine {
+                compaction: None,
+                ..Engine::open_with_options(&dir, options).unwrap()
+            };
+            engine.put(b"cold".to_vec(), b"c".to_vec()).unwrap();
+            let mut snapshot = None;
+            for round in 0..20u8 {
+                for key in 0..10u8 {
+                    engine.put(vec![key], vec![round]).unwrap();
+                }
+                engine.delete(vec![round % 10]).unwrap();
+                engine.flush().unwrap();
+                if round == 5 {
+                    snapshot = Some(engine.snapshot());
+                }
+            }
 
-        engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
-        engine.put(b"f1".to_vec(), b"v".to_vec()).unwrap();
+            let snapshot = snapshot.unwrap();
+            for key in 0..10u8 {
+                let expected = (key != 9).then(|| vec![19]);
+                assert_eq!(engine.get(&[key]).unwrap(), expected);
+                let expected = (key != 5).then(|| vec![5]);
+                assert_eq!(snapshot.get(&[key]).unwrap(), expected);
+            }
+            assert_eq!(engine.get(b"cold").unwrap(), Some(b"c".to_vec()));
+            let tables = engine.sstables.read().unwrap().len();
+            drop(snapshot);
+            drop(engine);
 
-        engine.delete(b"k1".to_vec()).unwrap();
-        engine.put(b"f2".to_vec(), b"v".to_vec()).unwrap();
+            // Only the live tables are left on disk, and they reopen to the same data.
+            let engine = Engine::open(&dir, 1 << 20).unwrap();
+            assert_eq!(engine.sstables.read().unwrap().len(), tables);
+            assert_eq!(engine.get(&[0]).unwrap(), Some(vec![19]));
+            assert_eq!(engine.get(&[9]).unwrap(), None);
+            drop(engine);
+            let _ = std::fs::remove_dir_all(dir);
+            tables
+        };
 
-        assert_eq!(engine.get(b"k1").unwrap(), None);
-        let _ = std::fs::remove_dir_all(dir);
+        assert_eq!(run(None), 20);
+        assert_eq!(run(Some(FlushMergeOptions::default())), 1);
+        let strict = FlushMergeOptions {
+            max_table_size: 64,
+            ..FlushMergeOptions::default()
+        };
+        assert_eq!(run(Some(strict)), 20);
     }
 }
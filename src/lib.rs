@@ -4,13 +4,32 @@
 //! This engine supports efficient writes (via WAL and MemTable), persistent storage (SSTables),
 //! background compaction, and Bloom filters for optimized lookups.
 
+#[cfg(feature = "async")]
+pub mod async_engine;
 pub mod bloom;
+pub mod cache;
 pub mod compaction;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 pub mod engine;
 pub mod memtable;
+pub mod snapshot;
 pub mod sstable;
+pub mod storage;
+pub mod version;
 pub mod wal;
 
-pub use engine::Engine;
+#[cfg(feature = "async")]
+pub use async_engine::AsyncEngine;
+pub use cache::CacheManager;
+#[cfg(feature = "encryption")]
+pub use encryption::Encryption;
+pub use engine::{
+    CompactionPriority, CorruptionPolicy, Engine, EngineOptions, EngineStats, FlushMergeOptions,
+    OpenReport, OptimizeStats, ReadRetryPolicy, RepairReport, ScanShard, ScrubProgress, WriteBatch,
+};
 pub use memtable::{Entry, MemTable};
-pub use sstable::SSTable;
+pub use snapshot::Snapshot;
+pub use sstable::{SSTable, ValueCodec};
+pub use version::Version;
+pub use wal::WalSyncMode;
@@ -1,4 +1,16 @@
-use std::collections::BTreeMap;
+use crate::sstable::{SSTableBuilder, SSTableMetadata};
+use crate::version::Version;
+use std::collections::{BTreeMap, btree_map};
+use std::io;
+use std::ops::Bound;
+
+/// Bytes a `MemTable` accounts for each key on top of the key itself: its length and the
+/// sequence number, as they are framed in an SSTable record.
+pub const KEY_OVERHEAD: usize = 4 + 8;
+
+/// Bytes a `MemTable` accounts for each entry on top of its value: the value length field,
+/// which also marks tombstones.
+pub const ENTRY_OVERHEAD: usize = 4;
 
 /// Represents an entry in the storage engine.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -9,14 +21,33 @@ pub enum Entry {
     Tombstone,
 }
 
+impl Entry {
+    /// Returns the bytes this entry counts towards a `MemTable`'s size, excluding its key.
+    pub fn heap_size(&self) -> usize {
+        ENTRY_OVERHEAD
+            + match self {
+                Entry::Value(v) => v.len(),
+                Entry::Tombstone => 0,
+            }
+    }
+}
+
 /// An in-memory, ordered structure that stores key-value pairs.
 ///
 /// The `MemTable` uses a `BTreeMap` to maintain keys in sorted order, which is essential
 /// for efficient flushing to SSTables.
+///
+/// Every write is stamped with a sequence number, one higher than the last. Only the newest
+/// entry for each key is kept, together with the sequence number of the write that produced it
+/// and its metadata tag.
+///
+/// Cloning deep-copies the entries, giving an independent copy of the table at that moment.
+#[derive(Clone)]
 pub struct MemTable {
-    entries: BTreeMap<Vec<u8>, Entry>,
+    entries: BTreeMap<Vec<u8>, (Entry, u64, u8)>,
     approximate_size: usize,
     max_size: usize,
+    last_seqno: u64,
 }
 
 impl MemTable {
@@ -26,6 +57,7 @@ impl MemTable {
             entries: BTreeMap::new(),
             approximate_size: 0,
             max_size,
+            last_seqno: 0,
         }
     }
 
@@ -33,44 +65,86 @@ impl MemTable {
     ///
     /// Updates the approximate size of the table.
     pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        let key_len = key.len();
-        let val_len = value.len();
-        let size_diff = key_len + val_len;
-
-        if let Some(old_entry) = self.entries.insert(key, Entry::Value(value)) {
-            match old_entry {
-                Entry::Value(v) => {
-                    self.approximate_size -= v.len();
-                    self.approximate_size += val_len;
-                }
-                Entry::Tombstone => {
-                    self.approximate_size += val_len;
-                }
-            }
-        } else {
-            self.approximate_size += size_diff;
-        }
+        self.put_with_meta(key, value, 0);
+    }
+
+    /// Inserts or updates a key-value pair tagged with the application-defined `meta` byte.
+    pub fn put_with_meta(&mut self, key: Vec<u8>, value: Vec<u8>, meta: u8) {
+        self.insert(key, Entry::Value(value), meta);
+    }
+
+    /// Stores a key-value pair written at `version`, whose packed form becomes its sequence
+    /// number.
+    ///
+    /// Unlike the other writes, this one is dropped if the key already holds a newer
+    /// version, so that the last writer wins whatever order writes arrive in.
+    pub fn put_with_version(&mut self, key: Vec<u8>, value: Vec<u8>, meta: u8, version: Version) {
+        self.insert_versioned(key, Entry::Value(value), meta, version);
+    }
+
+    /// Marks a key as deleted at `version`; see [`MemTable::put_with_version`].
+    pub fn delete_with_version(&mut self, key: Vec<u8>, version: Version) {
+        self.insert_versioned(key, Entry::Tombstone, 0, version);
     }
 
     /// Retrieves an entry from the `MemTable` by its key.
     pub fn get(&self, key: &[u8]) -> Option<&Entry> {
-        self.entries.get(key)
+        self.entries.get(key).map(|(entry, _, _)| entry)
+    }
+
+    /// Retrieves an entry along with the sequence number of the write that produced it.
+    pub fn get_with_seqno(&self, key: &[u8]) -> Option<(&Entry, u64)> {
+        self.entries
+            .get(key)
+            .map(|(entry, seqno, _)| (entry, *seqno))
+    }
+
+    /// Retrieves an entry along with its metadata tag, which is `0` for tombstones and for
+    /// values written without one.
+    pub fn get_with_meta(&self, key: &[u8]) -> Option<(&Entry, u8)> {
+        self.entries.get(key).map(|(entry, _, meta)| (entry, *meta))
     }
 
     /// Marks a key as deleted by inserting a `Tombstone` entry.
     pub fn delete(&mut self, key: Vec<u8>) {
-        let key_len = key.len();
-        if let Some(old_entry) = self.entries.insert(key, Entry::Tombstone) {
-            match old_entry {
-                Entry::Value(v) => {
-                    self.approximate_size -= v.len();
-                }
-                Entry::Tombstone => {
-                    // Nothing to change in size
-                }
+        self.insert(key, Entry::Tombstone, 0);
+    }
+
+    /// Stores `entry` as the newest version of `key` under the next sequence number.
+    fn insert(&mut self, key: Vec<u8>, entry: Entry, meta: u8) {
+        let seqno = self.next_seqno();
+        self.insert_at(key, entry, meta, seqno);
+    }
+
+    /// Stores `entry` under the sequence number of `version`, unless `key` already holds a
+    /// newer one.
+    fn insert_versioned(&mut self, key: Vec<u8>, entry: Entry, meta: u8, version: Version) {
+        let seqno = version.to_seqno();
+        self.advance_seqno(seqno);
+        if self
+            .entries
+            .get(&key)
+            .is_some_and(|&(_, newest, _)| newest > seqno)
+        {
+            return;
+        }
+        self.insert_at(key, entry, meta, seqno);
+    }
+
+    /// Stores `entry` with sequence number `seqno` as the version of `key`, replacing any
+    /// other one.
+    ///
+    /// A key costs `KEY_OVERHEAD` plus its length and its entry `Entry::heap_size`, so the
+    /// size changes by the difference between the new and the replaced entry, or by the full
+    /// cost of a new key.
+    fn insert_at(&mut self, key: Vec<u8>, entry: Entry, meta: u8, seqno: u64) {
+        let key_cost = KEY_OVERHEAD + key.len();
+        let new_cost = entry.heap_size();
+        match self.entries.insert(key, (entry, seqno, meta)) {
+            Some((old_entry, _, _)) => {
+                self.approximate_size = self.approximate_size - old_entry.heap_size() + new_cost;
             }
-        } else {
-            self.approximate_size += key_len;
+            None => self.approximate_size += key_cost + new_cost,
         }
     }
 
@@ -80,8 +154,56 @@ impl MemTable {
     }
 
     /// Returns an iterator over the entries in the `MemTable`, sorted by key.
-    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry)> {
-        self.entries.iter()
+    pub fn iter(&self) -> Iter<'_> {
+        Iter(self.entries.iter())
+    }
+
+    /// Returns an iterator over the entries and their sequence numbers, sorted by key.
+    pub fn iter_with_seqno(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry, u64)> {
+        self.entries
+            .iter()
+            .map(|(key, (entry, seqno, _))| (key, entry, *seqno))
+    }
+
+    /// Returns an iterator over the entries with their sequence numbers and metadata tags,
+    /// sorted by key.
+    pub fn iter_with_meta(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry, u64, u8)> {
+        self.entries
+            .iter()
+            .map(|(key, (entry, seqno, meta))| (key, entry, *seqno, *meta))
+    }
+
+    /// Returns the entries as `(key, seqno, entry, meta)` records in key order, the stream
+    /// [`SSTableBuilder::build_from_iter`] consumes.
+    pub fn records(&self) -> impl Iterator<Item = (&[u8], u64, &Entry, u8)> {
+        self.entries
+            .iter()
+            .map(|(key, (entry, seqno, meta))| (key.as_slice(), *seqno, entry, *meta))
+    }
+
+    /// Writes every entry into `builder` and finishes the table.
+    pub fn merge_into(&self, builder: SSTableBuilder) -> io::Result<SSTableMetadata> {
+        builder.build_from_iter(self.records())
+    }
+
+    /// Returns the sequence number of the most recent write.
+    ///
+    /// This survives `clear`, so sequence numbers keep increasing across flushes.
+    pub fn last_seqno(&self) -> u64 {
+        self.last_seqno
+    }
+
+    /// Makes the next write use a sequence number above `seqno`.
+    ///
+    /// Used on recovery so that new writes are ordered after everything already persisted.
+    /// Sequence numbers never go backwards, so a lower `seqno` is ignored.
+    pub fn advance_seqno(&mut self, seqno: u64) {
+        self.last_seqno = self.last_seqno.max(seqno);
+    }
+
+    fn next_seqno(&mut self) -> u64 {
+        self.last_seqno += 1;
+        self.last_seqno
     }
 
     /// Returns the approximate size of the `MemTable` in bytes.
@@ -89,6 +211,58 @@ impl MemTable {
         self.approximate_size
     }
 
+    /// Returns the approximate size in bytes of the entries with keys in `[start, end)`,
+    /// counted the same way as [`MemTable::approximate_size`].
+    pub fn approximate_range_size(&self, start: &[u8], end: &[u8]) -> usize {
+        if start >= end {
+            return 0;
+        }
+        self.entries
+            .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+            .map(|(key, (entry, _, _))| KEY_OVERHEAD + key.len() + entry.heap_size())
+            .sum()
+    }
+
+    /// Returns an iterator over the entries with keys in `[start, end)`, sorted by key.
+    ///
+    /// The iterator is double-ended, so a range can be stepped from either end; the two ends
+    /// meet without yielding any entry twice.
+    pub fn range<'a>(
+        &'a self,
+        start: &[u8],
+        end: &[u8],
+    ) -> impl DoubleEndedIterator<Item = (&'a Vec<u8>, &'a Entry)> + use<'a> {
+        // An empty range must still be well-formed for `BTreeMap::range`.
+        let end = end.max(start);
+        self.entries
+            .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+            .map(|(key, (entry, _, _))| (key, entry))
+    }
+
+    /// Returns an iterator over the entries with keys in `[start, end)` and their sequence
+    /// numbers, sorted by key. An `end` of `None` runs to the last entry.
+    pub fn range_with_seqno<'a>(
+        &'a self,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> impl Iterator<Item = (&'a Vec<u8>, &'a Entry, u64)> + use<'a> {
+        // An empty range must still be well-formed for `BTreeMap::range`.
+        let end = end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.max(start)));
+        self.entries
+            .range::<[u8], _>((Bound::Included(start), end))
+            .map(|(key, (entry, seqno, _))| (key, entry, *seqno))
+    }
+
+    /// Returns the number of keys in `[start, end)`, including deleted ones.
+    pub fn count_range(&self, start: &[u8], end: &[u8]) -> usize {
+        if start >= end {
+            return 0;
+        }
+        self.entries
+            .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+            .count()
+    }
+
     /// Clears all entries from the `MemTable`.
     pub fn clear(&mut self) {
         self.entries.clear();
@@ -96,6 +270,74 @@ impl MemTable {
     }
 }
 
+/// An iterator over the entries of a [`MemTable`] in key order, returned by
+/// [`MemTable::iter`].
+pub struct Iter<'a>(btree_map::Iter<'a, Vec<u8>, (Entry, u64, u8)>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Vec<u8>, &'a Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, (entry, _, _))| (key, entry))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back().map(|(key, (entry, _, _))| (key, entry))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+impl<'a> IntoIterator for &'a MemTable {
+    type Item = (&'a Vec<u8>, &'a Entry);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// An owning iterator over the entries of a [`MemTable`] in key order.
+///
+/// Sequence numbers and metadata tags are dropped along with the table.
+pub struct IntoIter(btree_map::IntoIter<Vec<u8>, (Entry, u64, u8)>);
+
+impl Iterator for IntoIter {
+    type Item = (Vec<u8>, Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, (entry, _, _))| (key, entry))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for IntoIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back().map(|(key, (entry, _, _))| (key, entry))
+    }
+}
+
+impl ExactSizeIterator for IntoIter {}
+
+impl IntoIterator for MemTable {
+    type Item = (Vec<u8>, Entry);
+    type IntoIter = IntoIter;
+
+    /// Consumes the table, moving its keys and entries out without copying them.
+    fn into_iter(self) -> IntoIter {
+        IntoIter(self.entries.into_iter())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,23 +413,53 @@ mod tests {
         assert_eq!(keys, vec![b"a".to_vec(), b"m".to_vec(), b"z".to_vec()]);
     }
 
+    #[test]
+    fn test_into_iterator_by_reference_and_by_value() {
+        let mut mt = MemTable::new(1024);
+        mt.put(b"b".to_vec(), b"2".to_vec());
+        mt.put(b"a".to_vec(), b"1".to_vec());
+        mt.delete(b"c".to_vec());
+
+        let mut keys = Vec::new();
+        for (key, _) in &mt {
+            keys.push(key.clone());
+        }
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        let borrowed: Vec<(&Vec<u8>, &Entry)> = (&mt).into_iter().rev().collect();
+        assert_eq!(borrowed[0], (&b"c".to_vec(), &Entry::Tombstone));
+        assert_eq!((&mt).into_iter().len(), 3);
+
+        let expected: Vec<(Vec<u8>, Entry)> =
+            mt.iter().map(|(k, e)| (k.clone(), e.clone())).collect();
+        let owned: Vec<(Vec<u8>, Entry)> = mt.clone().into_iter().collect();
+        assert_eq!(owned, expected);
+
+        let mut values = Vec::new();
+        for (_, entry) in mt {
+            if let Entry::Value(value) = entry {
+                values.push(value);
+            }
+        }
+        assert_eq!(values, vec![b"1".to_vec(), b"2".to_vec()]);
+    }
+
     #[test]
     fn test_size_tracking() {
         let mut mt = MemTable::new(1024);
-        mt.put(b"key1".to_vec(), b"val1".to_vec()); // 4 + 4 = 8
-        assert_eq!(mt.approximate_size(), 8);
-        mt.put(b"key2".to_vec(), b"val2".to_vec()); // 4 + 4 = 8 -> Total 16
-        assert_eq!(mt.approximate_size(), 16);
+        mt.put(b"key1".to_vec(), b"val1".to_vec()); // 12 + 4 + 4 + 4 = 24
+        assert_eq!(mt.approximate_size(), 24);
+        mt.put(b"key2".to_vec(), b"val2".to_vec()); // 24 -> Total 48
+        assert_eq!(mt.approximate_size(), 48);
     }
 
     #[test]
     fn test_full_threshold() {
-        let mut mt = MemTable::new(10);
-        mt.put(b"k1".to_vec(), b"v1".to_vec()); // 4
+        let mut mt = MemTable::new(50);
+        mt.put(b"k1".to_vec(), b"v1".to_vec()); // 20
         assert!(!mt.is_full());
-        mt.put(b"k2".to_vec(), b"v2".to_vec()); // 4 -> 8
+        mt.put(b"k2".to_vec(), b"v2".to_vec()); // 20 -> 40
         assert!(!mt.is_full());
-        mt.put(b"k3".to_vec(), b"v3".to_vec()); // 4 -> 12
+        mt.put(b"k3".to_vec(), b"v3".to_vec()); // 20 -> 60
         assert!(mt.is_full());
     }
 
@@ -207,6 +479,26 @@ mod tests {
         assert_eq!(mt.iter().count(), 0);
     }
 
+    #[test]
+    fn test_seqno_assignment() {
+        let mut mt = MemTable::new(1024);
+        mt.advance_seqno(10);
+        mt.put(b"a".to_vec(), b"1".to_vec());
+        mt.put(b"b".to_vec(), b"2".to_vec());
+        mt.delete(b"a".to_vec());
+        assert_eq!(mt.get_with_seqno(b"a"), Some((&Entry::Tombstone, 13)));
+        assert_eq!(mt.last_seqno(), 13);
+
+        mt.clear();
+        mt.advance_seqno(5);
+        mt.put(b"c".to_vec(), b"3".to_vec());
+        let items: Vec<_> = mt.iter_with_seqno().collect();
+        assert_eq!(
+            items,
+            vec![(&b"c".to_vec(), &Entry::Value(b"3".to_vec()), 14)]
+        );
+    }
+
     #[test]
     fn test_iter_with_tombstones() {
         let mut mt = MemTable::new(1024);
@@ -217,4 +509,91 @@ mod tests {
         assert_eq!(items[0].1, &Entry::Value(b"v1".to_vec()));
         assert_eq!(items[1].1, &Entry::Tombstone);
     }
+
+    #[test]
+    fn test_size_consistent_across_transitions() {
+        fn recomputed(mt: &MemTable) -> usize {
+            mt.iter()
+                .map(|(key, entry)| KEY_OVERHEAD + key.len() + entry.heap_size())
+                .sum()
+        }
+
+        // Every state a key can be in, and a write that moves it into each state.
+        let states: [Option<Entry>; 4] = [
+            None,
+            Some(Entry::Tombstone),
+            Some(Entry::Value(b"short".to_vec())),
+            Some(Entry::Value(vec![7; 300])),
+        ];
+        let apply = |mt: &mut MemTable, key: &[u8], state: &Option<Entry>| match state {
+            None => {}
+            Some(Entry::Value(v)) => mt.put(key.to_vec(), v.clone()),
+            Some(Entry::Tombstone) => mt.delete(key.to_vec()),
+        };
+
+        for from in &states {
+            for to in states.iter().flatten() {
+                let mut mt = MemTable::new(1024);
+                mt.put(b"other".to_vec(), b"value".to_vec());
+                apply(&mut mt, b"key", from);
+                assert_eq!(mt.approximate_size(), recomputed(&mt), "{:?}", from);
+                apply(&mut mt, b"key", &Some(to.clone()));
+                assert_eq!(
+                    mt.approximate_size(),
+                    recomputed(&mt),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+                mt.clear();
+                assert_eq!(mt.approximate_size(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_range_from_both_ends() {
+        let mut mt = MemTable::new(1024);
+        for key in [b"a", b"b", b"c", b"d", b"e", b"f"] {
+            mt.put(key.to_vec(), key.to_vec());
+        }
+        mt.delete(b"c".to_vec());
+
+        let keys = |it: &mut dyn Iterator<Item = (&Vec<u8>, &Entry)>| -> Vec<Vec<u8>> {
+            it.map(|(key, _)| key.clone()).collect()
+        };
+        let mut range = mt.range(b"b", b"f");
+        assert_eq!(range.next().unwrap().0, b"b");
+        assert_eq!(range.next_back().unwrap().0, b"e");
+        assert_eq!(range.next_back().unwrap().0, b"d");
+        assert_eq!(range.next(), Some((&b"c".to_vec(), &Entry::Tombstone)));
+        assert_eq!(range.next(), None);
+        assert_eq!(range.next_back(), None);
+
+        assert_eq!(
+            keys(&mut mt.range(b"a", b"d").rev()),
+            vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]
+        );
+        assert!(keys(&mut mt.range(b"d", b"d")).is_empty());
+        assert!(keys(&mut mt.range(b"e", b"b")).is_empty());
+    }
+
+    #[test]
+    fn test_clone_is_independent() {
+        let mut original = MemTable::new(1024);
+        original.put(b"k1".to_vec(), b"v1".to_vec());
+        original.delete(b"k2".to_vec());
+
+        let mut copy = original.clone();
+        assert_eq!(copy.approximate_size(), original.approximate_size());
+        assert_eq!(copy.last_seqno(), original.last_seqno());
+
+        copy.put(b"k1".to_vec(), b"changed".to_vec());
+        copy.put(b"k3".to_vec(), b"v3".to_vec());
+        assert_eq!(original.get(b"k1"), Some(&Entry::Value(b"v1".to_vec())));
+        assert_eq!(original.get(b"k3"), None);
+        assert_eq!(original.iter().count(), 2);
+        assert_ne!(copy.approximate_size(), original.approximate_size());
+        assert_eq!(copy.last_seqno(), original.last_seqno() + 2);
+    }
 }
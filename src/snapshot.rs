@@ -0,0 +1,113 @@
+//! Point-in-time read views of an [`Engine`](crate::Engine).
+
+use crate::engine::TableLocation;
+use crate::memtable::{Entry, MemTable};
+use crate::sstable::SSTable;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The sequence numbers of every live snapshot, shared by an engine and its snapshots.
+#[derive(Default)]
+pub(crate) struct SnapshotList {
+    live: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    /// Returns the sequence number of the oldest live snapshot, if any.
+    pub(crate) fn min_seqno(&self) -> Option<u64> {
+        self.live.lock().unwrap().keys().next().copied()
+    }
+
+    fn register(&self, seqno: u64) {
+        *self.live.lock().unwrap().entry(seqno).or_insert(0) += 1;
+    }
+
+    fn release(&self, seqno: u64) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&seqno) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&seqno);
+            }
+        }
+    }
+}
+
+/// A consistent, read-only view of an engine as of a single sequence number.
+///
+/// Created by [`Engine::snapshot`](crate::Engine::snapshot). Writes made after the snapshot
+/// was taken are invisible to it, and while it is alive compaction keeps every version of a
+/// key that it can still observe, including values that have since been deleted.
+pub struct Snapshot {
+    seqno: u64,
+    memtable: BTreeMap<Vec<u8>, Entry>,
+    sstables: Arc<RwLock<Vec<Arc<SSTable>>>>,
+    /// Where the tables live, for pinning their files while they are read.
+    location: TableLocation,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of `memtable` and the tables in `sstables`, which live in `location`.
+    ///
+    /// The caller must hold `memtable` locked against writes so that its contents match its
+    /// last sequence number. The MemTable only keeps the newest version of each key, so its
+    /// entries are copied; tables are immutable and are read through the live list.
+    pub(crate) fn new(
+        memtable: &MemTable,
+        sstables: Arc<RwLock<Vec<Arc<SSTable>>>>,
+        location: TableLocation,
+        list: Arc<SnapshotList>,
+    ) -> Self {
+        let seqno = memtable.last_seqno();
+        list.register(seqno);
+        Self {
+            seqno,
+            memtable: memtable
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect(),
+            sstables,
+            location,
+            list,
+        }
+    }
+
+    /// Returns the sequence number this snapshot reads at.
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+
+    /// Retrieves the value a key had when the snapshot was taken.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let entry = match self.memtable.get(key) {
+            Some(entry) => Some(entry.clone()),
+            None => {
+                let (ssts, _pin) = {
+                    let ssts = self.sstables.read().unwrap();
+                    (ssts.clone(), self.location.pin())
+                };
+                let mut found = None;
+                for sst in ssts.iter() {
+                    found = sst.get_entry_at(key, self.seqno)?;
+                    if found.is_some() {
+                        break;
+                    }
+                }
+                found
+            }
+        };
+
+        Ok(match entry {
+            Some(Entry::Value(v)) => Some(v),
+            Some(Entry::Tombstone) | None => None,
+        })
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seqno);
+    }
+}
@@ -0,0 +1,705 @@
+//! Storage backends for SSTable data.
+//!
+//! `SSTable` and `SSTableBuilder` never touch `File` directly; they read through a
+//! [`ReadableStore`] and write through a [`WritableStore`]. `File` implements both traits and is
+//! used by the path-based constructors, while [`MemoryStore`] keeps a table entirely in memory.
+//! [`CachedFile`] reads a file whose descriptor is shared out by a bounded [`FileCache`].
+//! With the `direct-io` feature, `DirectFile` reads a file past the operating system's page
+//! cache.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Makes the directory entries of `dir` durable.
+///
+/// Syncing a file only persists its contents; the entry that names it lives in the directory
+/// and needs its own sync before a newly created, renamed or removed file is guaranteed to
+/// survive a crash. On platforms where a directory cannot be opened this is a no-op.
+pub fn fsync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        File::open(dir)?.sync_all()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Ok(())
+    }
+}
+
+/// A source of bytes that supports positioned reads.
+pub trait ReadableStore: Send + Sync {
+    /// Reads bytes starting at `offset` into `buf`, returning how many were read.
+    ///
+    /// A return value of `0` means `offset` is at or past the end of the store.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Returns the total number of bytes in the store.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Returns `true` if the store contains no bytes.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// A sink of bytes that is written sequentially from the start.
+pub trait WritableStore: Send + Sync {
+    /// Appends all of `buf` to the store.
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Makes all bytes written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Shortens the store to its first `len` bytes, discarding a partially written tail.
+    ///
+    /// Later writes append after the remaining bytes. The default implementation fails with
+    /// `Unsupported`.
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let _ = len;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "store cannot be shortened",
+        ))
+    }
+}
+
+impl ReadableStore for File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl WritableStore for File {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        Write::write_all(self, buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        // Truncating leaves the cursor where it was; the next write must land at the new end
+        // rather than past a zero-filled gap.
+        File::set_len(self, len)?;
+        self.seek(SeekFrom::Start(len))?;
+        Ok(())
+    }
+}
+
+impl ReadableStore for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = std::cmp::min(offset, self.len() as u64) as usize;
+        let n = std::cmp::min(buf.len(), self.len() - start);
+        buf[..n].copy_from_slice(&self[start..start + n]);
+        Ok(n)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(Vec::len(self) as u64)
+    }
+}
+
+/// A file read with direct I/O, bypassing the operating system's page cache.
+///
+/// Direct reads must cover whole blocks of the device and land in a buffer aligned to them,
+/// so each read is widened to `alignment`-byte boundaries and the requested bytes are copied
+/// out. On platforms without direct I/O, or on file systems such as tmpfs that refuse it, the
+/// file is opened for ordinary buffered reads instead; [`DirectFile::is_direct`] tells which.
+#[cfg(feature = "direct-io")]
+pub struct DirectFile {
+    file: File,
+    len: u64,
+    alignment: u64,
+    direct: bool,
+}
+
+#[cfg(feature = "direct-io")]
+impl DirectFile {
+    /// Opens the file at `path` for direct reads in `alignment`-byte blocks.
+    ///
+    /// `alignment` must be a power of two, and at least the logical block size of the device
+    /// for the reads to be accepted.
+    pub fn open(path: impl AsRef<Path>, alignment: u64) -> io::Result<Self> {
+        if !alignment.is_power_of_two() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("direct I/O alignment {} is not a power of two", alignment),
+            ));
+        }
+        let path = path.as_ref();
+        let (file, direct) = match open_direct(path) {
+            Some(Ok(file)) => (file, true),
+            _ => (File::open(path)?, false),
+        };
+        Ok(Self {
+            len: file.metadata()?.len(),
+            file,
+            alignment,
+            direct,
+        })
+    }
+
+    /// Returns `true` if reads bypass the page cache, or `false` if the file fell back to
+    /// buffered reads.
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+}
+
+/// Opens `path` read-only with `O_DIRECT`, or returns `None` where the flag is unknown.
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+fn open_direct(path: &Path) -> Option<io::Result<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // The value of `O_DIRECT` differs between architectures.
+    let flag = if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+        0o40000
+    } else if cfg!(any(target_arch = "arm", target_arch = "aarch64")) {
+        0o200000
+    } else {
+        return None;
+    };
+    Some(
+        std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(flag)
+            .open(path),
+    )
+}
+
+/// Opens `path` read-only with `O_DIRECT`, or returns `None` where the flag is unknown.
+#[cfg(all(feature = "direct-io", not(target_os = "linux")))]
+fn open_direct(_path: &Path) -> Option<io::Result<File>> {
+    None
+}
+
+#[cfg(feature = "direct-io")]
+impl ReadableStore for DirectFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if !self.direct {
+            return self.file.read_at(buf, offset);
+        }
+        if offset >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let wanted = buf.len().min((self.len - offset) as usize);
+        let start = offset - offset % self.alignment;
+        let end = (offset + wanted as u64).next_multiple_of(self.alignment);
+        let span = (end - start) as usize;
+
+        // Over-allocate so that an aligned window of `span` bytes fits inside.
+        let mut block = vec![0u8; span + self.alignment as usize];
+        let skew = block.as_ptr().align_offset(self.alignment as usize);
+        let aligned = &mut block[skew..skew + span];
+        let mut filled = 0;
+        while filled < span {
+            match self
+                .file
+                .read_at(&mut aligned[filled..], start + filled as u64)?
+            {
+                0 => break,
+                n => filled += n,
+            }
+        }
+
+        let skip = (offset - start) as usize;
+        let n = wanted.min(filled.saturating_sub(skip));
+        buf[..n].copy_from_slice(&aligned[skip..skip + n]);
+        Ok(n)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+/// An in-memory byte buffer that can be written by an `SSTableBuilder` and read back by an
+/// `SSTable`.
+///
+/// Clones share the same underlying buffer, so a table can be built through one clone and
+/// opened through another.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    data: Arc<RwLock<Vec<u8>>>,
+}
+
+impl MemoryStore {
+    /// Creates a new, empty `MemoryStore`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the bytes written so far.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+}
+
+impl ReadableStore for MemoryStore {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.data.read().unwrap().read_at(buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+}
+
+impl WritableStore for MemoryStore {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.data.write().unwrap().extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.data.write().unwrap().truncate(len as usize);
+        Ok(())
+    }
+}
+
+/// A bounded pool of open file descriptors shared by [`CachedFile`]s.
+///
+/// At most `capacity` files are kept open; opening another one closes the least recently
+/// used. A file that is being read when it is evicted stays open until that read finishes,
+/// so the limit can briefly be exceeded by the number of concurrent reads.
+pub struct FileCache {
+    capacity: usize,
+    next_id: AtomicU64,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Open files by `CachedFile` ID, with the tick at which each was last used.
+    open: HashMap<u64, (Arc<File>, u64)>,
+    /// IDs of the open files by last use, oldest first.
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl FileCache {
+    /// Creates a cache that keeps at most `capacity` files open (at least one).
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            capacity: capacity.max(1),
+            next_id: AtomicU64::new(0),
+            state: Mutex::new(CacheState::default()),
+        })
+    }
+
+    /// Returns the number of files the cache currently holds open.
+    pub fn open_files(&self) -> usize {
+        self.state.lock().unwrap().open.len()
+    }
+
+    /// Returns the open file for `id`, opening `path` and evicting the least recently used
+    /// file if it is not open.
+    fn get(&self, id: u64, path: &Path) -> io::Result<Arc<File>> {
+        let mut state = self.state.lock().unwrap();
+        let CacheState { open, lru, tick } = &mut *state;
+        *tick += 1;
+        if let Some((file, last_used)) = open.get_mut(&id) {
+            lru.remove(last_used);
+            *last_used = *tick;
+            lru.insert(*tick, id);
+            return Ok(Arc::clone(file));
+        }
+
+        let file = Arc::new(File::open(path)?);
+        while open.len() >= self.capacity {
+            let Some((_, oldest)) = lru.pop_first() else {
+                break;
+            };
+            open.remove(&oldest);
+        }
+        open.insert(id, (Arc::clone(&file), *tick));
+        lru.insert(*tick, id);
+        Ok(file)
+    }
+
+    /// Closes the file for `id`, if it is open.
+    fn forget(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, last_used)) = state.open.remove(&id) {
+            state.lru.remove(&last_used);
+        }
+    }
+}
+
+/// A read-only file that is opened through a [`FileCache`] only while it is being used.
+///
+/// The file is reopened by path whenever the cache has closed it, so it must not be renamed
+/// or removed while it is still read.
+pub struct CachedFile {
+    id: u64,
+    path: PathBuf,
+    len: u64,
+    cache: Arc<FileCache>,
+}
+
+impl CachedFile {
+    /// Opens the file at `path` through `cache`.
+    pub fn open(path: impl AsRef<Path>, cache: &Arc<FileCache>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let id = cache.next_id.fetch_add(1, Ordering::Relaxed);
+        let len = match cache.get(id, &path).and_then(|file| file.metadata()) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                cache.forget(id);
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            id,
+            path,
+            len,
+            cache: Arc::clone(cache),
+        })
+    }
+}
+
+impl ReadableStore for CachedFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.cache.get(self.id, &self.path)?.read_at(buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+impl Drop for CachedFile {
+    fn drop(&mut self) {
+        self.cache.forget(self.id);
+    }
+}
+
+/// Adapts a `WritableStore` to the `Write` trait so it can be wrapped in a `BufWriter`.
+pub(crate) struct StoreWriter(pub(crate) Box<dyn WritableStore>);
+
+impl Write for StoreWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Adapts a `ReadableStore` to the `Read` and `Seek` traits by tracking a cursor position.
+pub(crate) struct StoreReader {
+    store: Arc<dyn ReadableStore>,
+    pos: u64,
+}
+
+impl StoreReader {
+    /// Creates a reader positioned at the start of `store`.
+    pub(crate) fn new(store: Arc<dyn ReadableStore>) -> Self {
+        Self { store, pos: 0 }
+    }
+}
+
+impl Read for StoreReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.store.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for StoreReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.store.len()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// A store wrapper for tests that fails with an I/O error once a byte budget is exhausted.
+///
+/// Writes that straddle the budget are applied partially before failing, which simulates a
+/// torn write on a full disk. Clones share both the wrapped store and the budgets.
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct FaultyStore<S> {
+    inner: S,
+    write_budget: Arc<std::sync::atomic::AtomicU64>,
+    read_budget: Arc<std::sync::atomic::AtomicU64>,
+    /// Number of upcoming reads that fail with `TimedOut`.
+    timed_out_reads: Arc<std::sync::atomic::AtomicU64>,
+}
+
+#[cfg(test)]
+impl<S> FaultyStore<S> {
+    /// Wraps `inner` so that writes fail after `bytes` bytes have been written successfully.
+    pub(crate) fn fail_writes_after(inner: S, bytes: u64) -> Self {
+        Self::new(inner, bytes, u64::MAX)
+    }
+
+    /// Lets `bytes` more bytes be written, as if space had been freed on a full disk.
+    pub(crate) fn allow_writes(&self, bytes: u64) {
+        self.write_budget
+            .fetch_add(bytes, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Wraps `inner` so that reads fail after `bytes` bytes have been read successfully.
+    pub(crate) fn fail_reads_after(inner: S, bytes: u64) -> Self {
+        Self::new(inner, u64::MAX, bytes)
+    }
+
+    /// Makes the next `count` reads fail with `TimedOut` before reaching the wrapped store,
+    /// as a networked filesystem may for a read that succeeds when repeated.
+    pub(crate) fn time_out_reads(&self, count: u64) {
+        self.timed_out_reads
+            .fetch_add(count, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Returns how many more bytes may be read before reads start failing.
+    pub(crate) fn reads_left(&self) -> u64 {
+        self.read_budget.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn new(inner: S, write_budget: u64, read_budget: u64) -> Self {
+        Self {
+            inner,
+            write_budget: Arc::new(write_budget.into()),
+            read_budget: Arc::new(read_budget.into()),
+            timed_out_reads: Arc::default(),
+        }
+    }
+
+    /// Takes up to `wanted` bytes from `budget`, returning how many were granted.
+    fn take(budget: &std::sync::atomic::AtomicU64, wanted: u64) -> u64 {
+        use std::sync::atomic::Ordering;
+        let granted = std::cmp::min(wanted, budget.load(Ordering::SeqCst));
+        budget.fetch_sub(granted, Ordering::SeqCst);
+        granted
+    }
+
+    fn injected_fault() -> io::Error {
+        io::Error::other("injected fault")
+    }
+}
+
+#[cfg(test)]
+impl<S: WritableStore> WritableStore for FaultyStore<S> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let granted = Self::take(&self.write_budget, buf.len() as u64) as usize;
+        self.inner.write_all(&buf[..granted])?;
+        if granted < buf.len() {
+            return Err(Self::injected_fault());
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+}
+
+#[cfg(test)]
+impl<S: ReadableStore> ReadableStore for FaultyStore<S> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if Self::take(&self.timed_out_reads, 1) == 1 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "injected timeout"));
+        }
+        let granted = Self::take(&self.read_budget, buf.len() as u64) as usize;
+        if granted == 0 && !buf.is_empty() {
+            return Err(Self::injected_fault());
+        }
+        let n = self.inner.read_at(&mut buf[..granted], offset)?;
+        // Give back whatever the inner store did not actually return.
+        self.read_budget
+            .fetch_add((granted - n) as u64, std::sync::atomic::Ordering::SeqCst);
+        Ok(n)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store_write_and_read_at() {
+        let mut store = MemoryStore::new();
+        store.write_all(b"hello world").unwrap();
+        store.sync().unwrap();
+        assert_eq!(store.len().unwrap(), 11);
+
+        let mut buf = [0u8; 5];
+        assert_eq!(store.read_at(&mut buf, 6).unwrap(), 5);
+        assert_eq!(&buf, b"world");
+        assert_eq!(store.read_at(&mut buf, 11).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_store_reader_seek_and_read() {
+        let mut reader = StoreReader::new(Arc::new(b"0123456789".to_vec()));
+        reader.seek(SeekFrom::End(-3)).unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"789");
+        assert!(reader.seek(SeekFrom::Current(-20)).is_err());
+    }
+
+    #[test]
+    fn test_file_store_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "lsm_test_file_store_{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        {
+            let mut file = File::create(&path).unwrap();
+            WritableStore::write_all(&mut file, b"abcdef").unwrap();
+            file.sync().unwrap();
+        }
+
+        let file = File::open(&path).unwrap();
+        let mut buf = [0u8; 3];
+        assert_eq!(ReadableStore::len(&file).unwrap(), 6);
+        assert_eq!(file.read_at(&mut buf, 2).unwrap(), 3);
+        assert_eq!(&buf, b"cde");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(feature = "direct-io")]
+    #[test]
+    fn test_direct_file_reads_unaligned_ranges() {
+        let path = std::env::temp_dir().join(format!(
+            "lsm_test_direct_file_{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        assert!(DirectFile::open(&path, 300).is_err());
+
+        // Whether or not the file system allows direct I/O, reads return the same bytes.
+        let file = DirectFile::open(&path, 512).unwrap();
+        assert_eq!(file.len().unwrap(), 5000);
+        for (offset, len) in [(0, 512), (3, 10), (500, 30), (1000, 2100), (4990, 40)] {
+            let mut buf = vec![0u8; len];
+            let n = file.read_at(&mut buf, offset as u64).unwrap();
+            let end = (offset + len).min(data.len());
+            assert_eq!(&buf[..n], &data[offset..end]);
+        }
+        assert_eq!(file.read_at(&mut [0u8; 8], 5000).unwrap(), 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_fsync_dir() {
+        let dir = std::env::temp_dir();
+        fsync_dir(&dir).unwrap();
+        #[cfg(unix)]
+        assert!(fsync_dir(&dir.join("lsm_test_no_such_dir")).is_err());
+    }
+
+    #[test]
+    fn test_faulty_store_fails_writes_after_budget() {
+        let inner = MemoryStore::new();
+        let mut store = FaultyStore::fail_writes_after(inner.clone(), 5);
+        store.write_all(b"abc").unwrap();
+        assert!(store.write_all(b"defg").is_err());
+        assert_eq!(inner.to_vec(), b"abcde");
+        assert!(store.write_all(b"h").is_err());
+    }
+
+    #[test]
+    fn test_faulty_store_fails_reads_after_budget() {
+        let store = FaultyStore::fail_reads_after(b"0123456789".to_vec(), 4);
+        let mut buf = [0u8; 3];
+        assert_eq!(store.read_at(&mut buf, 0).unwrap(), 3);
+        assert_eq!(store.read_at(&mut buf, 3).unwrap(), 1);
+        assert!(store.read_at(&mut buf, 4).is_err());
+        assert_eq!(store.len().unwrap(), 10);
+    }
+
+    #[test]
+    fn test_file_cache_evicts_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!(
+            "lsm_test_file_cache_{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = FileCache::new(2);
+        let files: Vec<CachedFile> = (0..4u8)
+            .map(|i| {
+                let path = dir.join(format!("{}.dat", i));
+                std::fs::write(&path, [i; 16]).unwrap();
+                CachedFile::open(&path, &cache).unwrap()
+            })
+            .collect();
+        assert_eq!(cache.open_files(), 2);
+
+        let mut buf = [0u8; 4];
+        for _ in 0..2 {
+            for (i, file) in files.iter().enumerate() {
+                assert_eq!(file.len().unwrap(), 16);
+                assert_eq!(file.read_at(&mut buf, 12).unwrap(), 4);
+                assert_eq!(buf, [i as u8; 4]);
+                assert!(cache.open_files() <= 2);
+            }
+        }
+        // The two most recently read files are the ones kept open.
+        let state = cache.state.lock().unwrap();
+        let mut open: Vec<u64> = state.open.keys().copied().collect();
+        open.sort();
+        assert_eq!(open, vec![files[2].id, files[3].id]);
+        drop(state);
+
+        drop(files);
+        assert_eq!(cache.open_files(), 0);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
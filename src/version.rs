@@ -0,0 +1,119 @@
+//! Versions that order writes across independently written stores.
+
+use std::sync::Mutex;
+
+/// The position of a write in a last-writer-wins order shared by several stores.
+///
+/// Versions compare by `timestamp`, then by `node_id`, so writes of different nodes never
+/// tie and every replica picks the same winner for a key. An engine with
+/// [`EngineOptions::node_id`](crate::EngineOptions::node_id) set stamps each write with a
+/// version and uses [`Version::to_seqno`] as its sequence number. The WAL, SSTables and
+/// compaction, which already keep the version of a key with the highest sequence number,
+/// then carry and compare versions as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    /// Milliseconds since the Unix epoch, moved past the wall clock when writes arrive faster
+    /// than one per millisecond. At most [`Version::MAX_TIMESTAMP`].
+    pub timestamp: u64,
+    /// The node that made the write.
+    pub node_id: u16,
+}
+
+impl Version {
+    /// Largest timestamp a sequence number can hold.
+    pub const MAX_TIMESTAMP: u64 = (1 << 48) - 1;
+
+    /// Packs the version into a sequence number that orders the same way.
+    ///
+    /// A timestamp above [`Version::MAX_TIMESTAMP`] is clamped to it.
+    pub fn to_seqno(self) -> u64 {
+        (self.timestamp.min(Self::MAX_TIMESTAMP) << 16) | u64::from(self.node_id)
+    }
+
+    /// Unpacks a sequence number written by [`Version::to_seqno`].
+    pub fn from_seqno(seqno: u64) -> Self {
+        Self {
+            timestamp: seqno >> 16,
+            node_id: seqno as u16,
+        }
+    }
+}
+
+/// Hands out the versions of one node's writes, each above the one before.
+///
+/// This is a hybrid logical clock: it follows the wall clock, but never repeats or goes back
+/// a timestamp, so writes stay ordered across clock adjustments and restarts.
+pub(crate) struct VersionClock {
+    node_id: u16,
+    /// Timestamp of the last version handed out.
+    last: Mutex<u64>,
+}
+
+impl VersionClock {
+    /// Creates a clock for `node_id` whose versions come after the sequence number `seqno`.
+    pub(crate) fn new(node_id: u16, seqno: u64) -> Self {
+        Self {
+            node_id,
+            last: Mutex::new(Version::from_seqno(seqno).timestamp),
+        }
+    }
+
+    /// Returns the version of the next write.
+    pub(crate) fn next(&self) -> Version {
+        let mut last = self.last.lock().unwrap();
+        *last = wall_clock_millis().max(*last + 1);
+        Version {
+            timestamp: *last,
+            node_id: self.node_id,
+        }
+    }
+}
+
+/// Returns the milliseconds since the Unix epoch, or `0` where there is no clock.
+#[cfg(not(target_arch = "wasm32"))]
+fn wall_clock_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Returns the milliseconds since the Unix epoch, or `0` where there is no clock.
+#[cfg(target_arch = "wasm32")]
+fn wall_clock_millis() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seqno_orders_like_version() {
+        let versions = [
+            Version {
+                timestamp: 5,
+                node_id: u16::MAX,
+            },
+            Version {
+                timestamp: 6,
+                node_id: 0,
+            },
+            Version {
+                timestamp: 6,
+                node_id: 1,
+            },
+        ];
+        for pair in versions.windows(2) {
+            assert!(pair[0] < pair[1]);
+            assert!(pair[0].to_seqno() < pair[1].to_seqno());
+        }
+        for version in versions {
+            assert_eq!(Version::from_seqno(version.to_seqno()), version);
+        }
+
+        let clock = VersionClock::new(3, versions[2].to_seqno());
+        let (first, second) = (clock.next(), clock.next());
+        assert!(versions[2] < first && first < second);
+        assert_eq!(second.node_id, 3);
+    }
+}
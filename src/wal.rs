@@ -1,6 +1,13 @@
+#[cfg(feature = "encryption")]
+use crate::encryption::{self, Encryption, FileCipher};
+use crate::sstable;
+use crate::storage;
+use crate::version::Version;
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 /// Represents an entry in the Write-Ahead Log.
 #[derive(Debug, PartialEq, Eq)]
@@ -11,21 +18,102 @@ pub enum WalEntry {
         key: Vec<u8>,
         /// The value associated with the key.
         value: Vec<u8>,
+        /// Application-defined metadata tag, `0` if none was given.
+        meta: u8,
+        /// The write's version, if the engine stamps writes with one.
+        version: Option<Version>,
     },
     /// A record of a delete operation.
     Delete {
         /// The key being deleted.
         key: Vec<u8>,
+        /// The delete's version, if the engine stamps writes with one.
+        version: Option<Version>,
     },
 }
 
+impl WalEntry {
+    /// Fails with `InvalidInput` if the entry's key is longer than
+    /// [`MAX_KEY_LEN`](crate::sstable::MAX_KEY_LEN) or its value longer than
+    /// [`MAX_VALUE_LEN`](crate::sstable::MAX_VALUE_LEN).
+    ///
+    /// Such an entry could not be flushed, and its length fields would be truncated in the log.
+    pub fn check_lengths(&self) -> io::Result<()> {
+        match self {
+            WalEntry::Put { key, value, .. } => {
+                sstable::check_record_lengths(key.len(), Some(value.len()))
+            }
+            WalEntry::Delete { key, .. } => sstable::check_record_lengths(key.len(), None),
+        }
+    }
+}
+
+/// How far recovery got through a WAL file; see [`Wal::recover_with_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoverReport {
+    /// Bytes from the start of the file up to the end of the last complete entry, headers
+    /// included. A torn log should be cut back to this length before appending.
+    pub bytes_consumed: u64,
+    /// Bytes after the last complete entry that recovery gave up on, or `0` if it read the
+    /// whole file.
+    pub bytes_discarded: u64,
+    /// Why recovery stopped before the end of the file, or `None` if it did not.
+    pub stopped_reason: Option<String>,
+}
+
+impl RecoverReport {
+    /// Returns the offset of the torn final entry, if recovery stopped at one.
+    pub fn torn_at(&self) -> Option<u64> {
+        self.stopped_reason.as_ref().map(|_| self.bytes_consumed)
+    }
+
+    /// A report on a file of `len` bytes that was recovered to its end.
+    fn clean(len: u64) -> Self {
+        Self {
+            bytes_consumed: len,
+            ..Self::default()
+        }
+    }
+
+    /// A report on a file of `len` bytes whose recovery stopped at `offset` for `reason`.
+    fn torn(len: u64, offset: u64, reason: String) -> Self {
+        Self {
+            bytes_consumed: offset,
+            bytes_discarded: len - offset,
+            stopped_reason: Some(reason),
+        }
+    }
+}
+
+/// When a write logged to the WAL counts as durable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalSyncMode {
+    /// Entries are handed to the operating system but not fsynced. They survive the process
+    /// crashing, but not the machine losing power.
+    #[default]
+    None,
+    /// A write returns only once its entry is fsynced. Concurrent writers share fsyncs through
+    /// group commit, so many writers cost little more than one.
+    Fsync,
+}
+
 /// A Write-Ahead Log that provides persistence for the `MemTable`.
 ///
 /// Every write operation is first appended to the WAL before being applied to the in-memory
 /// structure, ensuring that data can be recovered after a crash.
 pub struct Wal {
-    writer: BufWriter<File>,
+    store: Box<dyn storage::WritableStore>,
     path: PathBuf,
+    /// Log position of the first byte in the current file, advanced by truncation.
+    base: u64,
+    /// Bytes of complete entries in the current file.
+    len: u64,
+    /// Set when a failed append may have left part of an entry after `len` that could not be
+    /// cut off yet.
+    torn: bool,
+    /// Seals each entry when the log is encrypted.
+    #[cfg(feature = "encryption")]
+    sealer: Option<Sealer>,
 }
 
 impl Wal {
@@ -33,89 +121,327 @@ impl Wal {
     pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
         let path = path.as_ref().to_path_buf();
         let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let mut wal = Self::with_store(file, path);
+        wal.len = len;
+        Ok(wal)
+    }
 
-        Ok(Self {
-            writer: BufWriter::new(file),
+    /// Creates a WAL that appends to `store`, which must be empty and is the log stored at
+    /// `path`. Positions start from zero.
+    pub(crate) fn with_store(store: impl storage::WritableStore + 'static, path: PathBuf) -> Self {
+        Self {
+            store: Box::new(store),
             path,
-        })
+            base: 0,
+            len: 0,
+            torn: false,
+            #[cfg(feature = "encryption")]
+            sealer: None,
+        }
+    }
+
+    /// Opens the WAL at the specified path, encrypting its entries with `encryption`.
+    ///
+    /// A new log starts with a header holding a fresh nonce prefix. The entries of an existing
+    /// one are sealed again under a fresh prefix, so that an entry torn by a crash before can
+    /// never share a nonce with one appended now. Fails with `PermissionDenied` if the log
+    /// was encrypted with another key, and `InvalidInput` if it is not encrypted.
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted(path: impl AsRef<Path>, encryption: &Encryption) -> io::Result<Self> {
+        let mut wal = Self::open(path)?;
+        let data = std::fs::read(&wal.path)?;
+        if data.is_empty() {
+            let (sealer, header) = Sealer::new(encryption)?;
+            wal.store.write_all(&header)?;
+            wal.len = header.len() as u64;
+            wal.sealer = Some(sealer);
+        } else {
+            wal.sealer = Some(Sealer {
+                encryption: encryption.clone(),
+                cipher: FileCipher::from_header(&data, encryption)?,
+                next: 0,
+            });
+            wal.truncate_to(encryption::HEADER_SIZE as u64)?;
+        }
+        Ok(wal)
     }
 
     /// Appends a `WalEntry` to the log and flushes it to disk.
+    ///
+    /// If writing fails part-way, for example on a full disk, the partial entry is cut off
+    /// again before the error is returned, so the log holds exactly the entries appended
+    /// successfully and the same entry can be retried. Should cutting it off fail as well,
+    /// every later append first retries that and fails until it succeeds.
     pub fn append(&mut self, entry: &WalEntry) -> io::Result<()> {
+        self.append_with_sync(entry, WalSyncMode::None)
+    }
+
+    /// Like [`Wal::append`], but with [`WalSyncMode::Fsync`] also fsyncs the log before
+    /// returning, whatever mode the log is otherwise used with.
+    ///
+    /// If the fsync fails, the entry is cut off as after a failed write.
+    pub fn append_with_sync(&mut self, entry: &WalEntry, sync: WalSyncMode) -> io::Result<()> {
+        entry.check_lengths()?;
+        if self.torn {
+            self.store.set_len(self.len)?;
+            self.torn = false;
+        }
+
+        let mut record = Vec::new();
         match entry {
-            WalEntry::Put { key, value } => {
-                self.writer.write_all(&[0])?; // Type 0 for Put
-                self.writer.write_all(&(key.len() as u32).to_le_bytes())?;
-                self.writer.write_all(key)?;
-                self.writer.write_all(&(value.len() as u32).to_le_bytes())?;
-                self.writer.write_all(value)?;
+            WalEntry::Put {
+                key,
+                value,
+                meta,
+                version,
+            } => {
+                // Type 0 for Put, 2 for a Put followed by its metadata tag, or 3 for a
+                // versioned Put, which always has the tag
+                match version {
+                    Some(version) => {
+                        record.push(3);
+                        record.extend_from_slice(&version.to_seqno().to_le_bytes());
+                    }
+                    None => record.push(if *meta == 0 { 0 } else { 2 }),
+                }
+                record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                record.extend_from_slice(key);
+                record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                record.extend_from_slice(value);
+                if *meta != 0 || version.is_some() {
+                    record.push(*meta);
+                }
             }
-            WalEntry::Delete { key } => {
-                self.writer.write_all(&[1])?; // Type 1 for Delete
-                self.writer.write_all(&(key.len() as u32).to_le_bytes())?;
-                self.writer.write_all(key)?;
+            WalEntry::Delete { key, version } => {
+                // Type 1 for Delete, or 4 for a versioned Delete
+                match version {
+                    Some(version) => {
+                        record.push(4);
+                        record.extend_from_slice(&version.to_seqno().to_le_bytes());
+                    }
+                    None => record.push(1),
+                }
+                record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                record.extend_from_slice(key);
+            }
+        }
+        #[cfg(feature = "encryption")]
+        let record = match &mut self.sealer {
+            Some(sealer) => sealer.seal(&record),
+            None => record,
+        };
+        let written = self.store.write_all(&record).and_then(|()| match sync {
+            WalSyncMode::None => Ok(()),
+            WalSyncMode::Fsync => self.store.sync(),
+        });
+        if let Err(e) = written {
+            self.torn = self.store.set_len(self.len).is_err();
+            return Err(e);
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    /// Flushes every appended entry to stable storage.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.store.sync()
+    }
+
+    /// Returns the log position just past the last appended entry.
+    ///
+    /// Positions keep growing across [`Wal::truncate_to`] and [`Wal::truncate`], so one
+    /// recorded earlier can still be passed to `truncate_to` after the log was shortened.
+    /// When the WAL is opened, positions start at the size of the existing file.
+    pub fn position(&self) -> u64 {
+        self.base + self.len
+    }
+
+    /// Discards every entry before the log position `offset`, keeping the ones after it.
+    ///
+    /// `offset` must be a value returned by [`Wal::position`]; an offset that was already
+    /// discarded is a no-op. The remaining entries are written to a fresh file that atomically
+    /// replaces the log, so a crash leaves either the old or the new log in place.
+    pub fn truncate_to(&mut self, offset: u64) -> io::Result<()> {
+        if offset <= self.base {
+            return Ok(());
+        }
+        if offset > self.position() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "WAL truncation offset is past the end of the log",
+            ));
+        }
+        let mut tail = Vec::new();
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset - self.base))?;
+        // Stop at the last complete entry, ignoring a partial one a failed append left behind.
+        file.take(self.position() - offset).read_to_end(&mut tail)?;
+        // An encrypted log gets a new header, and its entries are sealed again under the new
+        // nonce prefix.
+        #[cfg(feature = "encryption")]
+        let (tail, sealer) = match &self.sealer {
+            Some(sealer) => {
+                let (sealer, contents) = sealer.reseal(&tail)?;
+                (contents, Some(sealer))
             }
+            None => (tail, None),
+        };
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&tail)?;
+        tmp.sync_all()?;
+        drop(tmp);
+        std::fs::rename(&tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            storage::fsync_dir(dir)?;
+        }
+
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        self.store = Box::new(file);
+        // The new file ends at the same position as the old one.
+        self.base = self.position() - tail.len() as u64;
+        self.len = tail.len() as u64;
+        self.torn = false;
+        #[cfg(feature = "encryption")]
+        {
+            self.sealer = sealer;
         }
-        self.writer.flush()?;
         Ok(())
     }
 
     /// Recovers all entries from the WAL file at the given path.
+    ///
+    /// A final entry cut short by a crash mid-write is ignored; see [`Wal::recover_with_tail`].
     pub fn recover(path: impl AsRef<Path>) -> io::Result<Vec<WalEntry>> {
+        Ok(Self::recover_with_tail(path)?.0)
+    }
+
+    /// Like [`Wal::recover`], but also returns the offset of a torn final entry, if any.
+    ///
+    /// An entry whose declared lengths run past the end of the file is what a write torn by a
+    /// crash leaves behind. Recovery stops there and keeps the entries before it, and the file
+    /// should be cut back to the returned offset before appending. An entry of unknown type is
+    /// still an error, since that is corruption rather than a torn write.
+    pub fn recover_with_tail(path: impl AsRef<Path>) -> io::Result<(Vec<WalEntry>, Option<u64>)> {
+        let (entries, report) = Self::recover_with_report(path)?;
+        Ok((entries, report.torn_at()))
+    }
+
+    /// Like [`Wal::recover_with_tail`], but reports how many bytes were recovered and how
+    /// many were discarded with a torn final entry, and why recovery stopped.
+    pub fn recover_with_report(
+        path: impl AsRef<Path>,
+    ) -> io::Result<(Vec<WalEntry>, RecoverReport)> {
         let path = path.as_ref();
         if !path.exists() {
-            return Ok(Vec::new());
+            return Ok((Vec::new(), RecoverReport::default()));
         }
 
         let file = File::open(path)?;
-        let mut reader = BufReader::new(file);
+        let len = file.metadata()?.len();
+        let mut input = EntryReader {
+            reader: BufReader::new(file),
+            remaining: len,
+        };
+        // No entry type starts with the magic, so this only ever matches an encrypted log.
+        #[cfg(feature = "encryption")]
+        if encryption::is_sealed(io::BufRead::fill_buf(&mut input.reader)?) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is encrypted; recover it with its key", path.display()),
+            ));
+        }
         let mut entries = Vec::new();
 
         loop {
-            let mut type_buf = [0u8; 1];
-            if let Err(e) = reader.read_exact(&mut type_buf) {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    break;
+            let start = len - input.remaining;
+            // Running out here is a clean end at an entry boundary.
+            let Some(kind) = input.bytes(1)? else {
+                return Ok((entries, RecoverReport::clean(len)));
+            };
+            match input.entry(kind[0])? {
+                Some(entry) => entries.push(entry),
+                None => {
+                    let reason = format!("entry at offset {} runs past the end of the log", start);
+                    return Ok((entries, RecoverReport::torn(len, start, reason)));
                 }
-                return Err(e);
             }
+        }
+    }
 
-            match type_buf[0] {
-                0 => {
-                    // Put
-                    let mut len_buf = [0u8; 4];
-                    reader.read_exact(&mut len_buf)?;
-                    let key_len = u32::from_le_bytes(len_buf) as usize;
-                    let mut key = vec![0u8; key_len];
-                    reader.read_exact(&mut key)?;
+    /// Like [`Wal::recover_with_tail`], for a log written by [`Wal::open_encrypted`].
+    ///
+    /// A sealed entry cut short by a crash is a torn tail as usual, and so is a header cut
+    /// short while a new log was being created. A complete entry that fails authentication is
+    /// an `InvalidData` error. Fails with `PermissionDenied` if the log was encrypted with
+    /// another key.
+    #[cfg(feature = "encryption")]
+    pub fn recover_encrypted(
+        path: impl AsRef<Path>,
+        encryption: &Encryption,
+    ) -> io::Result<(Vec<WalEntry>, Option<u64>)> {
+        let (entries, report) = Self::recover_encrypted_with_report(path, encryption)?;
+        Ok((entries, report.torn_at()))
+    }
 
-                    reader.read_exact(&mut len_buf)?;
-                    let value_len = u32::from_le_bytes(len_buf) as usize;
-                    let mut value = vec![0u8; value_len];
-                    reader.read_exact(&mut value)?;
+    /// Like [`Wal::recover_encrypted`], but returns a [`RecoverReport`] as
+    /// [`Wal::recover_with_report`] does.
+    #[cfg(feature = "encryption")]
+    pub fn recover_encrypted_with_report(
+        path: impl AsRef<Path>,
+        encryption: &Encryption,
+    ) -> io::Result<(Vec<WalEntry>, RecoverReport)> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok((Vec::new(), RecoverReport::default()));
+        }
 
-                    entries.push(WalEntry::Put { key, value });
-                }
-                1 => {
-                    // Delete
-                    let mut len_buf = [0u8; 4];
-                    reader.read_exact(&mut len_buf)?;
-                    let key_len = u32::from_le_bytes(len_buf) as usize;
-                    let mut key = vec![0u8; key_len];
-                    reader.read_exact(&mut key)?;
-
-                    entries.push(WalEntry::Delete { key });
-                }
+        let data = std::fs::read(path)?;
+        let len = data.len() as u64;
+        if data.is_empty() {
+            return Ok((Vec::new(), RecoverReport::default()));
+        }
+        if data.len() < encryption::HEADER_SIZE && encryption::is_sealed(&data) {
+            let reason = "log header runs past the end of the log".to_string();
+            return Ok((Vec::new(), RecoverReport::torn(len, 0, reason)));
+        }
+        let cipher = FileCipher::from_header(&data, encryption)?;
+        let (records, torn_at) = open_frames(&cipher, &data[encryption::HEADER_SIZE..])?;
+        let mut entries = Vec::with_capacity(records.len());
+        for record in records {
+            let mut input = EntryReader {
+                reader: record.as_slice(),
+                remaining: record.len() as u64,
+            };
+            let entry = match input.bytes(1)? {
+                Some(kind) => input.entry(kind[0])?,
+                None => None,
+            };
+            match entry {
+                Some(entry) if input.remaining == 0 => entries.push(entry),
                 _ => {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidData,
-                        "Invalid WalEntry type",
+                        "sealed WAL record does not hold exactly one entry",
                     ));
                 }
             }
         }
-
-        Ok(entries)
+        let report = match torn_at {
+            Some(offset) => {
+                let offset = (encryption::HEADER_SIZE + offset) as u64;
+                let reason = format!(
+                    "sealed entry at offset {} runs past the end of the log",
+                    offset
+                );
+                RecoverReport::torn(len, offset, reason)
+            }
+            None => RecoverReport::clean(len),
+        };
+        Ok((entries, report))
     }
 
     /// Truncates the WAL, effectively clearing all recorded entries.
@@ -124,7 +450,18 @@ impl Wal {
             .write(true)
             .truncate(true)
             .open(&self.path)?;
-        self.writer = BufWriter::new(file);
+        let end = self.position();
+        self.len = 0;
+        #[cfg(feature = "encryption")]
+        if let Some(sealer) = &mut self.sealer {
+            let (fresh, header) = Sealer::new(&sealer.encryption)?;
+            (&file).write_all(&header)?;
+            *sealer = fresh;
+            self.len = header.len() as u64;
+        }
+        self.store = Box::new(file);
+        self.base = end - self.len;
+        self.torn = false;
         Ok(())
     }
 
@@ -134,6 +471,274 @@ impl Wal {
     }
 }
 
+/// Shares WAL fsyncs between concurrent writers.
+///
+/// Each writer appends its entry and then calls [`GroupCommit::commit`] with the position
+/// after it. The first writer to find no fsync in progress becomes the leader: it waits up to
+/// `window` for other writers to append, or until `max_bytes` are pending, then syncs once for
+/// all of them. Writers that arrive meanwhile wait for that fsync, or the next one.
+pub(crate) struct GroupCommit {
+    window: Duration,
+    max_bytes: u64,
+    state: Mutex<GroupState>,
+    cond: Condvar,
+}
+
+struct GroupState {
+    /// Highest position a writer is waiting on.
+    appended: u64,
+    /// Every entry before this position is durable.
+    synced: u64,
+    /// Whether a leader is gathering writers or syncing.
+    syncing: bool,
+    /// The last failed fsync, and the highest position that was waiting on it.
+    failed: Option<(u64, io::ErrorKind, String)>,
+}
+
+impl GroupCommit {
+    /// Creates a group commit for a log whose entries before `position` are already durable.
+    pub(crate) fn new(window: Duration, max_bytes: u64, position: u64) -> Self {
+        Self {
+            window,
+            max_bytes,
+            state: Mutex::new(GroupState {
+                appended: position,
+                synced: position,
+                syncing: false,
+                failed: None,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Returns once every entry before `position` is durable.
+    ///
+    /// `sync` makes the log durable and returns the position it synced up to; it is called by
+    /// at most one writer at a time. If it fails, every writer waiting on that fsync gets the
+    /// error, and their entries may or may not be durable.
+    pub(crate) fn commit(
+        &self,
+        position: u64,
+        sync: impl Fn() -> io::Result<u64>,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.appended = state.appended.max(position);
+        // A leader gathering writers may now have enough bytes to stop waiting.
+        self.cond.notify_all();
+        loop {
+            if state.synced >= position {
+                return Ok(());
+            }
+            if let Some((through, kind, message)) = &state.failed
+                && position <= *through
+            {
+                return Err(io::Error::new(*kind, message.clone()));
+            }
+            if state.syncing {
+                state = self.cond.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            let deadline = Instant::now() + self.window;
+            while state.appended - state.synced < self.max_bytes {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+            }
+            let waiting = state.appended;
+            drop(state);
+            let result = sync();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            self.cond.notify_all();
+            match result {
+                Ok(synced) => state.synced = state.synced.max(synced),
+                Err(e) => {
+                    state.failed = Some((waiting, e.kind(), e.to_string()));
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Bytes in front of each sealed entry of an encrypted WAL: its counter and sealed length.
+#[cfg(feature = "encryption")]
+const FRAME_HEADER_SIZE: usize = 12;
+
+/// Seals the entries of an encrypted WAL.
+///
+/// Each entry is written as its counter, the length of the sealed entry and the sealed entry
+/// itself. Counters only grow, even past an append that failed, so no two entries of one file
+/// are sealed under the same nonce.
+#[cfg(feature = "encryption")]
+struct Sealer {
+    encryption: Encryption,
+    cipher: FileCipher,
+    next: u64,
+}
+
+#[cfg(feature = "encryption")]
+impl Sealer {
+    /// Creates the sealer of a new log file, along with the header to start it with.
+    fn new(encryption: &Encryption) -> io::Result<(Self, Vec<u8>)> {
+        let (cipher, header) = FileCipher::create(encryption)?;
+        let sealer = Self {
+            encryption: encryption.clone(),
+            cipher,
+            next: 0,
+        };
+        Ok((sealer, header))
+    }
+
+    /// Seals the entry `record`.
+    fn seal(&mut self, record: &[u8]) -> Vec<u8> {
+        let sealed = self.cipher.seal(self.next, &[], record);
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + sealed.len());
+        frame.extend_from_slice(&self.next.to_le_bytes());
+        frame.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&sealed);
+        self.next += 1;
+        frame
+    }
+
+    /// Returns a sealer for a new log file, with the contents of that file: a new header
+    /// followed by the complete sealed entries `frames`, sealed again under the new nonce
+    /// prefix. The contents are as long as the header and `frames` together.
+    fn reseal(&self, frames: &[u8]) -> io::Result<(Self, Vec<u8>)> {
+        let (records, torn_at) = open_frames(&self.cipher, frames)?;
+        if torn_at.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WAL ends part-way through a sealed entry",
+            ));
+        }
+        let (mut sealer, mut contents) = Self::new(&self.encryption)?;
+        for record in records {
+            contents.extend_from_slice(&sealer.seal(&record));
+        }
+        Ok((sealer, contents))
+    }
+}
+
+/// Opens the sealed entries in `frames`, which start right after the header.
+///
+/// Returns the entries along with the offset in `frames` of a final entry cut short, if any.
+#[cfg(feature = "encryption")]
+fn open_frames(cipher: &FileCipher, frames: &[u8]) -> io::Result<(Vec<Vec<u8>>, Option<usize>)> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    let mut last_counter = None;
+    while offset < frames.len() {
+        let rest = &frames[offset..];
+        if rest.len() < FRAME_HEADER_SIZE {
+            return Ok((records, Some(offset)));
+        }
+        let counter = u64::from_le_bytes(rest[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(rest[8..FRAME_HEADER_SIZE].try_into().unwrap()) as usize;
+        let Some(sealed) = rest.get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len) else {
+            return Ok((records, Some(offset)));
+        };
+        // Entries cannot have been reordered or repeated without their counters showing it.
+        if last_counter.is_some_and(|last| counter <= last) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "sealed WAL entries are out of order",
+            ));
+        }
+        records.push(cipher.open(counter, &[], sealed)?);
+        last_counter = Some(counter);
+        offset += FRAME_HEADER_SIZE + len;
+    }
+    Ok((records, None))
+}
+
+/// Reads the fields of WAL entries, knowing how many bytes are left in the file.
+struct EntryReader<R> {
+    reader: R,
+    remaining: u64,
+}
+
+impl<R: Read> EntryReader<R> {
+    /// Reads the next `n` bytes, or returns `None` if the file ends before them.
+    fn bytes(&mut self, n: u64) -> io::Result<Option<Vec<u8>>> {
+        if n > self.remaining {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; n as usize];
+        self.reader.read_exact(&mut buf)?;
+        self.remaining -= n;
+        Ok(Some(buf))
+    }
+
+    /// Reads a length-prefixed field.
+    fn field(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let Some(len) = self.bytes(4)? else {
+            return Ok(None);
+        };
+        self.bytes(u32::from_le_bytes(len.try_into().unwrap()) as u64)
+    }
+
+    /// Reads the version of a versioned entry.
+    fn version(&mut self) -> io::Result<Option<Version>> {
+        Ok(self
+            .bytes(8)?
+            .map(|seqno| Version::from_seqno(u64::from_le_bytes(seqno.try_into().unwrap()))))
+    }
+
+    /// Reads the rest of a Put entry, or returns `None` if the file ends part-way through it.
+    fn put(&mut self, has_meta: bool, version: Option<Version>) -> io::Result<Option<WalEntry>> {
+        let Some(key) = self.field()? else {
+            return Ok(None);
+        };
+        let Some(value) = self.field()? else {
+            return Ok(None);
+        };
+        let meta = match has_meta {
+            true => match self.bytes(1)? {
+                Some(meta) => meta[0],
+                None => return Ok(None),
+            },
+            false => 0,
+        };
+        Ok(Some(WalEntry::Put {
+            key,
+            value,
+            meta,
+            version,
+        }))
+    }
+
+    /// Reads the rest of an entry of type `kind`, or returns `None` if the file ends part-way
+    /// through it.
+    fn entry(&mut self, kind: u8) -> io::Result<Option<WalEntry>> {
+        match kind {
+            // Put, with a trailing metadata tag for type 2
+            0 | 2 => self.put(kind == 2, None),
+            1 => self.delete(None),
+            // Versioned Put or Delete, led by the version
+            3 | 4 => match self.version()? {
+                Some(version) if kind == 3 => self.put(true, Some(version)),
+                Some(version) => self.delete(Some(version)),
+                None => Ok(None),
+            },
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid WalEntry type",
+            )),
+        }
+    }
+
+    /// Reads the rest of a Delete entry, or returns `None` if the file ends part-way through
+    /// it.
+    fn delete(&mut self, version: Option<Version>) -> io::Result<Option<WalEntry>> {
+        Ok(self.field()?.map(|key| WalEntry::Delete { key, version }))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -162,9 +767,34 @@ mod tests {
             WalEntry::Put {
                 key: b"k1".to_vec(),
                 value: b"v1".to_vec(),
+                meta: 0,
+                version: None,
             },
             WalEntry::Delete {
                 key: b"k2".to_vec(),
+                version: None,
+            },
+            WalEntry::Put {
+                key: b"k3".to_vec(),
+                value: b"v3".to_vec(),
+                meta: 7,
+                version: None,
+            },
+            WalEntry::Put {
+                key: b"k4".to_vec(),
+                value: b"v4".to_vec(),
+                meta: 0,
+                version: Some(Version {
+                    timestamp: 1 << 40,
+                    node_id: 9,
+                }),
+            },
+            WalEntry::Delete {
+                key: b"k4".to_vec(),
+                version: Some(Version {
+                    timestamp: (1 << 40) + 1,
+                    node_id: 2,
+                }),
             },
         ];
 
@@ -187,6 +817,8 @@ mod tests {
             wal.append(&WalEntry::Put {
                 key: vec![i as u8],
                 value: vec![i as u8; 10],
+                meta: 0,
+                version: None,
             })
             .unwrap();
         }
@@ -206,7 +838,104 @@ mod tests {
     }
 
     #[test]
-    fn test_corrupted_entry() {
+    fn test_recover_stops_at_torn_tail() {
+        let dir = setup_test_dir("torn_tail");
+        let wal_path = dir.join("torn.wal");
+        let kept = || WalEntry::Put {
+            key: b"ok".to_vec(),
+            value: b"val".to_vec(),
+            meta: 0,
+            version: None,
+        };
+        {
+            let mut wal = Wal::open(&wal_path).unwrap();
+            wal.append(&kept()).unwrap();
+            wal.append(&WalEntry::Put {
+                key: b"torn".to_vec(),
+                value: b"value".to_vec(),
+                meta: 9,
+                version: None,
+            })
+            .unwrap();
+        }
+        let bytes = std::fs::read(&wal_path).unwrap();
+        let boundary = 1 + 4 + 2 + 4 + 3;
+
+        // Ending exactly at an entry boundary is clean.
+        std::fs::write(&wal_path, &bytes[..boundary]).unwrap();
+        assert_eq!(
+            Wal::recover_with_tail(&wal_path).unwrap(),
+            (vec![kept()], None)
+        );
+
+        // Ending anywhere inside the second entry, including inside a length field, keeps
+        // the first one and reports where the torn entry starts.
+        for end in boundary + 1..bytes.len() {
+            std::fs::write(&wal_path, &bytes[..end]).unwrap();
+            assert_eq!(
+                Wal::recover_with_tail(&wal_path).unwrap(),
+                (vec![kept()], Some(boundary as u64)),
+                "cut at {}",
+                end
+            );
+        }
+
+        // A length that claims far more bytes than are left is a torn tail too.
+        std::fs::write(
+            &wal_path,
+            [&bytes[..boundary], &[1, 0xE8, 0x03, 0, 0, b'k']].concat(),
+        )
+        .unwrap();
+        assert_eq!(Wal::recover(&wal_path).unwrap(), vec![kept()]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_recover_report_counts_discarded_bytes() {
+        let dir = setup_test_dir("recover_report");
+        let wal_path = dir.join("report.wal");
+        {
+            let mut wal = Wal::open(&wal_path).unwrap();
+            for key in [&b"a"[..], b"b"] {
+                wal.append(&WalEntry::Delete {
+                    key: key.to_vec(),
+                    version: None,
+                })
+                .unwrap();
+            }
+        }
+        let clean_len = std::fs::metadata(&wal_path).unwrap().len();
+        let (entries, report) = Wal::recover_with_report(&wal_path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(report, RecoverReport::clean(clean_len));
+        assert_eq!(report.bytes_discarded, 0);
+        assert_eq!(report.torn_at(), None);
+
+        // A Put whose key claims 100 bytes, of which only two were written.
+        let mut f = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        f.write_all(&[0, 100, 0, 0, 0, b'c', b'c']).unwrap();
+        drop(f);
+        let (entries, report) = Wal::recover_with_report(&wal_path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(report.bytes_consumed, clean_len);
+        assert_eq!(report.bytes_discarded, 7);
+        assert_eq!(report.torn_at(), Some(clean_len));
+        let reason = report.stopped_reason.unwrap();
+        assert!(
+            reason.contains(&format!("offset {}", clean_len)),
+            "{}",
+            reason
+        );
+
+        assert_eq!(
+            Wal::recover_with_report(dir.join("missing.wal")).unwrap(),
+            (Vec::new(), RecoverReport::default())
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_unknown_entry_type_fails_recovery() {
         let dir = setup_test_dir("corrupt");
         let wal_path = dir.join("corrupt.wal");
         {
@@ -214,10 +943,12 @@ mod tests {
             it.append(&WalEntry::Put {
                 key: b"ok".to_vec(),
                 value: b"val".to_vec(),
+                meta: 0,
+                version: None,
             })
             .unwrap();
             let mut f = OpenOptions::new().append(true).open(&wal_path).unwrap();
-            f.write_all(&[0, 0, 0, 100]).unwrap();
+            f.write_all(&[9, 0, 0, 0, 0]).unwrap();
         }
 
         let recovered = Wal::recover(&wal_path);
@@ -233,6 +964,8 @@ mod tests {
         wal.append(&WalEntry::Put {
             key: b"k1".to_vec(),
             value: b"v1".to_vec(),
+            meta: 0,
+            version: None,
         })
         .unwrap();
 
@@ -241,4 +974,262 @@ mod tests {
         assert!(recovered.is_empty());
         let _ = std::fs::remove_dir_all(dir);
     }
+
+    #[test]
+    fn test_truncate_to_keeps_unflushed_tail() {
+        let dir = setup_test_dir("truncate_to");
+        let wal_path = dir.join("test.wal");
+        let put = |i: u8| WalEntry::Put {
+            key: vec![i],
+            value: vec![i; 10],
+            meta: 0,
+            version: None,
+        };
+        let mut wal = Wal::open(&wal_path).unwrap();
+        for i in 0..3 {
+            wal.append(&put(i)).unwrap();
+        }
+        let flushed = wal.position();
+        assert_eq!(flushed, 60);
+        wal.append(&put(3)).unwrap();
+        wal.append(&WalEntry::Delete {
+            key: vec![0],
+            version: None,
+        })
+        .unwrap();
+
+        wal.truncate_to(flushed).unwrap();
+        assert_eq!(wal.position(), 86);
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 26);
+        assert_eq!(
+            Wal::recover(&wal_path).unwrap(),
+            vec![
+                put(3),
+                WalEntry::Delete {
+                    key: vec![0],
+                    version: None
+                }
+            ]
+        );
+
+        // Appends continue after the kept tail, and an already discarded offset is a no-op.
+        wal.append(&put(4)).unwrap();
+        wal.truncate_to(flushed).unwrap();
+        drop(wal);
+        assert_eq!(
+            Wal::recover(&wal_path).unwrap(),
+            vec![
+                put(3),
+                WalEntry::Delete {
+                    key: vec![0],
+                    version: None
+                },
+                put(4)
+            ]
+        );
+
+        let mut wal = Wal::open(&wal_path).unwrap();
+        assert_eq!(wal.position(), 46);
+        let err = wal.truncate_to(47).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        wal.truncate_to(46).unwrap();
+        assert!(Wal::recover(&wal_path).unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_append_fault_leaves_only_complete_entries() {
+        use crate::storage::{FaultyStore, MemoryStore};
+
+        let put = |i: u8| WalEntry::Put {
+            key: vec![i],
+            value: vec![i; 10],
+            meta: 0,
+            version: None,
+        };
+        let store = MemoryStore::new();
+        // The first entry is 20 bytes; the disk fills up 5 bytes into the second.
+        let faulty = FaultyStore::fail_writes_after(store.clone(), 25);
+        let mut wal = Wal::with_store(faulty.clone(), PathBuf::from("faulty.wal"));
+
+        wal.append(&put(1)).unwrap();
+        assert!(wal.append(&put(2)).is_err());
+        assert_eq!(wal.position(), 20);
+        assert_eq!(store.to_vec().len(), 20);
+
+        // Once space is freed, retrying writes the entry exactly once.
+        faulty.allow_writes(1024);
+        wal.append(&put(2)).unwrap();
+        assert_eq!(wal.position(), 40);
+
+        let expected = MemoryStore::new();
+        let mut clean = Wal::with_store(expected.clone(), PathBuf::from("clean.wal"));
+        clean.append(&put(1)).unwrap();
+        clean.append(&put(2)).unwrap();
+        assert_eq!(store.to_vec(), expected.to_vec());
+    }
+
+    #[test]
+    fn test_append_fault_after_truncate_leaves_no_gap() {
+        use crate::storage::{FaultyStore, WritableStore};
+        use std::sync::Arc;
+
+        /// One file handle, and so one write position, shared by every clone.
+        #[derive(Clone)]
+        struct SharedFile(Arc<Mutex<File>>);
+
+        impl WritableStore for SharedFile {
+            fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+                WritableStore::write_all(&mut *self.0.lock().unwrap(), buf)
+            }
+
+            fn sync(&mut self) -> io::Result<()> {
+                WritableStore::sync(&mut *self.0.lock().unwrap())
+            }
+
+            fn set_len(&mut self, len: u64) -> io::Result<()> {
+                WritableStore::set_len(&mut *self.0.lock().unwrap(), len)
+            }
+        }
+
+        let dir = setup_test_dir("wal_fault_after_truncate");
+        let wal_path = dir.join("test.wal");
+        let put = |i: u8| WalEntry::Put {
+            key: vec![i],
+            value: vec![i; 10],
+            meta: 0,
+            version: None,
+        };
+        let mut wal = Wal::open(&wal_path).unwrap();
+        wal.append(&put(0)).unwrap();
+        wal.truncate().unwrap();
+        drop(wal);
+
+        // Reopened the way `Wal::truncate` does, without append mode, so the write position
+        // is the file's own cursor. The disk fills up 5 bytes into the second entry.
+        let file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&wal_path)
+            .unwrap();
+        let faulty = FaultyStore::fail_writes_after(SharedFile(Arc::new(Mutex::new(file))), 25);
+        let mut wal = Wal::with_store(faulty.clone(), wal_path.clone());
+        wal.append(&put(1)).unwrap();
+        assert!(wal.append(&put(2)).is_err());
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 20);
+
+        faulty.allow_writes(1024);
+        wal.append(&put(2)).unwrap();
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 40);
+        drop(wal);
+        assert_eq!(Wal::recover(&wal_path).unwrap(), vec![put(1), put(2)]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_wal_recovers_tears_and_truncates() {
+        let dir = setup_test_dir("wal_encrypted");
+        let wal_path = dir.join("test.wal");
+        let encryption = Encryption::new([3; 32]);
+        let put = |i: u8| WalEntry::Put {
+            key: vec![i],
+            value: b"secret".to_vec(),
+            meta: i,
+            version: None,
+        };
+
+        let mut wal = Wal::open_encrypted(&wal_path, &encryption).unwrap();
+        wal.append(&put(1)).unwrap();
+        let kept_from = wal.position();
+        wal.append(&put(2)).unwrap();
+        wal.append(&WalEntry::Delete {
+            key: vec![1],
+            version: None,
+        })
+        .unwrap();
+        let clean_len = wal.position();
+        drop(wal);
+        let raw = std::fs::read(&wal_path).unwrap();
+        assert!(!raw.windows(6).any(|w| w == b"secret"));
+
+        let mut wal = Wal::open_encrypted(&wal_path, &encryption).unwrap();
+        assert_eq!(wal.position(), clean_len);
+        wal.truncate_to(kept_from).unwrap();
+        assert_eq!(wal.position(), clean_len);
+        // Every entry ends up sealed under a nonce prefix the log never had before.
+        let header = encryption::HEADER_SIZE;
+        assert_ne!(std::fs::read(&wal_path).unwrap()[..header], raw[..header]);
+        wal.append(&put(3)).unwrap();
+        drop(wal);
+
+        // A torn final entry is cut off, as in a plain log.
+        let len = std::fs::metadata(&wal_path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&wal_path).unwrap();
+        file.set_len(len - 5).unwrap();
+        let (entries, torn_at) = Wal::recover_encrypted(&wal_path, &encryption).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                put(2),
+                WalEntry::Delete {
+                    key: vec![1],
+                    version: None
+                }
+            ]
+        );
+        assert_eq!(torn_at, Some(header as u64 + clean_len - kept_from));
+        let (_, report) = Wal::recover_encrypted_with_report(&wal_path, &encryption).unwrap();
+        assert_eq!(report.torn_at(), torn_at);
+        assert_eq!(report.bytes_consumed + report.bytes_discarded, len - 5);
+
+        let err = Wal::recover_encrypted(&wal_path, &Encryption::new([4; 32])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = Wal::recover(&wal_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_group_commit_shares_fsyncs() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let group = Arc::new(GroupCommit::new(Duration::from_millis(50), u64::MAX, 0));
+        let appended = Arc::new(AtomicU64::new(0));
+        let syncs = Arc::new(AtomicU64::new(0));
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let (group, appended, syncs) = (group.clone(), appended.clone(), syncs.clone());
+                std::thread::spawn(move || {
+                    let position = appended.fetch_add(10, Ordering::SeqCst) + 10;
+                    group.commit(position, || {
+                        syncs.fetch_add(1, Ordering::SeqCst);
+                        Ok(appended.load(Ordering::SeqCst))
+                    })
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        assert!(syncs.load(Ordering::SeqCst) < 8);
+
+        // Positions that are already durable need no fsync at all.
+        group.commit(80, || panic!("already synced")).unwrap();
+    }
+
+    #[test]
+    fn test_group_commit_reports_failed_fsync() {
+        let group = GroupCommit::new(Duration::ZERO, 0, 0);
+        let err = group
+            .commit(10, || Err(io::Error::other("disk gone")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "disk gone");
+        assert!(group.commit(10, || Ok(10)).is_err());
+
+        // A later write gets a fresh fsync.
+        group.commit(20, || Ok(20)).unwrap();
+        group.commit(10, || panic!("already synced")).unwrap();
+    }
 }
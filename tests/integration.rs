@@ -1,6 +1,5 @@
-use lsm_storage_engine::Engine;
-use std::path::PathBuf;
-use std::time::Duration;
+use lsm_storage_engine::{Engine, EngineOptions};
+use std::path::{Path, PathBuf};
 
 fn setup_test_dir(name: &str) -> PathBuf {
     let mut path = std::env::temp_dir();
@@ -16,6 +15,16 @@ fn setup_test_dir(name: &str) -> PathBuf {
     path
 }
 
+/// Opens an engine whose MemTable flushes after a few bytes, opting out of the warning.
+fn open_tiny(dir: &Path, max_memtable_size: usize) -> Engine {
+    let options = EngineOptions {
+        max_memtable_size,
+        allow_tiny_memtable: true,
+        ..EngineOptions::default()
+    };
+    Engine::open_with_options(dir, options).unwrap()
+}
+
 #[test]
 fn test_engine_operations_sequence() {
     let dir = setup_test_dir("sequence");
@@ -48,7 +57,7 @@ fn test_engine_operations_sequence() {
 fn test_engine_with_compaction_integration() {
     let dir = setup_test_dir("comp_int");
     // Use very small memtable to trigger many flushes
-    let engine = Engine::open(&dir, 20).unwrap();
+    let engine = open_tiny(&dir, 20);
 
     for i in 0..100 {
         let key = format!("k{:03}", i).into_bytes();
@@ -56,8 +65,7 @@ fn test_engine_with_compaction_integration() {
         engine.put(key, val).unwrap();
     }
 
-    // Give some time for background compaction
-    std::thread::sleep(Duration::from_millis(500));
+    engine.flush_and_wait_compaction().unwrap();
 
     // Verify all keys
     for i in 0..100 {
@@ -77,14 +85,14 @@ fn test_engine_with_compaction_integration() {
 fn test_recovery_full_scenario() {
     let dir = setup_test_dir("recovery");
     {
-        let engine = Engine::open(&dir, 50).unwrap();
+        let engine = open_tiny(&dir, 50);
         engine.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
         engine.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
         engine.delete(b"k3".to_vec()).unwrap();
     }
 
     // Reopen and check
-    let engine = Engine::open(&dir, 50).unwrap();
+    let engine = open_tiny(&dir, 50);
     assert_eq!(engine.get(b"k1").unwrap(), Some(b"v1".to_vec()));
     assert_eq!(engine.get(b"k2").unwrap(), Some(b"v2".to_vec()));
     assert_eq!(engine.get(b"k3").unwrap(), None);